@@ -1,50 +1,538 @@
-use crate::interface::{RobotError, RobotState, OCTService, OCTError, Move, Robot};
-use tokio::sync::{mpsc, oneshot, Notify};
-use tokio::time::{sleep, Duration, Instant};
+use crate::interface::{RobotError, RobotState, OCTService, OCTError, Move, Robot, RobotLimits};
+use crate::interface::{RobotEndpoint, DistanceRequest, StateRequest, MoveRequest, MovePriority};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{sleep, timeout, Duration, Instant};
 use std::collections::VecDeque;
+use tokio::task::JoinSet;
 use roots::find_root_brent;
+use roots::SearchError;
 use roots::SimpleConvergency;
-use crate::predictor::BrainPredictor;
+use crate::predictor::{BrainPredictor, CoefSink, DistanceWindow};
+use crate::motion;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 
-//How close we allow our robot to get to the brain
+//How close we allow our robot to get to the brain, we panic once it is within half of this
 const MIN_DISTANCE_BRAIN_TO_ARM_NM: u64 = 200_000;
+//How far above the closest the brain came during calibration we park the inserter, has to be more than the above
+const CALIBRATION_MARGIN_NM: u64 = 250_000;
 //Number of samples we take during the calibration period
 const CALIBRATION_SAMPLES: u64 = 1000;
-//Max size of queues
+//Number of samples we take to check a cached pre move location is still safe when we calibrate again
+const VERIFICATION_SAMPLES: usize = 100;
+//Max size of queues once calibrated
 const MAX_DISTANCES: u64 = 100;
 const MAX_STATES: u64 = 100;
 //Max time in brain before we panic
 const MAX_IB_TIME: u64 = 30_000; // HAS TO CHANGE
-//Max consecutive prediction errors before we panic
-const MAX_CONSECUTIVE_PREDICTION_ERRORS: u64 = 20;
+//We panic when ABNORMAL_THRESHOLD of the last ABNORMAL_WINDOW samples are abnormal
+const ABNORMAL_WINDOW: usize = 40;
+const ABNORMAL_THRESHOLD: usize = 20;
+//Minimum confidence (R^2 for the regression predictors) a prediction needs before we move on it
+const MIN_PREDICTION_CONFIDENCE: f64 = 0.5;
+//Number of insertion attempts at one depth before we record it as a failure and move on
+const MAX_ATTEMPTS_PER_DEPTH: u64 = 10;
+//Panics at one depth we recover from and retry after, the depth is recorded as a failure at the next one
+const MAX_PANIC_RECOVERIES_PER_DEPTH: u64 = 5;
+//Consecutive root finding failures within one insertion before we report a prediction error
+const MAX_CONSECUTIVE_PREDICTION_FAILURES: u64 = 5;
+//Calibrations in a row that can't find a safe pre move location before we give up on the brain
+const MAX_FAILED_CALIBRATIONS: u64 = 3;
+//Number of raw OCT samples the distance filter smooths over
+const DISTANCE_FILTER_WINDOW: usize = 5;
+//Number of recent raw samples, most of which have to be too close to the brain before we panic
+const TOO_CLOSE_WINDOW: usize = 3;
 //Max prediction error before we actually count it
 const MAX_PREDICTION_ERROR_NM: u64 = 50_000;
-//Max distance from robot to brain before moving
-const MAX_DIST_FROM_PREMOVE_TO_MOVE: u64 = MIN_DISTANCE_BRAIN_TO_ARM_NM + 3000;
+//How much further than the calibration margin the brain can be from the inserter for us to move
+const MOVE_TRIGGER_SLACK_NM: u64 = 3000;
+
+//Connection errors in a row we retry a robot request through before giving up on the robot
+const MAX_CONNECTION_RETRIES: u64 = 100;
+//OCT reads we try for the depth an insertion achieved before giving up on knowing it
+const ACHIEVED_DEPTH_READS: u64 = 3;
 
 //Polling rates
-const OCT_POLL_MILLIS: u64 = 5;
+pub(crate) const OCT_POLL_MILLIS: u64 = 5;
 const ROBOT_STATE_POLL_MILLIS: u64 = 5;
+//Default PollRates::slow_millis. Calibration counts samples, so polling slower makes it watch the brain
+//for longer
+const SLOW_POLL_MILLIS: u64 = 10;
+//Default PollRates::fast_within_nm, well before a move can trigger
+const FAST_POLL_WITHIN_NM: u64 = 2 * MIN_DISTANCE_BRAIN_TO_ARM_NM;
+//Most polls of one kind we let be in flight at once. The OCT answers one read at a time and is polled
+//faster than it answers, so requests queue up behind a slow read. This keeps the queue bounded while
+//there is always a request waiting for the OCT
+const MAX_OUTSTANDING_POLLS: usize = 4;
 const NEEDLE_ACCELERATION_NM_MS: i64 = 250;
-const COMMANDED_DEPTH_MIN_NM: u64 = 3_000_000;
-const COMMANDED_DEPTH_MAX_NM: u64 = 7_000_000;
+const NEEDLE_VELOCITY_NM_MS: u64 = 250_000;
+/// Shallowest and deepest depths (in nm) the controller accepts a command for by default.
+pub const COMMANDED_DEPTH_MIN_NM: u64 = 3_000_000;
+pub const COMMANDED_DEPTH_MAX_NM: u64 = 7_000_000;
 
 
-#[derive(PartialEq, Clone, Copy)]
-enum ControllerState {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ControllerState {
     Dead,
     OutOfBrainUncalibrated,
     OutOfBrainCalibrated,
     InBrain,
-    Panic
+    Panic(PanicReason)
+}
+
+/// DistanceFilter smooths the OCT distances the predictor sees over the last distance_filter_window
+/// raw samples. Safety checks always use the raw distance.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DistanceFilter {
+    None,
+    MovingAverage,
+    Median,
+}
+
+/// AbnormalDetector decides how far off a prediction may be before its sample counts as abnormal.
+///  - FixedThreshold: more than MAX_PREDICTION_ERROR_NM off, however noisy the brain is
+///  - RollingQuantile: more than factor times the quantile of the prediction errors of the last window normal
+///    samples off, and at least min_nm, so the threshold follows quiet and noisy periods. The window is
+///    filled while calibrating, until it is full the fixed threshold applies. Abnormal samples are left out
+///    of it, so a seizure doesn't raise the threshold it is judged by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AbnormalDetector {
+    FixedThreshold,
+    RollingQuantile { window: usize, quantile: f64, factor: f64, min_nm: f64 },
+}
+
+/// SoftLanding slows the needle down for the end of an insertion, so it doesn't arrive at full speed.
+/// The needle covers all but the last distance_nm of the insertion as usual, then the rest at no more than
+/// max_velocity_nm_ms. Insertions shorter than distance_nm are made at the capped velocity throughout.
+/// distance_nm should stay below the shallowest commanded depth, so the fast segment still ends in the brain.
+/// The target is predicted for when the slow segment ends, so a long slow segment costs accuracy.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SoftLanding {
+    pub distance_nm: u64,
+    pub max_velocity_nm_ms: u64,
+}
+
+/// DriftMonitor recalibrates us once the predictions have been off for a while, which catches slow degradation
+/// (such as sensor drift) that never makes a single sample abnormal. It only watches while we are calibrated.
+///  - window: number of recent samples the RMSE between predicted and observed distances is taken over
+///  - max_rmse_nm: RMSE the predictions may reach over a full window
+///  - sustain_ms: how long the RMSE has to stay above max_rmse_nm before we recalibrate
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DriftMonitor {
+    pub window: usize,
+    pub max_rmse_nm: f64,
+    pub sustain_ms: u64,
+}
+
+/// PanicCooldown holds the next insertion back after recovering from a panic until the brain has calmed down, so
+/// a seizure that is still going on doesn't make us panic again as soon as we go back in.
+///  - samples: number of recent samples the RMSE between predicted and observed distances is taken over
+///  - max_rmse_nm: RMSE the predictions may reach over a full window of samples for the brain to count as calm
+///  - max_wait_ms: how long we wait for the brain to calm down, after which the attempt counts as timed out
+///    and the next one waits again
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PanicCooldown {
+    pub samples: usize,
+    pub max_rmse_nm: f64,
+    pub max_wait_ms: u64,
+}
+
+/// RootFinding tunes the search for when the needle meets the commanded depth below the moving brain.
+/// The search runs from now until the time the longest commanded insertion takes plus bracket_margin_ms,
+/// a brain moving away from the needle quickly can put the meeting past that. eps and max_iter are the
+/// Brent solver's tolerance and iteration limit.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RootFinding {
+    pub bracket_margin_ms: f64,
+    pub eps: f64,
+    pub max_iter: usize,
+}
+
+impl Default for RootFinding {
+    fn default() -> Self {
+        RootFinding { bracket_margin_ms: 100.0, eps: 1e-15, max_iter: 30 }
+    }
+}
+
+/// GraspRetry retries a grasp that failed before giving up on the attempt. The first retry waits
+/// initial_backoff_ms, every one after waits twice as long as the one before, up to max_backoff_ms.
+/// max_retries of 0 gives up on the first failure.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GraspRetry {
+    pub max_retries: u64,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for GraspRetry {
+    fn default() -> Self {
+        GraspRetry { max_retries: 3, initial_backoff_ms: 10, max_backoff_ms: 100 }
+    }
+}
+
+/// PollRates sets how often the OCT and robot state are polled. Out of brain, while the last distance
+/// is further than fast_within_nm from the brain, we poll every slow_millis. Once the brain comes closer
+/// than that, and in every other state, we poll every fast_millis.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PollRates {
+    pub slow_millis: u64,
+    pub fast_millis: u64,
+    pub fast_within_nm: u64,
+}
+
+impl Default for PollRates {
+    fn default() -> Self {
+        PollRates { slow_millis: SLOW_POLL_MILLIS, fast_millis: OCT_POLL_MILLIS, fast_within_nm: FAST_POLL_WITHIN_NM }
+    }
+}
+
+/// PanicReason records what sent the controller into a panic.
+///  - TooClose: most recent distance samples came within half of the minimum safe distance to the brain,
+///    distance is their median
+///  - AbnormalDistances: too many recent samples didn't match our predictions
+///  - PredictionErrors: as AbnormalDistances, but the sample that tipped us over was a prediction error
+///  - NoSafeCalibration: calibration found the brain closer than the calibration margin
+///  - NotAtOrigin: calibration was entered with the robot away from the origin, at state
+///
+/// count is the number of abnormal samples within the abnormal window when we panicked.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PanicReason {
+    TooClose { distance: u64 },
+    AbnormalDistances { count: usize },
+    PredictionErrors { count: usize },
+    NoSafeCalibration { min_distance: u64 },
+    NotAtOrigin { state: RobotState },
+}
+
+impl std::fmt::Display for PanicReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PanicReason::TooClose { distance } => write!(f, "TooClose({}nm)", distance),
+            PanicReason::AbnormalDistances { count } => write!(f, "AbnormalDistances({})", count),
+            PanicReason::PredictionErrors { count } => write!(f, "PredictionErrors({})", count),
+            PanicReason::NoSafeCalibration { min_distance } => write!(f, "NoSafeCalibration({}nm)", min_distance),
+            PanicReason::NotAtOrigin { state } => write!(f, "NotAtOrigin(inserter {}nm, needle {}nm)", state.inserter_z, state.needle_z),
+        }
+    }
+}
+
+/// CalibrationError is why calibration couldn't start.
+///  - RobotStateUnavailable: the robot couldn't report its state, which kills the controller
+///  - NotAtOrigin: the robot wasn't at the origin, where calibration has to stare at the brain from
+///  - NotUncalibrated: the controller was in another state than OutOfBrainUncalibrated
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CalibrationError {
+    RobotStateUnavailable,
+    NotAtOrigin { state: RobotState },
+    NotUncalibrated { state: ControllerState },
+}
+
+impl std::fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CalibrationError::RobotStateUnavailable => write!(f, "robot state unavailable"),
+            CalibrationError::NotAtOrigin { state } => write!(f, "robot not at the origin: {:?}", state),
+            CalibrationError::NotUncalibrated { state } => write!(f, "controller in {} instead of OutOfBrainUncalibrated", state),
+        }
+    }
+}
+
+/// ControllerError is why a session ended before it went through every commanded depth.
+///  - CalibrationFailed: calibration couldn't start, or found no safe pre move location max_failed_calibrations times in a row
+///  - RobotDisconnected: the robot stopped answering, either its state or a move
+///  - RobotFault: the robot reported a position error, so we can no longer trust where it is
+///  - MoveFailed: the robot answered a state read with a failed move, so we don't know where it is
+///  - Aborted: an abort was requested, or a panic was recovered from with `PanicRecovery::Abort`
+///  - InvalidCommands: every commanded depth was outside the depths we insert to, so nothing was attempted
+#[derive(Debug, PartialEq, Clone)]
+pub enum ControllerError {
+    CalibrationFailed,
+    RobotDisconnected,
+    RobotFault,
+    MoveFailed,
+    Aborted,
+    InvalidCommands { rejected: Vec<u64> },
+}
+
+impl ControllerError {
+    //Why the controller dies when the robot answers with error
+    fn from_robot_error(error: &RobotError) -> ControllerError {
+        match error {
+            RobotError::PositionError { .. } => ControllerError::RobotFault,
+            RobotError::ConnectionError { .. } => ControllerError::RobotDisconnected,
+            RobotError::MoveError { .. } => ControllerError::MoveFailed,
+        }
+    }
+}
+
+impl std::fmt::Display for ControllerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ControllerError::CalibrationFailed => write!(f, "calibration failed"),
+            ControllerError::RobotDisconnected => write!(f, "robot disconnected"),
+            ControllerError::RobotFault => write!(f, "robot reported a position error"),
+            ControllerError::MoveFailed => write!(f, "robot reported a failed move"),
+            ControllerError::Aborted => write!(f, "aborted"),
+            ControllerError::InvalidCommands { rejected } => write!(f, "no commanded depth could be inserted to: {:?}", rejected),
+        }
+    }
+}
+
+impl std::error::Error for ControllerError {}
+
+/// SessionSummary is what a session that went through every commanded depth leaves behind.
+///  - move_records: the record of each commanded depth, as in `Controller::get_move_records`
+///  - panic_samples: the index of the distance sample behind each panic, as in `Controller::get_panic_samples`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSummary {
+    pub move_records: Vec<MoveRecord>,
+    pub panic_samples: Vec<u64>,
+}
+
+/// PanicRecovery is what we do once a panic has pulled the needle out of the brain.
+///  - Recalibrate: retract the inserter to the origin and calibrate again
+///  - RetryFromCalibrated: keep the inserter at the pre move location and try again without recalibrating
+///  - Abort: retract the inserter to the origin and die
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PanicRecovery {
+    Recalibrate,
+    RetryFromCalibrated,
+    Abort,
+}
+
+impl PanicReason {
+    /// The default recovery from this panic, which recalibrates whatever the reason.
+    pub fn default_recovery(&self) -> PanicRecovery {
+        PanicRecovery::Recalibrate
+    }
+
+    /// A recovery that skips the recalibration after prediction errors, which say nothing about where the brain
+    /// is, and retries from the pre move location instead. Every other reason recalibrates as by default.
+    pub fn retry_after_prediction_errors(&self) -> PanicRecovery {
+        match self {
+            PanicReason::PredictionErrors { .. } => PanicRecovery::RetryFromCalibrated,
+            _ => self.default_recovery(),
+        }
+    }
 }
 
+//Why an insertion attempt ended, so the run loop can decide whether to try again
+#[derive(Debug)]
 enum InBrainOutcome{
-    Success,
-    Failure,
-    Panic
+    //The needle reached the target we commanded, and the depth the OCT measured there, if we checked it
+    Success { final_target: u64, achieved_depth: Option<u64> },
+    //The needle reached the target we commanded, but the OCT measured it further than config.success_tolerance_nm
+    //from the commanded depth, or couldn't measure it at all
+    OutOfTolerance { final_target: u64, achieved_depth: Option<u64> },
+    //The robot couldn't report its state or make the move
+    Failure { cause: RobotError },
+    //The time budget ran out, either waiting to move or because the move wouldn't finish in time
+    Timeout,
+    Panic { reason: PanicReason },
+    //We died during the insertion, e.g. from a position error outside of the insertion itself
+    Dead,
+    //The target is outside the robot's limits, so it was rejected before or by the robot without moving
+    Unreachable,
+    //We couldn't grasp a thread, so we never entered the brain
+    GraspFailed,
+    //An abort was requested, so we retracted and died
+    Aborted,
+    //The drift monitor asked for a recalibration while we waited to move, so we went back to the origin
+    Drifted,
+}
+
+/// MoveRecord stores the result of inserting a thread at one commanded depth.
+///  - commanded_depth: depth below the brain surface we were asked to insert to in nm
+///  - predicted_target: needle position relative to inserter_z we commanded, if a move was sent
+///  - success: whether the in brain move completed without error
+///  - attempts: number of insertion attempts made, including ones ended by a panic
+///  - time_in_brain_ms: total time the needle spent in the brain across all attempts, from commanding it
+///    past zero until it was back at zero
+///  - achieved_depth: depth below the brain surface the OCT measured the needle at after the move, only
+///    measured with a config.success_tolerance_nm
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveRecord {
+    pub commanded_depth: u64,
+    pub predicted_target: Option<u64>,
+    pub success: bool,
+    pub attempts: u64,
+    pub time_in_brain_ms: u64,
+    pub achieved_depth: Option<u64>,
+}
+
+/// CalibrationReport describes the stare at the brain behind a calibration.
+///  - min_distance: closest the brain came to the inserter in nm
+///  - valid_samples, error_samples: number of samples stared at that were distances and that were errors
+///  - pre_move_location: where the inserter was parked, None if the brain came too close for anywhere to be safe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationReport {
+    pub min_distance: u64,
+    pub valid_samples: usize,
+    pub error_samples: usize,
+    pub pre_move_location: Option<u64>,
+}
+
+/// ControllerConfig holds the tunable parameters of the controller.
+///  - abnormal_window: number of recent distance samples we keep abnormal flags for
+///  - abnormal_threshold: number of abnormal samples within the window that triggers a panic
+///  - abnormal_detector: how a sample's prediction error is judged abnormal, see `AbnormalDetector`
+///  - min_prediction_confidence: predictions less confident than this are not moved on
+///  - max_attempts_per_depth: attempts at one commanded depth before it is recorded as a failure
+///  - max_panic_recoveries_per_depth: panics at one commanded depth we recover from and try it again after.
+///    The depth is recorded as a failure at the next panic, whether it came in or out of the brain
+///  - max_consecutive_prediction_failures: root finding failures in a row that count as one abnormal sample
+///  - max_ib_time_ms: time budget for one insertion, including the needle move itself
+///  - max_outstanding_polls: most distance (and, separately, robot state) requests in flight at once
+///  - max_failed_calibrations: calibrations in a row without a safe pre move location before we die
+///  - distance_filter: filter applied to the distances we predict from once calibrated
+///  - distance_filter_window: number of raw samples the distance filter covers
+///  - too_close_window: recent raw samples, most of which have to be too close to the brain to panic. 1 panics
+///    on any single sample
+///  - needle_velocity_nm_ms, needle_accel_nm_ms2: the robot's needle motion, which we time our moves with
+///  - verification_samples: samples a recalibration takes to check the cached pre move location is still safe
+///  - calibration_samples: samples a calibration from scratch stares at the brain for, at the fast poll rate.
+///    While we are uncalibrated the distance queue holds at least this many
+///  - distance_queue_capacity: most distance samples we keep once calibrated, which is what we predict from
+///  - panic_recovery: picks how we recover from each panic reason. PanicReason::default_recovery always
+///    recalibrates, PanicReason::retry_after_prediction_errors skips that after prediction errors
+///  - max_outcome_history: most move records (and so outcomes) we keep, dropping the oldest. None keeps them all
+///  - simultaneous_moves: reposition the inserter and needle with one `Move::Both` instead of one axis after the other
+///  - soft_landing: slow down the end of every insertion, None inserts at full speed
+///  - dry_run: plan moves without commanding them, see `Controller::get_planned_moves`
+///  - root_finding: how we search for the needle's intersection with the commanded depth
+///  - grasp_retry: how often and after how long we retry a failed grasp, see `GraspRetry`
+///  - min_commanded_depth_nm, max_commanded_depth_nm: range of commanded depths we insert to, depths outside
+///    of it are recorded as failures without an attempt
+///  - poll_rates: how often we poll the OCT and robot state, slower while far from the brain out of brain
+///  - min_distance_to_brain_nm: how close the inserter may get to the brain, we panic once most recent samples
+///    are within half of it
+///  - calibration_margin_nm: how far above the closest the brain came during calibration we park the inserter.
+///    Has to be larger than min_distance_to_brain_nm
+///  - transition_log: file every state change is appended to as `{elapsed_ms},{from},{to},{reason}`, with
+///    elapsed_ms counted from when the controller was built. Fields with a comma, such as a NotAtOrigin
+///    panic, are quoted. reason is `transition`, `from_panic` for a recovery out of a panic, `blocked` for
+///    a transition that was refused, or `set` for a state forced without the state machine's checks (such
+///    as dying). None logs nothing
+///  - success_tolerance_nm: how far from the commanded depth the OCT may measure a finished insertion before we
+///    count it as a failure. None counts every insertion the robot finished as a success
+///  - drift_monitor: recalibrate when the predictions stay off for a while, see `DriftMonitor`. None never does
+///  - panic_cooldown: wait for the brain to calm down after a panic before inserting again, see `PanicCooldown`.
+///    None inserts again as soon as we have recovered
+///  - allow_close_approach: never panic for the brain coming within half of min_distance_to_brain_nm, to study
+///    predictions close to the brain. Only the robot's limits keep the needle in check then, so this is unsafe
+///    outside of experiments
+#[derive(Debug, Clone)]
+pub struct ControllerConfig {
+    pub abnormal_window: usize,
+    pub abnormal_threshold: usize,
+    pub abnormal_detector: AbnormalDetector,
+    pub min_prediction_confidence: f64,
+    pub max_attempts_per_depth: u64,
+    pub max_panic_recoveries_per_depth: u64,
+    pub max_consecutive_prediction_failures: u64,
+    pub max_ib_time_ms: u64,
+    pub max_outstanding_polls: usize,
+    pub max_failed_calibrations: u64,
+    pub distance_filter: DistanceFilter,
+    pub distance_filter_window: usize,
+    pub too_close_window: usize,
+    pub needle_velocity_nm_ms: u64,
+    pub needle_accel_nm_ms2: i64,
+    pub verification_samples: usize,
+    pub calibration_samples: usize,
+    pub distance_queue_capacity: usize,
+    pub panic_recovery: fn(&PanicReason) -> PanicRecovery,
+    pub max_outcome_history: Option<usize>,
+    pub simultaneous_moves: bool,
+    pub soft_landing: Option<SoftLanding>,
+    pub dry_run: bool,
+    pub min_commanded_depth_nm: u64,
+    pub max_commanded_depth_nm: u64,
+    pub root_finding: RootFinding,
+    pub grasp_retry: GraspRetry,
+    pub poll_rates: PollRates,
+    pub transition_log: Option<PathBuf>,
+    pub min_distance_to_brain_nm: u64,
+    pub calibration_margin_nm: u64,
+    pub success_tolerance_nm: Option<u64>,
+    pub drift_monitor: Option<DriftMonitor>,
+    pub panic_cooldown: Option<PanicCooldown>,
+    pub allow_close_approach: bool,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        ControllerConfig {
+            abnormal_window: ABNORMAL_WINDOW,
+            abnormal_threshold: ABNORMAL_THRESHOLD,
+            abnormal_detector: AbnormalDetector::FixedThreshold,
+            min_prediction_confidence: MIN_PREDICTION_CONFIDENCE,
+            max_attempts_per_depth: MAX_ATTEMPTS_PER_DEPTH,
+            max_panic_recoveries_per_depth: MAX_PANIC_RECOVERIES_PER_DEPTH,
+            max_consecutive_prediction_failures: MAX_CONSECUTIVE_PREDICTION_FAILURES,
+            max_ib_time_ms: MAX_IB_TIME,
+            max_outstanding_polls: MAX_OUTSTANDING_POLLS,
+            max_failed_calibrations: MAX_FAILED_CALIBRATIONS,
+            distance_filter: DistanceFilter::None,
+            distance_filter_window: DISTANCE_FILTER_WINDOW,
+            too_close_window: TOO_CLOSE_WINDOW,
+            needle_velocity_nm_ms: NEEDLE_VELOCITY_NM_MS,
+            needle_accel_nm_ms2: NEEDLE_ACCELERATION_NM_MS,
+            verification_samples: VERIFICATION_SAMPLES,
+            calibration_samples: CALIBRATION_SAMPLES as usize,
+            distance_queue_capacity: MAX_DISTANCES as usize,
+            panic_recovery: PanicReason::default_recovery,
+            max_outcome_history: None,
+            simultaneous_moves: false,
+            soft_landing: None,
+            dry_run: false,
+            min_commanded_depth_nm: COMMANDED_DEPTH_MIN_NM,
+            max_commanded_depth_nm: COMMANDED_DEPTH_MAX_NM,
+            root_finding: RootFinding::default(),
+            grasp_retry: GraspRetry::default(),
+            poll_rates: PollRates::default(),
+            transition_log: None,
+            min_distance_to_brain_nm: MIN_DISTANCE_BRAIN_TO_ARM_NM,
+            calibration_margin_nm: CALIBRATION_MARGIN_NM,
+            success_tolerance_nm: None,
+            drift_monitor: None,
+            panic_cooldown: None,
+            allow_close_approach: false,
+        }
+    }
+}
+
+/// ControllerStatus is a snapshot of the controller's internals, taken under a single lock.
+///  - state: the state machine's current state
+///  - abnormal_count: abnormal distance samples within the current abnormal window
+///  - consecutive_prediction_failures: root finding failures in a row during the current insertion
+///  - pre_move_location: the calibrated inserter position, if we are calibrated
+///  - distance_queue_len: number of distance samples currently queued
+///  - last_distance: the most recent distance sample
+#[derive(Debug, Clone)]
+pub struct ControllerStatus {
+    pub state: ControllerState,
+    pub abnormal_count: usize,
+    pub consecutive_prediction_failures: u64,
+    pub pre_move_location: Option<u64>,
+    pub distance_queue_len: usize,
+    pub last_distance: Option<Result<u64, OCTError>>,
+}
+
+impl std::fmt::Display for ControllerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let last_distance = match &self.last_distance {
+            Some(Ok(distance)) => distance.to_string(),
+            Some(Err(_)) => "error".to_string(),
+            None => "none".to_string(),
+        };
+        write!(f, "{} abnormal={} prediction_failures={} premove={:?} queued={} last={}", self.state, self.abnormal_count,
+            self.consecutive_prediction_failures, self.pre_move_location, self.distance_queue_len, last_distance)
+    }
 }
 
 impl std::fmt::Display for ControllerState {
@@ -54,7 +542,7 @@ impl std::fmt::Display for ControllerState {
             ControllerState::OutOfBrainUncalibrated => write!(f, "OutOfBrainUncalibrated"),
             ControllerState::OutOfBrainCalibrated => write!(f, "OutOfBrainCalibrated"),
             ControllerState::InBrain => write!(f, "InBrain"),
-            ControllerState::Panic => write!(f, "Panic")
+            ControllerState::Panic(reason) => write!(f, "Panic({})", reason)
         }
     }
 }
@@ -64,30 +552,153 @@ pub struct ControllerInfo{
     current_state: ControllerState, //ControllerState,
     distance_queue: VecDeque<Result<u64, OCTError>>, //VecDeque<(Result<u64, OCTError>, Instant>>>,
     distance_time_queue: VecDeque<Instant>,
+    raw_distance_window: VecDeque<u64>, //The last distance_filter_window raw Ok distances, for the distance filter
+    too_close_window: VecDeque<u64>, //The last too_close_window raw Ok distances, for the too close check
     robot_queue: VecDeque<Result<RobotState, RobotError>>, //VecDeque<(Result<RobotState, RobotError>, Instant>>>,
     robot_time_queue: VecDeque<Instant>,
-    consecutive_errors: u64, //Local prediction errors
+    abnormal_flags: VecDeque<bool>, //Whether each of the last abnormal_window samples was abnormal
+    normal_errors: VecDeque<f64>, //Prediction errors of the last normal samples, for the rolling quantile detector
+    quantile_buffer: Vec<f64>, //Scratch copy of normal_errors the quantile is selected in, reused across samples
+    prediction_errors: RmseWindow, //Prediction errors of the last drift_monitor.window samples
+    consecutive_prediction_failures: u64, //Root finding failures in a row during the current insertion
+    failed_calibrations: u64, //Calibrations in a row that couldn't find a safe pre move location
     pre_move_location: Option<u64>, //u64
-    pub outcomes: Vec<bool>,
+    cached_pre_move_location: Option<u64>, //The last calibrated pre move location, kept across recalibrations
+    calibration_samples: Vec<usize>, //How long each calibration stared at the brain for, in samples at the fast poll rate
+    last_calibration: Option<CalibrationReport>, //What the last calibration to finish saw
+    move_records: VecDeque<MoveRecord>, //The last max_outcome_history records, oldest first
     notified_distances: Vec<Result<u64, OCTError>>,
     notified_distance_times: Vec<Instant>,
+    shutdown_requested: bool,
+    abort_requested: bool,
+    samples_processed: u64, //Number of distance samples process_distances has handled
+    panic_samples: Vec<u64>, //Index of the distance sample that caused each panic
+    planned_moves: Vec<Move>, //Moves a dry run would have commanded, in order
+    planned_state: RobotState, //Where a dry run's planned moves have taken the robot
+    needle_in_since: Option<Instant>, //When the needle was commanded past zero, None while it is at zero
+    time_in_brain: Duration, //Time the needle spent in the brain since it was last taken
+    session_active: bool, //Whether a session is running on this controller
+    death_cause: Option<ControllerError>, //What first killed the controller this session, None if nothing has
+    prediction_cache: Option<CachedPrediction>, //The last prediction made, reused until a newer sample arrives
+    cooldown_errors: Option<RmseWindow>, //Prediction errors of the samples since a panic, None unless cooling down
 }
 
 impl ControllerInfo{
+    fn new(config: &ControllerConfig) -> ControllerInfo {
+        ControllerInfo{
+            current_state: ControllerState::Dead, //ControllerState::Dead,
+            distance_queue: VecDeque::new(), //VecDeque::new(),
+            robot_queue: VecDeque::new(), //VecDeque::new(),
+            distance_time_queue: VecDeque::new(), //VecDeque::new(),
+            raw_distance_window: VecDeque::new(),
+            too_close_window: VecDeque::new(),
+            robot_time_queue: VecDeque::new(),
+            abnormal_flags: VecDeque::with_capacity(config.abnormal_window),
+            normal_errors: VecDeque::new(),
+            quantile_buffer: Vec::new(),
+            prediction_errors: RmseWindow::default(),
+            consecutive_prediction_failures: 0,
+            failed_calibrations: 0,
+            pre_move_location: None,
+            cached_pre_move_location: None,
+            calibration_samples: Vec::new(),
+            last_calibration: None,
+            move_records: VecDeque::new(),
+            notified_distances: Vec::new(),
+            notified_distance_times: Vec::new(),
+            shutdown_requested: false,
+            abort_requested: false,
+            samples_processed: 0,
+            panic_samples: Vec::new(),
+            planned_moves: Vec::new(),
+            planned_state: RobotState{inserter_z: 0, needle_z: 0},
+            needle_in_since: None,
+            time_in_brain: Duration::ZERO,
+            session_active: false,
+            death_cause: None,
+            prediction_cache: None,
+            cooldown_errors: None,
+        }
+    }
+
     fn clear_distance_queue(&mut self) {
         self.distance_queue.clear();
         self.distance_time_queue.clear();
+        self.raw_distance_window.clear();
+        self.too_close_window.clear();
+        self.prediction_errors.clear();
+        self.prediction_cache = None;
+    }
+}
+
+//A brain position function from the predictor, shared so that a cached prediction can be handed out again
+type Forecast = Arc<dyn Fn(f64) -> f64 + Send + Sync>;
+
+//A prediction along with the time of the newest sample it was made from, when it was made, and the
+//coefficients (with any R^2) the predictor handed to its sink while making it. Predictions only depend on
+//their samples, so within one OCT cycle the move location reuses the prediction the abnormal check just
+//made instead of fitting the same samples again
+struct CachedPrediction {
+    latest: Instant,
+    made_at: Instant,
+    prediction: Option<(Forecast, f64)>,
+    coefs: Vec<(Vec<f64>, Option<f64>)>,
+}
+
+//Running RMSE of the differences between predicted and observed distances, for the drift monitor and the panic cooldown
+#[derive(Debug, Default)]
+struct RmseWindow {
+    squared_errors: VecDeque<f64>,
+    sum: f64,
+    breached_since: Option<Instant>, //When the RMSE last went above the monitor's limit, None while it is below
+}
+
+impl RmseWindow {
+    //Adds a prediction error to the last window ones, returning their RMSE once there are window of them
+    fn push(&mut self, error: f64, window: usize) -> Option<f64> {
+        self.squared_errors.push_back(error * error);
+        self.sum += error * error;
+        while self.squared_errors.len() > window {
+            self.sum -= self.squared_errors.pop_front().unwrap();
+        }
+        (self.squared_errors.len() == window).then(|| (self.sum.max(0.0) / window as f64).sqrt())
+    }
+
+    //Adds the prediction error of a sample taken at time, returning whether the RMSE over a full window has been
+    //above monitor.max_rmse_nm for monitor.sustain_ms
+    fn add(&mut self, error: f64, time: Instant, monitor: &DriftMonitor) -> bool {
+        if !self.push(error, monitor.window).is_some_and(|rmse| rmse > monitor.max_rmse_nm) {
+            self.breached_since = None;
+            return false;
+        }
+        let breached_since = *self.breached_since.get_or_insert(time);
+        time.saturating_duration_since(breached_since) >= Duration::from_millis(monitor.sustain_ms)
+    }
+
+    fn clear(&mut self) {
+        self.squared_errors.clear();
+        self.sum = 0.0;
+        self.breached_since = None;
     }
 }
 
-pub struct Controller<P: BrainPredictor>{
+//The controller talks to the robot and OCT through R. By default this is a RobotEndpoint over channels, but any
+//in process Robot + OCTService can be driven directly through Controller::with_robot
+pub struct Controller<P: BrainPredictor, R: Robot + OCTService = RobotEndpoint>{
     info: Mutex<ControllerInfo>,
-    distance_tx: mpsc::Sender<((), oneshot::Sender<Result<u64, OCTError>>)>,
-    state_tx: mpsc::Sender<((), oneshot::Sender<Result<RobotState, RobotError>>)>,
-    move_tx: mpsc::Sender<(Move, oneshot::Sender<Result<(), RobotError>>)>,
-    dead_tx: mpsc::Sender<()>,
+    robot: Arc<R>,
+    //Only set when the robot is on the other end of channels and has to be told to stop
+    dead_tx: Option<mpsc::Sender<()>>,
     predictor: P,
     can_move: Notify,
+    shutdown: Notify,
+    abort: Notify,
+    //Woken whenever we enter a panic, so an insertion in flight can be cut short
+    panicked: Notify,
+    //Buffered, and flushed once we die, so logging doesn't write to disk on every transition
+    transition_log: Option<Mutex<BufWriter<File>>>,
+    built_at: Instant,
+    config: ControllerConfig,
 }
 
 impl<P: BrainPredictor> Controller<P>{
@@ -104,11 +715,11 @@ impl<P: BrainPredictor> Controller<P>{
     ///
     /// The robot time queue stores the times at which the robot states were received.
     ///
-    /// The consecutive errors variable stores the number of consecutive prediction errors.
+    /// The abnormal flags queue stores whether each recent distance sample was abnormal.
     ///
     /// The pre move location variable stores the location of the inserter z after calibration
     ///
-    /// The outcomes vector stores the outcomes of the last few moves.
+    /// The move records vector stores one record per commanded depth.
     ///
     /// The notified distances vector stores the distances that have been notified to the
     /// controller.
@@ -116,29 +727,62 @@ impl<P: BrainPredictor> Controller<P>{
     /// The notified distance times vector stores the times at which the distances were
     /// notified.
     
-    pub fn new(distance_tx: mpsc::Sender<((), oneshot::Sender<Result<u64, OCTError>>)>,
-    state_tx: mpsc::Sender<((), oneshot::Sender<Result<RobotState, RobotError>>)>,
-    move_tx: mpsc::Sender<(Move, oneshot::Sender<Result<(), RobotError>>)>,
-    dead_tx: mpsc::Sender<()>, predictor: P) -> Controller<P>{
+    pub fn new(distance_tx: mpsc::Sender<DistanceRequest>, state_tx: mpsc::Sender<StateRequest>,
+    move_tx: mpsc::Sender<MoveRequest>, dead_tx: mpsc::Sender<()>, predictor: P) -> Controller<P>{
+        Controller::with_config(distance_tx, state_tx, move_tx, dead_tx, predictor, ControllerConfig::default())
+    }
+
+    /// Creates a new controller like `new`, but with the given tunable parameters.
+    pub fn with_config(distance_tx: mpsc::Sender<DistanceRequest>, state_tx: mpsc::Sender<StateRequest>,
+    move_tx: mpsc::Sender<MoveRequest>, dead_tx: mpsc::Sender<()>, predictor: P, config: ControllerConfig) -> Controller<P>{
+        Controller::with_endpoint(RobotEndpoint::new(distance_tx, state_tx, move_tx, dead_tx), predictor, config)
+    }
+
+    /// Creates a new controller that drives whatever robot answers the other end of the endpoint.
+    pub fn with_endpoint(endpoint: RobotEndpoint, predictor: P, config: ControllerConfig) -> Controller<P>{
+        let dead_tx = endpoint.dead_tx();
+        Controller::build(Arc::new(endpoint), Some(dead_tx), predictor, config)
+    }
+}
+
+impl<P: BrainPredictor, R: Robot + OCTService> Controller<P, R>{
+
+    /// Creates a new controller that calls the given robot directly instead of going through channels.
+    pub fn with_robot(robot: Arc<R>, predictor: P) -> Controller<P, R>{
+        Controller::build(robot, None, predictor, ControllerConfig::default())
+    }
+
+    fn build(robot: Arc<R>, dead_tx: Option<mpsc::Sender<()>>, predictor: P, config: ControllerConfig) -> Controller<P, R>{
+        //Parked at the minimum distance, the brain's usual motion would already bring it too close
+        assert!(config.calibration_margin_nm > config.min_distance_to_brain_nm,
+            "Calibration margin of {}nm has to be larger than the minimum distance to the brain of {}nm", config.calibration_margin_nm, config.min_distance_to_brain_nm);
+        assert!(config.calibration_samples > 0 && config.distance_queue_capacity > 0, "Calibration and the distance queue need at least one sample");
+        if config.allow_close_approach {
+            println!("!!! WARNING: allow_close_approach is set, the controller will NOT panic when the brain comes within {}nm. Experiments only !!!",
+                config.min_distance_to_brain_nm / 2);
+        }
+        //Losing the log shouldn't stop us from inserting, so a file we can't open only leaves us without one
+        let transition_log = config.transition_log.as_ref().and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(Mutex::new(BufWriter::new(file))),
+                Err(error) => {
+                    println!("Could not open transition log {}: {}", path.display(), error);
+                    None
+                }
+            }
+        });
         Controller{
-            info: Mutex::new(ControllerInfo{
-                current_state: ControllerState::Dead, //ControllerState::Dead,
-                distance_queue: VecDeque::new(), //VecDeque::new(),
-                robot_queue: VecDeque::new(), //VecDeque::new(),
-                distance_time_queue: VecDeque::new(), //VecDeque::new(),
-                robot_time_queue: VecDeque::new(),
-                consecutive_errors: 0,
-                pre_move_location: None,
-                outcomes:Vec::new(),
-                notified_distances: Vec::new(),
-                notified_distance_times: Vec::new(),
-            }),
-            distance_tx,
-            state_tx,
-            move_tx,
+            info: Mutex::new(ControllerInfo::new(&config)),
+            robot,
             dead_tx,
             predictor,
             can_move: Notify::new(),
+            shutdown: Notify::new(),
+            abort: Notify::new(),
+            panicked: Notify::new(),
+            transition_log,
+            built_at: Instant::now(),
+            config,
         }
     }
 
@@ -152,9 +796,19 @@ impl<P: BrainPredictor> Controller<P>{
         info.current_state == ControllerState::OutOfBrainCalibrated
     }
 
+    //Out of brain and far from it we only need the occasional sample, everywhere else we poll fast
+    fn poll_interval(&self) -> Duration {
+        let rates = &self.config.poll_rates;
+        let info = self.info.lock().unwrap();
+        let out_of_brain = matches!(info.current_state, ControllerState::OutOfBrainUncalibrated | ControllerState::OutOfBrainCalibrated);
+        let far = matches!(info.distance_queue.back(), Some(Ok(distance)) if *distance > rates.fast_within_nm);
+        let millis = if out_of_brain && far { rates.slow_millis } else { rates.fast_millis };
+        Duration::from_millis(millis)
+    }
+
     fn in_panic(&self) -> bool {
         let info = self.info.lock().unwrap();
-        info.current_state == ControllerState::Panic
+        matches!(info.current_state, ControllerState::Panic(_))
     }
 
     fn in_brain(&self) -> bool {
@@ -178,8 +832,9 @@ impl<P: BrainPredictor> Controller<P>{
     /// This function uses the predicted brain position and the commanded depth to 
     /// determine the optimal move location for the robot. It first checks if the 
     /// brain is close enough to the needle before proceeding. If the brain is too 
-    /// far, the function returns `None`. It uses a function to calculate the 
-    /// intersection of the brain's predicted path and the needle's path, and returns the position
+    /// far, receding faster than the needle can travel, or the predictor isn't
+    /// confident enough in its fit, the function returns `None`. It uses a function
+    /// to calculate the intersection of the brain's predicted path and the needle's path, and returns the position
     /// relative to the inserter z the needle should move based on the intersection. If a valid 
     /// root is found, it returns the calculated move location; otherwise, it returns 
     /// `None`.
@@ -188,56 +843,293 @@ impl<P: BrainPredictor> Controller<P>{
     /// - `commanded_depth`: The depth to which the robot is commanded to move.
     ///
     /// # Returns
-    /// `Option<u64>`: The calculated move location if successful, otherwise `None`.
-    fn get_move_location(&self, commanded_depth: u64) -> Option<u64> {
-        let info = self.info.lock().unwrap();
-        let Some(brain_position_function) = self.predictor.predict(&info.notified_distances, &info.notified_distance_times, true) else {
+    /// `Ok(Some(u64))`: The calculated move location if successful.
+    /// `Ok(None)`: We shouldn't move right now, but the prediction itself didn't fail.
+    /// `Err(OCTError::PredictionError)`: No intersection between the needle and the brain was found.
+    fn get_move_location(&self, commanded_depth: u64) -> Result<Option<u64>, OCTError> {
+        let mut info = self.info.lock().unwrap();
+        let info = &mut *info;
+        let window = DistanceWindow::new(&info.notified_distances, &info.notified_distance_times);
+        let print_coefs = |coefs: &[f64], r_squared: Option<f64>| match r_squared {
+            Some(r_squared) => println!("Coefs: {:?}, R^2: {}", coefs, r_squared),
+            None => println!("Coefs: {:?}", coefs),
+        };
+        //The notified samples are the ones the abnormal check predicted from, so this is usually a cache hit
+        let Some((brain_position_function, confidence)) = self.predict_cached(&mut info.prediction_cache, &window, Some(&print_coefs)) else {
             println!("No brain position function");
-            return None;
+            return Ok(None);
         };
+        //A poor fit is treated the same as no fit at all
+        if confidence < self.config.min_prediction_confidence {
+            println!("Prediction confidence too low: {}", confidence);
+            return Ok(None);
+        }
         //We only move the robot if the brain is sufficiently close to the needle before moving
-        if info.notified_distances.last().cloned().unwrap().is_err() || info.notified_distances.last().cloned().unwrap().unwrap() > MAX_DIST_FROM_PREMOVE_TO_MOVE {
+        if !matches!(info.notified_distances.last(), Some(Ok(distance)) if *distance <= self.move_trigger_distance()) {
             println!("We are too far away from the brain to move");
-            return None;
+            return Ok(None);
         }
         //We calculate how far to move the robot based on where its path intersects the commanded location's path
-        let needle_pos = |x: f64| {NEEDLE_ACCELERATION_NM_MS as f64/4.0 * x * x};
+        //A move to needle_pos(x) arrives after x ms on the robot's own trapezoidal profile
+        let needle_pos = |x: f64| self.needle_distance_for_move_time(x);
         let intersection_fn = |x|{brain_position_function(x as f64) + commanded_depth as f64 - needle_pos(x as f64)};
-        let furthest_needle_move = (4.0*COMMANDED_DEPTH_MAX_NM as f64/NEEDLE_ACCELERATION_NM_MS as f64).sqrt()+100.0;
-        let mut convergency = SimpleConvergency { eps:1e-15f64, max_iter:30 };
-        let Ok(root) = find_root_brent(0.0, furthest_needle_move, &intersection_fn, &mut convergency) else{
-            println!("Failed to find root with furthest needle move: {}", furthest_needle_move);
-            return None;
-        };
-        return Some(brain_position_function(root) as u64 + commanded_depth);
+        let root_finding = self.config.root_finding;
+        let furthest_needle_move = self.needle_move_time(self.config.max_commanded_depth_nm).as_millis() as f64 + root_finding.bracket_margin_ms;
+        //A prediction that isn't a number has nowhere to move to, rather than a root we failed to find
+        if !brain_position_function(0.0).is_finite() || !brain_position_function(furthest_needle_move).is_finite() {
+            println!("Brain position is not finite within {} ms", furthest_needle_move);
+            return Ok(None);
+        }
+        //A brain receding faster than the needle can travel can't be caught, whatever root Brent comes up with,
+        //so we wait for it to slow down instead of chasing it
+        if let Some(kinematics) = self.predictor.predict_kinematics(&window) {
+            if kinematics.velocity > self.config.needle_velocity_nm_ms as f64 {
+                println!("Brain receding at {} nm/ms, faster than the needle's {} nm/ms", kinematics.velocity, self.config.needle_velocity_nm_ms);
+                return Ok(None);
+            }
+        }
+        let mut convergency = SimpleConvergency { eps: root_finding.eps, max_iter: root_finding.max_iter };
+        match find_root_brent(0.0, furthest_needle_move, &intersection_fn, &mut convergency) {
+            Ok(root) => {
+                let brain_position = brain_position_function(root);
+                let target = brain_position + commanded_depth as f64;
+                //Casting NaN gives 0 and infinity gives u64::MAX, either would send the needle somewhere no prediction put it
+                if !root.is_finite() || !brain_position.is_finite() || brain_position < 0.0 || !target.is_finite() || target > u64::MAX as f64 {
+                    println!("Invalid move location: brain at {} after {} ms", brain_position, root);
+                    return Ok(None);
+                }
+                Ok(Some(target as u64))
+            }
+            //The intersection has the same sign at both ends, so the needle can't meet the depth within the bracket
+            Err(SearchError::NoBracketing) => {
+                println!("No needle intersection within {} ms", furthest_needle_move);
+                Err(OCTError::PredictionError { msg: format!("No needle intersection within {} ms", furthest_needle_move), at_ms: None })
+            }
+            Err(error) => {
+                println!("Root finding failed within {} ms: {:?}", furthest_needle_move, error);
+                Err(OCTError::PredictionError { msg: format!("Root finding failed within {} ms: {:?}", furthest_needle_move, error), at_ms: None })
+            }
+        }
     }
     
+    //Sends a move to the robot at the given priority, or only plans it in a dry run
+    async fn send_move(&self, move_type: &Move, priority: MovePriority) -> Result<(), RobotError> {
+        self.track_needle_move(move_type, None);
+        let response = if self.config.dry_run {
+            println!("Dry run, planned move: {}", move_type);
+            let mut info = self.info.lock().unwrap();
+            info.planned_state = state_after_move(info.planned_state, move_type);
+            info.planned_moves.push(move_type.clone());
+            Ok(())
+        } else {
+            match priority {
+                MovePriority::Normal => self.robot.command_move(move_type).await,
+                MovePriority::Emergency => self.robot.command_emergency_move(move_type).await,
+            }
+        };
+        self.track_needle_move(move_type, Some(response.is_ok()));
+        response
+    }
+
+    //How long an insertion of distance_nm takes, soft landing if configured
+    fn needle_move_time(&self, distance_nm: u64) -> Duration {
+        let (velocity, accel) = (self.config.needle_velocity_nm_ms, self.config.needle_accel_nm_ms2);
+        match self.config.soft_landing {
+            Some(landing) => motion::calculate_soft_landing_move_time(distance_nm as i64, velocity, accel, landing.distance_nm, landing.max_velocity_nm_ms),
+            None => motion::calculate_needlez_move_time(distance_nm as i64, velocity, accel),
+        }
+    }
+
+    //Inverse of needle_move_time
+    fn needle_distance_for_move_time(&self, total_ms: f64) -> f64 {
+        let (velocity, accel) = (self.config.needle_velocity_nm_ms, self.config.needle_accel_nm_ms2);
+        match self.config.soft_landing {
+            Some(landing) => motion::soft_landing_distance_for_move_time(total_ms, velocity, accel, landing.distance_nm, landing.max_velocity_nm_ms),
+            None => motion::needlez_distance_for_move_time(total_ms, velocity, accel),
+        }
+    }
+
+    //Predicts from window, reusing the cached prediction if it was made from samples up to the same newest one,
+    //in which case its coefficients are handed to coef_sink again. A prediction is only reused within the fast poll
+    //interval it was made in, past that its samples have aged and the predictor has to judge their staleness again.
+    //An empty window is never cached
+    fn predict_cached(&self, cache: &mut Option<CachedPrediction>, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(Forecast, f64)> {
+        let Some(latest) = window.times().last().copied() else {
+            return self.predictor.predict(window, coef_sink).map(|(forecast, confidence)| (Arc::new(forecast) as Forecast, confidence));
+        };
+        let max_age = Duration::from_millis(self.config.poll_rates.fast_millis);
+        if let Some(cached) = cache.as_ref().filter(|cached| cached.latest == latest && cached.made_at.elapsed() < max_age) {
+            if let Some(sink) = coef_sink {
+                cached.coefs.iter().for_each(|(coefs, r_squared)| sink(coefs, *r_squared));
+            }
+            return cached.prediction.clone();
+        }
+        let coefs = RefCell::new(Vec::new());
+        let record_coefs = |fitted: &[f64], r_squared: Option<f64>| {
+            coefs.borrow_mut().push((fitted.to_vec(), r_squared));
+            if let Some(sink) = coef_sink {
+                sink(fitted, r_squared);
+            }
+        };
+        let prediction = self.predictor.predict(window, Some(&record_coefs))
+            .map(|(forecast, confidence)| (Arc::new(forecast) as Forecast, confidence));
+        *cache = Some(CachedPrediction { latest, made_at: Instant::now(), prediction: prediction.clone(), coefs: coefs.into_inner() });
+        prediction
+    }
+
+    //How far distance is from where the samples before it predicted it, None if they predict nothing
+    fn prediction_error(&self, distance: u64) -> Option<f64> {
+        let mut info = self.info.lock().unwrap();
+        //Predict straight off the queues instead of copying them on every sample
+        let info = &mut *info;
+        let last_time = info.distance_time_queue.back().copied()?;
+        let window = DistanceWindow::new(info.distance_queue.make_contiguous(), info.distance_time_queue.make_contiguous());
+        let (brain_position_function, _) = self.predict_cached(&mut info.prediction_cache, &window, None)?;
+        let prediction = brain_position_function(last_time.elapsed().as_millis() as f64);
+        Some((distance as f64 - prediction).abs())
+    }
+
     //This function checks if the the brain has abnormal moving activity
     //The hyper local predictions allow us to check in real time whether the
     //brian is moving abnormally, or "siezing". In the case it is, we panic.
-    fn is_abnormal_distance(&self, distance: u64) -> bool {
-        let info = self.info.lock().unwrap();
-        let distances = Vec::from(info.distance_queue.clone());
-        let times = Vec::from(info.distance_time_queue.clone());
-        let Some(brain_position_function) = self.predictor.predict(&distances, &times, false) else {
-            return true;
+    //A sample we couldn't predict is abnormal too, unless the predictor is only waiting out a dropout
+    fn is_abnormal_distance(&self, prediction_error: Option<f64>) -> bool {
+        let Some(diff) = prediction_error else {
+            return !self.awaiting_clean_samples();
         };
-        let prediction = brain_position_function(info.distance_time_queue[info.distance_time_queue.len()-1].elapsed().as_millis() as f64);
-        let diff = (distance as f64 - prediction).abs();
-        if diff > MAX_PREDICTION_ERROR_NM as f64{
+        let max_error = self.max_prediction_error();
+        if diff > max_error {
             println!("ABNORMAL PREDICTION: Diff was: {}", diff);
         }
-        return diff > MAX_PREDICTION_ERROR_NM as f64;
+        diff > max_error
+    }
+
+    //Whether the predictor reports that OCT errors among the newest samples are all that keeps it from predicting
+    fn awaiting_clean_samples(&self) -> bool {
+        let mut info = self.info.lock().unwrap();
+        let info = &mut *info;
+        let window = DistanceWindow::new(info.distance_queue.make_contiguous(), info.distance_time_queue.make_contiguous());
+        self.predictor.awaiting_clean_samples(&window)
+    }
+
+    //How far off a prediction may be before its sample is abnormal, as config.abnormal_detector picks
+    fn max_prediction_error(&self) -> f64 {
+        let AbnormalDetector::RollingQuantile { window, quantile, factor, min_nm } = self.config.abnormal_detector else {
+            return MAX_PREDICTION_ERROR_NM as f64;
+        };
+        let mut info = self.info.lock().unwrap();
+        let info = &mut *info;
+        if info.normal_errors.len() < window {
+            return MAX_PREDICTION_ERROR_NM as f64;
+        }
+        let errors = &mut info.quantile_buffer;
+        errors.clear();
+        errors.extend(info.normal_errors.iter().copied());
+        let rank = ((quantile * errors.len() as f64).ceil() as usize).clamp(1, errors.len());
+        let (_, error, _) = errors.select_nth_unstable_by(rank - 1, f64::total_cmp);
+        (factor * *error).max(min_nm)
+    }
+
+    //Adds a sample's prediction error to the rolling quantile detector's window. Once the window is full,
+    //abnormal samples are left out of it
+    fn add_normal_error(&self, error: f64, abnormal: bool) {
+        let AbnormalDetector::RollingQuantile { window, .. } = self.config.abnormal_detector else {
+            return;
+        };
+        let mut info = self.info.lock().unwrap();
+        if abnormal && info.normal_errors.len() >= window {
+            return;
+        }
+        info.normal_errors.push_back(error);
+        while info.normal_errors.len() > window {
+            info.normal_errors.pop_front();
+        }
+    }
+
+    //Adds a sample's prediction error to the drift monitor's window, returning whether it has been breached for long enough
+    fn add_prediction_error(&self, error: f64, time: Instant, monitor: &DriftMonitor) -> bool {
+        let mut info = self.info.lock().unwrap();
+        info.prediction_errors.add(error, time, monitor)
+    }
+
+    //Starts waiting for the brain to calm down after a panic, if the config asks us to
+    fn start_cooldown(&self) {
+        let mut info = self.info.lock().unwrap();
+        info.cooldown_errors = self.config.panic_cooldown.map(|_| RmseWindow::default());
+    }
+
+    fn cooling_down(&self) -> bool {
+        let info = self.info.lock().unwrap();
+        info.cooldown_errors.is_some()
+    }
+
+    //Adds a sample's prediction error to the cooldown window, ending the cooldown once the RMSE over a full window
+    //is low enough. A sample we couldn't predict starts the window over
+    fn add_cooldown_error(&self, error: Option<f64>) {
+        let Some(cooldown) = self.config.panic_cooldown else {
+            return;
+        };
+        let mut info = self.info.lock().unwrap();
+        let Some(errors) = info.cooldown_errors.as_mut() else {
+            return;
+        };
+        let Some(error) = error else {
+            errors.clear();
+            return;
+        };
+        if errors.push(error, cooldown.samples).is_some_and(|rmse| rmse <= cooldown.max_rmse_nm) {
+            println!("Brain calmed down after the panic");
+            info.cooldown_errors = None;
+        }
     }
 
     //We assume here that getting the robot state is instant
+    //A position error means we can no longer trust the robot, so we die and return None
     async fn get_recent_robot_state(&self) -> Option<RobotState> {
-        Some(self.get_robot_state().await.unwrap())
+        match self.get_robot_state().await {
+            Ok(state) => Some(state),
+            Err(error) => {
+                die_with(self, ControllerError::from_robot_error(&error));
+                None
+            }
+        }
     }
 
-    fn set_state(&self, state: ControllerState) {
+    //Keeps the first reason we died, as whatever follows is usually a consequence of it
+    fn record_death_cause(&self, cause: ControllerError) {
+        let mut info = self.info.lock().unwrap();
+        info.death_cause.get_or_insert(cause);
+    }
+
+    fn take_death_cause(&self) -> Option<ControllerError> {
         let mut info = self.info.lock().unwrap();
-        info.current_state = state;
+        info.death_cause.take()
+    }
+
+    fn set_state(&self, state: ControllerState) {
+        self.change_state(state, "set");
+    }
+
+    fn change_state(&self, state: ControllerState, reason: &str) {
+        let from = std::mem::replace(&mut self.info.lock().unwrap().current_state, state);
+        self.log_transition(from, state, reason);
+        if matches!(state, ControllerState::Panic(_)) {
+            self.panicked.notify_waiters();
+        }
+    }
+
+    fn log_transition(&self, from: ControllerState, to: ControllerState, reason: &str) {
+        let Some(transition_log) = &self.transition_log else {
+            return;
+        };
+        let mut writer = transition_log.lock().unwrap();
+        let mut result = writeln!(writer, "{},{},{},{}", self.built_at.elapsed().as_millis(), csv_field(&from.to_string()), csv_field(&to.to_string()), csv_field(reason));
+        if to == ControllerState::Dead {
+            result = result.and_then(|_| writer.flush());
+        }
+        if let Err(error) = result {
+            println!("Could not log transition from {} to {}: {}", from, to, error);
+        }
     }
 
     fn get_state(&self) -> ControllerState {
@@ -245,19 +1137,46 @@ impl<P: BrainPredictor> Controller<P>{
         return info.current_state;
     }
 
-    fn add_error(&self) {
+    //Pushes whether the latest sample was abnormal, forgetting samples older than the window
+    fn record_abnormal(&self, abnormal: bool) {
+        let mut info = self.info.lock().unwrap();
+        info.abnormal_flags.push_back(abnormal);
+        while info.abnormal_flags.len() > self.config.abnormal_window {
+            info.abnormal_flags.pop_front();
+        }
+    }
+
+    fn clear_abnormal(&self) {
+        let mut info = self.info.lock().unwrap();
+        info.abnormal_flags.clear();
+    }
+
+    //Counts a prediction failure, returning true once there have been enough in a row to report
+    fn add_prediction_failure(&self) -> bool {
+        let mut info = self.info.lock().unwrap();
+        info.consecutive_prediction_failures += 1;
+        if info.consecutive_prediction_failures >= self.config.max_consecutive_prediction_failures {
+            info.consecutive_prediction_failures = 0;
+            return true;
+        }
+        false
+    }
+
+    fn clear_prediction_failures(&self) {
         let mut info = self.info.lock().unwrap();
-        info.consecutive_errors += 1;
+        info.consecutive_prediction_failures = 0;
     }
 
-    fn clear_error(&self) {
+    //Returns true once max_failed_calibrations calibrations in a row have failed
+    fn add_failed_calibration(&self) -> bool {
         let mut info = self.info.lock().unwrap();
-        info.consecutive_errors = 0;
+        info.failed_calibrations += 1;
+        info.failed_calibrations >= self.config.max_failed_calibrations
     }
 
-    fn get_consecutive_errors(&self) -> u64 {
+    fn get_abnormal_count(&self) -> usize {
         let info = self.info.lock().unwrap();
-        return info.consecutive_errors;
+        info.abnormal_flags.iter().filter(|abnormal| **abnormal).count()
     }
 
     fn get_pre_move_location(&self) -> Option<u64> {
@@ -270,170 +1189,553 @@ impl<P: BrainPredictor> Controller<P>{
         info.pre_move_location = None;
     }
 
-    fn add_outcome(&self, outcome: bool) {
+    //Takes the last calibrated pre move location, so a failed verification can't reuse it
+    fn take_cached_pre_move_location(&self) -> Option<u64> {
+        let mut info = self.info.lock().unwrap();
+        info.cached_pre_move_location.take()
+    }
+
+    fn add_move_record(&self, record: MoveRecord) {
+        let mut info = self.info.lock().unwrap();
+        info.move_records.push_back(record);
+        if let Some(max_outcome_history) = self.config.max_outcome_history {
+            while info.move_records.len() > max_outcome_history {
+                info.move_records.pop_front();
+            }
+        }
+    }
+
+    //Returns the distance the predictor should see for this raw sample. Calibration looks for the brain's
+    //true closest approach, so it always gets the raw distance
+    fn filter_distance(&self, distance: u64) -> u64 {
+        if self.config.distance_filter == DistanceFilter::None || self.out_of_brain_uncalibrated() {
+            return distance;
+        }
+        let mut info = self.info.lock().unwrap();
+        info.raw_distance_window.push_back(distance);
+        while info.raw_distance_window.len() > self.config.distance_filter_window.max(1) {
+            info.raw_distance_window.pop_front();
+        }
+        let window = &info.raw_distance_window;
+        match self.config.distance_filter {
+            DistanceFilter::None => distance,
+            DistanceFilter::MovingAverage => window.iter().sum::<u64>() / window.len() as u64,
+            DistanceFilter::Median => {
+                let mut sorted = window.iter().copied().collect::<Vec<u64>>();
+                sorted.sort_unstable();
+                sorted[sorted.len() / 2]
+            }
+        }
+    }
+
+    //Adds the raw sample to the too close window and returns the window's median once most of the window is
+    //within half of the minimum safe distance to the brain, so that one spurious low reading can't panic us
+    fn too_close_distance(&self, distance: u64) -> Option<u64> {
+        let too_close_nm = self.config.min_distance_to_brain_nm / 2;
+        let window_len = self.config.too_close_window.max(1);
         let mut info = self.info.lock().unwrap();
-        info.outcomes.push(outcome);
+        info.too_close_window.push_back(distance);
+        while info.too_close_window.len() > window_len {
+            info.too_close_window.pop_front();
+        }
+        let too_close = info.too_close_window.iter().filter(|distance| **distance < too_close_nm).count();
+        if too_close <= window_len / 2 {
+            return None;
+        }
+        let mut sorted = info.too_close_window.iter().copied().collect::<Vec<u64>>();
+        sorted.sort_unstable();
+        Some(sorted[sorted.len() / 2])
+    }
+
+    //Capacity of a queue that holds runtime_capacity samples, and every calibration sample while we calibrate
+    fn queue_capacity(&self, runtime_capacity: usize) -> usize {
+        if self.out_of_brain_uncalibrated() {
+            runtime_capacity.max(self.config.calibration_samples)
+        } else {
+            runtime_capacity
+        }
     }
 
     fn add_distance(&self, distance: Result<u64, OCTError>) {
-        let expected_length = if self.out_of_brain_uncalibrated() {CALIBRATION_SAMPLES} else {MAX_DISTANCES};
+        let expected_length = self.queue_capacity(self.config.distance_queue_capacity);
         let mut info = self.info.lock().unwrap();
         info.distance_queue.push_back(distance);
-        while info.distance_queue.len() > expected_length.try_into().unwrap() {
+        while info.distance_queue.len() > expected_length {
             info.distance_queue.pop_front();
         }
     }
 
     fn add_distance_time(&self, time: Instant) {
-        let expected_length = if self.out_of_brain_uncalibrated() {CALIBRATION_SAMPLES} else {MAX_DISTANCES};
+        let expected_length = self.queue_capacity(self.config.distance_queue_capacity);
         let mut info = self.info.lock().unwrap();
         info.distance_time_queue.push_back(time);
-        while info.distance_time_queue.len() > expected_length.try_into().unwrap() {
+        while info.distance_time_queue.len() > expected_length {
             info.distance_time_queue.pop_front();
         }
     }
 
     fn add_robot_state(&self, state: Result<RobotState, RobotError>) {
-        let expected_length = if self.out_of_brain_uncalibrated() {CALIBRATION_SAMPLES} else {MAX_STATES};
+        let expected_length = self.queue_capacity(MAX_STATES as usize);
         let mut info = self.info.lock().unwrap();
         info.robot_queue.push_back(state);
-        while info.robot_queue.len() > expected_length.try_into().unwrap() {
+        while info.robot_queue.len() > expected_length {
             info.robot_queue.pop_front();
         }
     }
 
     fn add_robot_state_time(&self, time: Instant) {
-        let expected_length = if self.out_of_brain_uncalibrated() {CALIBRATION_SAMPLES} else {MAX_STATES};
+        let expected_length = self.queue_capacity(MAX_STATES as usize);
         let mut info = self.info.lock().unwrap();
         info.robot_time_queue.push_back(time);
-        while info.robot_time_queue.len() > expected_length.try_into().unwrap() {
+        while info.robot_time_queue.len() > expected_length {
             info.robot_time_queue.pop_front();
         }
     }
 
     pub fn get_outcomes(&self) -> Vec<bool> {
         let info = self.info.lock().unwrap();
-        return info.outcomes.clone();
+        return info.move_records.iter().map(|record| record.success).collect();
     }
 
-    //The notificiation system works as follows: When the process_distances task
-    //notices that the brain is close enough to the robot to move, it will notify
-    // the move task.The move task will only move if it was already waiting for a
-    //notificaiton (we dont want these notifications to persist because we might
-    //move at the wrong time in the future).
-    fn set_move_notification(& self) {
+    //Counts a processed distance sample, remembering its index if it sent us into a panic
+    fn count_processed_sample(&self, caused_panic: bool) {
         let mut info = self.info.lock().unwrap();
-        info.notified_distance_times = Vec::from(info.distance_time_queue.clone());
-        info.notified_distances = Vec::from(info.distance_queue.clone());
-        self.can_move.notify_waiters();
+        if caused_panic {
+            let index = info.samples_processed;
+            info.panic_samples.push(index);
+        }
+        info.samples_processed += 1;
     }
-    
-}
 
-fn die<P: BrainPredictor>(control_state: Arc<Controller<P>>) {
-    control_state.set_state(ControllerState::Dead);
-}
+    /// Returns the index of the distance sample that caused each panic, in order.
+    pub fn get_panic_samples(&self) -> Vec<u64> {
+        let info = self.info.lock().unwrap();
+        info.panic_samples.clone()
+    }
 
-//This task is responsible for polling the robot for its distance from the surface
-//Since polling is IO bound, a new task is spawned for each poll so that we get 
-//values every 5ms instead of every 15ms as per the project description
-async fn poll_distance<P: BrainPredictor + 'static>(
-    control_state: Arc<Controller<P>>,
-    tx: mpsc::Sender<Result<u64, OCTError>>
-){
-    loop {
-        let tx_clone = tx.clone();
-        let control_clone = control_state.clone();
-        tokio::task::spawn_local({
-            async move {
-                let distance = control_clone.get_surface_distance().await;
-                if tx_clone.send(distance).await.is_err() {
-                    println!("Receiver dropped, stopping polling.");
-                }
+    /// Returns how long each calibration stared at the brain for, in order, counted in samples at the fast
+    /// poll rate. A recalibration that only verified the cached pre move location takes verification_samples.
+    pub fn get_calibration_samples(&self) -> Vec<usize> {
+        let info = self.info.lock().unwrap();
+        info.calibration_samples.clone()
+    }
+
+    /// Returns what the last calibration to finish saw, None until one has. Calibrations that found no safe pre
+    /// move location are reported too.
+    pub fn last_calibration(&self) -> Option<CalibrationReport> {
+        let info = self.info.lock().unwrap();
+        info.last_calibration
+    }
+
+    /// Returns the moves a dry run planned instead of commanding, in order. Empty unless config.dry_run is set.
+    pub fn get_planned_moves(&self) -> Vec<Move> {
+        let info = self.info.lock().unwrap();
+        info.planned_moves.clone()
+    }
+
+    /// Returns a snapshot of the controller's internals that is safe to poll while it runs.
+    pub fn status(&self) -> ControllerStatus {
+        let info = self.info.lock().unwrap();
+        ControllerStatus {
+            state: info.current_state,
+            abnormal_count: info.abnormal_flags.iter().filter(|abnormal| **abnormal).count(),
+            consecutive_prediction_failures: info.consecutive_prediction_failures,
+            pre_move_location: info.pre_move_location,
+            distance_queue_len: info.distance_queue.len(),
+            last_distance: info.distance_queue.back().cloned(),
+        }
+    }
+
+    /// Returns up to the last n distance samples the controller has queued, oldest first, as (distance, ms
+    /// since the controller was built). Bad reads are None. Once calibrated these are the filtered distances
+    /// the predictor sees. No more than the queue holds are returned, however large n is.
+    pub fn recent_distances(&self, n: usize) -> Vec<(Option<u64>, u64)> {
+        let info = self.info.lock().unwrap();
+        //The two queues are pushed to one after the other, so we pair them up from their newest samples
+        let mut recent = info.distance_queue.iter().rev().zip(info.distance_time_queue.iter().rev())
+            .take(n)
+            .map(|(distance, time)| (distance.as_ref().ok().copied(), time.saturating_duration_since(self.built_at).as_millis() as u64))
+            .collect::<Vec<(Option<u64>, u64)>>();
+        recent.reverse();
+        recent
+    }
+
+    //Called with succeeded None before a move is sent and with its outcome after. Starts the in brain clock
+    //when a move takes the needle past zero and stops it once a move has brought it back. A move that fails
+    //part way may still have taken the needle in, so the clock starts before the move is sent
+    fn track_needle_move(&self, command: &Move, succeeded: Option<bool>) {
+        let needle_target = match *command {
+            Move::NeedleZ(needle_z) | Move::NeedleZWithVelocity{target: needle_z, ..} | Move::Both{needle_z, ..} => needle_z,
+            Move::InserterZ(_) => return,
+        };
+        let mut info = self.info.lock().unwrap();
+        if needle_target > 0 && succeeded.is_none() {
+            info.needle_in_since.get_or_insert_with(Instant::now);
+        } else if needle_target == 0 && succeeded == Some(true) {
+            if let Some(since) = info.needle_in_since.take() {
+                info.time_in_brain += since.elapsed();
             }
-        });
+        }
+    }
+
+    //Returns the time the needle has spent in the brain since the last call
+    fn take_time_in_brain_ms(&self) -> u64 {
+        let mut info = self.info.lock().unwrap();
+        std::mem::take(&mut info.time_in_brain).as_millis() as u64
+    }
+
+    //The brain has to come this close to the parked inserter before we move
+    fn move_trigger_distance(&self) -> u64 {
+        self.config.calibration_margin_nm + MOVE_TRIGGER_SLACK_NM
+    }
+
+    //Whether commanded_depth is within the configured range of depths we insert to
+    fn accepts_depth(&self, commanded_depth: u64) -> bool {
+        (self.config.min_commanded_depth_nm..=self.config.max_commanded_depth_nm).contains(&commanded_depth)
+    }
+
+    /// Whether an insertion to `commanded_depth` fits the robot's limits, so impossible depths can be skipped
+    /// before an attempt. Once calibrated the brain is at least `calibration_margin_nm` below the parked inserter,
+    /// so the needle has to travel that much past the commanded depth, and the parked inserter has to be within
+    /// its own travel. Uncalibrated, only the commanded depth itself is checked against the needle's travel.
+    /// Depths outside of the configured range are never reachable.
+    pub fn is_depth_reachable(&self, commanded_depth: u64) -> bool {
+        if !self.accepts_depth(commanded_depth) {
+            return false;
+        }
+        let limits = self.limits();
+        let pre_move_location = self.get_pre_move_location();
+        if pre_move_location.is_some_and(|location| location > limits.inserter_z_max) {
+            return false;
+        }
+        let min_brain_distance = if pre_move_location.is_some() { self.config.calibration_margin_nm } else { 0 };
+        commanded_depth.saturating_add(min_brain_distance) <= limits.needle_z_max
+    }
+
+    pub fn get_move_records(&self) -> Vec<MoveRecord> {
+        let info = self.info.lock().unwrap();
+        info.move_records.iter().cloned().collect()
+    }
+
+    //Tells the polling and processing tasks to stop. The flag covers tasks that
+    //aren't currently waiting on the notification when it fires
+    fn request_shutdown(&self) {
+        let mut info = self.info.lock().unwrap();
+        info.shutdown_requested = true;
+        self.shutdown.notify_waiters();
+    }
+
+    fn shutdown_requested(&self) -> bool {
+        let info = self.info.lock().unwrap();
+        info.shutdown_requested
+    }
+
+    /// Aborts the session from outside, e.g. from a supervisor. An insertion in progress stops waiting
+    /// to move (or stops its move), retracts the needle and the controller dies.
+    pub fn abort(&self) {
+        let mut info = self.info.lock().unwrap();
+        info.abort_requested = true;
+        self.abort.notify_waiters();
+    }
+
+    fn abort_requested(&self) -> bool {
+        let info = self.info.lock().unwrap();
+        info.abort_requested
+    }
+
+    /// Clears everything a session left behind (queues, records, calibration, panics, planned moves and
+    /// any abort) and puts the controller back in `OutOfBrainUncalibrated`, so `start` can run again.
+    /// The robot has to outlive the session for that, which robots behind channels don't, as the session
+    /// stops them when it ends. Returns false without changing anything while a session is running.
+    pub fn reset(&self) -> bool {
+        let mut info = self.info.lock().unwrap();
+        if info.session_active {
+            return false;
+        }
+        *info = ControllerInfo::new(&self.config);
+        info.current_state = ControllerState::OutOfBrainUncalibrated;
+        true
+    }
+
+    //The notificiation system works as follows: When the process_distances task
+    //notices that the brain is close enough to the robot to move, it will notify
+    // the move task.The move task will only move if it was already waiting for a
+    //notificaiton (we dont want these notifications to persist because we might
+    //move at the wrong time in the future).
+    fn set_move_notification(& self) {
+        let mut info = self.info.lock().unwrap();
+        //Copy into the existing buffers so they only allocate when the queues outgrow them
+        let info = &mut *info;
+        info.notified_distance_times.clear();
+        info.notified_distance_times.extend(info.distance_time_queue.iter());
+        info.notified_distances.clear();
+        info.notified_distances.extend(info.distance_queue.iter().cloned());
+        self.can_move.notify_waiters();
+    }
+    
+}
+
+fn die<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>) {
+    control_state.set_state(ControllerState::Dead);
+    //Wake an insertion waiting to move so it sees we are dead instead of waiting out its budget
+    control_state.can_move.notify_waiters();
+}
+
+//Dies for a reason start reports, unless we already died of something else
+fn die_with<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>, cause: ControllerError) {
+    control_state.record_death_cause(cause);
+    die(control_state);
+}
+
+//Sleeps for the given duration, returning false early if shutdown is requested
+async fn sleep_until_shutdown<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>, duration: Duration) -> bool {
+    if control_state.shutdown_requested() {
+        return false;
+    }
+    tokio::select! {
+        _ = sleep(duration) => !control_state.shutdown_requested(),
+        _ = control_state.shutdown.notified() => false,
+    }
+}
+
+//Runs the future to completion, returning None instead if an abort is requested first
+async fn until_abort<P: BrainPredictor, R: Robot + OCTService, F: std::future::Future>(control_state: &Controller<P, R>, future: F) -> Option<F::Output> {
+    if control_state.abort_requested() {
+        return None;
+    }
+    tokio::select! {
+        output = future => Some(output),
+        _ = control_state.abort.notified() => None,
+    }
+}
+
+//Runs the future to completion, returning None instead if we panic first
+async fn until_panic<P: BrainPredictor, R: Robot + OCTService, F: std::future::Future>(control_state: &Controller<P, R>, future: F) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = control_state.panicked.notified() => None,
+    }
+}
+
+//Receives the next message, returning None once the channel closes or shutdown is requested
+async fn recv_until_shutdown<P: BrainPredictor, R: Robot + OCTService, T>(control_state: &Controller<P, R>, rx: &mut mpsc::Receiver<T>) -> Option<T> {
+    if control_state.shutdown_requested() {
+        return None;
+    }
+    tokio::select! {
+        message = rx.recv() => message,
+        _ = control_state.shutdown.notified() => None,
+    }
+}
+
+//This task is responsible for polling the robot for its distance from the surface
+//Since polling is IO bound, a new task is spawned for each poll so that we get 
+//values every 5ms instead of every 15ms as per the project description. While we are far from the brain
+//out of brain there is no need for that, so we poll at config.poll_rates.slow_millis instead
+//If max_outstanding_polls requests are still in flight we skip the tick rather than pile up more tasks
+//On shutdown we wait for the outstanding polls so none are dropped mid request
+async fn poll_distance<P: BrainPredictor + 'static, R: Robot + OCTService + 'static>(
+    control_state: Arc<Controller<P, R>>,
+    tx: mpsc::Sender<(Result<u64, OCTError>, Instant)>
+){
+    let mut polls = JoinSet::new();
+    loop {
+        while polls.try_join_next().is_some() {}
+        if polls.len() < control_state.config.max_outstanding_polls {
+            let tx_clone = tx.clone();
+            let control_clone = control_state.clone();
+            polls.spawn_local({
+                async move {
+                    let distance = control_clone.get_surface_distance().await;
+                    if tx_clone.send((distance, Instant::now())).await.is_err() {
+                        println!("Receiver dropped, stopping polling.");
+                    }
+                }
+            });
+        }
 
-        // Wait for 5 seconds before polling again to keep under 20Hz
-        sleep(Duration::from_millis(OCT_POLL_MILLIS)).await;
+        // Wait before polling again, slower while we are far from the brain
+        if !sleep_until_shutdown(&control_state, control_state.poll_interval()).await {
+            break;
+        }
     }
+    while polls.join_next().await.is_some() {}
 }
 
-async fn poll_state<P: BrainPredictor + 'static>(
-    control_state: Arc<Controller<P>>,
+async fn poll_state<P: BrainPredictor + 'static, R: Robot + OCTService + 'static>(
+    control_state: Arc<Controller<P, R>>,
     tx: mpsc::Sender<Result<RobotState, RobotError>>
 ){
+    let mut polls = JoinSet::new();
     loop {
-        let tx_clone = tx.clone();
-        let control_clone = control_state.clone();
+        while polls.try_join_next().is_some() {}
+        if polls.len() < control_state.config.max_outstanding_polls {
+            let tx_clone = tx.clone();
+            let control_clone = control_state.clone();
 
-        // The future here must be 'static. Adding `+ 'static` to P helps.
-        tokio::task::spawn_local({
-            async move {
-                let distance = control_clone.get_robot_state().await;
-                if tx_clone.send(distance).await.is_err() {
-                    println!("Receiver dropped, stopping polling.");
+            // The future here must be 'static. Adding `+ 'static` to P helps.
+            polls.spawn_local({
+                async move {
+                    let distance = control_clone.get_robot_state().await;
+                    if tx_clone.send(distance).await.is_err() {
+                        println!("Receiver dropped, stopping polling.");
+                    }
                 }
-            }
-        });
+            });
+        }
 
-        // Wait for 5 seconds before polling again
-        sleep(Duration::from_millis(OCT_POLL_MILLIS)).await;
+        // Wait before polling again
+        if !sleep_until_shutdown(&control_state, control_state.poll_interval()).await {
+            break;
+        }
     }
+    while polls.join_next().await.is_some() {}
 }
 //This task is responsible for processing the distance values from the robot
 //Processing involves two steps: 1. Checking if the distance is abnormal 
 //2. Checking if the distance is close enough to the brain to trigger a move
-async fn process_distances<P: BrainPredictor>(control_state: Arc<Controller<P>>, mut rx: mpsc::Receiver<Result<u64, OCTError>>) {
-    while let Some(distance_result) = rx.recv().await {
-        match distance_result {
-            Ok(distance) => {
-                //We can only panic when OOBC or IB in the state machine
-                let can_panic = control_state.out_of_brain_calibrated() || control_state.in_brain();
-                // Check for abnormal distance
-                let too_close_to_brain = distance < MIN_DISTANCE_BRAIN_TO_ARM_NM/2;
-                if too_close_to_brain && can_panic {
-                    println!("Too close to brain: {}", distance);
-                    transition_state(control_state.clone(), ControllerState::Panic, false);
-                }
-                else if can_panic && control_state.is_abnormal_distance(distance) {
-                    control_state.add_error();
-                    if control_state.get_consecutive_errors() > MAX_CONSECUTIVE_PREDICTION_ERRORS && can_panic
-                    {
-                        println!("Too many consecutive errors");
-                        assert!(!control_state.in_panic());
-                        transition_state(control_state.clone(), ControllerState::Panic, false);
-                    }
-                } else {
-                    //If we are not in panic, clear the error since they are non consecutive
-                    control_state.clear_error();
-                }
-                //If we notice we can trigger a move, we trigger it
-                if distance < MAX_DIST_FROM_PREMOVE_TO_MOVE {
-                    println!("Found premove location");
-                    control_state.set_move_notification();
-                }
+async fn process_distances<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, mut rx: mpsc::Receiver<(Result<u64, OCTError>, Instant)>) {
+    while let Some((distance_result, time)) = recv_until_shutdown(&control_state, &mut rx).await {
+        process_distance(control_state.clone(), distance_result, time);
+    }
+}
+
+//Handles one distance sample taken at time: the too close and abnormal checks that may make us panic, the move
+//notification and finally the queues
+fn process_distance<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, distance_result: Result<u64, OCTError>, time: Instant) {
+    let was_in_panic = control_state.in_panic();
+    if let Ok(distance) = distance_result {
+        //We can only panic when OOBC or IB in the state machine
+        let can_panic = control_state.out_of_brain_calibrated() || control_state.in_brain();
+        // Check for abnormal distance, unless close approaches were asked for
+        let too_close_to_brain = control_state.too_close_distance(distance).filter(|_| can_panic && !control_state.config.allow_close_approach);
+        if let Some(distance) = too_close_to_brain {
+            println!("Too close to brain: {}", distance);
+            transition_state(control_state.clone(), ControllerState::Panic(PanicReason::TooClose { distance }), false);
+        }
+        //Every sample goes into the abnormal window, including ones that were also too close,
+        //so a streak is only forgotten once enough normal samples push it out
+        if can_panic {
+            let prediction_error = control_state.prediction_error(distance);
+            let abnormal = control_state.is_abnormal_distance(prediction_error);
+            record_abnormal_sample(control_state.clone(), abnormal, |count| PanicReason::AbnormalDistances { count });
+            control_state.add_cooldown_error(prediction_error);
+            if let Some(error) = prediction_error {
+                control_state.add_normal_error(error, abnormal);
+                record_prediction_error(control_state.clone(), error, time);
             }
-            Err(_) => {}
-        };
+        } else if matches!(control_state.config.abnormal_detector, AbnormalDetector::RollingQuantile { .. }) && control_state.out_of_brain_uncalibrated() {
+            //We stare at the brain while calibrating anyway, so the rolling quantile detector learns how noisy it is
+            if let Some(error) = control_state.prediction_error(distance) {
+                control_state.add_normal_error(error, false);
+            }
+        }
+        //If we notice we can trigger a move, we trigger it
+        if distance < control_state.move_trigger_distance() {
+            println!("Found premove location");
+            control_state.set_move_notification();
+        }
+    }
+
+    // Update queues, predicting from the filtered distance now that the checks above have used the raw one
+    let distance_result = distance_result.map(|distance| control_state.filter_distance(distance));
+    control_state.add_distance(distance_result);
+    control_state.add_distance_time(time);
+    control_state.count_processed_sample(!was_in_panic && control_state.in_panic());
+}
+
+#[cfg(test)]
+impl<P: BrainPredictor, R: Robot + OCTService> Controller<P, R> {
+    //Runs a distance sample taken at at through the same processing as process_distances, synchronously, so the
+    //panic triggers can be tested without a robot or channels
+    pub(crate) fn feed_distance(self: &Arc<Self>, distance: Result<u64, OCTError>, at: Instant) {
+        process_distance(Arc::clone(self), distance, at);
+    }
+}
+
+//We panic once enough of the recent samples are abnormal, whether or not they were consecutive
+//reason builds the panic reason from the number of abnormal samples in the window
+fn record_abnormal_sample<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, abnormal: bool, reason: impl FnOnce(usize) -> PanicReason) {
+    control_state.record_abnormal(abnormal);
+    let abnormal_count = control_state.get_abnormal_count();
+    if abnormal && abnormal_count >= control_state.config.abnormal_threshold && !control_state.in_panic() {
+        println!("Too many abnormal samples");
+        transition_state(control_state.clone(), ControllerState::Panic(reason(abnormal_count)), false);
+    }
+}
+
+//Once the drift monitor has seen the predictions stay off for long enough while we are calibrated, we recalibrate.
+//An insertion waiting to move is woken up to take us back to the origin for it
+fn record_prediction_error<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, error: f64, time: Instant) {
+    let Some(monitor) = control_state.config.drift_monitor else {
+        return;
+    };
+    if !control_state.out_of_brain_calibrated() || !control_state.add_prediction_error(error, time, &monitor) {
+        return;
+    }
+    println!("Predictions drifted past an RMSE of {}nm, recalibrating", monitor.max_rmse_nm);
+    transition_state(control_state.clone(), ControllerState::OutOfBrainUncalibrated, false);
+    control_state.can_move.notify_waiters();
+}
+
+//Prediction errors are an OCT level fault: a systematic inability to predict where the brain
+//is going counts towards a panic in the same way as readings that don't match our predictions
+fn report_oct_error<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, error: OCTError) {
+    if let OCTError::PredictionError { msg, .. } = &error {
+        println!("Prediction error: {}", msg);
+        record_abnormal_sample(control_state, true, |count| PanicReason::PredictionErrors { count });
+    }
+}
 
-        // Update queues
-        control_state.add_distance(distance_result);
-        control_state.add_distance_time(Instant::now());
-        
-        //tokio::task::yield_now().await;
+//Finds where to move the needle, reporting a prediction error once root finding has failed
+//max_consecutive_prediction_failures times in a row
+fn next_move_location<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, commanded_depth: u64) -> Option<u64> {
+    match control_state.get_move_location(commanded_depth) {
+        Ok(Some(relative_position)) => {
+            control_state.clear_prediction_failures();
+            Some(relative_position)
+        }
+        Ok(None) => None,
+        Err(error) => {
+            if control_state.add_prediction_failure() {
+                report_oct_error(control_state, error);
+            }
+            None
+        }
+    }
+}
+
+//Sends each recorded sample at its offset from when we started replaying
+async fn replay_distances<P, R, I>(control_state: Arc<Controller<P, R>>, tx: mpsc::Sender<(Result<u64, OCTError>, Instant)>, samples: I)
+where
+    P: BrainPredictor,
+    R: Robot + OCTService,
+    I: IntoIterator<Item = (u64, Result<u64, OCTError>)>,
+{
+    let replay_start = Instant::now();
+    for (elapsed_ms, distance) in samples {
+        let time = replay_start + Duration::from_millis(elapsed_ms);
+        if !sleep_until_shutdown(&control_state, time.saturating_duration_since(Instant::now())).await {
+            return;
+        }
+        if tx.send((distance, time)).await.is_err() {
+            return;
+        }
     }
+    println!("Replayed every recorded distance");
+    die(&control_state);
 }
 
 //The code currently doesn;t utilize the robot state in any way aside from checking values for the state machine
-async fn process_robot_state<P: BrainPredictor>(control_state: Arc<Controller<P>>, mut rx: mpsc::Receiver<Result<RobotState, RobotError>>) {
-    while let Some(robot_state) = rx.recv().await {
-        match robot_state {
+async fn process_robot_state<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, mut rx: mpsc::Receiver<Result<RobotState, RobotError>>) {
+    while let Some(robot_state) = recv_until_shutdown(&control_state, &mut rx).await {
+        match &robot_state {
             Ok(_) => {}
-            Err(RobotError::ConnectionError{..}) | Err(RobotError::MoveError{..}) => {
-                println!("Received error in processing robot state");
+            Err(error @ RobotError::ConnectionError{..}) | Err(error @ RobotError::MoveError{..}) => {
+                println!("Received error in processing robot state at {:?}ms: {:?}", error.at_ms(), error);
             }
             Err(RobotError::PositionError{..}) => {
-                die(control_state.clone());
+                die_with(&control_state, ControllerError::RobotFault);
             }
         };
         control_state.add_robot_state(robot_state);
@@ -443,46 +1745,135 @@ async fn process_robot_state<P: BrainPredictor>(control_state: Arc<Controller<P>
 }
 
 //When panicing, we move the needl to the origin first to potentially get out of the brain
-//We then move the inserter to the origin and recalibrate our robot, since panics
-//could have occured due to abnormal brain activity/bad motion predictions
-async fn panic<P: BrainPredictor>(control_state: Arc<Controller<P>>) {
-    move_bot(control_state.clone(), &Move::NeedleZ(0), ControllerState::Panic, false).await;
-    move_bot(control_state.clone(), &Move::InserterZ(0), ControllerState::Panic, false).await;
-    transition_state(control_state,ControllerState::OutOfBrainUncalibrated, true);
+//We then recover as config.panic_recovery picks for the reason. Usually we move the inserter to the origin
+//and recalibrate our robot, since panics could have occured due to abnormal brain activity/bad motion predictions
+async fn panic<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>) {
+    //We stay in the panic we are in, keeping its reason, until we have recovered
+    let panic_state = control_state.get_state();
+    let ControllerState::Panic(reason) = panic_state else {
+        return;
+    };
+    move_bot_with_priority(control_state.clone(), &Move::NeedleZ(0), panic_state, false, MovePriority::Emergency).await;
+    let recovery = (control_state.config.panic_recovery)(&reason);
+    println!("Recovering from {} with {:?}", reason, recovery);
+    //We can only retry from where we were if we know where that is
+    match (recovery, control_state.get_pre_move_location()) {
+        (PanicRecovery::RetryFromCalibrated, Some(pre_move_location)) => {
+            move_bot(control_state.clone(), &Move::InserterZ(pre_move_location), panic_state, false).await;
+            //The samples that made us panic would otherwise make us panic again straight away
+            control_state.clear_abnormal();
+            control_state.clear_distance_queue();
+            control_state.start_cooldown();
+            transition_state(control_state, ControllerState::OutOfBrainCalibrated, true);
+        }
+        (PanicRecovery::Abort, _) => {
+            move_bot(control_state.clone(), &Move::InserterZ(0), panic_state, false).await;
+            die_with(&control_state, ControllerError::Aborted);
+        }
+        _ => {
+            move_bot(control_state.clone(), &Move::InserterZ(0), panic_state, false).await;
+            control_state.start_cooldown();
+            transition_state(control_state, ControllerState::OutOfBrainUncalibrated, true);
+        }
+    }
 }
 
-//The calibration sequence is very simple - we stare at the brain for CALIBRATION_SAMPLES OCT samples,
+//The calibration sequence is very simple - we stare at the brain for config.calibration_samples OCT samples,
 //calculate the closest the brain got to the robot, and move the inserter 200 microns above that location.
-async fn calibrate<P: BrainPredictor>(control_state: Arc<Controller<P>>) {
-    assert!(control_state.get_recent_robot_state().await.unwrap() == RobotState{inserter_z: 0, needle_z: 0} && control_state.out_of_brain_uncalibrated());
-    println!("Out of assert in calibrate");
+//If the brain came within 200 microns of the robot there is no safe location, so we panic to retract and
+//try again, and die once that has happened max_failed_calibrations times in a row.
+//When we calibrate again (after a panic) we first check the last pre move location over only
+//verification_samples samples, keeping it if the brain stayed at least 200 microns below it. Otherwise we
+//keep staring until we have config.calibration_samples samples and calibrate from scratch.
+//Sample counts are at the fast poll rate, so while we poll slower the stare ends once its samples span as
+//long as that many fast polls would, rather than taking longer.
+//Calibration only starts from the origin while OutOfBrainUncalibrated, otherwise it returns why it couldn't.
+async fn calibrate<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>) -> Result<(), CalibrationError> {
+    let Some(robot_state) = control_state.get_recent_robot_state().await else {
+        return Err(CalibrationError::RobotStateUnavailable);
+    };
+    if robot_state != (RobotState{inserter_z: 0, needle_z: 0}) {
+        return Err(CalibrationError::NotAtOrigin { state: robot_state });
+    }
+    if !control_state.out_of_brain_uncalibrated() {
+        return Err(CalibrationError::NotUncalibrated { state: control_state.get_state() });
+    }
+    println!("Starting calibration");
     //Reset the robots state to relearn all parameters
     let calibration_init = Instant::now();
-    control_state.clear_error();
+    control_state.clear_abnormal();
     control_state.clear_distance_queue();
     control_state.clear_pre_move_location();
+    let cached_pre_move_location = control_state.take_cached_pre_move_location();
+    let mut required_samples = match cached_pre_move_location {
+        Some(_) => control_state.config.verification_samples.min(control_state.config.calibration_samples),
+        None => control_state.config.calibration_samples,
+    };
     loop{
+        //Nothing will ever finish calibrating us once we are dead or aborted
+        if control_state.dead() || control_state.abort_requested() {
+            return Ok(());
+        }
         {
             let mut controller = control_state.info.lock().unwrap();
             let distance_queue = &controller.distance_queue;
             let distance_time_queue = &controller.distance_time_queue;
-            if distance_queue.len() >= CALIBRATION_SAMPLES.try_into().unwrap() && distance_queue.front().unwrap().is_ok() && *distance_time_queue.front().unwrap() >= calibration_init {
-                let min_distance = distance_queue.iter().filter(|d| d.is_ok()).min_by_key(|d| d.as_ref().unwrap()).unwrap().as_ref().unwrap();
-                assert!(*min_distance > MIN_DISTANCE_BRAIN_TO_ARM_NM);
-                //Calculate our premove location by staring at the brain for a while
-                controller.pre_move_location = Some(*min_distance - MIN_DISTANCE_BRAIN_TO_ARM_NM);
-                break;
+            let stare = Duration::from_millis(required_samples as u64 * control_state.config.poll_rates.fast_millis);
+            let stared_long_enough = distance_queue.len() >= required_samples || distance_time_queue.front().zip(distance_time_queue.back())
+                .is_some_and(|(first, last)| last.duration_since(*first) >= stare);
+            if stared_long_enough && distance_queue.front().unwrap().is_ok() && *distance_time_queue.front().unwrap() >= calibration_init {
+                let min_distance = *distance_queue.iter().filter(|d| d.is_ok()).min_by_key(|d| d.as_ref().unwrap()).unwrap().as_ref().unwrap();
+                let valid_samples = distance_queue.iter().filter(|d| d.is_ok()).count();
+                let error_samples = distance_queue.len() - valid_samples;
+                let verifying = required_samples < control_state.config.calibration_samples;
+                let pre_move_location = match cached_pre_move_location {
+                    Some(cached) if verifying => (min_distance >= cached + control_state.config.calibration_margin_nm).then_some(cached),
+                    _ => min_distance.checked_sub(control_state.config.calibration_margin_nm).filter(|location| *location > 0),
+                };
+                //Stopping short of the inserter's limit only leaves the needle further to go
+                let pre_move_location = pre_move_location.map(|location| location.min(control_state.limits().inserter_z_max));
+                //A failed verification goes on to calibrate from scratch, which reports instead
+                if pre_move_location.is_some() || !verifying {
+                    controller.last_calibration = Some(CalibrationReport { min_distance, valid_samples, error_samples, pre_move_location });
+                }
+                if let Some(pre_move_location) = pre_move_location {
+                    //Calculate our premove location by staring at the brain for a while
+                    controller.pre_move_location = Some(pre_move_location);
+                    controller.cached_pre_move_location = Some(pre_move_location);
+                    controller.calibration_samples.push(required_samples);
+                    controller.failed_calibrations = 0;
+                    break;
+                }
+                if verifying {
+                    println!("Cached pre move location is no longer safe, the brain came within {}nm, recalibrating", min_distance);
+                    required_samples = control_state.config.calibration_samples;
+                    continue;
+                }
+                drop(controller);
+                println!("No safe pre move location, the brain came within {}nm", min_distance);
+                if control_state.add_failed_calibration() {
+                    die_with(&control_state, ControllerError::CalibrationFailed);
+                } else {
+                    transition_state(control_state, ControllerState::Panic(PanicReason::NoSafeCalibration { min_distance }), false);
+                }
+                return Ok(());
             }
         }
         sleep(Duration::from_millis(10)).await;
     }
     //Set our premove location and move the robot to the premove lcoation
     //By the state machine, we guarantee the robot will move to {premove_location, 0}
+    //The needle is already retracted here, so both axes can move at once when the config allows it
     let premove_location = control_state.get_pre_move_location().unwrap();
-    move_bot(control_state.clone(), &Move::InserterZ(premove_location), ControllerState::OutOfBrainUncalibrated, false).await;
-    move_bot(control_state.clone(), &Move::NeedleZ(0), ControllerState::OutOfBrainCalibrated, false).await;
+    if control_state.config.simultaneous_moves {
+        move_bot(control_state.clone(), &Move::Both { inserter_z: premove_location, needle_z: 0 }, ControllerState::OutOfBrainCalibrated, false).await;
+    } else {
+        move_bot(control_state.clone(), &Move::InserterZ(premove_location), ControllerState::OutOfBrainUncalibrated, false).await;
+        move_bot(control_state.clone(), &Move::NeedleZ(0), ControllerState::OutOfBrainCalibrated, false).await;
+    }
     control_state.clear_distance_queue();
     println!("---------------------------------------------------------------------------------------------------------------------------------------");
+    Ok(())
 }
 
 //We start our two polling tasks, one for distances and one for robot state
@@ -493,131 +1884,465 @@ async fn calibrate<P: BrainPredictor>(control_state: Arc<Controller<P>>) {
 
 //The transition from panic -->OOBC is moving to the origin, from OOBU -->OOBC is calibration, and from OOBC --> IB
 //is entering the brain
-pub async fn start<P: BrainPredictor + 'static>(control_state: Arc<Controller<P>>, commanded_depth: &Vec<u64>) {
+//The session ends in a summary once every commanded depth has been gone through, whether or not it succeeded,
+//and in the ControllerError that killed the controller otherwise
+pub async fn start<P: BrainPredictor + 'static, R: Robot + OCTService + 'static>(control_state: Arc<Controller<P, R>>, commanded_depth: &[u64]) -> Result<SessionSummary, ControllerError> {
     println!("Starting controller...");
-    //Make channels for communicating with robot simulation
-    let (tx_distance, rx_distance) = mpsc::channel::<Result<u64, OCTError>>(20);
-    let (tx_state, rx_state) = mpsc::channel::<Result<RobotState, RobotError>>(20);
-    //Spawn our polling and processing tasks
-    tokio::task::spawn_local({let me = Arc::clone(&control_state);
+    let (tx_distance, rx_distance) = mpsc::channel::<(Result<u64, OCTError>, Instant)>(20);
+    let distance_source = tokio::task::spawn_local({let me = Arc::clone(&control_state);
     async move {
         poll_distance(me, tx_distance).await;
     }});
-    tokio::task::spawn_local({let me = Arc::clone(&control_state);
+    run(control_state, commanded_depth, distance_source, rx_distance).await
+}
+
+/// Runs the controller like `start`, but the distances come from a recording of
+/// (elapsed ms since the start, distance) samples instead of the OCT. Each sample is delivered at its
+/// recorded offset and stamped with the instant it was recorded at, so the distance processing is
+/// deterministic. Moves and robot states still go to the live robot.
+/// Once the recording runs out there is nothing left to control on, so the controller dies, which still ends
+/// the session in a summary.
+pub async fn start_with_distance_source<P, R, I>(control_state: Arc<Controller<P, R>>, commanded_depth: &[u64], samples: I) -> Result<SessionSummary, ControllerError>
+where
+    P: BrainPredictor + 'static,
+    R: Robot + OCTService + 'static,
+    I: IntoIterator<Item = (u64, Result<u64, OCTError>)> + 'static,
+{
+    println!("Starting controller from recorded distances...");
+    let (tx_distance, rx_distance) = mpsc::channel::<(Result<u64, OCTError>, Instant)>(20);
+    let distance_source = tokio::task::spawn_local({let me = Arc::clone(&control_state);
+    async move {
+        replay_distances(me, tx_distance, samples).await;
+    }});
+    run(control_state, commanded_depth, distance_source, rx_distance).await
+}
+
+async fn run<P: BrainPredictor + 'static, R: Robot + OCTService + 'static>(control_state: Arc<Controller<P, R>>, commanded_depth: &[u64],
+    distance_source: tokio::task::JoinHandle<()>, rx_distance: mpsc::Receiver<(Result<u64, OCTError>, Instant)>) -> Result<SessionSummary, ControllerError> {
+    control_state.info.lock().unwrap().session_active = true;
+    //Make channels for communicating with robot simulation
+    let (tx_state, rx_state) = mpsc::channel::<Result<RobotState, RobotError>>(20);
+    //Spawn our polling and processing tasks, keeping their handles so we can wait for them on shutdown
+    let mut handles = vec![distance_source];
+    handles.push(tokio::task::spawn_local({let me = Arc::clone(&control_state);
         async move {
             poll_state(me, tx_state).await;
-        }});
+        }}));
     println!("Starting to process distances...");
-    tokio::task::spawn_local({let me = Arc::clone(&control_state);
+    handles.push(tokio::task::spawn_local({let me = Arc::clone(&control_state);
         async move {
             process_distances(me, rx_distance).await;
-        }});
+        }}));
     println!("Starting to process robot state...");
-    tokio::task::spawn_local({let me = Arc::clone(&control_state);
+    handles.push(tokio::task::spawn_local({let me = Arc::clone(&control_state);
         async move {
             process_robot_state(me, rx_state).await;
-        }});
+        }}));
     
+    //Depths we don't insert to are rejected up front, so a bad command can't stop the session part way through
+    let rejected_depths = commanded_depth.iter().filter(|depth| !control_state.accepts_depth(**depth)).collect::<Vec<&u64>>();
+    if !rejected_depths.is_empty() {
+        println!("Skipping depths outside of [{}, {}]: {:?}", control_state.config.min_commanded_depth_nm, control_state.config.max_commanded_depth_nm, rejected_depths);
+    }
+    //With nothing left to insert to the loop below only records the rejections
+    if rejected_depths.len() == commanded_depth.len() && !commanded_depth.is_empty() {
+        control_state.record_death_cause(ControllerError::InvalidCommands { rejected: commanded_depth.to_vec() });
+    }
     //Start the state machine
     control_state.set_state(ControllerState::OutOfBrainUncalibrated);
     for (_i, depth) in commanded_depth.iter().enumerate() {
+        let mut record = MoveRecord{commanded_depth: *depth, predicted_target: None, success: false, attempts: 0, time_in_brain_ms: 0, achieved_depth: None};
+        //Rejected depths are recorded as failures without an attempt, keeping the records in commanded order
+        if !control_state.accepts_depth(*depth) {
+            control_state.add_move_record(record);
+            continue;
+        }
+        //Panics at this depth, in or out of the brain. A brain that keeps making us panic would otherwise
+        //have us recover and retry it until we run out of attempts
+        let mut panics = 0;
         loop{
+            //If the depth keeps eluding us we give up on it rather than retrying forever
+            if record.attempts >= control_state.config.max_attempts_per_depth {
+                println!("Giving up on depth {} after {} attempts", depth, record.attempts);
+                break;
+            }
             if control_state.in_panic(){
                 panic(control_state.clone()).await;
+                if give_up_after_panic(&control_state, *depth, &mut panics) {
+                    break;
+                }
             }
             if control_state.out_of_brain_uncalibrated(){
-                calibrate(control_state.clone()).await;
-                println!("Calibrated");
+                match calibrate(control_state.clone()).await {
+                    Ok(()) => println!("Calibrated"),
+                    //Away from the origin we panic, which brings us back to it to calibrate again
+                    Err(CalibrationError::NotAtOrigin { state }) => {
+                        println!("Cannot calibrate: robot not at the origin: {:?}", state);
+                        transition_state(control_state.clone(), ControllerState::Panic(PanicReason::NotAtOrigin { state }), false);
+                    }
+                    //Without a robot state we already died of its error
+                    Err(error) => {
+                        println!("Cannot calibrate: {}", error);
+                        die_with(&control_state, ControllerError::CalibrationFailed);
+                    }
+                }
+            }
+            //Calibration gives up waiting on an abort, leaving us to die here
+            if control_state.abort_requested() {
+                die_with(&control_state, ControllerError::Aborted);
+            }
+            //If the robot reported a position error we stop commanding it altogether
+            if control_state.dead(){
+                break;
+            }
+            //A failed calibration panics so that we retract and calibrate again
+            if control_state.in_panic(){
+                continue;
             }
             assert!(control_state.out_of_brain_calibrated(), "Expected out of brain calibrated but was: {}", control_state.get_state());
-            assert!(control_state.get_robot_state().await.unwrap().needle_z == 0);
+            let Some(robot_state) = control_state.get_recent_robot_state().await else {
+                break;
+            };
+            assert!(robot_state.needle_z == 0);
+            //After a panic we only go back in once the brain has calmed down
+            match wait_for_cooldown(&control_state).await {
+                Cooldown::Calm => {}
+                Cooldown::Interrupted => continue,
+                Cooldown::TimedOut => {
+                    println!("Brain didn't calm down within {}ms, retrying depth {}", control_state.config.panic_cooldown.unwrap().max_wait_ms, depth);
+                    record.attempts += 1;
+                    continue;
+                }
+            }
             println!("Inserting {} thread", _i);
-            let outcome = insert_ib_open_loop(control_state.clone(), *depth).await;
+            let (outcome, predicted_target) = insert_ib_open_loop(control_state.clone(), *depth).await;
+            record.attempts += 1;
+            record.time_in_brain_ms += control_state.take_time_in_brain_ms();
+            record.predicted_target = predicted_target.or(record.predicted_target);
+            //A failed move or a dead controller ends this depth, everything else is worth another attempt
             match outcome {
-                InBrainOutcome::Success => {
-                    control_state.add_outcome(true);
+                InBrainOutcome::Success { final_target, achieved_depth } => {
+                    println!("Reached {} for depth {}", final_target, depth);
+                    record.success = true;
+                    record.achieved_depth = achieved_depth;
+                    break;
+                }
+                InBrainOutcome::OutOfTolerance { final_target, achieved_depth } => {
+                    println!("Reached {} for depth {}, but measured the needle at {:?}", final_target, depth, achieved_depth);
+                    record.achieved_depth = achieved_depth;
                     break;
                 }
-                InBrainOutcome::Failure => {
-                    control_state.add_outcome(false);
-                    println!("Failure");
+                InBrainOutcome::Failure { cause } => {
+                    println!("Failure at depth {}: {:?}", depth, cause);
                     break;
                 }
-                _ => {}
+                //The insertion already recovered from its panic
+                InBrainOutcome::Panic { reason } => {
+                    println!("Panicked at depth {}: {}", depth, reason);
+                    if give_up_after_panic(&control_state, *depth, &mut panics) {
+                        break;
+                    }
+                }
+                InBrainOutcome::Dead | InBrainOutcome::Aborted => {
+                    println!("Stopped at depth {}: {:?}", depth, outcome);
+                    break;
+                }
+                InBrainOutcome::Timeout | InBrainOutcome::Unreachable | InBrainOutcome::GraspFailed | InBrainOutcome::Drifted => {
+                    println!("Retrying depth {}: {:?}", depth, outcome);
+                }
             }
         }
+        control_state.add_move_record(record);
+        if control_state.dead(){
+            println!("Controller died, stopping early");
+            break;
+        }
     }
     transition_state(control_state.clone(), ControllerState::Dead, false);
     println!("Done");
+    //Stop our own tasks first so that no poll is left waiting on a robot that has stopped
+    control_state.request_shutdown();
+    for handle in handles {
+        handle.await.unwrap();
+    }
     //Send a message to the robot to stop
-    control_state.dead_tx.send(()).await.unwrap();
+    if let Some(dead_tx) = &control_state.dead_tx {
+        dead_tx.send(()).await.unwrap();
+    }
+    control_state.info.lock().unwrap().session_active = false;
+    match control_state.take_death_cause() {
+        Some(cause) => Err(cause),
+        None => Ok(SessionSummary { move_records: control_state.get_move_records(), panic_samples: control_state.get_panic_samples() }),
+    }
+}
+
+//Counts a panic at depth, returning true once there have been more than config.max_panic_recoveries_per_depth
+fn give_up_after_panic<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>, depth: u64, panics: &mut u64) -> bool {
+    *panics += 1;
+    if *panics > control_state.config.max_panic_recoveries_per_depth {
+        println!("Giving up on depth {} after {} panics", depth, panics);
+        return true;
+    }
+    false
+}
+
+//How waiting for the brain to calm down after a panic ended
+#[derive(Debug, PartialEq)]
+enum Cooldown {
+    Calm,
+    //We left OutOfBrainCalibrated or an abort was requested, which the run loop has to handle first
+    Interrupted,
+    TimedOut,
+}
+
+//Waits, at the pre move location, until the distance processor has seen the brain calm down since the last panic.
+//Without config.panic_cooldown there is nothing to wait for
+async fn wait_for_cooldown<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>) -> Cooldown {
+    let Some(cooldown) = control_state.config.panic_cooldown else {
+        return Cooldown::Calm;
+    };
+    let started = Instant::now();
+    while control_state.cooling_down() {
+        if !control_state.out_of_brain_calibrated() || control_state.abort_requested() {
+            return Cooldown::Interrupted;
+        }
+        if started.elapsed() >= Duration::from_millis(cooldown.max_wait_ms) {
+            return Cooldown::TimedOut;
+        }
+        sleep(control_state.poll_interval()).await;
+    }
+    Cooldown::Calm
 }
 
 //Move the needle to the pre_move_location
-async fn retract_ib<P: BrainPredictor>(control_state: Arc<Controller<P>>) {
+async fn retract_ib<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>) {
     move_bot(control_state.clone(), &Move::NeedleZ(0), ControllerState::OutOfBrainCalibrated, false).await;
-    assert!(control_state.get_recent_robot_state().await.unwrap().needle_z == 0);
+    //Having lost the robot, we have no idea where the needle ended up
+    if control_state.dead() {
+        return;
+    }
+    let Some(robot_state) = control_state.get_recent_robot_state().await else {
+        return;
+    };
+    assert!(robot_state.needle_z == 0);
+    //A panic during the retraction blocks the move's transition, so we recover from it here with the needle out
+    if control_state.in_panic() {
+        panic(control_state.clone()).await;
+        return;
+    }
     assert!(control_state.out_of_brain_calibrated());
 }
 
+//Pulls the needle out of the brain on an abort and dies. We keep whatever state we are in until the needle
+//is out, since a panic can't be left by a move. The retract is an emergency move, as the move we stopped
+//waiting on may still be going
+async fn abort_ib<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>) -> (InBrainOutcome, Option<u64>) {
+    println!("Aborting insertion");
+    let state = control_state.get_state();
+    move_bot_with_priority(control_state.clone(), &Move::NeedleZ(0), state, false, MovePriority::Emergency).await;
+    die_with(&control_state, ControllerError::Aborted);
+    (InBrainOutcome::Aborted, None)
+}
+
+//Grasps a thread, retrying failed grasps with the exponential backoff of config.grasp_retry. We stop retrying
+//once we are no longer calibrated out of brain, and return None if an abort is requested while backing off
+async fn grasp_with_retries<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>) -> Option<Result<(), RobotError>> {
+    let retry = control_state.config.grasp_retry;
+    let mut backoff = Duration::from_millis(retry.initial_backoff_ms);
+    let mut retries = 0;
+    loop {
+        let error = match control_state.command_grasp().await {
+            Ok(()) => return Some(Ok(())),
+            Err(error) => error,
+        };
+        if retries >= retry.max_retries || !control_state.out_of_brain_calibrated() {
+            return Some(Err(error));
+        }
+        retries += 1;
+        println!("Failed to grasp thread: {:?}, retrying in {}ms", error, backoff.as_millis());
+        until_abort(control_state, sleep(backoff)).await?;
+        backoff = (backoff * 2).min(Duration::from_millis(retry.max_backoff_ms));
+    }
+}
+
 //Moving the needle into the brain
-async fn insert_ib_open_loop<P: BrainPredictor>(control_state: Arc<Controller<P>>, commanded_depth: u64) -> InBrainOutcome {
-    assert!(commanded_depth >= COMMANDED_DEPTH_MIN_NM && commanded_depth <= COMMANDED_DEPTH_MAX_NM);
-    let pos = control_state.get_recent_robot_state().await.unwrap();
+//Returns the outcome along with the needle position we commanded, if we got far enough to command one
+async fn insert_ib_open_loop<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, commanded_depth: u64) -> (InBrainOutcome, Option<u64>) {
+    assert!(control_state.accepts_depth(commanded_depth));
+    let pos = match control_state.get_robot_state().await {
+        Ok(pos) => pos,
+        Err(cause) => {
+            die_with(&control_state, ControllerError::from_robot_error(&cause));
+            return (InBrainOutcome::Failure { cause }, None);
+        }
+    };
     assert!(pos.needle_z == 0 && pos.inserter_z == control_state.get_pre_move_location().unwrap(), "Needle not at zero, instead at: {:?}", pos);
+    //The needle can only be driven into the brain once it holds a thread
+    match grasp_with_retries(&control_state).await {
+        None => return abort_ib(control_state.clone()).await,
+        Some(Err(error)) => {
+            println!("Failed to grasp thread: {:?}", error);
+            return (InBrainOutcome::GraspFailed, None);
+        }
+        Some(Ok(())) => {}
+    }
     let init_time = Instant::now();
+    let max_ib_time = Duration::from_millis(control_state.config.max_ib_time_ms);
+    control_state.clear_prediction_failures();
     //Move the needle into the brain while we arent panicing or havent spent too long waiting
-    while !control_state.in_panic() && Instant::now().duration_since(init_time).as_millis() < MAX_IB_TIME.into() {
-        //Wait for the distance processor to tell us we can move
-        control_state.can_move.notified().await;
+    while !control_state.in_panic() && !control_state.dead() && init_time.elapsed() < max_ib_time {
+        //Wait for the distance processor to tell us we can move, but never past our time budget
+        match until_abort(&control_state, timeout(max_ib_time.saturating_sub(init_time.elapsed()), control_state.can_move.notified())).await {
+            None => return abort_ib(control_state.clone()).await,
+            Some(Err(_)) => break,
+            Some(Ok(_)) => {}
+        }
+        //The sample that woke us may also have made us panic, or the drift monitor recalibrate
+        if control_state.in_panic() || control_state.dead() || control_state.out_of_brain_uncalibrated() {
+            break;
+        }
         //If the move location is None, then we dont have a vlaid move on hand, based on the assumptions in predictor.rs
-        let Some(relative_position) = control_state.get_move_location(commanded_depth) else{
+        let Some(relative_position) = next_move_location(control_state.clone(), commanded_depth) else{
             continue;
         };
-        let response = {
-            control_state.command_move(&Move::NeedleZ(relative_position)).await
+        //The robot would reject a target past the needle's travel, so we don't spend the move on it
+        let needle_z_max = control_state.limits().needle_z_max;
+        if relative_position > needle_z_max {
+            println!("Position {} is past the needle limit of {}", relative_position, needle_z_max);
+            return (InBrainOutcome::Unreachable, Some(relative_position));
+        }
+        //A move can take far longer than the budget we have left, so we refuse to start one that won't finish in time
+        let move_time = control_state.needle_move_time(relative_position);
+        if init_time.elapsed() + move_time > max_ib_time {
+            println!("Move to {} would take {}ms, past the in brain time budget", relative_position, move_time.as_millis());
+            retract_ib(control_state.clone()).await;
+            return (InBrainOutcome::Timeout, None);
+        }
+        let insertion = command_insertion(&control_state, relative_position);
+        tokio::pin!(insertion);
+        let Some(response) = until_abort(&control_state, until_panic(&control_state, insertion.as_mut())).await else {
+            return abort_ib(control_state.clone()).await;
+        };
+        //A panic mid insertion retracts with an emergency move while the insertion is still in flight, which
+        //preempts it on robots that can
+        let response = match (response, control_state.get_state()) {
+            (Some(response), _) => response,
+            (None, ControllerState::Panic(reason)) => {
+                let _ = tokio::join!(insertion, panic(control_state.clone()));
+                return (InBrainOutcome::Panic { reason }, Some(relative_position));
+            }
+            //The panic was over before we got to it, so the insertion carries on
+            (None, _) => insertion.await,
         };
         //In all cases we break, either considering ourselves a success or a failure
         match response {
             Ok(_) => {
                 println!("Success full in brain move");
+                //Measured before retracting, while the needle is still where the move left it
+                let Some(tolerance) = control_state.config.success_tolerance_nm.filter(|_| !control_state.config.dry_run) else {
+                    retract_ib(control_state.clone()).await;
+                    return (InBrainOutcome::Success { final_target: relative_position, achieved_depth: None }, Some(relative_position));
+                };
+                let achieved_depth = measure_achieved_depth(&control_state, relative_position).await;
+                retract_ib(control_state.clone()).await;
+                let outcome = match achieved_depth {
+                    Some(depth) if depth.abs_diff(commanded_depth) <= tolerance => InBrainOutcome::Success { final_target: relative_position, achieved_depth },
+                    _ => InBrainOutcome::OutOfTolerance { final_target: relative_position, achieved_depth },
+                };
+                return (outcome, Some(relative_position));
+            }
+            //The robot tells us where a failed move left the needle, so we don't have to ask it
+            Err(cause @ RobotError::MoveError{ achieved_z, .. }) => {
+                println!("Move to position {} failed with the needle at {}", relative_position, achieved_z);
                 retract_ib(control_state.clone()).await;
-                return InBrainOutcome::Success;
+                return (InBrainOutcome::Failure { cause }, Some(relative_position));
             }
-            Err(RobotError::MoveError{..}) | Err(RobotError::ConnectionError{..}) => {
+            Err(cause @ RobotError::ConnectionError{..}) => {
                 println!("Connection error in moving to position: {}", relative_position);
                 retract_ib(control_state.clone()).await;
-                return InBrainOutcome::Failure;
+                return (InBrainOutcome::Failure { cause }, Some(relative_position));
             }
+            //The robot rejects targets outside of its limits without moving, so we back out and try again
             Err(RobotError::PositionError{..}) => {
-                die(control_state.clone());
-                break;
+                println!("Position {} is outside of the robot's limits", relative_position);
+                retract_ib(control_state.clone()).await;
+                return (InBrainOutcome::Unreachable, Some(relative_position));
             }
         }
     }
+    //If the robot reported a position error, we stop commanding it
+    if control_state.dead() {
+        return (InBrainOutcome::Dead, None);
+    }
     //If we panic, panic
-    if control_state.in_panic() {
+    if let ControllerState::Panic(reason) = control_state.get_state() {
         panic(control_state.clone()).await;
-    }else{
-        //If we dont panic, then we exit the brain
-        retract_ib(control_state.clone()).await;
+        return (InBrainOutcome::Panic { reason }, None);
+    }
+    //The needle is only ever in the brain during a move, so we can head straight back to the origin to recalibrate
+    if control_state.out_of_brain_uncalibrated() {
+        move_bot(control_state.clone(), &Move::InserterZ(0), ControllerState::OutOfBrainUncalibrated, false).await;
+        return (InBrainOutcome::Drifted, None);
+    }
+    //If we dont panic, then we ran out of time and exit the brain
+    retract_ib(control_state.clone()).await;
+    (InBrainOutcome::Timeout, None)
+}
+
+//Depth below the brain surface of a needle needle_z past the inserter, from a fresh OCT read of the surface.
+//None if the OCT failed ACHIEVED_DEPTH_READS times in a row
+async fn measure_achieved_depth<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>, needle_z: u64) -> Option<u64> {
+    for _ in 0..ACHIEVED_DEPTH_READS {
+        match control_state.get_surface_distance().await {
+            Ok(distance) => return Some(needle_z.saturating_sub(distance)),
+            Err(error) => println!("Could not measure the achieved depth: {:?}", error),
+        }
+    }
+    None
+}
+
+//Drives the needle to relative_position in one move, or with config.soft_landing in a fast move followed by a
+//slow one over the final segment. A failed fast move is returned without attempting the slow one
+async fn command_insertion<P: BrainPredictor, R: Robot + OCTService>(control_state: &Controller<P, R>, relative_position: u64) -> Result<(), RobotError> {
+    let Some(landing) = control_state.config.soft_landing else {
+        return control_state.command_move(&Move::NeedleZ(relative_position)).await;
+    };
+    if relative_position > landing.distance_nm {
+        control_state.command_move(&Move::NeedleZ(relative_position - landing.distance_nm)).await?;
     }
-    return InBrainOutcome::Panic;
+    control_state.command_move(&Move::NeedleZWithVelocity { target: relative_position, max_velocity: landing.max_velocity_nm_ms }).await
 }
 
 //This function is meant for moving outside of the brain and guarantees eventual consistency by looping until the move is successful
-async fn move_bot<P: BrainPredictor>(control_state: Arc<Controller<P>>, command: &Move, next_state: ControllerState, from_panic: bool) -> () {
+async fn move_bot<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, command: &Move, next_state: ControllerState, from_panic: bool) -> () {
+    move_bot_with_priority(control_state, command, next_state, from_panic, MovePriority::Normal).await
+}
+
+//Same as move_bot, but emergency moves preempt whatever the robot is doing
+async fn move_bot_with_priority<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, command: &Move, next_state: ControllerState, from_panic: bool, priority: MovePriority) -> () {
+    let mut connection_retries = 0;
     loop {
-        let response = control_state.command_move(command).await;
+        let response = control_state.send_move(command, priority).await;
         match response {
             Ok(_) => {
                 break;
             }
-            Err(RobotError::MoveError{..}) | Err(RobotError::ConnectionError{..}) => {
+            Err(RobotError::ConnectionError{..}) if connection_retries >= MAX_CONNECTION_RETRIES => {
+                println!("Lost the robot while moving to position: {}", command);
+                die_with(&control_state, ControllerError::RobotDisconnected);
+                return;
+            }
+            Err(RobotError::MoveError{ achieved_z, .. }) => {
+                println!("Error in moving to position: {}, the move stopped at {}", command, achieved_z);
+                connection_retries = 0;
+            }
+            Err(RobotError::ConnectionError{..}) => {
                 println!("Error in moving to position: {}", command);
+                connection_retries += 1;
             }
             Err(RobotError::PositionError{..}) => {
-                die(control_state.clone());
+                die_with(&control_state, ControllerError::RobotFault);
+                return;
             }
         }
         tokio::task::yield_now().await;
@@ -626,58 +2351,1674 @@ async fn move_bot<P: BrainPredictor>(control_state: Arc<Controller<P>>, command:
     transition_state(control_state,next_state, from_panic);
 }
 
+//Quotes a transition log field that holds a comma or quote, doubling its quotes, so every row keeps its four columns
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if !field.contains([',', '"']) {
+        return std::borrow::Cow::Borrowed(field);
+    }
+    std::borrow::Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+}
+
 //This function transitions our state
 //If we are ever in a panic state, we shouldn't let a successful move from prveious exit the panic
 //Thus we check this with the from_panic flag
-fn transition_state<P: BrainPredictor>(control_state: Arc<Controller<P>>, next_state: ControllerState, from_panic: bool) {
+fn transition_state<P: BrainPredictor, R: Robot + OCTService>(control_state: Arc<Controller<P, R>>, next_state: ControllerState, from_panic: bool) {
     let mut  can_change = !control_state.in_panic() || from_panic;
     can_change = can_change && !control_state.dead();
     if !can_change {
         println!("Cannot change state from {} to {}", control_state.get_state(), next_state);
+        control_state.log_transition(control_state.get_state(), next_state, "blocked");
         return;
     }
-    control_state.set_state(next_state);
+    control_state.change_state(next_state, if from_panic {"from_panic"} else {"transition"});
+}
+
+//Where a move takes the robot from state, once it has finished
+fn state_after_move(state: RobotState, command: &Move) -> RobotState {
+    match *command {
+        Move::InserterZ(inserter_z) => RobotState{inserter_z, ..state},
+        Move::NeedleZ(needle_z) | Move::NeedleZWithVelocity{target: needle_z, ..} => RobotState{needle_z, ..state},
+        Move::Both{inserter_z, needle_z} => RobotState{inserter_z, needle_z},
+    }
 }
 
 //This is the interface between the controller and the robot
-//Command grasp is mocked as always succeeding
+//Command grasp is passed through to the robot
 //Command move and get robot state ask to move until it receives a response from the robot
-impl<P: BrainPredictor> Robot for Controller<P>{
+//In a dry run nothing reaches the robot: moves are recorded as planned and complete instantly, grasps always
+//succeed, and the robot state is wherever the planned moves have taken it from the origin
+impl<P: BrainPredictor, R: Robot + OCTService> Robot for Controller<P, R>{
 
     async fn command_grasp(& self) -> Result<(), RobotError> {
-       return Ok(());
+        if self.config.dry_run {
+            return Ok(());
+        }
+        self.robot.command_grasp().await
     }
     
     async fn command_move(& self, move_type: &Move) -> Result<(), RobotError> {
-        loop{
-            let (tx, rx) = oneshot::channel();
-            match self.move_tx.send((move_type.clone(), tx)).await{
-                Ok(_) => return rx.await.unwrap(),
-                Err(_) => {}
-            }
-        };
+        self.send_move(move_type, MovePriority::Normal).await
     }
-    async fn get_robot_state(& self) -> Result<RobotState, RobotError> {
+
+    async fn command_emergency_move(& self, move_type: &Move) -> Result<(), RobotError> {
+        self.send_move(move_type, MovePriority::Emergency).await
+    }
+    fn limits(&self) -> RobotLimits {
+        self.robot.limits()
+    }
+
+    //Connection errors are retried until we get a state back, unless MAX_CONNECTION_RETRIES of them in a row
+    //say the robot is gone. Position errors are returned to the caller
+    async fn get_robot_state(& self) -> Result<RobotState, RobotError> {
+        if self.config.dry_run {
+            return Ok(self.info.lock().unwrap().planned_state);
+        }
+        let mut retries = 0;
         loop{
-            let (tx, rx) = oneshot::channel();
-            match self.state_tx.send(((), tx)).await{
-                Ok(_) => return rx.await.unwrap(),
-                Err(_) => {}
+            match self.robot.get_robot_state().await {
+                Err(RobotError::ConnectionError{..}) if retries < MAX_CONNECTION_RETRIES => retries += 1,
+                response => return response,
             }
         };
     }
 
 }
 
-impl<P: BrainPredictor> OCTService for Controller<P>{
+impl<P: BrainPredictor, R: Robot + OCTService> OCTService for Controller<P, R>{
     
     async fn get_surface_distance(& self) -> Result<u64, OCTError> {
-        loop{
-            let (tx, rx) = oneshot::channel();
-            match self.distance_tx.send(((), tx)).await{
-                Ok(_) => return rx.await.unwrap(),
-                Err(_) => {}
+        self.robot.get_surface_distance().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::predictor::CoefSink;
+    use crate::predictor::mock::MockPredictor;
+    use crate::predictor::quadratic_regression::{QuadraticRegression, LR_SIZE};
+
+    //Predicts the brain stays at 1mm from the inserter once it has any data
+    struct ConstantPredictor;
+
+    impl BrainPredictor for ConstantPredictor {
+        fn predict(&self, window: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)> {
+            if window.is_empty() {
+                return None;
             }
+            Some((|_: f64| 1_000_000.0, 1.0))
+        }
+    }
+
+    fn make_controller(config: ControllerConfig) -> Arc<Controller<ConstantPredictor>> {
+        make_controller_with(ConstantPredictor, config)
+    }
+
+    fn make_controller_with<P: BrainPredictor>(predictor: P, config: ControllerConfig) -> Arc<Controller<P>> {
+        let (distance_tx, _) = mpsc::channel(1);
+        let (state_tx, _) = mpsc::channel(1);
+        let (move_tx, _) = mpsc::channel(1);
+        let (dead_tx, _) = mpsc::channel(1);
+        Arc::new(Controller::with_config(distance_tx, state_tx, move_tx, dead_tx, predictor, config))
+    }
+
+    //Sets the notified distances as if they were sampled every 5ms up until now
+    fn notify_distances<P: BrainPredictor>(controller: &Controller<P>, distances: &[u64]) {
+        let now = Instant::now();
+        let mut info = controller.info.lock().unwrap();
+        info.notified_distances = distances.iter().map(|distance| Ok(*distance)).collect();
+        info.notified_distance_times = (0..distances.len()).rev().map(|i| now - Duration::from_millis(5 * i as u64)).collect();
+    }
+
+    //Feeds the distances through process_distances while the controller is calibrated
+    async fn process(controller: Arc<Controller<ConstantPredictor>>, distances: Vec<u64>) {
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        let (tx, rx) = mpsc::channel(distances.len());
+        for distance in distances {
+            tx.send((Ok(distance), Instant::now())).await.unwrap();
+        }
+        drop(tx);
+        process_distances(controller, rx).await;
+    }
+
+    //A signal that alternates between predicted and far off readings never has two abnormal
+    //samples in a row, but half of the window is abnormal so we should panic
+    #[tokio::test]
+    async fn test_alternating_abnormal_distances_panic() {
+        let controller = make_controller(ControllerConfig::default());
+        let distances = (0..100).map(|i| if i % 2 == 0 {1_000_000} else {2_000_000}).collect();
+        process(controller.clone(), distances).await;
+        assert!(matches!(controller.get_state(), ControllerState::Panic(PanicReason::AbnormalDistances{..})), "Unexpected state: {}", controller.get_state());
+    }
+
+    //Readings that stay inside half the safety margin panic as soon as they are most of the too close window,
+    //whatever the abnormal window holds
+    #[tokio::test]
+    async fn test_too_close_panic_reason() {
+        let controller = make_controller(ControllerConfig::default());
+        process(controller.clone(), vec![1_000_000, 1_000_000, 90_000, 80_000]).await;
+        assert!(controller.get_state() == ControllerState::Panic(PanicReason::TooClose{distance: 90_000}), "Unexpected state: {}", controller.get_state());
+    }
+
+    //A single spurious reading inside half the safety margin, surrounded by safe ones, is not enough to panic
+    #[tokio::test]
+    async fn test_single_too_close_reading_does_not_panic() {
+        let controller = make_controller(ControllerConfig::default());
+        process(controller.clone(), vec![1_000_000, 1_000_000, 90_000, 1_000_000, 1_000_000]).await;
+        assert!(controller.out_of_brain_calibrated(), "Unexpected state: {}", controller.get_state());
+    }
+
+    //Samples where the brain is predicted to be are queued without counting as abnormal, and one close enough
+    //to move at wakes the insertion with the samples before it
+    #[test]
+    fn test_fed_normal_distances() {
+        let controller = make_controller(ControllerConfig::default());
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        for _ in 0..5 {
+            controller.feed_distance(Ok(1_000_000), Instant::now());
+        }
+        assert!(controller.out_of_brain_calibrated(), "Unexpected state: {}", controller.get_state());
+        //Only the first sample, with nothing to predict it from, was abnormal
+        assert!(controller.get_abnormal_count() == 1);
+        assert!(controller.status().distance_queue_len == 5);
+        assert!(controller.info.lock().unwrap().notified_distances.is_empty());
+
+        controller.feed_distance(Ok(controller.move_trigger_distance() - 1), Instant::now());
+        assert!(controller.info.lock().unwrap().notified_distances.len() == 5);
+        assert!(controller.get_panic_samples().is_empty());
+    }
+
+    //Samples far from the prediction panic once they fill the abnormal threshold, on the sample that did it
+    #[test]
+    fn test_fed_abnormal_distances_panic() {
+        let controller = make_controller(ControllerConfig::default());
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        controller.feed_distance(Ok(1_000_000), Instant::now());
+        for _ in 1..ABNORMAL_THRESHOLD {
+            controller.feed_distance(Ok(2_000_000), Instant::now());
+        }
+        assert!(controller.get_state() == ControllerState::Panic(PanicReason::AbnormalDistances { count: ABNORMAL_THRESHOLD }), "Unexpected state: {}", controller.get_state());
+        assert!(controller.get_panic_samples() == vec![ABNORMAL_THRESHOLD as u64 - 1]);
+    }
+
+    //A dropout leaves the regression without a prediction until it is LR_SIZE samples back. The regression
+    //reports that it is waiting it out, so those samples aren't abnormal, unlike the ones before it had any
+    #[tokio::test(start_paused = true)]
+    async fn test_samples_awaiting_a_dropout_are_not_abnormal() {
+        let controller = make_controller_with(QuadraticRegression{}, ControllerConfig::default());
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        for _ in 0..LR_SIZE {
+            controller.feed_distance(Ok(1_000_000), Instant::now());
+            tokio::time::advance(Duration::from_millis(15)).await;
+        }
+        assert!(controller.get_abnormal_count() == LR_SIZE);
+        controller.feed_distance(Err(OCTError::CommunicationError { msg: "Connection error".to_string(), at_ms: None }), Instant::now());
+        for _ in 0..LR_SIZE - 1 {
+            tokio::time::advance(Duration::from_millis(15)).await;
+            controller.feed_distance(Ok(1_000_000), Instant::now());
+        }
+        assert!(controller.get_abnormal_count() == LR_SIZE, "Unexpected abnormal count: {}", controller.get_abnormal_count());
+        assert!(controller.out_of_brain_calibrated(), "Unexpected state: {}", controller.get_state());
+    }
+
+    //Most of the too close window inside half the safety margin panics at the window's median, but only once
+    //we are calibrated
+    #[test]
+    fn test_fed_too_close_distances_panic() {
+        let controller = make_controller(ControllerConfig::default());
+        controller.set_state(ControllerState::OutOfBrainUncalibrated);
+        for distance in [90_000, 80_000] {
+            controller.feed_distance(Ok(distance), Instant::now());
+        }
+        assert!(controller.out_of_brain_uncalibrated(), "Unexpected state: {}", controller.get_state());
+
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        controller.feed_distance(Ok(70_000), Instant::now());
+        assert!(controller.get_state() == ControllerState::Panic(PanicReason::TooClose { distance: 80_000 }), "Unexpected state: {}", controller.get_state());
+        assert!(controller.get_panic_samples() == vec![2]);
+    }
+
+    //Predicts the brain stays 200um from the inserter, counting how often it is asked to
+    #[derive(Default)]
+    struct CountedPredictor {
+        predictions: std::sync::atomic::AtomicUsize,
+    }
+
+    impl BrainPredictor for CountedPredictor {
+        fn predict(&self, window: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)> {
+            self.predictions.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if window.is_empty() {
+                return None;
+            }
+            Some((|_: f64| 200_000.0, 1.0))
+        }
+        fn predict_kinematics(&self, _: &DistanceWindow) -> Option<crate::predictor::Kinematics> {
+            Some(crate::predictor::Kinematics { position: 200_000.0, velocity: 0.0, acceleration: 0.0 })
+        }
+    }
+
+    //Every sample is checked for abnormality and close enough to move at, yet the move location reuses the
+    //prediction the abnormal check made, so each sample is only predicted from once
+    #[test]
+    fn test_prediction_is_cached_per_sample() {
+        const SAMPLES: usize = 20;
+        let controller = make_controller_with(CountedPredictor::default(), ControllerConfig::default());
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        let start = Instant::now() - Duration::from_millis(5 * SAMPLES as u64);
+        for i in 0..SAMPLES {
+            controller.feed_distance(Ok(200_000), start + Duration::from_millis(5 * i as u64));
+            let location = controller.get_move_location(3_100_000).unwrap();
+            //The first move location has no samples to predict from
+            assert!(location.is_some() == (i > 0), "Unexpected move location {:?} after {} samples", location, i + 1);
+        }
+        let predictions = controller.predictor.predictions.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(predictions <= SAMPLES, "Predicted {} times for {} samples", predictions, SAMPLES);
+
+        //A newer sample is predicted from afresh
+        controller.feed_distance(Ok(200_000), Instant::now());
+        assert!(controller.predictor.predictions.load(std::sync::atomic::Ordering::SeqCst) == predictions + 1);
+    }
+
+    //Without a newer sample, a prediction older than a poll interval is made again, so the predictor can reject the
+    //samples as stale rather than the cache serving them
+    #[tokio::test(start_paused = true)]
+    async fn test_cached_prediction_expires() {
+        let controller = make_controller_with(CountedPredictor::default(), ControllerConfig::default());
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        controller.feed_distance(Ok(200_000), Instant::now() - Duration::from_millis(5));
+        controller.feed_distance(Ok(200_000), Instant::now());
+        controller.get_move_location(3_100_000).unwrap();
+        let predictions = controller.predictor.predictions.load(std::sync::atomic::Ordering::SeqCst);
+        tokio::time::advance(Duration::from_millis(OCT_POLL_MILLIS)).await;
+        controller.get_move_location(3_100_000).unwrap();
+        assert!(controller.predictor.predictions.load(std::sync::atomic::Ordering::SeqCst) == predictions + 1);
+    }
+
+    //A brain approaching to 80um, below half of the minimum distance, but where it is predicted to be, panics
+    //unless close approaches are allowed
+    #[test]
+    fn test_close_approach_does_not_panic_when_allowed() {
+        let approach = [150_000, 120_000, 100_000, 90_000, 85_000, 80_000, 80_000, 80_000];
+        for allow_close_approach in [false, true] {
+            let config = ControllerConfig{allow_close_approach, ..ControllerConfig::default()};
+            let controller = make_controller_with(MockPredictor::always(vec![80_000.0]), config);
+            controller.set_state(ControllerState::OutOfBrainCalibrated);
+            for distance in approach {
+                controller.feed_distance(Ok(distance), Instant::now());
+            }
+            if allow_close_approach {
+                assert!(controller.out_of_brain_calibrated(), "Unexpected state: {}", controller.get_state());
+                assert!(controller.get_panic_samples().is_empty());
+            } else {
+                assert!(matches!(controller.get_state(), ControllerState::Panic(PanicReason::TooClose{..})), "Unexpected state: {}", controller.get_state());
+            }
+        }
+    }
+
+    //The samples are read back oldest first, as many as were asked for
+    #[tokio::test(start_paused = true)]
+    async fn test_recent_distances() {
+        let controller = make_controller(ControllerConfig::default());
+        process(controller.clone(), vec![1_000_000, 1_001_000]).await;
+        sleep(Duration::from_millis(10)).await;
+        process(controller.clone(), vec![1_002_000, 1_003_000]).await;
+        controller.add_distance(Err(OCTError::CommunicationError{msg: "Connection error".to_string(), at_ms: None}));
+        controller.add_distance_time(Instant::now());
+
+        let recent = controller.recent_distances(3);
+        assert_eq!(recent, vec![(Some(1_002_000), 10), (Some(1_003_000), 10), (None, 10)]);
+        let all = controller.recent_distances(100);
+        assert_eq!(all.iter().map(|(distance, _)| *distance).collect::<Vec<Option<u64>>>(),
+            vec![Some(1_000_000), Some(1_001_000), Some(1_002_000), Some(1_003_000), None]);
+        assert_eq!(all[0].1, 0);
+    }
+
+    //With the robot's ends of the channels dropped, requests fail instead of being resent forever and a
+    //move that can't reach the robot kills the controller
+    #[tokio::test]
+    async fn test_closed_robot_channels_return_errors() {
+        let controller = make_controller(ControllerConfig::default());
+        let response = timeout(Duration::from_secs(1), controller.robot.command_move(&Move::NeedleZ(0))).await.expect("command_move hung");
+        assert!(matches!(response, Err(RobotError::ConnectionError{..})), "Unexpected response: {:?}", response);
+        let response = timeout(Duration::from_secs(1), controller.get_robot_state()).await.expect("get_robot_state hung");
+        assert!(matches!(response, Err(RobotError::ConnectionError{..})), "Unexpected response: {:?}", response);
+        let response = timeout(Duration::from_secs(1), controller.get_surface_distance()).await.expect("get_surface_distance hung");
+        assert!(matches!(response, Err(OCTError::CommunicationError{..})), "Unexpected response: {:?}", response);
+
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        timeout(Duration::from_secs(1), move_bot(controller.clone(), &Move::InserterZ(0), ControllerState::OutOfBrainUncalibrated, false)).await.expect("move_bot hung");
+        assert!(controller.dead(), "Unexpected state: {}", controller.get_state());
+    }
+
+    //Normal samples between abnormal ones must not reset the count, it only drains as the window moves past them
+    #[tokio::test]
+    async fn test_abnormal_count_survives_normal_samples() {
+        let controller = make_controller(ControllerConfig::default());
+        //The very first reading has nothing to be predicted from and is always abnormal
+        process(controller.clone(), vec![1_000_000]).await;
+        controller.clear_abnormal();
+        process(controller.clone(), vec![1_000_000, 2_000_000, 1_000_000, 1_000_000, 2_000_000, 1_000_000]).await;
+        assert_eq!(controller.get_abnormal_count(), 2);
+        process(controller.clone(), vec![1_000_000; ABNORMAL_WINDOW - 2]).await;
+        assert_eq!(controller.get_abnormal_count(), 1);
+        process(controller.clone(), vec![1_000_000; 2]).await;
+        assert_eq!(controller.get_abnormal_count(), 0);
+    }
+
+    //Readings that trigger the too close panic are abnormal too and still count towards the window
+    #[tokio::test]
+    async fn test_too_close_samples_count_as_abnormal() {
+        let controller = make_controller(ControllerConfig::default());
+        process(controller.clone(), vec![1_000_000]).await;
+        controller.clear_abnormal();
+        process(controller.clone(), vec![1_000_000, 90_000, 80_000]).await;
+        assert!(matches!(controller.get_state(), ControllerState::Panic(PanicReason::TooClose{..})), "Unexpected state: {}", controller.get_state());
+        assert_eq!(controller.get_abnormal_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_abnormal_distances_do_not_panic() {
+        let controller = make_controller(ControllerConfig::default());
+        let distances = (0..100).map(|i| if i % 5 == 0 {2_000_000} else {1_000_000}).collect();
+        process(controller.clone(), distances).await;
+        assert!(controller.out_of_brain_calibrated());
+    }
+
+    //A brain moving away quickly is only caught after the default bracket ends, a wider one finds the intersection
+    #[test]
+    fn test_wider_root_bracket_finds_late_intersection() {
+        let coefs = vec![200_000.0, 32_000.0];
+        let controller = make_controller_with(MockPredictor::always(coefs.clone()), ControllerConfig::default());
+        notify_distances(&controller, &[200_000]);
+        assert!(matches!(controller.get_move_location(3_000_000), Err(OCTError::PredictionError{..})));
+
+        let root_finding = RootFinding{bracket_margin_ms: 1_000.0, ..RootFinding::default()};
+        let controller = make_controller_with(MockPredictor::always(coefs), ControllerConfig{root_finding, ..ControllerConfig::default()});
+        notify_distances(&controller, &[200_000]);
+        let target = controller.get_move_location(3_000_000).unwrap().unwrap();
+        //The needle meets the brain after the default bracket of roughly 435ms
+        let meeting_ms = (target as f64 - 3_200_000.0) / 32_000.0;
+        assert!(meeting_ms > 435.0, "Met the brain after {}ms at {}", meeting_ms, target);
+        assert!((controller.needle_distance_for_move_time(meeting_ms) - target as f64).abs() < 1_000.0);
+    }
+
+    #[test]
+    fn test_smooth_fit_moves() {
+        let controller = make_controller_with(QuadraticRegression{}, ControllerConfig::default());
+        notify_distances(&controller, &[202_000, 201_500, 201_000, 200_500, 200_000]);
+        assert!(controller.get_move_location(3_000_000).unwrap().is_some());
+    }
+
+    //Readings that jump around with no trend have an R^2 near zero, so we shouldn't move on them
+    #[test]
+    fn test_noisy_fit_skips_move() {
+        let controller = make_controller_with(QuadraticRegression{}, ControllerConfig::default());
+        notify_distances(&controller, &[190_500, 189_500, 190_500, 189_500, 190_500]);
+        {
+            let info = controller.info.lock().unwrap();
+            let window = DistanceWindow::new(&info.notified_distances, &info.notified_distance_times);
+            let (_, confidence) = controller.predictor.predict(&window, None).unwrap();
+            assert!(confidence < MIN_PREDICTION_CONFIDENCE, "Expected a poor fit but got R^2 {}", confidence);
+        }
+        assert!(controller.get_move_location(3_000_000).unwrap().is_none());
+    }
+
+    //Predicts the brain is running away faster than the needle could ever catch it
+    struct RunawayPredictor;
+
+    impl BrainPredictor for RunawayPredictor {
+        fn predict(&self, _: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)> {
+            Some((|x: f64| 200_000.0 + 1_000.0 * x * x, 1.0))
+        }
+    }
+
+    //Predicts a brain position that isn't a number, as degenerate coefficients would
+    struct NaNPredictor;
+
+    impl BrainPredictor for NaNPredictor {
+        fn predict(&self, _: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)> {
+            Some((|_: f64| f64::NAN, 1.0))
+        }
+    }
+
+    //NaN would be cast to a brain at 0, commanding a move to the commanded depth itself
+    #[test]
+    fn test_nan_prediction_has_no_move_location() {
+        let controller = make_controller_with(NaNPredictor, ControllerConfig::default());
+        notify_distances(&controller, &[200_000]);
+        assert!(controller.get_move_location(3_000_000).unwrap().is_none());
+    }
+
+    //Predicts the brain recedes from the inserter at 300um/ms, faster than the needle's 250um/ms
+    struct RecedingPredictor;
+
+    impl BrainPredictor for RecedingPredictor {
+        fn predict(&self, _: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)> {
+            Some((|x: f64| 200_000.0 + 300_000.0 * x, 1.0))
+        }
+    }
+
+    //The needle could never catch this brain, so we wait instead of moving, however deep the command
+    #[test]
+    fn test_receding_brain_waits_for_a_better_window() {
+        let controller = make_controller_with(RecedingPredictor, ControllerConfig::default());
+        notify_distances(&controller, &[200_000]);
+        for depth in [COMMANDED_DEPTH_MIN_NM, 3_000_000, COMMANDED_DEPTH_MAX_NM] {
+            assert!(controller.get_move_location(depth).unwrap().is_none(), "Chased a receding brain to {}", depth);
+        }
+    }
+
+    //Predicts the brain drifts away from the inserter at 100nm/ms
+    struct DriftingPredictor;
+
+    impl BrainPredictor for DriftingPredictor {
+        fn predict(&self, _: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)> {
+            Some((|x: f64| 200_000.0 + 100.0 * x, 1.0))
+        }
+    }
+
+    //A needle this slow cruises for almost all of a long move, so modelling it as accelerating the
+    //whole way would time the move, and so where the brain has drifted to, far too early
+    #[tokio::test(start_paused = true)]
+    async fn test_move_location_matches_needle_profile() {
+        use crate::robot::{RobotArmBuilder, SimulatedRobot};
+        const DEPTH: u64 = 6_000_000;
+        let config = ControllerConfig{needle_velocity_nm_ms: 2_000, needle_accel_nm_ms2: 250, ..ControllerConfig::default()};
+        let controller = make_controller_with(DriftingPredictor, config.clone());
+        notify_distances(&controller, &[200_000]);
+        //Depth below the brain surface the needle reaches when the robot runs the move, on a brain that
+        //drifts away just as the predictor says
+        let reached_depth = async |target: u64| {
+            let mut arm = RobotArmBuilder::new().error_probability(0.0)
+                .needle_velocity_nm_ms(config.needle_velocity_nm_ms).needle_accel_nm_ms2(config.needle_accel_nm_ms2).build();
+            arm.brain_location_fn = |elapsed_ms| 200_000 + 100 * elapsed_ms;
+            let robot = SimulatedRobot::new(arm);
+            robot.command_move(&Move::NeedleZ(target)).await.unwrap();
+            let arm = robot.arm();
+            let arm = arm.lock().await;
+            let snapshot = arm.snapshot();
+            assert!(snapshot.state.needle_z == target && arm.brain_distances.len() == 1, "Unexpected robot: {:?}, {:?}", snapshot, arm.brain_distances);
+            arm.brain_distances[0]
         };
+        let target = controller.get_move_location(DEPTH).unwrap().unwrap();
+        let reached = reached_depth(target).await;
+        let error = reached.abs_diff(DEPTH);
+        //Root of a/4 x^2 = 200_000 + 100x + DEPTH, the target if the needle never stopped accelerating
+        let a = config.needle_accel_nm_ms2 as f64;
+        let quadratic_arrival = (100.0 + (100.0f64.powi(2) + a * (200_000 + DEPTH) as f64).sqrt()) / (a / 2.0);
+        let quadratic_target = (200_000.0 + 100.0 * quadratic_arrival) as u64 + DEPTH;
+        let quadratic_error = reached_depth(quadratic_target).await.abs_diff(DEPTH);
+        assert!(error < 2_000, "Expected to reach {} but reached {}", DEPTH, reached);
+        assert!(quadratic_error > 100 * error, "Expected the quadratic model to be far off but it was {} vs {}", quadratic_error, error);
+    }
+
+    #[test]
+    fn test_repeated_prediction_failures_panic() {
+        let config = ControllerConfig{max_consecutive_prediction_failures: 3, abnormal_threshold: 2, ..ControllerConfig::default()};
+        let controller = make_controller_with(RunawayPredictor, config);
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        notify_distances(&controller, &[200_000]);
+        assert!(matches!(controller.get_move_location(3_000_000), Err(OCTError::PredictionError{..})));
+        //Every third failure is one abnormal sample, and two abnormal samples panic
+        for _ in 0..5 {
+            assert!(next_move_location(controller.clone(), 3_000_000).is_none());
+        }
+        assert!(controller.out_of_brain_calibrated());
+        assert!(next_move_location(controller.clone(), 3_000_000).is_none());
+        assert!(controller.get_state() == ControllerState::Panic(PanicReason::PredictionErrors{count: 2}), "Unexpected state: {}", controller.get_state());
+    }
+
+    //An OCT that stalls on every read, counting how many reads are in flight at once
+    #[derive(Default)]
+    struct StalledOCT {
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+    }
+
+    impl OCTService for StalledOCT {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            use std::sync::atomic::Ordering;
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            sleep(Duration::from_millis(200)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(1_000_000)
+        }
+    }
+
+    impl Robot for StalledOCT {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            Ok(RobotState{inserter_z: 0, needle_z: 0})
+        }
+        async fn command_move(&self, _: &Move) -> Result<(), RobotError> {
+            Ok(())
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            Ok(())
+        }
+    }
+
+    //A robot whose encoder reports an out of range position on every state read
+    struct BrokenEncoderRobot;
+
+    impl OCTService for BrokenEncoderRobot {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            Ok(1_000_000)
+        }
+    }
+
+    impl Robot for BrokenEncoderRobot {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            Err(RobotError::PositionError { msg: "test".to_string(), at_ms: None })
+        }
+        async fn command_move(&self, _: &Move) -> Result<(), RobotError> {
+            Ok(())
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            Ok(())
+        }
     }
-}
\ No newline at end of file
+
+    //A position error on a state read kills the controller for a robot fault and wakes anything waiting to move
+    #[tokio::test(start_paused = true)]
+    async fn test_position_error_on_state_read_dies() {
+        let controller = Arc::new(Controller::with_robot(Arc::new(BrokenEncoderRobot), ConstantPredictor));
+        controller.set_state(ControllerState::OutOfBrainUncalibrated);
+        tokio::task::LocalSet::new().run_until(async {
+            let waiter = tokio::task::spawn_local({let controller = Arc::clone(&controller);
+                async move { controller.can_move.notified().await }});
+            tokio::task::yield_now().await;
+            assert!(matches!(calibrate(Arc::clone(&controller)).await, Err(CalibrationError::RobotStateUnavailable)));
+            timeout(Duration::from_millis(1), waiter).await.expect("The waiter wasn't woken").unwrap();
+        }).await;
+        assert!(controller.dead());
+        assert!(controller.take_death_cause() == Some(ControllerError::RobotFault));
+    }
+
+    //Only a lost connection counts as the robot disconnecting, a failed move has a cause of its own
+    #[test]
+    fn test_robot_errors_map_to_their_cause() {
+        let cases = [
+            (RobotError::ConnectionError { msg: String::new(), at_ms: None }, ControllerError::RobotDisconnected),
+            (RobotError::MoveError { msg: String::new(), at_ms: None, achieved_z: 0 }, ControllerError::MoveFailed),
+            (RobotError::PositionError { msg: String::new(), at_ms: None }, ControllerError::RobotFault),
+        ];
+        for (error, expected) in cases {
+            assert!(ControllerError::from_robot_error(&error) == expected, "{:?} mapped to {:?}", error, ControllerError::from_robot_error(&error));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_oct_bounds_outstanding_polls() {
+        let oct = Arc::new(StalledOCT::default());
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&oct), ConstantPredictor));
+        let (tx, _rx) = mpsc::channel(100);
+        let local = tokio::task::LocalSet::new();
+        local.run_until(async {
+            let polls = tokio::task::spawn_local(poll_distance(Arc::clone(&controller), tx));
+            //Long enough for 100 polls if every tick spawned one
+            sleep(Duration::from_millis(100 * OCT_POLL_MILLIS)).await;
+            controller.request_shutdown();
+            polls.await.unwrap();
+        }).await;
+        let max_in_flight = oct.max_in_flight.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(max_in_flight == MAX_OUTSTANDING_POLLS, "Expected at most {} reads in flight but saw {}", MAX_OUTSTANDING_POLLS, max_in_flight);
+    }
+
+    //Mean absolute error of Taylor forecasts 20ms ahead of the simulated brain, made from what
+    //filter_distance queues for noisy samples taken every 5ms
+    fn taylor_forecast_error(distance_filter: DistanceFilter) -> f64 {
+        use crate::predictor::taylor_approx::TaylorQuadraticApproximator;
+        use crate::robot::RobotArmBuilder;
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::StdRng;
+        const SAMPLE_MILLIS: u64 = 5;
+        const HORIZON_MS: u64 = 20;
+        const HISTORY: usize = 10;
+        let controller = make_controller(ControllerConfig{distance_filter, ..ControllerConfig::default()});
+        controller.set_state(ControllerState::OutOfBrainCalibrated);
+        let brain_location_fn = RobotArmBuilder::new().build().brain_location_fn;
+        let mut rng = StdRng::seed_from_u64(7);
+        let queued = (0..1_000u64).map(|i| {
+            let noisy = brain_location_fn(i * SAMPLE_MILLIS) as f64 + rng.gen_range(-5_000.0..5_000.0);
+            controller.filter_distance(noisy as u64)
+        }).collect::<Vec<u64>>();
+        let errors = (HISTORY..queued.len()).step_by(7).map(|i| {
+            //Stamped afresh for every forecast so a slow test runner can't make the samples stale
+            let now = Instant::now();
+            let times = (0..HISTORY as u64).rev().map(|j| now - Duration::from_millis(j * SAMPLE_MILLIS)).collect::<Vec<Instant>>();
+            let distances = queued[i + 1 - HISTORY..=i].iter().map(|distance| Ok(*distance)).collect::<Vec<Result<u64, OCTError>>>();
+            let window = DistanceWindow::new(&distances, &times);
+            let (forecast, _) = TaylorQuadraticApproximator{}.predict(&window, None).unwrap();
+            (forecast(HORIZON_MS as f64) - brain_location_fn(i as u64 * SAMPLE_MILLIS + HORIZON_MS) as f64).abs()
+        }).collect::<Vec<f64>>();
+        errors.iter().sum::<f64>() / errors.len() as f64
+    }
+
+    #[test]
+    fn test_distance_filter_reduces_forecast_error() {
+        let raw = taylor_forecast_error(DistanceFilter::None);
+        let averaged = taylor_forecast_error(DistanceFilter::MovingAverage);
+        assert!(averaged < raw / 2.0, "Expected averaging to forecast much better than raw distances but got {} vs {}", averaged, raw);
+        //A median of a few samples does little against uniform noise, but it shouldn't hurt
+        let median = taylor_forecast_error(DistanceFilter::Median);
+        assert!(median < raw, "Expected the median to forecast better than raw distances but got {} vs {}", median, raw);
+    }
+
+    //A robot whose moves finish instantly over a still brain, by default 1.2mm below the inserter's origin.
+    //Every commanded move and OCT read is recorded
+    struct InstantRobot {
+        state: std::sync::Mutex<RobotState>,
+        moves: std::sync::Mutex<Vec<Move>>,
+        brain_z: std::sync::atomic::AtomicU64,
+        limits: RobotLimits,
+        oct_reads: std::sync::atomic::AtomicU64,
+        //Every oct_error_every-th OCT read fails, none do when 0
+        oct_error_every: u64,
+    }
+
+    impl InstantRobot {
+        fn new() -> InstantRobot {
+            InstantRobot{
+                state: std::sync::Mutex::new(RobotState{inserter_z: 0, needle_z: 0}),
+                moves: std::sync::Mutex::new(Vec::new()),
+                brain_z: std::sync::atomic::AtomicU64::new(1_200_000),
+                limits: RobotLimits::default(),
+                oct_reads: std::sync::atomic::AtomicU64::new(0),
+                oct_error_every: 0,
+            }
+        }
+    }
+
+    impl OCTService for InstantRobot {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            let reads = self.oct_reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            if self.oct_error_every > 0 && reads.is_multiple_of(self.oct_error_every) {
+                return Err(OCTError::CommunicationError { msg: "Connection error".to_string(), at_ms: None });
+            }
+            Ok(self.brain_z.load(std::sync::atomic::Ordering::SeqCst) - self.state.lock().unwrap().inserter_z)
+        }
+    }
+
+    impl Robot for InstantRobot {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            Ok(*self.state.lock().unwrap())
+        }
+        async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+            let mut state = self.state.lock().unwrap();
+            *state = state_after_move(*state, command);
+            self.moves.lock().unwrap().push(command.clone());
+            Ok(())
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            Ok(())
+        }
+        fn limits(&self) -> RobotLimits {
+            self.limits
+        }
+    }
+
+    //Staring at a brain 1.2mm away during calibration we poll at the slow rate, once in the brain at the fast one
+    #[tokio::test(start_paused = true)]
+    async fn test_polling_is_slower_far_out_of_brain() {
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), ConstantPredictor));
+        let reads = || robot.oct_reads.load(std::sync::atomic::Ordering::SeqCst);
+        controller.set_state(ControllerState::OutOfBrainUncalibrated);
+        tokio::task::LocalSet::new().run_until(async {
+            let (tx, rx) = mpsc::channel(20);
+            let polling = tokio::task::spawn_local(poll_distance(Arc::clone(&controller), tx));
+            let processing = tokio::task::spawn_local(process_distances(Arc::clone(&controller), rx));
+            sleep(Duration::from_millis(1_000)).await;
+            let calibration_reads = reads();
+
+            //1mm away, as the predictor expects, so the samples in the brain aren't abnormal
+            robot.state.lock().unwrap().inserter_z = 200_000;
+            controller.set_state(ControllerState::InBrain);
+            sleep(Duration::from_millis(1_000)).await;
+            let insertion_reads = reads() - calibration_reads;
+
+            controller.request_shutdown();
+            polling.await.unwrap();
+            processing.await.unwrap();
+            assert!(2 * insertion_reads > 3 * calibration_reads, "{} reads calibrating, {} inserting", calibration_reads, insertion_reads);
+        }).await;
+    }
+
+    //Polling slower while calibrating far from the brain takes fewer samples, not a longer stare
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_polling_keeps_the_calibration_stare() {
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        let stare_ms = CALIBRATION_SAMPLES * OCT_POLL_MILLIS;
+        let calibration_start = Instant::now();
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+        }).await;
+        let calibration_ms = calibration_start.elapsed().as_millis() as u64;
+        assert!(calibration_ms < stare_ms * 6 / 5, "Calibrating took {}ms for a {}ms stare", calibration_ms, stare_ms);
+        let reads = robot.oct_reads.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(reads < CALIBRATION_SAMPLES * 3 / 4, "Calibrating took {} OCT reads", reads);
+        assert!(controller.get_pre_move_location() == Some(1_200_000 - CALIBRATION_MARGIN_NM));
+    }
+
+    //With a 400um calibration margin the inserter parks 400um above the still brain at 1.2mm, but only readings
+    //within half of the 100um minimum distance panic us
+    #[tokio::test(start_paused = true)]
+    async fn test_calibration_margin_is_separate_from_min_distance() {
+        let config = ControllerConfig{calibration_margin_nm: 400_000, min_distance_to_brain_nm: 100_000, ..ControllerConfig::default()};
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![400_000.0]), config.clone()));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let moves = robot.moves.lock().unwrap().clone();
+        assert!(moves.iter().any(|command| matches!(command, Move::InserterZ(800_000))), "Unexpected moves: {:?}", moves);
+        let records = controller.get_move_records();
+        assert!(records.len() == 1 && records[0].success, "Unexpected records: {:?}", records);
+
+        let controller = make_controller(config);
+        process(controller.clone(), vec![1_000_000, 90_000, 80_000, 60_000]).await;
+        assert!(controller.out_of_brain_calibrated(), "Unexpected state: {}", controller.get_state());
+        process(controller.clone(), vec![40_000, 30_000]).await;
+        assert!(controller.get_state() == ControllerState::Panic(PanicReason::TooClose{distance: 40_000}), "Unexpected state: {}", controller.get_state());
+    }
+
+    //Parked 250um above the still brain, a predictor biased by 10um reaches 10um past the commanded depth, which is
+    //within a 20um tolerance, and one biased by 40um reaches 40um past it, which isn't
+    #[tokio::test(start_paused = true)]
+    async fn test_success_tolerance_classifies_insertions() {
+        const DEPTH: u64 = 3_100_000;
+        let config = ControllerConfig{success_tolerance_nm: Some(20_000), ..ControllerConfig::default()};
+        for (bias, success) in [(10_000, true), (40_000, false)] {
+            let robot = Arc::new(InstantRobot::new());
+            let predictor = MockPredictor::always(vec![(CALIBRATION_MARGIN_NM + bias) as f64]);
+            let controller = Arc::new(Controller::build(Arc::clone(&robot), None, predictor, config.clone()));
+            tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[DEPTH])).await.unwrap();
+            let records = controller.get_move_records();
+            assert!(records.len() == 1 && records[0].success == success, "Unexpected records with a {}nm bias: {:?}", bias, records);
+            assert!(records[0].achieved_depth == Some(DEPTH + bias), "Unexpected records with a {}nm bias: {:?}", bias, records);
+            //The move itself went through either way
+            assert!(robot.moves.lock().unwrap().iter().any(|command| matches!(command, Move::NeedleZ(target) if *target == CALIBRATION_MARGIN_NM + bias + DEPTH)));
+        }
+    }
+
+    //Staring at the still brain 1.2mm away through an OCT that fails every 7th read
+    #[tokio::test(start_paused = true)]
+    async fn test_calibration_report_counts_error_samples() {
+        let robot = Arc::new(InstantRobot{oct_error_every: 7, ..InstantRobot::new()});
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), ConstantPredictor));
+        assert!(controller.last_calibration().is_none());
+        controller.set_state(ControllerState::OutOfBrainUncalibrated);
+        tokio::task::LocalSet::new().run_until(async {
+            let (tx, rx) = mpsc::channel(20);
+            let polling = tokio::task::spawn_local(poll_distance(Arc::clone(&controller), tx));
+            let processing = tokio::task::spawn_local(process_distances(Arc::clone(&controller), rx));
+            calibrate(Arc::clone(&controller)).await.unwrap();
+            controller.request_shutdown();
+            polling.await.unwrap();
+            processing.await.unwrap();
+        }).await;
+        let report = controller.last_calibration().unwrap();
+        assert!(report.error_samples > 0, "Unexpected report: {:?}", report);
+        //This far from the brain the stare polls at the slow rate, except straight after an error
+        let stare_samples = report.valid_samples + report.error_samples;
+        assert!((CALIBRATION_SAMPLES as usize / 2..CALIBRATION_SAMPLES as usize).contains(&stare_samples), "Unexpected report: {:?}", report);
+        assert!(report.min_distance == 1_200_000, "Unexpected report: {:?}", report);
+        assert!(report.pre_move_location == Some(1_200_000 - CALIBRATION_MARGIN_NM), "Unexpected report: {:?}", report);
+        assert!(report.pre_move_location == controller.get_pre_move_location());
+    }
+
+    #[test]
+    #[should_panic(expected = "has to be larger")]
+    fn test_calibration_margin_has_to_exceed_min_distance() {
+        make_controller(ControllerConfig{calibration_margin_nm: 100_000, min_distance_to_brain_nm: 100_000, ..ControllerConfig::default()});
+    }
+
+    //A 3.3mm target is past a 3mm needle limit, so every attempt is turned down before the needle moves and
+    //the depth is recorded as a failure
+    #[tokio::test(start_paused = true)]
+    async fn test_target_past_needle_limit_is_not_commanded() {
+        let robot = Arc::new(InstantRobot{limits: RobotLimits{needle_z_max: 3_000_000, ..RobotLimits::default()}, ..InstantRobot::new()});
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.len() == 1 && !records[0].success, "Unexpected records: {:?}", records);
+        assert!(records[0].attempts == MAX_ATTEMPTS_PER_DEPTH && records[0].predicted_target == Some(3_300_000), "Unexpected record: {:?}", records[0]);
+        let moves = robot.moves.lock().unwrap();
+        assert!(!moves.iter().any(|command| matches!(command, Move::NeedleZ(z) if *z > 0)), "Unexpected moves: {:?}", moves);
+    }
+
+    //With the needle limited to 4mm, a 3.9mm depth only fits until calibration parks the inserter 250um above
+    //the brain. A 3.5mm one fits either way and a 6mm one never does
+    #[tokio::test(start_paused = true)]
+    async fn test_depth_reachability_follows_needle_limit() {
+        let robot = Arc::new(InstantRobot{limits: RobotLimits{needle_z_max: 4_000_000, ..RobotLimits::default()}, ..InstantRobot::new()});
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        assert!(controller.is_depth_reachable(3_900_000));
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+        }).await;
+        assert!(controller.is_depth_reachable(3_500_000));
+        assert!(!controller.is_depth_reachable(3_900_000));
+        assert!(!controller.is_depth_reachable(6_000_000));
+        //Below the accepted range, however short the move
+        assert!(!controller.is_depth_reachable(1_000_000));
+    }
+
+    //A brain slowly receding from a predictor that keeps forecasting 250um is never abnormal, but keeps the RMSE
+    //high for long enough that we give up on the insertion and go back to the origin to recalibrate
+    #[tokio::test(start_paused = true)]
+    async fn test_sustained_drift_triggers_recalibration() {
+        use std::sync::atomic::Ordering;
+        let robot = Arc::new(InstantRobot::new());
+        let drift_monitor = DriftMonitor{window: 20, max_rmse_nm: 20_000.0, sustain_ms: 1_000};
+        let config = ControllerConfig{drift_monitor: Some(drift_monitor), ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![250_000.0]), config));
+        let outcome = tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+            //Recedes 35um at 5um/s, past the monitor's limit but short of an abnormal prediction
+            tokio::task::spawn_local({ let robot = Arc::clone(&robot); async move {
+                for _ in 0..700 {
+                    robot.brain_z.fetch_add(50, Ordering::SeqCst);
+                    sleep(Duration::from_millis(10)).await;
+                }
+            }});
+            insert_ib_open_loop(Arc::clone(&controller), 3_100_000).await.0
+        }).await;
+        assert!(matches!(outcome, InBrainOutcome::Drifted), "Unexpected outcome: {:?}", outcome);
+        assert!(controller.out_of_brain_uncalibrated());
+        assert!(*robot.state.lock().unwrap() == RobotState{inserter_z: 0, needle_z: 0});
+        assert!(controller.get_panic_samples().is_empty());
+    }
+
+    //A single insertion is logged from the start of the session until the controller dies
+    #[tokio::test(start_paused = true)]
+    async fn test_transitions_are_logged_to_file() {
+        let path = std::env::temp_dir().join(format!("transitions_{}.csv", std::process::id()));
+        let config = ControllerConfig{transition_log: Some(path.clone()), ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::new(InstantRobot::new()), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = contents.lines().map(|line| line.split(',').collect::<Vec<&str>>()).collect::<Vec<Vec<&str>>>();
+        assert!(lines.iter().all(|line| line.len() == 4), "Malformed log: {}", contents);
+        let elapsed = lines.iter().map(|line| line[0].parse::<u64>().unwrap()).collect::<Vec<u64>>();
+        assert!(elapsed.windows(2).all(|pair| pair[0] <= pair[1]), "Out of order log: {}", contents);
+        let transitions = lines.iter().map(|line| (line[1], line[2])).collect::<Vec<(&str, &str)>>();
+        assert!(transitions[0] == ("Dead", "OutOfBrainUncalibrated") && lines[0][3] == "set", "Unexpected log: {}", contents);
+        let calibrated = transitions.iter().position(|transition| *transition == ("OutOfBrainUncalibrated", "OutOfBrainCalibrated"));
+        assert!(calibrated.is_some_and(|index| index < transitions.len() - 1), "Unexpected log: {}", contents);
+        assert!(transitions.last().unwrap().1 == "Dead", "Unexpected log: {}", contents);
+    }
+
+    //Leaving a panic without from_panic is refused, which is logged too
+    #[test]
+    fn test_blocked_transition_is_logged() {
+        let path = std::env::temp_dir().join(format!("blocked_transitions_{}.csv", std::process::id()));
+        let controller = make_controller(ControllerConfig{transition_log: Some(path.clone()), ..ControllerConfig::default()});
+        let panic = ControllerState::Panic(PanicReason::AbnormalDistances{count: 20});
+        controller.set_state(panic);
+        transition_state(controller.clone(), ControllerState::OutOfBrainCalibrated, false);
+        transition_state(controller.clone(), ControllerState::OutOfBrainUncalibrated, true);
+        die(&controller);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = contents.lines().map(|line| line.split_once(',').unwrap().1).collect::<Vec<&str>>();
+        assert!(lines == vec![
+            "Dead,Panic(AbnormalDistances(20)),set",
+            "Panic(AbnormalDistances(20)),OutOfBrainCalibrated,blocked",
+            "Panic(AbnormalDistances(20)),OutOfBrainUncalibrated,from_panic",
+            "OutOfBrainUncalibrated,Dead,set",
+        ], "Unexpected log: {}", contents);
+    }
+
+    //A NotAtOrigin panic's reason holds a comma, so its states are quoted to keep the row at four columns
+    #[test]
+    fn test_transition_with_comma_is_quoted() {
+        let path = std::env::temp_dir().join(format!("quoted_transitions_{}.csv", std::process::id()));
+        let controller = make_controller(ControllerConfig{transition_log: Some(path.clone()), ..ControllerConfig::default()});
+        let panic = ControllerState::Panic(PanicReason::NotAtOrigin { state: RobotState{inserter_z: 5, needle_z: 0} });
+        controller.set_state(panic);
+        die(&controller);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = contents.lines().map(|line| line.split_once(',').unwrap().1).collect::<Vec<&str>>();
+        assert!(lines == vec![
+            "Dead,\"Panic(NotAtOrigin(inserter 5nm, needle 0nm))\",set",
+            "\"Panic(NotAtOrigin(inserter 5nm, needle 0nm))\",Dead,set",
+        ], "Unexpected log: {}", contents);
+    }
+
+    //With the brain predicted to sit still 200um below the inserter, a 3.1mm insertion must target 3.3mm
+    #[tokio::test]
+    async fn test_mock_prediction_drives_one_insertion() {
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.len() == 1);
+        assert!(records[0].success && records[0].attempts == 1, "Unexpected record: {:?}", records[0]);
+        assert!(records[0].predicted_target == Some(3_300_000));
+        let insertions = robot.moves.lock().unwrap().iter().filter_map(|command| match command {
+            Move::NeedleZ(z) if *z != 0 => Some(*z),
+            _ => None,
+        }).collect::<Vec<u64>>();
+        assert!(insertions == vec![3_300_000], "Unexpected needle moves: {:?}", insertions);
+    }
+
+    //Calibration parks the inserter and needle in a single move when simultaneous moves are on
+    #[tokio::test]
+    async fn test_simultaneous_calibration_move() {
+        let robot = Arc::new(InstantRobot::new());
+        let config = ControllerConfig{simultaneous_moves: true, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        assert!(controller.get_move_records()[0].success);
+        let pre_move_location = controller.get_pre_move_location().unwrap();
+        let moves = robot.moves.lock().unwrap();
+        assert!(matches!(moves.first(), Some(Move::Both { inserter_z, needle_z: 0 }) if *inserter_z == pre_move_location), "Unexpected moves: {:?}", moves);
+        assert!(!moves.iter().any(|command| matches!(command, Move::InserterZ(_))), "Unexpected moves: {:?}", moves);
+    }
+
+    //With a soft landing the needle covers the final segment of the insertion at no more than the capped velocity
+    #[tokio::test(start_paused = true)]
+    async fn test_soft_landing_caps_final_velocity() {
+        use crate::robot::{RobotArmBuilder, SimulatedRobot};
+        let landing = SoftLanding{distance_nm: 300_000, max_velocity_nm_ms: 10_000};
+        let simulated = Arc::new(SimulatedRobot::new(RobotArmBuilder::new().error_probability(0.0).build()));
+        let arm = simulated.arm();
+        let config = ControllerConfig{soft_landing: Some(landing), ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(simulated, None, QuadraticRegression{}, config));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let record = controller.get_move_records()[0].clone();
+        assert!(record.success, "Unexpected record: {:?}", record);
+        let arm = arm.lock().await;
+        assert!(arm.brain_distances.len() == 1 && arm.brain_distances[0].abs_diff(3_100_000) < 200_000, "Reached {:?}", arm.brain_distances);
+
+        //Velocities between consecutive trajectory samples while the needle advances
+        let trajectory = arm.get_trajectory();
+        let target = trajectory.iter().map(|(_, state)| state.needle_z).max().unwrap();
+        let velocities = trajectory.windows(2)
+            .filter(|window| window[1].1.needle_z > window[0].1.needle_z && window[1].0 > window[0].0)
+            .map(|window| (window[0].1.needle_z, (window[1].1.needle_z - window[0].1.needle_z) as f64 / (window[1].0 - window[0].0) as f64))
+            .collect::<Vec<(u64, f64)>>();
+        let (slow, fast): (Vec<_>, Vec<_>) = velocities.into_iter().partition(|(z, _)| *z >= target - landing.distance_nm);
+        assert!(!slow.is_empty() && !fast.is_empty());
+        for (z, velocity) in slow {
+            assert!(velocity <= landing.max_velocity_nm_ms as f64, "Moving at {}nm/ms from {}", velocity, z);
+        }
+        assert!(fast.iter().any(|(_, velocity)| *velocity > 2.0 * landing.max_velocity_nm_ms as f64), "The rest of the insertion was slow too");
+    }
+
+    //Only the time from sending the needle in to having it back at zero counts as time in the brain, so waiting
+    //for a move is left out and successful insertions come in well under the time budget
+    #[tokio::test(start_paused = true)]
+    async fn test_time_in_brain_is_needle_dwell() {
+        use crate::robot::{RobotArmBuilder, SimulatedRobot};
+        let simulated = Arc::new(SimulatedRobot::new(RobotArmBuilder::new().error_probability(0.0).build()));
+        let controller = Arc::new(Controller::build(simulated, None, QuadraticRegression{}, ControllerConfig::default()));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000, 4_500_000, 6_000_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.iter().any(|record| record.success), "Unexpected records: {:?}", records);
+        for record in records.iter().filter(|record| record.success) {
+            assert!(record.time_in_brain_ms > 0 && record.time_in_brain_ms < MAX_IB_TIME, "Unexpected record: {:?}", record);
+        }
+    }
+
+    //A depth below the accepted range is recorded as a failure without an attempt, and the robot never moves
+    #[tokio::test(start_paused = true)]
+    async fn test_out_of_range_depth_is_skipped() {
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, QuadraticRegression{}, ControllerConfig::default()));
+        let result = tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[2_000_000])).await;
+        assert!(result == Err(ControllerError::InvalidCommands { rejected: vec![2_000_000] }), "Unexpected result: {:?}", result);
+        let records = controller.get_move_records();
+        assert_eq!(records, vec![MoveRecord{commanded_depth: 2_000_000, predicted_target: None, success: false, attempts: 0, time_in_brain_ms: 0, achieved_depth: None}]);
+        assert!(robot.moves.lock().unwrap().is_empty(), "Unexpected moves: {:?}", robot.moves.lock().unwrap());
+    }
+
+    //A reset controller runs a second session from scratch: it calibrates in full again instead of verifying the
+    //first session's pre move location, and only records the second session's depths
+    #[tokio::test(start_paused = true)]
+    async fn test_reset_between_sessions() {
+        use crate::robot::{RobotArmBuilder, SimulatedRobot, BRAIN_BASELINE_NM};
+        let mut arm = RobotArmBuilder::new().error_probability(0.0).build();
+        //Only the fast breathing. The slow swell brings the brain too close to where the second session calibrates,
+        //and the panics that follow cut its insertions short
+        arm.brain_location_fn = |x| (BRAIN_BASELINE_NM as f64 + 500_000.0 * (6.0 * x as f64/1000.0).sin()) as u64;
+        let simulated = Arc::new(SimulatedRobot::new(arm));
+        let controller = Arc::new(Controller::build(simulated, None, QuadraticRegression{}, ControllerConfig::default()));
+        let local = tokio::task::LocalSet::new();
+        local.run_until(async {
+            let session = tokio::task::spawn_local({let controller = Arc::clone(&controller);
+                async move { start(controller, &[3_100_000]).await }});
+            tokio::task::yield_now().await;
+            assert!(!controller.reset(), "Reset while a session was running");
+            session.await.unwrap().unwrap();
+        }).await;
+        assert!(controller.get_outcomes() == vec![true], "Unexpected records: {:?}", controller.get_move_records());
+
+        assert!(controller.reset());
+        assert!(controller.get_state() == ControllerState::OutOfBrainUncalibrated);
+        assert!(controller.get_move_records().is_empty() && controller.get_calibration_samples().is_empty());
+        local.run_until(start(Arc::clone(&controller), &[4_500_000, 5_000_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.iter().map(|record| record.commanded_depth).collect::<Vec<u64>>() == vec![4_500_000, 5_000_000], "Unexpected records: {:?}", records);
+        assert!(records.iter().all(|record| record.success), "Unexpected records: {:?}", records);
+        //A panic during the session may verify the location again afterwards, but the session starts from scratch
+        assert!(controller.get_calibration_samples().first() == Some(&(CALIBRATION_SAMPLES as usize)), "Calibrated with {:?}", controller.get_calibration_samples());
+    }
+
+    //A dry run against recorded distances plans the insertion, but never moves the robot
+    #[tokio::test(start_paused = true)]
+    async fn test_dry_run_plans_without_moving() {
+        const SAMPLE_MILLIS: u64 = 5;
+        //A still brain 1mm away for calibration, then 200um away once we would be at the premove location
+        //Planned moves finish instantly, so only a few samples go by before the premove location is reached
+        let trace = (0..1_200u64).map(|i| (i * SAMPLE_MILLIS, Ok(if i < 1_005 {1_000_000} else {200_000})))
+            .collect::<Vec<(u64, Result<u64, OCTError>)>>();
+        let robot = Arc::new(InstantRobot::new());
+        let config = ControllerConfig{dry_run: true, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(start_with_distance_source(Arc::clone(&controller), &[3_100_000], trace)).await.unwrap();
+
+        let planned = controller.get_planned_moves();
+        assert!(planned.iter().any(|command| matches!(command, Move::NeedleZ(3_300_000))), "Unexpected planned moves: {:?}", planned);
+        assert!(robot.moves.lock().unwrap().is_empty());
+        assert!(*robot.state.lock().unwrap() == RobotState{inserter_z: 0, needle_z: 0});
+    }
+
+    //Spawns the polling and processing tasks `run` would, for tests that drive the state machine by hand
+    fn spawn_polling_tasks<R: Robot + OCTService + 'static>(controller: &Arc<Controller<MockPredictor, R>>) {
+        let (tx_distance, rx_distance) = mpsc::channel(20);
+        let (tx_state, rx_state) = mpsc::channel(20);
+        tokio::task::spawn_local(poll_distance(Arc::clone(controller), tx_distance));
+        tokio::task::spawn_local(process_distances(Arc::clone(controller), rx_distance));
+        tokio::task::spawn_local(poll_state(Arc::clone(controller), tx_state));
+        tokio::task::spawn_local(process_robot_state(Arc::clone(controller), rx_state));
+    }
+
+    //A short calibration stares at only its own samples, while the queue still holds all of the runtime capacity
+    //once calibrated
+    #[tokio::test(start_paused = true)]
+    async fn test_distance_queue_capacity_is_separate_from_calibration() {
+        let config = ControllerConfig{calibration_samples: 50, distance_queue_capacity: 300, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::new(InstantRobot::new()), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+        }).await;
+        assert!(controller.get_calibration_samples() == vec![50]);
+        let calibration = controller.last_calibration().unwrap();
+        //Polling at the slow rate, the 50 sample stare takes about half as many
+        assert!((25..30).contains(&(calibration.valid_samples + calibration.error_samples)), "Unexpected calibration: {:?}", calibration);
+
+        assert!(controller.out_of_brain_calibrated());
+        for _ in 0..400 {
+            controller.add_distance(Ok(250_000));
+            controller.add_distance_time(Instant::now());
+        }
+        assert!(controller.status().distance_queue_len == 300, "Queued {}", controller.status().distance_queue_len);
+    }
+
+    //A panic raised while retracting is recovered from once the needle is out, rather than left for the caller
+    #[tokio::test]
+    async fn test_panic_during_retraction_is_recovered() {
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+            controller.set_state(ControllerState::Panic(PanicReason::TooClose { distance: 50_000 }));
+            retract_ib(Arc::clone(&controller)).await;
+            assert!(controller.out_of_brain_uncalibrated(), "Unexpected state: {}", controller.get_state());
+            assert!(controller.get_recent_robot_state().await == Some(RobotState{inserter_z: 0, needle_z: 0}));
+        }).await;
+    }
+
+    //Recalibrating after a panic only verifies the cached pre move location, unless the brain has come closer
+    #[tokio::test]
+    async fn test_recalibration_verifies_cached_pre_move_location() {
+        use std::sync::atomic::Ordering;
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+            assert!(controller.get_pre_move_location() == Some(1_200_000 - CALIBRATION_MARGIN_NM));
+
+            //The brain hasn't moved, so the cached location is verified
+            controller.set_state(ControllerState::Panic(PanicReason::AbnormalDistances { count: 0 }));
+            panic(Arc::clone(&controller)).await;
+            calibrate(Arc::clone(&controller)).await.unwrap();
+            assert!(controller.get_pre_move_location() == Some(1_200_000 - CALIBRATION_MARGIN_NM));
+
+            //The brain is now too close to the cached location, so we calibrate from scratch
+            controller.set_state(ControllerState::Panic(PanicReason::AbnormalDistances { count: 0 }));
+            robot.brain_z.store(1_100_000, Ordering::SeqCst);
+            panic(Arc::clone(&controller)).await;
+            calibrate(Arc::clone(&controller)).await.unwrap();
+            assert!(controller.get_pre_move_location() == Some(1_100_000 - CALIBRATION_MARGIN_NM));
+        }).await;
+        let samples = controller.get_calibration_samples();
+        assert!(samples == vec![CALIBRATION_SAMPLES as usize, VERIFICATION_SAMPLES, CALIBRATION_SAMPLES as usize], "Unexpected calibration samples: {:?}", samples);
+    }
+
+    //How a FaultyNeedleRobot's needle insertions misbehave
+    enum NeedleFault {
+        //The insertion reports its target straight away but takes a minute to finish
+        Stall,
+        //The insertion stops halfway and fails with a MoveError saying where
+        MoveError,
+    }
+
+    //An InstantRobot whose needle insertions (but not retractions) misbehave
+    struct FaultyNeedleRobot {
+        inner: InstantRobot,
+        fault: NeedleFault,
+    }
+
+    impl OCTService for FaultyNeedleRobot {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            self.inner.get_surface_distance().await
+        }
+    }
+
+    impl Robot for FaultyNeedleRobot {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            self.inner.get_robot_state().await
+        }
+        async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+            if !matches!(command, Move::NeedleZ(z) if *z != 0) {
+                return self.inner.command_move(command).await;
+            }
+            match self.fault {
+                NeedleFault::Stall => {
+                    self.inner.command_move(command).await?;
+                    sleep(Duration::from_secs(60)).await;
+                    Ok(())
+                }
+                NeedleFault::MoveError => {
+                    let Move::NeedleZ(target) = command else { unreachable!() };
+                    self.inner.command_move(&Move::NeedleZ(target / 2)).await?;
+                    Err(RobotError::MoveError { msg: "test".to_string(), at_ms: None, achieved_z: target / 2 })
+                }
+            }
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            self.inner.command_grasp().await
+        }
+    }
+
+    //Aborting mid insertion retracts the needle and kills the controller without waiting for the move
+    #[tokio::test]
+    async fn test_abort_retracts_and_dies() {
+        let robot = Arc::new(FaultyNeedleRobot{inner: InstantRobot::new(), fault: NeedleFault::Stall});
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        let start_time = Instant::now();
+        let result = tokio::task::LocalSet::new().run_until(async {
+            tokio::task::spawn_local({let controller = Arc::clone(&controller); let robot = Arc::clone(&robot);
+            async move {
+                while robot.inner.state.lock().unwrap().needle_z == 0 {
+                    sleep(Duration::from_millis(5)).await;
+                }
+                controller.abort();
+            }});
+            start(Arc::clone(&controller), &[3_100_000, 3_200_000]).await
+        }).await;
+        assert!(start_time.elapsed() < Duration::from_secs(30), "Abort took {}s", start_time.elapsed().as_secs());
+        assert!(result == Err(ControllerError::Aborted), "Unexpected result: {:?}", result);
+        assert!(controller.dead());
+        assert!(robot.inner.state.lock().unwrap().needle_z == 0);
+        let records = controller.get_move_records();
+        assert!(records.len() == 1 && !records[0].success, "Unexpected records: {:?}", records);
+    }
+
+    //Aborts the controller once the arm is partway through a needle insertion
+    async fn abort_mid_insertion<P: BrainPredictor, R: Robot + OCTService>(controller: Arc<Controller<P, R>>, arm: Arc<tokio::sync::Mutex<crate::robot::RobotArm>>) {
+        wait_mid_insertion(&arm).await;
+        controller.abort();
+    }
+
+    //Waits until the arm is partway through a needle insertion, returning the insertion's target
+    async fn wait_mid_insertion(arm: &tokio::sync::Mutex<crate::robot::RobotArm>) -> u64 {
+        loop {
+            let snapshot = arm.lock().await.snapshot();
+            if let Some(target) = snapshot.target.filter(|target| snapshot.is_moving && snapshot.progress > 0.2 && target.needle_z > 0) {
+                return target.needle_z;
+            }
+            sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    //Aborting mid move on a simulated robot stops the move where it is and retracts the needle from there
+    #[tokio::test(start_paused = true)]
+    async fn test_abort_simulated_robot_mid_move() {
+        use crate::robot::{RobotArmBuilder, SimulatedRobot};
+        let simulated = Arc::new(SimulatedRobot::new(RobotArmBuilder::new().error_probability(0.0).seed(0).build()));
+        let arm = simulated.arm();
+        let controller = Arc::new(Controller::with_robot(simulated, QuadraticRegression{}));
+        let result = tokio::task::LocalSet::new().run_until(async {
+            tokio::task::spawn_local(abort_mid_insertion(Arc::clone(&controller), Arc::clone(&arm)));
+            start(Arc::clone(&controller), &[3_100_000]).await
+        }).await;
+        assert!(result == Err(ControllerError::Aborted), "Unexpected result: {:?}", result);
+        assert!(controller.dead());
+        let arm = arm.lock().await;
+        let snapshot = arm.snapshot();
+        assert!(!snapshot.is_moving && snapshot.state.needle_z == 0, "Unexpected snapshot: {:?}", snapshot);
+        assert!(arm.brain_distances.is_empty(), "Unexpected brain distances: {:?}", arm.brain_distances);
+    }
+
+    //Aborting mid move on a robot behind channels preempts the move it is making and retracts the needle
+    #[tokio::test(start_paused = true)]
+    async fn test_abort_endpoint_mid_move() {
+        use crate::robot::RobotArmBuilder;
+        let (endpoint, requests) = RobotEndpoint::channel(100);
+        let arm = Arc::new(tokio::sync::Mutex::new(RobotArmBuilder::new().error_probability(0.0).seed(0).build()));
+        let controller = Arc::new(Controller::with_endpoint(endpoint, QuadraticRegression{}, ControllerConfig::default()));
+        let result = tokio::task::LocalSet::new().run_until(async {
+            let robot = tokio::task::spawn_local(crate::robot::start(requests.distance_rx, requests.state_rx, requests.move_rx, requests.dead_rx, Arc::clone(&arm)));
+            tokio::task::spawn_local(abort_mid_insertion(Arc::clone(&controller), Arc::clone(&arm)));
+            let result = start(Arc::clone(&controller), &[3_100_000]).await;
+            robot.await.unwrap();
+            result
+        }).await;
+        assert!(result == Err(ControllerError::Aborted), "Unexpected result: {:?}", result);
+        assert!(controller.dead());
+        let arm = arm.lock().await;
+        let snapshot = arm.snapshot();
+        assert!(!snapshot.is_moving && snapshot.state.needle_z == 0, "Unexpected snapshot: {:?}", snapshot);
+        assert!(arm.brain_distances.is_empty(), "Unexpected brain distances: {:?}", arm.brain_distances);
+    }
+
+    //Panicking mid insertion retracts the needle with an emergency move that cuts the insertion short, instead
+    //of waiting for the needle to reach its target first
+    #[tokio::test(start_paused = true)]
+    async fn test_panic_preempts_insertion() {
+        use crate::robot::{RobotArmBuilder, SimulatedRobot};
+        let simulated = Arc::new(SimulatedRobot::new(RobotArmBuilder::new().error_probability(0.0).seed(0).build()));
+        let arm = simulated.arm();
+        let config = ControllerConfig{panic_recovery: |_| PanicRecovery::Abort, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(simulated, None, QuadraticRegression{}, config));
+        let (result, target) = tokio::task::LocalSet::new().run_until(async {
+            let panicking = tokio::task::spawn_local({let controller = Arc::clone(&controller); let arm = Arc::clone(&arm);
+            async move {
+                let target = wait_mid_insertion(&arm).await;
+                controller.set_state(ControllerState::Panic(PanicReason::TooClose { distance: 50_000 }));
+                target
+            }});
+            let result = start(Arc::clone(&controller), &[3_100_000]).await;
+            (result, panicking.await.unwrap())
+        }).await;
+        assert!(result == Err(ControllerError::Aborted), "Unexpected result: {:?}", result);
+        let arm = arm.lock().await;
+        let deepest = arm.get_trajectory().iter().map(|(_, state)| state.needle_z).max().unwrap();
+        assert!(deepest < target, "The needle reached {} of its {} target", deepest, target);
+        assert!(arm.snapshot().state.needle_z == 0 && arm.brain_distances.is_empty(), "Unexpected arm: {:?}, {:?}", arm.snapshot(), arm.brain_distances);
+    }
+
+    //A move error mid insertion is reported with its cause, including where the needle stopped, rather than
+    //as a generic failure
+    #[tokio::test]
+    async fn test_move_error_outcome_carries_cause() {
+        let robot = Arc::new(FaultyNeedleRobot{inner: InstantRobot::new(), fault: NeedleFault::MoveError});
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        let (outcome, predicted_target) = tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+            insert_ib_open_loop(Arc::clone(&controller), 3_100_000).await
+        }).await;
+        assert!(matches!(outcome, InBrainOutcome::Failure { cause: RobotError::MoveError { achieved_z: 1_650_000, .. } }), "Unexpected outcome: {:?}", outcome);
+        assert!(predicted_target == Some(3_300_000));
+        assert!(robot.inner.state.lock().unwrap().needle_z == 0);
+    }
+
+    //An InstantRobot whose brain lunges to 40um below the inserter whenever a thread is grasped, and is back
+    //where it was once the inserter is at the origin
+    struct LungingBrainRobot {
+        inner: InstantRobot,
+    }
+
+    impl OCTService for LungingBrainRobot {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            self.inner.get_surface_distance().await
+        }
+    }
+
+    impl Robot for LungingBrainRobot {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            self.inner.get_robot_state().await
+        }
+        async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+            self.inner.command_move(command).await?;
+            if self.inner.state.lock().unwrap().inserter_z == 0 {
+                self.inner.brain_z.store(1_200_000, std::sync::atomic::Ordering::SeqCst);
+            }
+            Ok(())
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            let inserter_z = self.inner.state.lock().unwrap().inserter_z;
+            self.inner.brain_z.store(inserter_z + 40_000, std::sync::atomic::Ordering::SeqCst);
+            self.inner.command_grasp().await
+        }
+    }
+
+    //An InstantRobot whose brain seizes at the first grasp: the next OCT read sees it 40um below the inserter, and for
+    //2s after that it shakes 20um either side of where it was
+    struct SeizingRobot {
+        inner: InstantRobot,
+        seized_at: std::sync::Mutex<Option<Instant>>,
+        lunged: std::sync::atomic::AtomicBool,
+        insertions: std::sync::Mutex<Vec<Instant>>,
+    }
+
+    impl SeizingRobot {
+        const SHAKING: Duration = Duration::from_secs(2);
+
+        fn new() -> SeizingRobot {
+            SeizingRobot{inner: InstantRobot::new(), seized_at: std::sync::Mutex::new(None), lunged: std::sync::atomic::AtomicBool::new(false), insertions: std::sync::Mutex::new(Vec::new())}
+        }
+    }
+
+    impl OCTService for SeizingRobot {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            let distance = self.inner.get_surface_distance().await?;
+            let Some(seized_at) = *self.seized_at.lock().unwrap() else {
+                return Ok(distance);
+            };
+            if !self.lunged.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return Ok(40_000);
+            }
+            let shaking = seized_at.elapsed();
+            if shaking >= SeizingRobot::SHAKING {
+                return Ok(distance);
+            }
+            Ok(if (shaking.as_millis() / 50) % 2 == 0 { distance - 20_000 } else { distance + 20_000 })
+        }
+    }
+
+    impl Robot for SeizingRobot {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            self.inner.get_robot_state().await
+        }
+        async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+            if matches!(command, Move::NeedleZ(z) if *z != 0) {
+                self.insertions.lock().unwrap().push(Instant::now());
+            }
+            self.inner.command_move(command).await
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            self.seized_at.lock().unwrap().get_or_insert_with(Instant::now);
+            self.inner.command_grasp().await
+        }
+    }
+
+    //An InstantRobot whose first failing_grasps grasps fail
+    struct ClumsyRobot {
+        inner: InstantRobot,
+        failing_grasps: u64,
+        grasps: std::sync::Mutex<Vec<Instant>>,
+    }
+
+    impl OCTService for ClumsyRobot {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            self.inner.get_surface_distance().await
+        }
+    }
+
+    impl Robot for ClumsyRobot {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            self.inner.get_robot_state().await
+        }
+        async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+            self.inner.command_move(command).await
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            let mut grasps = self.grasps.lock().unwrap();
+            grasps.push(Instant::now());
+            if grasps.len() as u64 <= self.failing_grasps {
+                return Err(RobotError::MoveError { msg: "Failed to grasp the thread".to_string(), at_ms: None, achieved_z: 0 });
+            }
+            Ok(())
+        }
+    }
+
+    //An InstantRobot that stops answering robot requests from its first needle insertion on, while the OCT keeps working
+    struct DisconnectingRobot {
+        inner: InstantRobot,
+        disconnected: std::sync::atomic::AtomicBool,
+    }
+
+    impl DisconnectingRobot {
+        fn connection_error(&self) -> Result<(), RobotError> {
+            if self.disconnected.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(RobotError::ConnectionError { msg: "Disconnected".to_string(), at_ms: None });
+            }
+            Ok(())
+        }
+    }
+
+    impl OCTService for DisconnectingRobot {
+        async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+            self.inner.get_surface_distance().await
+        }
+    }
+
+    impl Robot for DisconnectingRobot {
+        async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+            self.connection_error()?;
+            self.inner.get_robot_state().await
+        }
+        async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+            if matches!(command, Move::NeedleZ(z) if *z != 0) {
+                self.disconnected.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+            self.connection_error()?;
+            self.inner.command_move(command).await
+        }
+        async fn command_grasp(&self) -> Result<(), RobotError> {
+            self.connection_error()?;
+            self.inner.command_grasp().await
+        }
+    }
+
+    //Losing the robot mid session ends it in RobotDisconnected rather than a summary
+    #[tokio::test(start_paused = true)]
+    async fn test_robot_disconnect_ends_session_in_error() {
+        let robot = Arc::new(DisconnectingRobot{inner: InstantRobot::new(), disconnected: std::sync::atomic::AtomicBool::new(false)});
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        let result = tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000, 3_200_000])).await;
+        assert!(result == Err(ControllerError::RobotDisconnected), "Unexpected result: {:?}", result);
+        assert!(controller.dead());
+        let records = controller.get_move_records();
+        assert!(records.len() == 1 && !records[0].success, "Unexpected records: {:?}", records);
+    }
+
+    //Every insertion panics as the brain comes too close, so each depth is given up on after its third panic
+    //instead of being retried until it runs out of attempts, and the session carries on to the next depth
+    #[tokio::test(start_paused = true)]
+    async fn test_panic_recoveries_per_depth_are_capped() {
+        let robot = Arc::new(LungingBrainRobot{inner: InstantRobot::new()});
+        let config = ControllerConfig{max_panic_recoveries_per_depth: 2, too_close_window: 1, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000, 3_200_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.len() == 2, "Unexpected records: {:?}", records);
+        for record in &records {
+            assert!(!record.success && record.attempts == 3, "Unexpected record: {:?}", record);
+        }
+        let insertions = robot.inner.moves.lock().unwrap().iter().filter(|command| matches!(command, Move::NeedleZ(z) if *z != 0)).count();
+        assert!(insertions == 0, "The needle went into a brain that was too close {} times", insertions);
+    }
+
+    //Predictions up to 120um off either way are just noise here. The fixed threshold panics on it, while the rolling
+    //quantile detector learns it while calibrating and only panics once samples are far off the noise
+    #[tokio::test]
+    async fn test_rolling_quantile_detector_adapts_to_noise() {
+        let noisy = |i: u64| 880_000 + (i * 37 % 241) * 1_000;
+        let rolling = AbnormalDetector::RollingQuantile { window: 200, quantile: 0.99, factor: 1.5, min_nm: 10_000.0 };
+        for abnormal_detector in [AbnormalDetector::FixedThreshold, rolling] {
+            let config = ControllerConfig{abnormal_detector, ..ControllerConfig::default()};
+            let controller = make_controller_with(MockPredictor::always(vec![1_000_000.0]), config);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            for i in 0..200 {
+                controller.feed_distance(Ok(noisy(i)), Instant::now());
+            }
+            controller.set_state(ControllerState::OutOfBrainCalibrated);
+            for i in 200..600 {
+                controller.feed_distance(Ok(noisy(i)), Instant::now());
+            }
+            if abnormal_detector == AbnormalDetector::FixedThreshold {
+                assert!(matches!(controller.get_state(), ControllerState::Panic(PanicReason::AbnormalDistances { .. })), "Expected a panic but was: {}", controller.get_state());
+                continue;
+            }
+            assert!(controller.out_of_brain_calibrated() && controller.get_abnormal_count() == 0, "Panicked on noise: {}", controller.get_state());
+            for _ in 0..ABNORMAL_THRESHOLD {
+                controller.feed_distance(Ok(1_400_000), Instant::now());
+            }
+            assert!(matches!(controller.get_state(), ControllerState::Panic(PanicReason::AbnormalDistances { .. })), "Expected a panic but was: {}", controller.get_state());
+        }
+    }
+
+    //The first two grasps fail, the third one after backing off 10ms and then 20ms holds, so the depth is reached
+    //on its first attempt
+    #[tokio::test(start_paused = true)]
+    async fn test_grasp_is_retried_with_backoff() {
+        let robot = Arc::new(ClumsyRobot{inner: InstantRobot::new(), failing_grasps: 2, grasps: std::sync::Mutex::new(Vec::new())});
+        let config = ControllerConfig{grasp_retry: GraspRetry{max_retries: 3, initial_backoff_ms: 10, max_backoff_ms: 100}, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.len() == 1 && records[0].success && records[0].attempts == 1, "Unexpected records: {:?}", records);
+        let grasps = robot.grasps.lock().unwrap().clone();
+        assert!(grasps.len() == 3, "Grasped {} times", grasps.len());
+        let backoffs = grasps.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<Duration>>();
+        assert!(backoffs == vec![Duration::from_millis(10), Duration::from_millis(20)], "Backed off for {:?}", backoffs);
+    }
+
+    //Once the retries run out the attempt fails, and the next attempt starts over with its own retries
+    #[tokio::test(start_paused = true)]
+    async fn test_grasp_retries_run_out() {
+        let robot = Arc::new(ClumsyRobot{inner: InstantRobot::new(), failing_grasps: 3, grasps: std::sync::Mutex::new(Vec::new())});
+        let config = ControllerConfig{grasp_retry: GraspRetry{max_retries: 1, ..GraspRetry::default()}, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.len() == 1 && records[0].success && records[0].attempts == 2, "Unexpected records: {:?}", records);
+        assert!(robot.grasps.lock().unwrap().len() == 4);
+    }
+
+    //The brain comes too close at the first grasp and keeps shaking for a while after. We retry from the pre move
+    //location, where the shaking brain is close enough to move at: without a cooldown we insert while it is still
+    //shaking, with one we wait until it has been calm for a full window of samples
+    #[tokio::test(start_paused = true)]
+    async fn test_panic_cooldown_waits_for_brain_to_calm_down() {
+        let cooldown = PanicCooldown{samples: 20, max_rmse_nm: 15_000.0, max_wait_ms: 10_000};
+        for panic_cooldown in [None, Some(cooldown)] {
+            let robot = Arc::new(SeizingRobot::new());
+            let config = ControllerConfig{
+                panic_cooldown,
+                too_close_window: 1,
+                panic_recovery: |_| PanicRecovery::RetryFromCalibrated,
+                ..ControllerConfig::default()
+            };
+            let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![240_000.0]), config));
+            tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+            let records = controller.get_move_records();
+            assert!(records.len() == 1 && records[0].success && records[0].attempts == 2, "Unexpected records: {:?}", records);
+            let calm_from = robot.seized_at.lock().unwrap().unwrap() + SeizingRobot::SHAKING;
+            let insertions = robot.insertions.lock().unwrap().clone();
+            assert!(insertions.len() == 1, "Unexpected insertions: {:?}", insertions);
+            assert!((insertions[0] >= calm_from) == panic_cooldown.is_some(), "Inserted {:?} before the brain calmed down with {:?}", calm_from - insertions[0], panic_cooldown);
+        }
+    }
+
+    //By default prediction errors are recalibrated for like any other panic
+    #[tokio::test]
+    async fn test_prediction_errors_recalibrate_by_default() {
+        let robot = Arc::new(InstantRobot::new());
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+
+            controller.set_state(ControllerState::Panic(PanicReason::PredictionErrors { count: 20 }));
+            panic(Arc::clone(&controller)).await;
+            assert!(controller.out_of_brain_uncalibrated(), "Expected out of brain uncalibrated but was: {}", controller.get_state());
+            assert!(*robot.state.lock().unwrap() == RobotState{inserter_z: 0, needle_z: 0});
+        }).await;
+    }
+
+    //Opting in, prediction errors retry from the pre move location, while a brain that came too close is
+    //recalibrated for
+    #[tokio::test]
+    async fn test_panic_recovery_policy() {
+        let robot = Arc::new(InstantRobot::new());
+        let config = ControllerConfig{panic_recovery: PanicReason::retry_after_prediction_errors, ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![200_000.0]), config));
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            calibrate(Arc::clone(&controller)).await.unwrap();
+
+            controller.set_state(ControllerState::Panic(PanicReason::PredictionErrors { count: 20 }));
+            panic(Arc::clone(&controller)).await;
+            assert!(controller.out_of_brain_calibrated(), "Expected out of brain calibrated but was: {}", controller.get_state());
+            assert!(*robot.state.lock().unwrap() == RobotState{inserter_z: 1_200_000 - CALIBRATION_MARGIN_NM, needle_z: 0});
+            assert!(controller.get_abnormal_count() == 0);
+
+            controller.set_state(ControllerState::Panic(PanicReason::TooClose { distance: 50_000 }));
+            panic(Arc::clone(&controller)).await;
+            assert!(controller.out_of_brain_uncalibrated(), "Expected out of brain uncalibrated but was: {}", controller.get_state());
+            assert!(*robot.state.lock().unwrap() == RobotState{inserter_z: 0, needle_z: 0});
+        }).await;
+        assert!(controller.get_calibration_samples().len() == 1);
+    }
+
+    //Only the newest max_outcome_history records are kept
+    #[tokio::test]
+    async fn test_outcome_history_is_capped() {
+        let robot = Arc::new(InstantRobot::new());
+        let config = ControllerConfig{max_outcome_history: Some(2), ..ControllerConfig::default()};
+        let controller = Arc::new(Controller::build(Arc::clone(&robot), None, MockPredictor::always(vec![200_000.0]), config));
+        let commands = vec![3_100_000, 3_200_000, 3_300_000, 3_400_000];
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &commands)).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.len() == 2 && controller.get_outcomes().len() == 2);
+        assert!(records.iter().map(|record| record.commanded_depth).collect::<Vec<u64>>() == commands[2..], "Unexpected records: {:?}", records);
+    }
+
+    //Calibration entered with the needle out is refused, and the run loop retracts and calibrates again
+    #[tokio::test]
+    async fn test_calibration_away_from_origin() {
+        let robot = Arc::new(InstantRobot::new());
+        robot.state.lock().unwrap().needle_z = 100_000;
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        tokio::task::LocalSet::new().run_until(async {
+            spawn_polling_tasks(&controller);
+            controller.set_state(ControllerState::OutOfBrainUncalibrated);
+            let error = calibrate(Arc::clone(&controller)).await.unwrap_err();
+            assert!(error == CalibrationError::NotAtOrigin { state: RobotState{inserter_z: 0, needle_z: 100_000} }, "Unexpected error: {}", error);
+        }).await;
+
+        let controller = Arc::new(Controller::with_robot(Arc::clone(&robot), MockPredictor::always(vec![200_000.0])));
+        tokio::task::LocalSet::new().run_until(start(Arc::clone(&controller), &[3_100_000])).await.unwrap();
+        let records = controller.get_move_records();
+        assert!(records.len() == 1 && records[0].success, "Unexpected records: {:?}", records);
+        assert!(matches!(robot.moves.lock().unwrap().first(), Some(Move::NeedleZ(0))));
+    }
+}
@@ -0,0 +1,657 @@
+use crate::controller::{self, Controller, ControllerConfig, ControllerError, MoveRecord, SessionSummary};
+use crate::interface::RobotEndpoint;
+use crate::predictor::BrainPredictor;
+#[cfg(any(test, feature = "virtual-clock"))]
+use crate::predictor::counting::CountingPredictor;
+use crate::robot::{self, RobotArm};
+#[cfg(any(test, feature = "virtual-clock"))]
+use crate::robot::{OCTJitter, RobotArmBuilder};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::{sync::Arc, thread};
+use tokio::sync::Mutex;
+use tokio::runtime::Builder;
+use tokio::task::LocalSet;
+
+/// The commanded depths (in nm) of a full session, 3.1mm to 6mm in 100um steps.
+pub fn default_commands() -> Vec<u64> {
+    (31..=60).map(|depth| depth * 100_000).collect()
+}
+
+/// SessionResult holds everything a finished session is checked against.
+///  - outcomes: whether each commanded depth succeeded, in commanded order
+///  - brain_distances: the depth below the brain surface each successful move actually reached
+///  - move_records: the controller's record of each commanded depth
+///  - panic_samples: the index of the distance sample behind each panic of the controller
+///  - seed: the robot's seed, which repeats the session's random draws when passed to `RobotArmBuilder::seed`
+///  - error: why the controller stopped early, None if it went through every commanded depth
+pub struct SessionResult {
+    pub outcomes: Vec<bool>,
+    pub brain_distances: Vec<u64>,
+    pub move_records: Vec<MoveRecord>,
+    pub panic_samples: Vec<u64>,
+    pub seed: u64,
+    pub error: Option<ControllerError>,
+}
+
+/// Environment variable that fixes the seed `session_seed` returns, to re-run a failed session.
+pub const SEED_ENV_VAR: &str = "ROBOT_SEED";
+
+/// The seed for a session's robot: the one in `SEED_ENV_VAR` if it is set, otherwise one drawn from the OS.
+/// Either way it is printed as `seed=...`, so a failed session can be re-run with the same draws.
+pub fn session_seed() -> u64 {
+    let seed = match std::env::var(SEED_ENV_VAR) {
+        Ok(seed) => seed.parse().unwrap_or_else(|_| panic!("{} has to be a u64, not {:?}", SEED_ENV_VAR, seed)),
+        Err(_) => rand::random(),
+    };
+    println!("Session running with seed={}", seed);
+    seed
+}
+
+/// A session running on its own threads, started by `Session::start`.
+pub struct Session<P: BrainPredictor> {
+    controller: Arc<Controller<P>>,
+    robot: Arc<Mutex<RobotArm>>,
+    seed: u64,
+    controller_handle: thread::JoinHandle<Result<SessionSummary, ControllerError>>,
+    robot_handle: thread::JoinHandle<()>,
+}
+
+impl<P: BrainPredictor + Send + Sync + 'static> Session<P> {
+    /// Starts a session of the given commanded depths against the robot simulation without waiting for it.
+    /// The controller and robot each get their own thread and single threaded runtime.
+    pub fn start(commands: Vec<u64>, predictor: P, robot_arm: RobotArm, config: ControllerConfig) -> Session<P> {
+        //Creates channels for communication between robot simulation and controller
+        let (endpoint, requests) = RobotEndpoint::channel(100);
+
+        let seed = robot_arm.seed;
+        let robot = Arc::new(Mutex::new(robot_arm));
+        let controller = Arc::new(Controller::with_endpoint(endpoint, predictor, config));
+
+        // Create and run the controller on its own thread
+        let controller_handle = thread::spawn({let controller = Arc::clone(&controller);
+        move || {
+            let rt = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let local = LocalSet::new();
+            local.block_on(&rt, async {
+                controller::start(controller, &commands).await
+            })
+        }});
+
+        // Create and run the robot sim on its own thread
+        let robot_handle = thread::spawn({let robot = Arc::clone(&robot);
+        move || {
+            let rt = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let local = LocalSet::new();
+            local.block_on(&rt, async move {
+                robot::start(requests.distance_rx, requests.state_rx, requests.move_rx, requests.dead_rx, robot).await;
+            });
+        }});
+
+        Session { controller, robot, seed, controller_handle, robot_handle }
+    }
+
+    /// The running controller, e.g. to poll its status.
+    pub fn controller(&self) -> &Controller<P> {
+        &self.controller
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.controller_handle.is_finished()
+    }
+
+    /// Waits for both threads to finish and collects the results.
+    pub fn join(self) -> SessionResult {
+        let result = self.controller_handle.join().unwrap();
+        self.robot_handle.join().unwrap();
+        let brain_distances = self.robot.blocking_lock().brain_distances.clone();
+        SessionResult {
+            outcomes: self.controller.get_outcomes(),
+            brain_distances,
+            move_records: self.controller.get_move_records(),
+            panic_samples: self.controller.get_panic_samples(),
+            seed: self.seed,
+            error: result.err(),
+        }
+    }
+}
+
+/// Runs a full session of the given commanded depths against the robot simulation and blocks until it finishes.
+pub fn run_session<P: BrainPredictor + Send + Sync + 'static>(commands: Vec<u64>, predictor: P, robot_arm: RobotArm) -> SessionResult {
+    run_session_with_config(commands, predictor, robot_arm, ControllerConfig::default())
+}
+
+/// Same as `run_session`, but with a configured controller.
+pub fn run_session_with_config<P: BrainPredictor + Send + Sync + 'static>(commands: Vec<u64>, predictor: P, robot_arm: RobotArm, config: ControllerConfig) -> SessionResult {
+    Session::start(commands, predictor, robot_arm, config).join()
+}
+
+/// Runs a full session on the current thread, with the controller and robot as tasks of the `LocalSet` it is
+/// awaited in. The two sides only hand over to each other where they await, so unlike `Session::start` the order
+/// they run in is decided by the program instead of by how two OS threads happen to be scheduled. On a runtime
+/// with a paused clock the timings are decided by it too, and a session is fully determined by its seed.
+pub async fn run_session_local<P: BrainPredictor + 'static>(commands: Vec<u64>, predictor: P, robot_arm: RobotArm, config: ControllerConfig) -> SessionResult {
+    let (endpoint, requests) = RobotEndpoint::channel(100);
+
+    let seed = robot_arm.seed;
+    let robot = Arc::new(Mutex::new(robot_arm));
+    let controller = Arc::new(Controller::with_endpoint(endpoint, predictor, config));
+
+    let robot_handle = tokio::task::spawn_local(robot::start(requests.distance_rx, requests.state_rx, requests.move_rx, requests.dead_rx, Arc::clone(&robot)));
+    let result = controller::start(Arc::clone(&controller), &commands).await;
+    robot_handle.await.unwrap();
+    let brain_distances = robot.lock().await.brain_distances.clone();
+    SessionResult {
+        outcomes: controller.get_outcomes(),
+        brain_distances,
+        move_records: controller.get_move_records(),
+        panic_samples: controller.get_panic_samples(),
+        seed,
+        error: result.err(),
+    }
+}
+
+/// Same as `run_session_with_config`, but runs the controller and robot on one single threaded runtime as in
+/// `run_session_local`, whose tokio clock is paused. Whenever every task is waiting on a timer the clock jumps
+/// straight to the next one, so the session takes as long as its computation rather than its simulated time.
+/// Everything in the controller, robot and predictors reads tokio's clock, so latencies and staleness are all
+/// measured in virtual time.
+/// Both sides have to share the runtime, as each runtime keeps its own paused clock.
+#[cfg(any(test, feature = "virtual-clock"))]
+pub fn run_session_virtual<P: BrainPredictor + 'static>(commands: Vec<u64>, predictor: P, robot_arm: RobotArm, config: ControllerConfig) -> SessionResult {
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    LocalSet::new().block_on(&rt, run_session_local(commands, predictor, robot_arm, config))
+}
+
+/// Returns the brain distances that belong to the given move records. The robot only records a brain
+/// distance for successful moves, so the n-th distance belongs to the n-th successful record. When the
+/// controller capped its history the oldest records are gone, so the newest distances are kept to match.
+pub fn aligned_brain_distances<'a>(records: &[MoveRecord], brain_distances: &'a [u64]) -> &'a [u64] {
+    let num_successes = records.iter().filter(|record| record.success).count();
+    &brain_distances[brain_distances.len().saturating_sub(num_successes)..]
+}
+
+/// Writes one CSV row per move record, in commanded order, for offline analysis.
+/// Distances are matched to records as in `aligned_brain_distances`. Records without one leave
+/// actual_distance and abs_error blank.
+pub fn export_csv(records: &[MoveRecord], brain_distances: &[u64], path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "index,commanded_depth,actual_distance,abs_error,success,attempts")?;
+    let mut brain_distances = aligned_brain_distances(records, brain_distances).iter();
+    for (index, record) in records.iter().enumerate() {
+        let actual_distance = if record.success { brain_distances.next() } else { None };
+        let (actual_distance, abs_error) = match actual_distance {
+            Some(distance) => (distance.to_string(), distance.abs_diff(record.commanded_depth).to_string()),
+            None => (String::new(), String::new()),
+        };
+        writeln!(writer, "{},{},{},{},{},{}", index, record.commanded_depth, actual_distance, abs_error, record.success, record.attempts)?;
+    }
+    writer.flush()
+}
+
+/// Summary of the absolute error between the commanded depth and the depth actually reached, over the
+/// successful moves. mean, max and stddev are None when no move succeeded. abs_errors holds every
+/// successful move's absolute error, smallest first, for the percentiles and histogram.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub mean: Option<f64>,
+    pub max: Option<u64>,
+    pub stddev: Option<f64>,
+    pub num_successes: usize,
+    pub abs_errors: Vec<u64>,
+}
+
+/// Absolute error edges (in nm) of the histogram printed by the demo, 50um wide up to 300um.
+pub const HISTOGRAM_EDGES_NM: [u64; 6] = [50_000, 100_000, 150_000, 200_000, 250_000, 300_000];
+
+/// One bucket of `Summary::histogram`, counting the absolute errors in [from_nm, to_nm). The last bucket
+/// has no upper edge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramBucket {
+    pub from_nm: u64,
+    pub to_nm: Option<u64>,
+    pub count: usize,
+}
+
+impl std::fmt::Display for HistogramBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.to_nm {
+            Some(to_nm) => write!(f, "{}-{}nm: {}", self.from_nm, to_nm, self.count),
+            None => write!(f, "{}nm and up: {}", self.from_nm, self.count),
+        }
+    }
+}
+
+impl Summary {
+    /// The absolute error `p` percent of the successful moves are within, by nearest rank. None when no
+    /// move succeeded.
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        if self.abs_errors.is_empty() {
+            return None;
+        }
+        let rank = (p / 100.0 * self.abs_errors.len() as f64).ceil() as usize;
+        Some(self.abs_errors[rank.clamp(1, self.abs_errors.len()) - 1])
+    }
+
+    /// Counts the absolute errors between consecutive `edges_nm`, which have to be ascending. The buckets
+    /// run from 0 to the first edge, between each pair of edges and from the last edge up.
+    pub fn histogram(&self, edges_nm: &[u64]) -> Vec<HistogramBucket> {
+        let lower_edges = std::iter::once(0).chain(edges_nm.iter().copied());
+        let upper_edges = edges_nm.iter().copied().map(Some).chain(std::iter::once(None));
+        lower_edges.zip(upper_edges).map(|(from_nm, to_nm)| {
+            let count = self.abs_errors.iter().filter(|error| **error >= from_nm && to_nm.is_none_or(|to_nm| **error < to_nm)).count();
+            HistogramBucket { from_nm, to_nm, count }
+        }).collect()
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.mean, self.max, self.stddev) {
+            (Some(mean), Some(max), Some(stddev)) => {
+                writeln!(f, "Average absolute distance: {:.0}", mean)?;
+                writeln!(f, "Max absolute distance: {}", max)?;
+                writeln!(f, "Std dev: {}", stddev)?;
+                for p in [50.0, 90.0, 99.0] {
+                    writeln!(f, "p{}: {}", p, self.percentile(p).unwrap())?;
+                }
+            }
+            _ => writeln!(f, "No successful moves")?,
+        }
+        write!(f, "Num successes: {}", self.num_successes)
+    }
+}
+
+/// Summarizes the absolute errors of the successful moves, matching distances to records as in
+/// `aligned_brain_distances`.
+pub fn summarize(records: &[MoveRecord], brain_distances: &[u64]) -> Summary {
+    let mut abs_errors = records.iter().filter(|record| record.success).zip(aligned_brain_distances(records, brain_distances).iter())
+        .map(|(record, distance)| distance.abs_diff(record.commanded_depth))
+        .collect::<Vec<u64>>();
+    if abs_errors.is_empty() {
+        return Summary { mean: None, max: None, stddev: None, num_successes: 0, abs_errors };
+    }
+    abs_errors.sort_unstable();
+    let len = abs_errors.len() as f64;
+    let mean = abs_errors.iter().sum::<u64>() as f64 / len;
+    let stddev = (abs_errors.iter().map(|error| (*error as f64 - mean).powi(2)).sum::<f64>() / len).sqrt();
+    Summary {
+        mean: Some(mean),
+        max: abs_errors.iter().max().copied(),
+        stddev: Some(stddev),
+        num_successes: abs_errors.len(),
+        abs_errors,
+    }
+}
+
+/// How long (in ms) the default setup acts on information the OCT hasn't caught up with: a distance read takes
+/// the OCT's mean latency to answer, and is made on average half an OCT poll interval after the brain got where
+/// the next read sees it. The needle's move time isn't counted, the predictors forecast the brain across it.
+pub const DEFAULT_TOTAL_LATENCY_MS: f64 = robot::OCT_LATENCY_MILLIS as f64 + controller::OCT_POLL_MILLIS as f64 / 2.0;
+
+/// Estimates the smallest absolute depth error (in nm) we can expect when the brain moves at
+/// `brain_velocity_nm_ms` and the controller only sees it `total_latency_ms` late, e.g. `DEFAULT_TOTAL_LATENCY_MS`.
+/// The model is that whatever the brain does within the latency is unseen, so it is off by as far as it moves in
+/// that time. Predictors win some of that back by extrapolating, so it isn't a hard floor, but mean errors well
+/// above it point at the controller rather than at the latency.
+pub fn theoretical_accuracy_bound(brain_velocity_nm_ms: f64, total_latency_ms: f64) -> u64 {
+    (brain_velocity_nm_ms.abs() * total_latency_ms.max(0.0)).round() as u64
+}
+
+/// The robot every predictor is benchmarked against. The OCT latency jitters, which the predictors have to
+/// cope with, but no errors are injected, as they panic the controller rather than test the predictor.
+/// Everything random is drawn from `seed`.
+#[cfg(any(test, feature = "virtual-clock"))]
+pub fn benchmark_robot(seed: u64) -> RobotArm {
+    RobotArmBuilder::new()
+        .seed(seed)
+        .oct_jitter(OCTJitter::Uniform { max_ms: 2 })
+        .build()
+}
+
+/// One predictor's row of a benchmark.
+///  - summary: accuracy of the successful moves, as in `summarize`
+///  - num_commands: how many depths were commanded
+///  - predict_calls: how many forecasts the controller asked the predictor for
+///  - predict_failures: how many of those came back as None
+#[cfg(any(test, feature = "virtual-clock"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkRow {
+    pub name: String,
+    pub summary: Summary,
+    pub num_commands: usize,
+    pub predict_calls: usize,
+    pub predict_failures: usize,
+}
+
+#[cfg(any(test, feature = "virtual-clock"))]
+impl BenchmarkRow {
+    /// Fraction of the commanded depths that were reached.
+    pub fn success_rate(&self) -> f64 {
+        if self.num_commands == 0 {
+            return 0.0;
+        }
+        self.summary.num_successes as f64 / self.num_commands as f64
+    }
+}
+
+/// Runs a full session of `commands` with the predictor against `benchmark_robot(seed)` on the virtual
+/// clock, so rows for the same seed and commands are reproducible and comparable across predictors.
+#[cfg(any(test, feature = "virtual-clock"))]
+pub fn benchmark_predictor<P: BrainPredictor + 'static>(name: &str, predictor: P, commands: &[u64], seed: u64) -> BenchmarkRow {
+    let predictor = CountingPredictor::new(predictor);
+    let counts = predictor.counts();
+    let session = run_session_virtual(commands.to_vec(), predictor, benchmark_robot(seed), ControllerConfig::default());
+    BenchmarkRow {
+        name: name.to_string(),
+        summary: summarize(&session.move_records, &session.brain_distances),
+        num_commands: commands.len(),
+        predict_calls: counts.calls(),
+        predict_failures: counts.failures(),
+    }
+}
+
+/// Formats benchmark rows as a table with one line per predictor. Errors are in nm, and are left as "-"
+/// for predictors without a successful move.
+#[cfg(any(test, feature = "virtual-clock"))]
+pub fn format_benchmark_table(rows: &[BenchmarkRow]) -> String {
+    let mut table = format!("{:<12} {:>10} {:>10} {:>10} {:>9} {:>8} {:>8}\n", "predictor", "mean", "max", "stddev", "success", "calls", "none");
+    for row in rows {
+        let mean = row.summary.mean.map_or("-".to_string(), |mean| format!("{:.0}", mean));
+        let max = row.summary.max.map_or("-".to_string(), |max| max.to_string());
+        let stddev = row.summary.stddev.map_or("-".to_string(), |stddev| format!("{:.0}", stddev));
+        let success = format!("{}/{}", row.summary.num_successes, row.num_commands);
+        table.push_str(&format!("{:<12} {:>10} {:>10} {:>10} {:>9} {:>8} {:>8}\n", row.name, mean, max, stddev, success, row.predict_calls, row.predict_failures));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(commanded_depth: u64, success: bool) -> MoveRecord {
+        MoveRecord{commanded_depth, predicted_target: None, success, attempts: 1, time_in_brain_ms: 0, achieved_depth: None}
+    }
+
+    //Three depths take tens of seconds of simulated time, all of which the paused clock has to pass through
+    #[test]
+    fn test_run_session_virtual() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        let commands = vec![3_100_000, 4_000_000, 5_000_000];
+        let rt = Builder::new_current_thread().enable_all().start_paused(true).build().unwrap();
+        let (session, elapsed) = LocalSet::new().block_on(&rt, async {
+            let start = tokio::time::Instant::now();
+            let session = run_session_local(commands.clone(), QuadraticRegression{}, RobotArm::new(0, false, false), ControllerConfig::default()).await;
+            (session, start.elapsed())
+        });
+        assert!(session.move_records.len() == commands.len());
+        assert!(session.outcomes.iter().all(|outcome| *outcome), "Unexpected outcomes: {:?}", session.outcomes);
+        for (record, distance) in session.move_records.iter().zip(session.brain_distances.iter()) {
+            assert!(distance.abs_diff(record.commanded_depth) < 200_000, "Reached {} for {}", distance, record.commanded_depth);
+        }
+        //Calibration alone stares at the brain for 1000 samples polled every 5ms
+        let virtual_ms = 5_000 + session.move_records.iter().map(|record| record.time_in_brain_ms).sum::<u64>();
+        assert!(elapsed.as_millis() as u64 >= virtual_ms, "The clock only advanced {}ms for {}ms of simulated time", elapsed.as_millis(), virtual_ms);
+    }
+
+    //A slow drift of the brain towards the inserter is absorbed by the calibration, so each depth is still reached
+    #[test]
+    fn test_run_session_virtual_with_drift() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        use crate::robot::{OCTDrift, RobotArmBuilder};
+        let commands = vec![3_100_000, 3_500_000, 4_000_000, 4_500_000, 5_000_000];
+        let robot_arm = RobotArmBuilder::new().error_probability(0.0).oct_drift(OCTDrift::Linear { nm_per_s: -1_000.0 }).build();
+        let session = run_session_virtual(commands.clone(), QuadraticRegression{}, robot_arm, ControllerConfig::default());
+        assert!(session.outcomes.iter().all(|outcome| *outcome), "Unexpected outcomes: {:?}", session.outcomes);
+        for (record, distance) in session.move_records.iter().zip(session.brain_distances.iter()) {
+            assert!(distance.abs_diff(record.commanded_depth) < 200_000, "Reached {} for {}", distance, record.commanded_depth);
+        }
+    }
+
+    //A seizure once the controller has calibrated makes it panic on abnormal distances and recalibrate, after which
+    //the session carries on. A first run without the seizure finds when calibration ends, which the seeded robot on
+    //the virtual clock repeats up to the seizure
+    #[test]
+    fn test_run_session_virtual_with_seizure() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        use crate::robot::{BrainSeizure, RobotArmBuilder};
+        let commands = vec![3_100_000, 3_500_000, 4_000_000, 4_500_000, 5_000_000];
+        let seed = session_seed();
+        let run = |seizures: Vec<BrainSeizure>, name: &str| {
+            let path = std::env::temp_dir().join(format!("{}_{}.csv", name, std::process::id()));
+            let config = ControllerConfig{transition_log: Some(path.clone()), ..ControllerConfig::default()};
+            let robot_arm = RobotArmBuilder::new().error_probability(0.0).seed(seed).seizures(seizures).build();
+            let session = run_session_virtual(commands.clone(), QuadraticRegression{}, robot_arm, config);
+            let log = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            (session, log)
+        };
+        let (calm, log) = run(Vec::new(), "before_seizure");
+        assert!(calm.panic_samples.is_empty(), "Panicked without a seizure, seed={}", seed);
+        let calibrated_ms = log.lines().find(|line| line.ends_with(",OutOfBrainUncalibrated,OutOfBrainCalibrated,transition"))
+            .and_then(|line| line.split(',').next()?.parse::<u64>().ok())
+            .unwrap_or_else(|| panic!("Never calibrated, seed={}: {}", seed, log));
+
+        let seizure = BrainSeizure { start_ms: calibrated_ms, duration_ms: 1_500, amplitude_nm: 300_000.0 };
+        let (session, log) = run(vec![seizure], "seizure");
+        assert!(!session.panic_samples.is_empty(), "The seizure didn't panic the controller, seed={}", seed);
+        let panics = log.lines().filter_map(|line| line.split(',').nth(2)).filter(|to| to.starts_with("Panic(")).collect::<Vec<&str>>();
+        assert!(!panics.is_empty() && panics.iter().all(|to| to.starts_with("Panic(AbnormalDistances(")), "Unexpected panics {:?}, seed={}", panics, seed);
+        assert!(session.outcomes.len() == commands.len(), "Session stopped after {:?}, seed={}", session.outcomes, seed);
+        for (record, distance) in session.move_records.iter().filter(|record| record.success).zip(session.brain_distances.iter()) {
+            assert!(distance.abs_diff(record.commanded_depth) < 200_000, "Reached {} for {}, seed={}", distance, record.commanded_depth, seed);
+        }
+    }
+
+    //OCT errors in long bursts leave the predictors without enough valid samples for a while, which may cost
+    //depths but has to leave the session running to the end
+    #[test]
+    fn test_run_session_virtual_with_bursty_dropouts() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        use crate::robot::{OCTDropouts, RobotArmBuilder};
+        let commands = vec![3_100_000, 4_000_000, 5_000_000];
+        let robot_arm = RobotArmBuilder::new().error_probability(0.0).distance_errors(true).seed(1834)
+            .oct_dropouts(OCTDropouts::Markov { enter_probability: 0.02, stay_probability: 0.95 }).build();
+        let session = run_session_virtual(commands.clone(), QuadraticRegression{}, robot_arm, ControllerConfig::default());
+        assert!(session.outcomes.len() == commands.len(), "Session stopped after {:?}", session.outcomes);
+        assert!(session.outcomes.iter().any(|outcome| *outcome), "No depth was reached: {:?}", session.outcomes);
+    }
+
+    //The open loop controller only sees the brain before the needle goes in, so a brain dimpling under the
+    //needle leaves every insertion short of its depth
+    #[test]
+    fn test_run_session_virtual_with_dimpling() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        use crate::robot::{Dimpling, RobotArmBuilder};
+        const SEED: u64 = 1829;
+        let commands = vec![3_100_000, 4_000_000, 5_000_000];
+        let run = |dimpling| {
+            let robot_arm = RobotArmBuilder::new().error_probability(0.0).seed(SEED).dimpling(dimpling).build();
+            let session = run_session_virtual(commands.clone(), QuadraticRegression{}, robot_arm, ControllerConfig::default());
+            summarize(&session.move_records, &session.brain_distances)
+        };
+        let rigid = run(Dimpling::None);
+        let dimpled = run(Dimpling::Proportional { fraction: 0.1 });
+        assert!(dimpled.num_successes > 0, "No insertion reached a dimpled brain");
+        assert!(dimpled.mean.unwrap() > rigid.mean.unwrap() + 100_000.0, "Dimpling didn't hurt the open loop accuracy\nRigid brain: {}\nDimpled brain: {}", rigid, dimpled);
+    }
+
+    //Quantized reads leave the Taylor predictor's differences over neighbouring samples noisy. Even reads rounded to
+    //1um cost it accuracy, and coarse ones may cost it every depth, but either way the session runs to the end
+    #[test]
+    fn test_run_session_virtual_with_quantized_oct() {
+        use crate::predictor::taylor_approx::TaylorQuadraticApproximator;
+        const SEED: u64 = 1864;
+        let commands = vec![3_100_000, 3_600_000, 4_100_000, 4_600_000, 5_100_000];
+        let run = |quantization_nm: Option<u64>| {
+            let mut builder = RobotArmBuilder::new().error_probability(0.0).seed(SEED);
+            if let Some(quantization_nm) = quantization_nm {
+                builder = builder.quantization_nm(quantization_nm);
+            }
+            let session = run_session_virtual(commands.clone(), TaylorQuadraticApproximator{}, builder.build(), ControllerConfig::default());
+            assert!(session.error.is_none() && session.outcomes.len() == commands.len(), "Session stopped after {:?}: {:?}", session.outcomes, session.error);
+            summarize(&session.move_records, &session.brain_distances)
+        };
+        let exact = run(None);
+        let fine = run(Some(1_000));
+        run(Some(20_000));
+        let (exact_mean, fine_mean) = (exact.mean.expect("No move succeeded"), fine.mean.expect("No move succeeded at 1um"));
+        assert!(fine_mean > exact_mean, "Quantizing to 1um didn't cost accuracy: {} vs {}", fine_mean, exact_mean);
+    }
+
+    //A session that fails a depth is repeated exactly by building its robot with the seed it reports
+    #[test]
+    fn test_failing_session_repeats_from_its_seed() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        //Fails the first and last depths
+        const SEED: u64 = 1843;
+        let commands = vec![3_100_000, 4_000_000, 5_000_000];
+        let config = ControllerConfig{max_attempts_per_depth: 1, ..ControllerConfig::default()};
+        let run = || run_session_virtual(commands.clone(), QuadraticRegression{}, RobotArmBuilder::new().move_errors(true).error_probability(0.5).seed(SEED).build(), config.clone());
+        let failing = run();
+        assert!(failing.seed == SEED && failing.outcomes.contains(&false), "seed={} didn't fail a depth: {:?}", SEED, failing.outcomes);
+        let repeated = run();
+        assert!(repeated.seed == failing.seed);
+        assert!(repeated.outcomes == failing.outcomes, "seed={} gave {:?} and then {:?}", failing.seed, failing.outcomes, repeated.outcomes);
+        assert!(repeated.brain_distances == failing.brain_distances, "seed={} reached {:?} and then {:?}", failing.seed, failing.brain_distances, repeated.brain_distances);
+    }
+
+    //Both sides of a session on one thread only interleave where they await, so on the paused clock the same seed
+    //runs the same session twice, down to the distance every move reached
+    #[tokio::test(start_paused = true)]
+    async fn test_local_session_is_determined_by_its_seed() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        const SEED: u64 = 1860;
+        let commands = vec![3_100_000, 4_000_000, 5_000_000];
+        let robot_arm = || RobotArmBuilder::new().seed(SEED).oct_jitter(OCTJitter::Uniform { max_ms: 2 }).build();
+        let local = LocalSet::new();
+        let first = local.run_until(run_session_local(commands.clone(), QuadraticRegression{}, robot_arm(), ControllerConfig::default())).await;
+        let second = local.run_until(run_session_local(commands.clone(), QuadraticRegression{}, robot_arm(), ControllerConfig::default())).await;
+        assert!(!first.brain_distances.is_empty(), "No move succeeded: {:?}", first.outcomes);
+        assert!(first.brain_distances == second.brain_distances, "Reached {:?} and then {:?}", first.brain_distances, second.brain_distances);
+        assert!(first.move_records == second.move_records && first.panic_samples == second.panic_samples);
+    }
+
+    //Every predictor has to reach most depths of a seeded session, so accuracy or availability regressions show up here
+    #[test]
+    fn test_benchmark_predictors() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        use crate::predictor::taylor_approx::TaylorQuadraticApproximator;
+        const SEED: u64 = 1810;
+        const MIN_SUCCESS_RATE: f64 = 0.8;
+        let commands = vec![3_100_000, 3_600_000, 4_100_000, 4_600_000, 5_100_000];
+        let rows = vec![
+            benchmark_predictor("taylor", TaylorQuadraticApproximator{}, &commands, SEED),
+            benchmark_predictor("regression", QuadraticRegression{}, &commands, SEED),
+        ];
+        println!("{}", format_benchmark_table(&rows));
+        for row in &rows {
+            assert!(row.predict_calls > 0 && row.predict_failures <= row.predict_calls);
+            assert!(row.success_rate() >= MIN_SUCCESS_RATE, "{} reached {}/{} depths", row.name, row.summary.num_successes, row.num_commands);
+        }
+    }
+
+    #[test]
+    fn test_export_csv_round_trip() {
+        let records = vec![record(3_100_000, true), record(4_000_000, false), record(5_000_000, true)];
+        let path = std::env::temp_dir().join(format!("move_records_{}.csv", std::process::id()));
+        export_csv(&records, &[3_150_000, 4_990_000], &path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = contents.lines().collect::<Vec<&str>>();
+        assert!(lines[0] == "index,commanded_depth,actual_distance,abs_error,success,attempts");
+        assert!(lines.len() == records.len() + 1);
+        assert!(lines[1] == "0,3100000,3150000,50000,true,1");
+        assert!(lines[2] == "1,4000000,,,false,1");
+        assert!(lines[3] == "2,5000000,4990000,10000,true,1");
+    }
+
+    #[test]
+    fn test_summarize() {
+        let records = vec![record(3_100_000, true), record(4_000_000, false), record(5_000_000, true)];
+        let summary = summarize(&records, &[3_150_000, 4_990_000]);
+        assert!(summary == Summary{mean: Some(30_000.0), max: Some(50_000), stddev: Some(20_000.0), num_successes: 2, abs_errors: vec![10_000, 50_000]}, "Unexpected summary: {:?}", summary);
+    }
+
+    #[test]
+    fn test_theoretical_accuracy_bound() {
+        assert!(theoretical_accuracy_bound(0.0, DEFAULT_TOTAL_LATENCY_MS) == 0);
+        assert!(theoretical_accuracy_bound(2_000.0, 0.0) == 0);
+        assert!(theoretical_accuracy_bound(2_000.0, 17.5) == 35_000);
+        //The brain moving away is as bad as it moving closer
+        assert!(theoretical_accuracy_bound(-4_000.0, 20.0) == 80_000);
+        assert!(theoretical_accuracy_bound(1_500.0, 10.4) == 15_600);
+    }
+
+    //The default brain moves at about 2um/ms on average, so over the default latency a clean session should end up
+    //within a factor of two of the bound
+    #[test]
+    fn test_clean_session_errors_are_near_the_accuracy_bound() {
+        use crate::predictor::quadratic_regression::QuadraticRegression;
+        //Long enough to cover the slowest component of the default brain motion, a sine with a period of 2pi seconds
+        const MOTION_PERIOD_MS: u64 = 6_283;
+        let commands = vec![3_100_000, 3_600_000, 4_100_000, 4_600_000, 5_100_000, 3_300_000, 4_300_000, 5_300_000];
+        let robot_arm = RobotArmBuilder::new().error_probability(0.0).seed(1859).build();
+        let mean_speed = (0..MOTION_PERIOD_MS).map(|ms| robot_arm.brain_location(ms + 1).abs_diff(robot_arm.brain_location(ms))).sum::<u64>() as f64 / MOTION_PERIOD_MS as f64;
+        let bound = theoretical_accuracy_bound(mean_speed, DEFAULT_TOTAL_LATENCY_MS) as f64;
+        let session = run_session_virtual(commands, QuadraticRegression{}, robot_arm, ControllerConfig::default());
+        let summary = summarize(&session.move_records, &session.brain_distances);
+        let mean = summary.mean.expect("No move succeeded");
+        assert!(mean > bound / 2.0 && mean < bound * 2.0, "Mean error {} isn't near the bound of {}", mean, bound);
+    }
+
+    //Nearest rank percentiles over ten known errors, and histogram buckets including the first and the open last one
+    #[test]
+    fn test_percentiles_and_histogram() {
+        let abs_errors = vec![5_000, 20_000, 40_000, 60_000, 70_000, 90_000, 120_000, 180_000, 260_000, 400_000];
+        let summary = Summary{mean: None, max: None, stddev: None, num_successes: abs_errors.len(), abs_errors};
+        assert!(summary.percentile(50.0) == Some(70_000));
+        assert!(summary.percentile(90.0) == Some(260_000));
+        assert!(summary.percentile(99.0) == Some(400_000));
+        let counts = summary.histogram(&HISTOGRAM_EDGES_NM).iter().map(|bucket| bucket.count).collect::<Vec<usize>>();
+        assert!(counts == vec![3, 3, 1, 1, 0, 1, 1], "Unexpected counts: {:?}", counts);
+        let buckets = summary.histogram(&[100_000]);
+        assert!(buckets == vec![HistogramBucket{from_nm: 0, to_nm: Some(100_000), count: 6}, HistogramBucket{from_nm: 100_000, to_nm: None, count: 4}]);
+    }
+
+    //Errors all within one bucket leave the others empty, and every percentile is one of those errors
+    #[test]
+    fn test_histogram_single_bucket() {
+        let records = vec![record(3_100_000, true), record(4_000_000, true), record(5_000_000, true)];
+        let summary = summarize(&records, &[3_110_000, 4_020_000, 4_970_000]);
+        let counts = summary.histogram(&HISTOGRAM_EDGES_NM).iter().map(|bucket| bucket.count).collect::<Vec<usize>>();
+        assert!(counts == vec![3, 0, 0, 0, 0, 0, 0], "Unexpected counts: {:?}", counts);
+        assert!(summary.percentile(50.0) == Some(20_000) && summary.percentile(99.0) == Some(30_000));
+    }
+
+    //A capped history has lost its oldest records, but the robot still has all of their distances
+    #[test]
+    fn test_summarize_capped_history() {
+        let records = vec![record(4_000_000, false), record(5_000_000, true)];
+        assert!(aligned_brain_distances(&records, &[3_150_000, 4_990_000]) == [4_990_000]);
+        let summary = summarize(&records, &[3_150_000, 4_990_000]);
+        assert!(summary == Summary{mean: Some(10_000.0), max: Some(10_000), stddev: Some(0.0), num_successes: 1, abs_errors: vec![10_000]}, "Unexpected summary: {:?}", summary);
+    }
+
+    #[test]
+    fn test_summarize_without_successes() {
+        let summary = summarize(&[record(3_100_000, false)], &[]);
+        assert!(summary == Summary{mean: None, max: None, stddev: None, num_successes: 0, abs_errors: Vec::new()});
+        assert!(summary.percentile(50.0).is_none());
+        assert!(summarize(&[], &[]) == summary);
+        assert!(summary.to_string().contains("No successful moves"));
+    }
+}
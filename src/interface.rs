@@ -1,14 +1,35 @@
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
 
+
+/// Errors carry when they were raised as `at_ms`, the milliseconds elapsed since the robot started, so error
+/// bursts can be lined up with the rest of a run. Errors raised outside the robot, like the controller's
+/// prediction errors, leave it as None.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OCTError {
     // Failed to acquire data from the OCT laser
-    AcquisitionError { msg: String },
+    AcquisitionError { msg: String, at_ms: Option<u64> },
     // Failed to communicate with the OCT driver
-    CommunicationError { msg: String },
+    CommunicationError { msg: String, at_ms: Option<u64> },
     // Timeout waiting for the OCT driver to respond
-    TimeoutError { msg: String },
+    TimeoutError { msg: String, at_ms: Option<u64> },
+
+    PredictionError { msg: String, at_ms: Option<u64> },
+}
 
-    PredictionError { msg: String },
+impl OCTError {
+    /// Milliseconds since the robot started when the error was raised, if known.
+    pub fn at_ms(&self) -> Option<u64> {
+        match self {
+            OCTError::AcquisitionError { at_ms, .. }
+            | OCTError::CommunicationError { at_ms, .. }
+            | OCTError::TimeoutError { at_ms, .. }
+            | OCTError::PredictionError { at_ms, .. } => *at_ms,
+        }
+    }
 }
     /// OCTService provides a high level interface with the OCT sensor.
     /// The only function defined here is get_surface_distance which returns
@@ -16,16 +37,22 @@ pub enum OCTError {
     /// the function is async because communication time between the software
     /// and the OCT sensor is non-deterministic.
     ///
-    /// The initial position of the brain relative to inserter_z is 7mm.
+    /// The initial position of the brain relative to inserter_z is 7mm, unless the simulation is built
+    /// with another `RobotArmBuilder::brain_baseline_nm`.
 pub trait OCTService {
     // returns the distance between inserter_z and the brain surface in nm
     async fn get_surface_distance(&self) -> Result<u64, OCTError>;
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Move {
     InserterZ(u64), // desired absolute position in nm
     NeedleZ(u64),   // desired absolute position in nm
+    // desired absolute positions in nm of both axes, moved at the same time
+    Both { inserter_z: u64, needle_z: u64 },
+    // desired absolute needle position in nm, moved at no more than max_velocity nm/ms
+    NeedleZWithVelocity { target: u64, max_velocity: u64 },
 }
 
 impl std::fmt::Display for Move {
@@ -33,10 +60,20 @@ impl std::fmt::Display for Move {
         match self {
             Move::InserterZ(pos) => write!(f, "InserterZ({})", pos),
             Move::NeedleZ(pos) => write!(f, "NeedleZ({})", pos),
+            Move::Both { inserter_z, needle_z } => write!(f, "Both(InserterZ({}), NeedleZ({}))", inserter_z, needle_z),
+            Move::NeedleZWithVelocity { target, max_velocity } => write!(f, "NeedleZ({}) at up to {}nm/ms", target, max_velocity),
         }
     }
 }
 
+/// How urgently a move has to be made. Emergency moves, such as the retraction when the controller panics,
+/// preempt the move in progress and any moves queued behind it on robots that support it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovePriority {
+    Normal,
+    Emergency,
+}
+
 /// RobotState represents the current state of the robot where
 /// each field represents an axis of our simplified robot.
 ///  - inserter_z: position of the tip of the needle cartridge which holds the needle
@@ -45,20 +82,49 @@ impl std::fmt::Display for Move {
 ///  A increase in position indicates movement towards the brain surface (down),
 ///  a decrease in position indicates movement away from the brain surface (up).
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RobotState {
     pub inserter_z: u64, // Absolute encoder position in nm
     pub needle_z: u64,   // Absolute encoder position in nm
 }
 
+/// Timestamped like `OCTError`.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RobotError {
-    // Failed to move the robot
-    MoveError { msg: String },
+    // Failed to move the robot. achieved_z is where the move left the axis it drives: the needle for
+    // moves that drive it, the inserter otherwise
+    MoveError { msg: String, at_ms: Option<u64>, achieved_z: u64 },
     // lost connection to the robot
-    ConnectionError { msg: String },
+    ConnectionError { msg: String, at_ms: Option<u64> },
     // Position exceeds the limits of the robot,
     // can only be thrown by `command_move()`
-    PositionError { msg: String },
+    PositionError { msg: String, at_ms: Option<u64> },
+}
+
+impl RobotError {
+    /// Milliseconds since the robot started when the error was raised, if known.
+    pub fn at_ms(&self) -> Option<u64> {
+        match self {
+            RobotError::MoveError { at_ms, .. }
+            | RobotError::ConnectionError { at_ms, .. }
+            | RobotError::PositionError { at_ms, .. } => *at_ms,
+        }
+    }
+}
+
+/// RobotLimits holds the furthest each axis can travel, as absolute encoder positions in nm. The robot
+/// rejects moves past them with a `PositionError`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RobotLimits {
+    pub inserter_z_max: u64,
+    pub needle_z_max: u64,
+}
+
+impl Default for RobotLimits {
+    fn default() -> Self {
+        RobotLimits { inserter_z_max: u64::MAX, needle_z_max: u64::MAX }
+    }
 }
 
 /// Robot provides a high level interface with the robot
@@ -73,4 +139,251 @@ pub trait Robot {
 
     async fn command_move(&self, command: &Move) -> Result<(), RobotError>;
     async fn command_grasp(&self) -> Result<(), RobotError>;
-}
\ No newline at end of file
+
+    // moves straight away, stopping any move in progress where it is. Robots that can't preempt their
+    // moves make it like any other
+    async fn command_emergency_move(&self, command: &Move) -> Result<(), RobotError> {
+        self.command_move(command).await
+    }
+
+    // the robot's travel limits, which are known up front. Unlimited unless the robot reports them
+    fn limits(&self) -> RobotLimits {
+        RobotLimits::default()
+    }
+}
+
+/// Future returned by the object safe interfaces. Not `Send`, like the `async fn`s it wraps, as everything
+/// runs on a single threaded runtime.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// Object safe version of `OCTService`, so OCTs can be held as `dyn DynOCTService`. Every `OCTService`
+/// implements it, keep implementing `OCTService` and only name this trait where a trait object is needed.
+pub trait DynOCTService {
+    fn get_surface_distance(&self) -> BoxFuture<'_, Result<u64, OCTError>>;
+}
+
+impl<T: OCTService> DynOCTService for T {
+    fn get_surface_distance(&self) -> BoxFuture<'_, Result<u64, OCTError>> {
+        Box::pin(OCTService::get_surface_distance(self))
+    }
+}
+
+/// Object safe version of `Robot`, implemented for every `Robot` like `DynOCTService`.
+pub trait DynRobot {
+    fn get_robot_state(&self) -> BoxFuture<'_, Result<RobotState, RobotError>>;
+
+    fn command_move<'a>(&'a self, command: &'a Move) -> BoxFuture<'a, Result<(), RobotError>>;
+    fn command_grasp(&self) -> BoxFuture<'_, Result<(), RobotError>>;
+    fn command_emergency_move<'a>(&'a self, command: &'a Move) -> BoxFuture<'a, Result<(), RobotError>>;
+    fn limits(&self) -> RobotLimits;
+}
+
+impl<T: Robot> DynRobot for T {
+    fn get_robot_state(&self) -> BoxFuture<'_, Result<RobotState, RobotError>> {
+        Box::pin(Robot::get_robot_state(self))
+    }
+
+    fn command_move<'a>(&'a self, command: &'a Move) -> BoxFuture<'a, Result<(), RobotError>> {
+        Box::pin(Robot::command_move(self, command))
+    }
+
+    fn command_grasp(&self) -> BoxFuture<'_, Result<(), RobotError>> {
+        Box::pin(Robot::command_grasp(self))
+    }
+
+    fn command_emergency_move<'a>(&'a self, command: &'a Move) -> BoxFuture<'a, Result<(), RobotError>> {
+        Box::pin(Robot::command_emergency_move(self, command))
+    }
+
+    fn limits(&self) -> RobotLimits {
+        Robot::limits(self)
+    }
+}
+
+/// A distance read sent to the robot side of a `RobotEndpoint`, answered through the oneshot.
+pub type DistanceRequest = ((), oneshot::Sender<Result<u64, OCTError>>);
+/// A robot state read sent to the robot side of a `RobotEndpoint`, answered through the oneshot.
+pub type StateRequest = ((), oneshot::Sender<Result<RobotState, RobotError>>);
+/// A move sent to the robot side of a `RobotEndpoint`, answered through the oneshot once it has finished
+/// or been preempted.
+pub type MoveRequest = (Move, MovePriority, oneshot::Sender<Result<(), RobotError>>);
+
+/// RobotEndpoint is the controller's end of the wire to a robot running elsewhere, such as the simulation
+/// in robot.rs or an adapter to a real robot. Every request goes down its own channel along with a oneshot
+/// sender for the response, and the robot is told to stop with a message on the dead channel.
+///
+/// The robot side drains the receivers of `RobotRequests` and answers every request it takes. A robot that
+/// drops its receivers, or a request unanswered, is reported to the controller as a connection error
+/// (a communication error for distances) instead of being waited on.
+pub struct RobotEndpoint {
+    distance_tx: mpsc::Sender<DistanceRequest>,
+    state_tx: mpsc::Sender<StateRequest>,
+    move_tx: mpsc::Sender<MoveRequest>,
+    dead_tx: mpsc::Sender<()>,
+}
+
+/// The robot's end of a `RobotEndpoint`.
+pub struct RobotRequests {
+    pub distance_rx: mpsc::Receiver<DistanceRequest>,
+    pub state_rx: mpsc::Receiver<StateRequest>,
+    pub move_rx: mpsc::Receiver<MoveRequest>,
+    pub dead_rx: mpsc::Receiver<()>,
+}
+
+impl RobotEndpoint {
+    /// Wraps channels that were made elsewhere.
+    pub fn new(distance_tx: mpsc::Sender<DistanceRequest>, state_tx: mpsc::Sender<StateRequest>,
+        move_tx: mpsc::Sender<MoveRequest>, dead_tx: mpsc::Sender<()>) -> RobotEndpoint {
+        RobotEndpoint { distance_tx, state_tx, move_tx, dead_tx }
+    }
+
+    /// Makes the channels, each holding up to `capacity` requests, and returns both of their ends.
+    pub fn channel(capacity: usize) -> (RobotEndpoint, RobotRequests) {
+        let (distance_tx, distance_rx) = mpsc::channel(capacity);
+        let (state_tx, state_rx) = mpsc::channel(capacity);
+        let (move_tx, move_rx) = mpsc::channel(capacity);
+        let (dead_tx, dead_rx) = mpsc::channel(capacity);
+        (RobotEndpoint::new(distance_tx, state_tx, move_tx, dead_tx), RobotRequests { distance_rx, state_rx, move_rx, dead_rx })
+    }
+
+    /// Sender the robot is told to stop through.
+    pub fn dead_tx(&self) -> mpsc::Sender<()> {
+        self.dead_tx.clone()
+    }
+
+    async fn send_move(&self, command: &Move, priority: MovePriority) -> Result<(), RobotError> {
+        let (tx, rx) = oneshot::channel();
+        self.move_tx.send((command.clone(), priority, tx)).await.map_err(|_| robot_disconnected())?;
+        rx.await.unwrap_or_else(|_| Err(robot_disconnected()))
+    }
+}
+
+//Sending only fails once the robot has dropped its end of the channel, which it never opens again, so a closed
+//channel (or a robot that drops the request unanswered) is returned as a connection error straight away
+//There is no grasp channel, so grasps over channels are mocked as always succeeding
+impl Robot for RobotEndpoint {
+    async fn command_grasp(&self) -> Result<(), RobotError> {
+        Ok(())
+    }
+
+    async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+        self.send_move(command, MovePriority::Normal).await
+    }
+
+    async fn command_emergency_move(&self, command: &Move) -> Result<(), RobotError> {
+        self.send_move(command, MovePriority::Emergency).await
+    }
+
+    async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+        let (tx, rx) = oneshot::channel();
+        self.state_tx.send(((), tx)).await.map_err(|_| robot_disconnected())?;
+        rx.await.unwrap_or_else(|_| Err(robot_disconnected()))
+    }
+}
+
+impl OCTService for RobotEndpoint {
+    async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+        let (tx, rx) = oneshot::channel();
+        let oct_disconnected = || OCTError::CommunicationError { msg: "OCT channel closed".to_string(), at_ms: None };
+        self.distance_tx.send(((), tx)).await.map_err(|_| oct_disconnected())?;
+        rx.await.unwrap_or_else(|_| Err(oct_disconnected()))
+    }
+}
+
+//The controller's end has no robot clock to stamp the error with
+fn robot_disconnected() -> RobotError {
+    RobotError::ConnectionError { msg: "Robot channel closed".to_string(), at_ms: None }
+}
+
+/// Instants are not serializable, so recorded times are converted to milliseconds
+/// elapsed since `origin` (usually the start of the run) before being written out.
+/// Instants from before `origin` are reported as 0.
+pub fn elapsed_millis_since(origin: Instant, instants: &[Instant]) -> Vec<u64> {
+    instants.iter().map(|instant| instant.saturating_duration_since(origin).as_millis() as u64).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A robot side that isn't the simulation only has to answer the requests coming out of the endpoint
+    #[tokio::test]
+    async fn test_endpoint_with_another_robot_side() {
+        let (endpoint, requests) = RobotEndpoint::channel(1);
+        let robot_side = tokio::spawn(async move {
+            //Takes all of the receivers, so they are all dropped once the robot side returns
+            let mut requests = requests;
+            let mut state = RobotState { inserter_z: 0, needle_z: 0 };
+            let (command, _, tx) = requests.move_rx.recv().await.unwrap();
+            if let Move::InserterZ(inserter_z) = command {
+                state.inserter_z = inserter_z;
+            }
+            tx.send(Ok(())).unwrap();
+            let (_, tx) = requests.state_rx.recv().await.unwrap();
+            tx.send(Ok(state)).unwrap();
+            requests.dead_rx.recv().await
+        });
+        Robot::command_move(&endpoint, &Move::InserterZ(1_000_000)).await.unwrap();
+        assert_eq!(Robot::get_robot_state(&endpoint).await.unwrap(), RobotState { inserter_z: 1_000_000, needle_z: 0 });
+        endpoint.dead_tx().send(()).await.unwrap();
+        assert!(robot_side.await.unwrap().is_some());
+        //The robot side is gone, so there is no one left to answer
+        assert!(matches!(OCTService::get_surface_distance(&endpoint).await, Err(OCTError::CommunicationError { .. })));
+    }
+
+    #[test]
+    fn test_elapsed_millis_since() {
+        let origin = Instant::now();
+        let instants = [origin, origin + std::time::Duration::from_millis(15), origin + std::time::Duration::from_millis(1_000)];
+        assert_eq!(elapsed_millis_since(origin, &instants), vec![0, 15, 1_000]);
+        assert_eq!(elapsed_millis_since(instants[2], &instants), vec![0, 0, 0]);
+    }
+
+    #[cfg(feature = "serde")]
+    mod serde_round_trip {
+        use super::super::*;
+
+        fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(value: &T) -> T {
+            let json = serde_json::to_string(value).unwrap();
+            serde_json::from_str(&json).unwrap()
+        }
+
+        #[test]
+        fn test_move_round_trip() {
+            for command in [Move::InserterZ(1_500_000), Move::NeedleZ(3_200_000), Move::Both { inserter_z: 1_500_000, needle_z: 0 }, Move::NeedleZWithVelocity { target: 3_200_000, max_velocity: 1_000 }] {
+                assert_eq!(format!("{:?}", round_trip(&command)), format!("{:?}", command));
+            }
+        }
+
+        #[test]
+        fn test_robot_state_round_trip() {
+            let state = RobotState{inserter_z: 4_800_000, needle_z: 3_100_000};
+            assert_eq!(round_trip(&state), state);
+        }
+
+        #[test]
+        fn test_oct_error_round_trip() {
+            let errors = [
+                OCTError::AcquisitionError { msg: "Acquisition error".to_string(), at_ms: Some(1_250) },
+                OCTError::CommunicationError { msg: "Connection error".to_string(), at_ms: Some(1_250) },
+                OCTError::TimeoutError { msg: "Timeout".to_string(), at_ms: Some(1_250) },
+                OCTError::PredictionError { msg: "No root found".to_string(), at_ms: Some(1_250) },
+            ];
+            for error in errors.iter() {
+                assert_eq!(format!("{:?}", round_trip(error)), format!("{:?}", error));
+            }
+        }
+
+        #[test]
+        fn test_robot_error_round_trip() {
+            let errors = [
+                RobotError::MoveError { msg: "Random error occurred after move".to_string(), at_ms: Some(1_250), achieved_z: 600_000 },
+                RobotError::ConnectionError { msg: "Connection error".to_string(), at_ms: Some(1_250) },
+                RobotError::PositionError { msg: "Out of range".to_string(), at_ms: Some(1_250) },
+            ];
+            for error in errors.iter() {
+                assert_eq!(format!("{:?}", round_trip(error)), format!("{:?}", error));
+            }
+        }
+    }
+}
@@ -1,5 +1,7 @@
 pub mod interface;
 pub mod controller;
 pub mod robot;
+pub mod motion;
 pub mod arima;
 pub mod predictor;
+pub mod harness;
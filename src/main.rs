@@ -1,91 +1,70 @@
 mod interface;
 mod controller;
 mod robot;
+mod motion;
 mod arima;
 mod predictor;
+mod harness;
 use robot::RobotArm;
-use std::{sync::Arc, thread};
-use tokio::sync::Mutex;
+use controller::ControllerConfig;
+use std::thread;
 use tokio::time::Instant;
-use tokio::runtime::Builder;
 
 use predictor::taylor_approx::TaylorQuadraticApproximator;
 use predictor::quadratic_regression::QuadraticRegression;
 use predictor::oracle_approx::OraclePredictor;
-use tokio::task::LocalSet;
+use predictor::any::AnyPredictor;
 
 fn main() {
     println!("Hello, world!");
-    //Creates channels for communication between robot simulation 
-    let (distance_tx, distance_rx) = tokio::sync::mpsc::channel(100);
-    let (state_tx, state_rx) = tokio::sync::mpsc::channel(100);
-    let (move_tx, move_rx) = tokio::sync::mpsc::channel(100);
-    let (dead_tx, dead_rx) = tokio::sync::mpsc::channel(100);
-
-    //Creates the robot simulation
-    let robot = Arc::new(Mutex::new(RobotArm::new(0, false, true)));
-    let robot_clone = Arc::clone(&robot);
-    //Creates the controller simulation
-    let controller = Arc::new(controller::Controller::new(distance_tx, state_tx, move_tx, dead_tx, QuadraticRegression{}));
-    let controller_clone = Arc::clone(&controller);
-    //Commanded depth in nanometers
-    let commands = vec![
-        3_100_000, 3_200_000, 3_300_000, 3_400_000, 3_500_000,
-        3_600_000, 3_700_000, 3_800_000, 3_900_000, 4_000_000,
-        4_100_000, 4_200_000, 4_300_000, 4_400_000, 4_500_000,
-        4_600_000, 4_700_000, 4_800_000, 4_900_000, 5_000_000,
-        5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
-        5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
-    let commands_clone = commands.clone();
-
-     // Create and run the controller on its own thread
+    //The predictor is picked by the first argument: taylor, quadratic (the default) or oracle
+    let robot_arm = RobotArm::new(0, false, true);
+    let predictor = match std::env::args().nth(1).as_deref() {
+        Some("taylor") => AnyPredictor::Taylor(TaylorQuadraticApproximator{}),
+        Some("quadratic") | None => AnyPredictor::Quadratic(QuadraticRegression{}),
+        Some("oracle") => AnyPredictor::Oracle(OraclePredictor::new(robot_arm.brain_location_fn, robot_arm.get_init_time())),
+        Some(other) => {
+            eprintln!("Unknown predictor {}, expected taylor, quadratic or oracle", other);
+            std::process::exit(1);
+        }
+    };
+    println!("Predictor: {}", predictor.name());
     let start = Instant::now();
-    let handle_one = thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async {
-            controller::start(controller, &commands).await
-        });
-    });
+    //Runs the controller and robot simulation on their own threads
+    let session = harness::Session::start(harness::default_commands(), predictor, robot_arm, ControllerConfig::default());
 
-    // Create and run the robot sim on its own thread
-    let handle_two = std::thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async move {
-            robot::start(distance_rx, state_rx, move_rx, dead_rx,robot).await; 
-        });
-    });
+    //Print a status line every second while the controller runs, along with the last distances it saw in um
+    while !session.is_finished() {
+        let recent = session.controller().recent_distances(10).iter()
+            .map(|(distance, _)| distance.map_or("-".to_string(), |distance| (distance / 1000).to_string()))
+            .collect::<Vec<String>>();
+        println!("Status: {} recent=[{}]", session.controller().status(), recent.join(" "));
+        thread::sleep(std::time::Duration::from_secs(1));
+    }
 
     // Wait for both threads to finish
-    handle_one.join().unwrap();
-    handle_two.join().unwrap();
+    let session = session.join();
 
     println!("Elapsed: {:.2?}", start.elapsed().as_secs());
+    if let Some(error) = &session.error {
+        println!("Session stopped early: {}", error);
+    }
 
-    //Filter indices with true value from controller_clone.outcomes
-    let outcome_indices = controller_clone.get_outcomes().iter().enumerate().filter(|(_, &x)| x).map(|(i, _)| i).collect::<Vec<usize>>();
-    assert!(outcome_indices.len() == robot_clone.blocking_lock().brain_distances.len());
+    //Pair each successful move record with the distance the robot actually reached
+    let successful_records = session.move_records.iter().filter(|record| record.success).collect::<Vec<_>>();
+    let brain_distances = harness::aligned_brain_distances(&session.move_records, &session.brain_distances);
+    assert!(successful_records.len() == brain_distances.len());
 
-    let mut abs_distances = Vec::new();
     //Print the commanded vs actual distance
-    for (j, i) in outcome_indices.iter().enumerate() {
-        let actual_distance = robot_clone.blocking_lock().brain_distances[j];
-        let commanded_distance = commands_clone[*i];
-        abs_distances.push(actual_distance.abs_diff(commanded_distance));
-        print!("{}, {}, {}, ", commanded_distance, actual_distance, *i);
+    for (record, actual_distance) in successful_records.iter().zip(brain_distances.iter()) {
+        print!("{}, {}, {}, {}, ", record.commanded_depth, actual_distance, record.attempts, record.time_in_brain_ms);
         println!("");
     }
 
-    println!("Average absolute distance: {}", abs_distances.iter().sum::<u64>() / abs_distances.len() as u64);
-    println!("Max absolute distance: {}", abs_distances.iter().max().unwrap());
-    println!("Std dev: {}", (abs_distances.iter().map(|x| (*x as f64 - abs_distances.iter().sum::<u64>() as f64 / abs_distances.len() as f64).powi(2)).sum::<f64>() / abs_distances.len() as f64).sqrt());
-    println!("Num successes: {}", outcome_indices.len());
-
-}
\ No newline at end of file
+    let summary = harness::summarize(&session.move_records, &session.brain_distances);
+    println!("{}", summary);
+    println!("Absolute error histogram:");
+    for bucket in summary.histogram(&harness::HISTOGRAM_EDGES_NM) {
+        println!("{}", bucket);
+    }
+}
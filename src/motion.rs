@@ -0,0 +1,150 @@
+use tokio::time::Duration;
+
+//Motion math for the simulated robot. Kept free of any RobotArm state so every simulation
+//shares the same physics, parameterised by the axis velocity and acceleration.
+
+/// Calculate total move time for needle moves using a trapezoidal profile.
+/// Inserter moves with an acceleration share the profile, see `calculate_inserter_move_time`.
+pub fn calculate_needlez_move_time(distance_nm: i64, velocity_nm_ms: u64, accel_nm_ms2: i64) -> Duration {
+    let a = accel_nm_ms2 as f64;
+    let v = velocity_nm_ms as f64;
+    let d = distance_nm.abs() as f64;
+    let d_min = v * v / a;
+
+    let total_time_ms = if d < d_min {
+        2.0*(d / a).sqrt()
+    } else {
+        let t_accel = v / a;
+        let d_accel = 0.5 * a * t_accel * t_accel;
+        let d_cruise = d - 2.0 * d_accel;
+        let t_cruise = d_cruise / v;
+        t_accel + t_cruise + t_accel
+    };
+
+    Duration::from_millis(total_time_ms as u64)
+}
+
+/// Inverse of `calculate_needlez_move_time`: the distance a needle move covers when it takes `total_ms`.
+/// Short moves never reach cruise velocity and cover a*t^2/4, longer ones cruise for the time in between.
+pub fn needlez_distance_for_move_time(total_ms: f64, velocity_nm_ms: u64, accel_nm_ms2: i64) -> f64 {
+    let a = accel_nm_ms2 as f64;
+    let v = velocity_nm_ms as f64;
+    let t = total_ms.max(0.0);
+    if t <= 2.0 * v / a {
+        a * t * t / 4.0
+    } else {
+        v * (t - v / a)
+    }
+}
+
+/// Move time of a soft landing over `distance_nm`: the needle covers all but the last `landing_nm` on its
+/// usual profile, then covers the rest in a second move capped at `landing_velocity_nm_ms`.
+/// Moves no longer than `landing_nm` are made entirely at the capped velocity.
+pub fn calculate_soft_landing_move_time(distance_nm: i64, velocity_nm_ms: u64, accel_nm_ms2: i64, landing_nm: u64, landing_velocity_nm_ms: u64) -> Duration {
+    let distance_nm = distance_nm.abs();
+    let landing_nm = (landing_nm as i64).min(distance_nm);
+    calculate_needlez_move_time(distance_nm - landing_nm, velocity_nm_ms, accel_nm_ms2)
+        + calculate_needlez_move_time(landing_nm, landing_velocity_nm_ms.min(velocity_nm_ms), accel_nm_ms2)
+}
+
+/// Inverse of `calculate_soft_landing_move_time`: the distance a soft landing covers when it takes `total_ms`.
+pub fn soft_landing_distance_for_move_time(total_ms: f64, velocity_nm_ms: u64, accel_nm_ms2: i64, landing_nm: u64, landing_velocity_nm_ms: u64) -> f64 {
+    let landing_velocity_nm_ms = landing_velocity_nm_ms.min(velocity_nm_ms);
+    let landing_ms = calculate_needlez_move_time(landing_nm as i64, landing_velocity_nm_ms, accel_nm_ms2).as_millis() as f64;
+    if total_ms <= landing_ms {
+        needlez_distance_for_move_time(total_ms, landing_velocity_nm_ms, accel_nm_ms2)
+    } else {
+        landing_nm as f64 + needlez_distance_for_move_time(total_ms - landing_ms, velocity_nm_ms, accel_nm_ms2)
+    }
+}
+
+//Positions along a move never leave the span between its start and target, nor go below zero.
+//Float rounding in the profiles can otherwise overshoot, e.g. just past zero on a retraction
+fn clamp_to_move(position: f64, start_z: i64, target_z: i64) -> i64 {
+    (position as i64).clamp(start_z.min(target_z), start_z.max(target_z)).max(0)
+}
+
+/// Interpolate needle moves using trapezoidal profile.
+pub fn interpolate_needlez_position(
+    start_z: i64,
+    target_z: i64,
+    elapsed: Duration,
+    total: Duration,
+    velocity_nm_ms: u64,
+    accel_nm_ms2: i64,
+) -> i64 {
+    let a = accel_nm_ms2 as f64;
+    let v = velocity_nm_ms as f64;
+    let d = (target_z - start_z) as f64;
+    let direction = if target_z >= start_z { 1.0 } else { -1.0 };
+
+    let t = elapsed.as_millis() as f64;
+    let total_t = total.as_millis() as f64;
+    let d_min = v * v / a;
+
+    if t >= total_t {
+        return target_z;
+    }
+
+    if d.abs() < d_min {
+        let half_t = total_t / 2.0;
+        if t <= half_t {
+            let s = 0.5 * a * t * t;
+            clamp_to_move(start_z as f64 + direction * s, start_z, target_z)
+        } else {
+            let half_v = a * half_t;
+            let dt = t - half_t;
+            let s = (d.abs() / 2.0) + half_v * dt - 0.5 * a * dt * dt;
+            clamp_to_move(start_z as f64 + direction * s, start_z, target_z)
+        }
+    } else {
+        let t_accel = v / a;
+        let d_accel = 0.5 * a * t_accel * t_accel;
+        let t_cruise = total_t - 2.0 * t_accel;
+        if t <= t_accel {
+            let s = 0.5 * a * t * t;
+            clamp_to_move(start_z as f64 + direction * s, start_z, target_z)
+        } else if t <= t_accel + t_cruise {
+            let dt = t - t_accel;
+            let s = d_accel + v * dt;
+            clamp_to_move(start_z as f64 + direction * s, start_z, target_z)
+        } else {
+            let dt = t - (t_accel + t_cruise);
+            let d_cruise = v * t_cruise;
+            let s = d_accel + d_cruise + v * dt - 0.5 * a * dt * dt;
+            clamp_to_move(start_z as f64 + direction * s, start_z, target_z)
+        }
+    }
+}
+
+/// Inserter moves without an acceleration are constant velocity motion that starts and stops instantly:
+/// total_time = distance / velocity_nm_ms
+/// With an acceleration they ramp up and down on the same trapezoidal profile as the needle.
+pub fn calculate_inserter_move_time(distance_nm: i64, velocity_nm_ms: u64, accel_nm_ms2: Option<i64>) -> Duration {
+    if let Some(accel_nm_ms2) = accel_nm_ms2 {
+        return calculate_needlez_move_time(distance_nm, velocity_nm_ms, accel_nm_ms2);
+    }
+    let distance = distance_nm.abs() as f64;
+    let time_ms = distance / velocity_nm_ms as f64;
+    Duration::from_millis(time_ms as u64)
+}
+
+/// Interpolate inserter moves linearly based on constant velocity, or on the trapezoidal profile
+/// when the inserter has an acceleration.
+pub fn interpolate_inserter_position(
+    start_z: i64,
+    target_z: i64,
+    elapsed: Duration,
+    total: Duration,
+    velocity_nm_ms: u64,
+    accel_nm_ms2: Option<i64>,
+) -> i64 {
+    if let Some(accel_nm_ms2) = accel_nm_ms2 {
+        return interpolate_needlez_position(start_z, target_z, elapsed, total, velocity_nm_ms, accel_nm_ms2);
+    }
+    let total_t = total.as_millis() as f64;
+    let t = elapsed.as_millis() as f64;
+    let d = (target_z - start_z) as f64;
+    let fraction = (t / total_t).min(1.0);
+    clamp_to_move(start_z as f64 + d * fraction, start_z, target_z)
+}
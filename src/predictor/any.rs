@@ -0,0 +1,90 @@
+use crate::predictor::{BrainPredictor, CoefSink, DistanceWindow, Kinematics};
+use crate::predictor::oracle_approx::OraclePredictor;
+use crate::predictor::quadratic_regression::QuadraticRegression;
+use crate::predictor::taylor_approx::TaylorQuadraticApproximator;
+
+//Picks the predictor at runtime, so one Controller<AnyPredictor> can run any of them, e.g. as chosen on the
+//command line. Each variant returns its own closure type, so the forecast closure dispatches over them
+//rather than boxing every forecast into a dyn Fn.
+pub enum AnyPredictor{
+    Taylor(TaylorQuadraticApproximator),
+    Quadratic(QuadraticRegression),
+    Oracle(OraclePredictor),
+}
+
+impl AnyPredictor{
+    pub fn name(&self) -> &'static str{
+        match self {
+            AnyPredictor::Taylor(_) => "taylor",
+            AnyPredictor::Quadratic(_) => "quadratic",
+            AnyPredictor::Oracle(_) => "oracle",
+        }
+    }
+}
+
+//One variant's forecast
+enum Forecast<T, Q, O>{
+    Taylor(T),
+    Quadratic(Q),
+    Oracle(O),
+}
+
+impl BrainPredictor for AnyPredictor {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (forecast, confidence) = match self {
+            AnyPredictor::Taylor(predictor) => predictor.predict(window, coef_sink).map(|(f, confidence)| (Forecast::Taylor(f), confidence))?,
+            AnyPredictor::Quadratic(predictor) => predictor.predict(window, coef_sink).map(|(f, confidence)| (Forecast::Quadratic(f), confidence))?,
+            AnyPredictor::Oracle(predictor) => predictor.predict(window, coef_sink).map(|(f, confidence)| (Forecast::Oracle(f), confidence))?,
+        };
+        Some(( move |x: f64|{
+            match &forecast {
+                Forecast::Taylor(f) => f(x),
+                Forecast::Quadratic(f) => f(x),
+                Forecast::Oracle(f) => f(x),
+            }
+        }, confidence))
+    }
+
+    fn predict_kinematics(&self, window: &DistanceWindow) -> Option<Kinematics>{
+        match self {
+            AnyPredictor::Taylor(predictor) => predictor.predict_kinematics(window),
+            AnyPredictor::Quadratic(predictor) => predictor.predict_kinematics(window),
+            AnyPredictor::Oracle(predictor) => predictor.predict_kinematics(window),
+        }
+    }
+
+    fn train(&self) -> bool{
+        match self {
+            AnyPredictor::Taylor(predictor) => predictor.train(),
+            AnyPredictor::Quadratic(predictor) => predictor.train(),
+            AnyPredictor::Oracle(predictor) => predictor.train(),
+        }
+    }
+
+    fn awaiting_clean_samples(&self, window: &DistanceWindow) -> bool{
+        match self {
+            AnyPredictor::Taylor(predictor) => predictor.awaiting_clean_samples(window),
+            AnyPredictor::Quadratic(predictor) => predictor.awaiting_clean_samples(window),
+            AnyPredictor::Oracle(predictor) => predictor.awaiting_clean_samples(window),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::ControllerConfig;
+    use crate::harness::run_session_virtual;
+    use crate::robot::RobotArmBuilder;
+
+    #[test]
+    fn test_quadratic_session() {
+        let commands = vec![3_100_000, 4_000_000];
+        let robot_arm = RobotArmBuilder::new().error_probability(0.0).build();
+        let session = run_session_virtual(commands.clone(), AnyPredictor::Quadratic(QuadraticRegression{}), robot_arm, ControllerConfig::default());
+        assert!(session.outcomes == vec![true; commands.len()], "Unexpected outcomes: {:?}", session.outcomes);
+        for (record, distance) in session.move_records.iter().zip(session.brain_distances.iter()) {
+            assert!(distance.abs_diff(record.commanded_depth) < 200_000, "Reached {} for {}", distance, record.commanded_depth);
+        }
+    }
+}
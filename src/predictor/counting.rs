@@ -0,0 +1,106 @@
+use crate::predictor::{BrainPredictor, CoefSink, DistanceWindow, Kinematics};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+//Counts how often the inner predictor is asked for a forecast and how often it has none, so predictors
+//can be compared on availability as well as accuracy. The controller owns its predictor, so the counts
+//are shared through an Arc that can be read while or after the session runs. Kinematics are counted apart
+//from forecasts, as the controller asks for them on its own schedule.
+#[derive(Debug, Default)]
+pub struct PredictCounts{
+    calls: AtomicUsize,
+    failures: AtomicUsize,
+    kinematics_calls: AtomicUsize,
+    kinematics_failures: AtomicUsize,
+}
+
+impl PredictCounts{
+    pub fn calls(&self) -> usize{
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    //Calls that returned None
+    pub fn failures(&self) -> usize{
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn kinematics_calls(&self) -> usize{
+        self.kinematics_calls.load(Ordering::Relaxed)
+    }
+
+    //Kinematics calls that returned None
+    pub fn kinematics_failures(&self) -> usize{
+        self.kinematics_failures.load(Ordering::Relaxed)
+    }
+
+    fn record<T>(prediction: &Option<T>, calls: &AtomicUsize, failures: &AtomicUsize){
+        calls.fetch_add(1, Ordering::Relaxed);
+        if prediction.is_none() {
+            failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct CountingPredictor<P>{
+    pub inner: P,
+    counts: Arc<PredictCounts>,
+}
+
+impl<P: BrainPredictor> CountingPredictor<P>{
+    pub fn new(inner: P) -> CountingPredictor<P>{
+        CountingPredictor{ inner, counts: Arc::new(PredictCounts::default()) }
+    }
+
+    pub fn counts(&self) -> Arc<PredictCounts>{
+        Arc::clone(&self.counts)
+    }
+}
+
+impl<P: BrainPredictor> BrainPredictor for CountingPredictor<P> {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let prediction = self.inner.predict(window, coef_sink);
+        PredictCounts::record(&prediction, &self.counts.calls, &self.counts.failures);
+        prediction
+    }
+
+    fn predict_kinematics(&self, window: &DistanceWindow) -> Option<Kinematics>{
+        let kinematics = self.inner.predict_kinematics(window);
+        PredictCounts::record(&kinematics, &self.counts.kinematics_calls, &self.counts.kinematics_failures);
+        kinematics
+    }
+
+    fn train(&self) -> bool{
+        self.inner.train()
+    }
+
+    fn awaiting_clean_samples(&self, window: &DistanceWindow) -> bool{
+        self.inner.awaiting_clean_samples(window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::predictor::mock::MockPredictor;
+
+    #[test]
+    fn test_counts_calls_and_failures() {
+        let predictor = CountingPredictor::new(MockPredictor::scripted(vec![Some(vec![1.0]), None, Some(vec![2.0])], None));
+        let counts = predictor.counts();
+        for _ in 0..4 {
+            predictor.predict(&DistanceWindow::new(&[], &[]), None);
+        }
+        assert!(counts.calls() == 4);
+        assert!(counts.failures() == 2);
+    }
+
+    #[test]
+    fn test_counts_kinematics_apart_from_forecasts() {
+        let predictor = CountingPredictor::new(MockPredictor::scripted(vec![Some(vec![1.0]), None], None));
+        let counts = predictor.counts();
+        predictor.predict(&DistanceWindow::new(&[], &[]), None);
+        predictor.predict_kinematics(&DistanceWindow::new(&[], &[]));
+        assert!(counts.calls() == 1 && counts.failures() == 0);
+        assert!(counts.kinematics_calls() == 1 && counts.kinematics_failures() == 1);
+    }
+}
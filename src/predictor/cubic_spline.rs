@@ -0,0 +1,173 @@
+use crate::interface::OCTError;
+use tokio::time::Instant;
+use nalgebra::{DMatrix, DVector};
+use crate::predictor::{skip_coincident, BrainPredictor, CoefSink, DistanceWindow, Kinematics};
+use crate::predictor::quadratic_regression::{QuadraticRegression, LR_SIZE};
+
+//Fewest samples the spline is fit through, two would only give a line
+const MIN_KNOTS: usize = 3;
+//Most samples the spline is fit through. Every sample is a knot, so more only cost time without
+//changing the final piece much
+const MAX_KNOTS: usize = 50;
+
+//Fits a natural cubic spline through the last `knots` valid samples and extrapolates the brain's motion with
+//the spline's final cubic piece. The spline passes through every sample, so unlike a global quadratic it
+//follows the sine motion over long windows, but it also passes through all of the OCT noise and its
+//extrapolation degrades quickly with the horizon. The spline has no fit to measure, so its confidence is
+//always 1.0. As in ParabolicPredictor, only the newest samples go through the staleness and latency checks, but a
+//sample coinciding with a newer one is dropped from every knot, as it would divide the spline's system by zero.
+pub struct CubicSplinePredictor{
+    pub knots: usize,
+}
+
+impl CubicSplinePredictor{
+    pub fn new(knots: usize) -> CubicSplinePredictor{
+        CubicSplinePredictor{ knots }
+    }
+
+    //Returns the last `knots` valid samples, or None if there are too few or the newest are stale
+    fn select_samples(&self, distances: &[Result<u64, OCTError>], times: &[Instant]) -> Option<(Vec<u64>, Vec<Instant>)>{
+        let knots = self.knots.clamp(MIN_KNOTS, MAX_KNOTS);
+        let (mut distance_queue, mut time_queue): (Vec<u64>, Vec<Instant>) = skip_coincident(distances.iter().zip(times.iter()).rev()
+            .filter_map(|(distance, time)| distance.as_ref().ok().map(|d| (*d, *time))))
+            .unzip();
+        distance_queue.reverse();
+        time_queue.reverse();
+        if distance_queue.len() < knots {
+            println!("Failing because distance queue is too small");
+            return None;
+        }
+        let distance_queue = distance_queue[distance_queue.len() - knots..].to_vec();
+        let time_queue = time_queue[time_queue.len() - knots..].to_vec();
+        let newest = knots.min(LR_SIZE);
+        QuadraticRegression::passes_latency_assumptions(&time_queue[knots - newest..]).ok()?;
+        Some((distance_queue, time_queue))
+    }
+
+    //Coefficients [a, b, c, d] of the spline's final piece a + bx + cx^2 + dx^3, where x is ms after the newest
+    //sample. The second derivatives at the knots solve the spline's tridiagonal system, with the natural
+    //condition that they are 0 at both ends
+    fn final_piece(distance_queue: &[u64], time_queue: &[Instant]) -> Option<[f64; 4]>{
+        let comp_time = *time_queue.last().unwrap();
+        let t = time_queue.iter().map(|time| -(comp_time.duration_since(*time).as_millis() as f64)).collect::<Vec<f64>>();
+        let y = distance_queue.iter().map(|distance| *distance as f64).collect::<Vec<f64>>();
+        let n = t.len();
+        let h = t.windows(2).map(|w| w[1] - w[0]).collect::<Vec<f64>>();
+
+        let mut a = DMatrix::<f64>::zeros(n, n);
+        let mut rhs = DVector::<f64>::zeros(n);
+        a[(0, 0)] = 1.0;
+        a[(n - 1, n - 1)] = 1.0;
+        for i in 1..n - 1 {
+            a[(i, i - 1)] = h[i - 1];
+            a[(i, i)] = 2.0 * (h[i - 1] + h[i]);
+            a[(i, i + 1)] = h[i];
+            rhs[i] = 6.0 * ((y[i + 1] - y[i]) / h[i] - (y[i] - y[i - 1]) / h[i - 1]);
+        }
+        let Some(second_derivatives) = a.lu().solve(&rhs) else {
+            println!("Spline system is not solvable");
+            return None;
+        };
+
+        let (m_before, m_last, h_last) = (second_derivatives[n - 2], second_derivatives[n - 1], h[n - 2]);
+        let slope = (y[n - 1] - y[n - 2]) / h_last + h_last * (m_before + 2.0 * m_last) / 6.0;
+        Some([y[n - 1], slope, m_last / 2.0, (m_last - m_before) / (6.0 * h_last)])
+    }
+}
+
+impl BrainPredictor for CubicSplinePredictor {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (distances, times) = (window.distances(), window.times());
+        let (distance_queue, time_queue) = self.select_samples(distances, times)?;
+        let coefs = Self::final_piece(&distance_queue, &time_queue)?;
+        if let Some(sink) = coef_sink {
+            sink(&coefs, None);
+        }
+        //Return the function of relative brain position wrt time
+        Some(( move |x: f64|{
+            coefs[0] + coefs[1]*x + coefs[2]*x*x + coefs[3]*x*x*x
+        }, 1.0))
+    }
+
+    fn predict_kinematics(&self, window: &DistanceWindow) -> Option<Kinematics>{
+        let (distances, times) = (window.distances(), window.times());
+        let (distance_queue, time_queue) = self.select_samples(distances, times)?;
+        let coefs = Self::final_piece(&distance_queue, &time_queue)?;
+        Some(Kinematics{ position: coefs[0], velocity: coefs[1], acceleration: 2.0 * coefs[2] })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::RobotArmBuilder;
+    use crate::predictor::parabolic::ParabolicPredictor;
+    use tokio::time::Duration;
+
+    const SAMPLE_MILLIS: u64 = 5;
+
+    //Mean absolute error of forecasting the noiseless simulated brain horizon_ms ahead of window samples
+    fn forecast_error<P: BrainPredictor>(predictor: &P, window: usize, horizon_ms: f64) -> f64 {
+        let brain_location_fn = RobotArmBuilder::new().build().brain_location_fn;
+        let now = Instant::now();
+        let errors = (0..50u64).map(|trial| {
+            //Start each trial at a different point of the brain's motion
+            let end_ms = 1_000 + trial * 97;
+            let times = (0..window as u64).rev().map(|i| now - Duration::from_millis(i * SAMPLE_MILLIS)).collect::<Vec<Instant>>();
+            let distances = (0..window as u64).rev().map(|i| Ok(brain_location_fn(end_ms - i * SAMPLE_MILLIS))).collect::<Vec<Result<u64, OCTError>>>();
+            let window = DistanceWindow::new(&distances, &times);
+            let (brain_position_function, _) = predictor.predict(&window, None).unwrap();
+            (brain_position_function(horizon_ms) - brain_location_fn(end_ms + horizon_ms as u64) as f64).abs()
+        }).collect::<Vec<f64>>();
+        errors.iter().sum::<f64>() / errors.len() as f64
+    }
+
+    #[test]
+    fn test_spline_beats_quadratic_over_short_horizons() {
+        const WINDOW: usize = 30;
+        let errors = [5.0, 20.0, 100.0].map(|horizon_ms| {
+            let spline = forecast_error(&CubicSplinePredictor::new(WINDOW), WINDOW, horizon_ms);
+            let quadratic = forecast_error(&ParabolicPredictor::new(WINDOW), WINDOW, horizon_ms);
+            (spline, quadratic)
+        });
+        let (spline, quadratic) = errors[0];
+        assert!(spline < quadratic, "Expected the spline to forecast 5ms ahead better but got {} vs {}", spline, quadratic);
+        //Extrapolating the final piece degrades with the horizon
+        assert!(errors[2].0 > 10.0 * errors[0].0, "Unexpected (spline, quadratic) errors 5, 20 and 100ms ahead: {:?}", errors);
+    }
+
+    //A spline through samples of a line is that line
+    #[test]
+    fn test_kinematics_of_line() {
+        let now = Instant::now();
+        let line = |x: f64| 1_000_000.0 - 200.0 * x;
+        let times = (0..10u64).rev().map(|i| now - Duration::from_millis(i * SAMPLE_MILLIS)).collect::<Vec<Instant>>();
+        let distances = (0..10u64).rev().map(|i| Ok(line(-5.0 * i as f64) as u64)).collect::<Vec<Result<u64, OCTError>>>();
+        let kinematics = CubicSplinePredictor::new(10).predict_kinematics(&DistanceWindow::new(&distances, &times)).unwrap();
+        assert!((kinematics.position - 1_000_000.0).abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.velocity + 200.0).abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+        assert!(kinematics.acceleration.abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+    }
+
+    //A coincident sample older than the newest LR_SIZE is dropped, and the knot before it takes its place
+    #[test]
+    fn test_old_coincident_knot_is_dropped() {
+        let now = Instant::now();
+        let line = |x: f64| 1_000_000.0 - 200.0 * x;
+        let mut times = (0..11u64).rev().map(|i| now - Duration::from_millis(i * SAMPLE_MILLIS)).collect::<Vec<Instant>>();
+        let distances = (0..11u64).rev().map(|i| Ok(line(-5.0 * i as f64) as u64)).collect::<Vec<Result<u64, OCTError>>>();
+        times[0] = times[1];
+        let kinematics = CubicSplinePredictor::new(10).predict_kinematics(&DistanceWindow::new(&distances, &times)).unwrap();
+        assert!((kinematics.position - 1_000_000.0).abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.velocity + 200.0).abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+    }
+
+    //Windows past MAX_KNOTS are capped instead of needing that many samples
+    #[test]
+    fn test_knots_are_capped() {
+        let now = Instant::now();
+        let times = (0..MAX_KNOTS as u64).rev().map(|i| now - Duration::from_millis(i * SAMPLE_MILLIS)).collect::<Vec<Instant>>();
+        let distances = (0..MAX_KNOTS as u64).map(|i| Ok(1_000_000 + i * 1_000)).collect::<Vec<Result<u64, OCTError>>>();
+        assert!(CubicSplinePredictor::new(10 * MAX_KNOTS).predict(&DistanceWindow::new(&distances, &times), None).is_some());
+    }
+}
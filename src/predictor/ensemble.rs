@@ -0,0 +1,102 @@
+use crate::predictor::{BrainPredictor, CoefSink, DistanceWindow};
+
+//Combines the forecasts of two predictors that fail under different conditions, e.g. Taylor, which is
+//strict about latency std, and regression, which needs LR_SIZE valid samples. When both succeed the
+//forecasts (and confidences) are averaged, weighting the first by first_weight and the second by the
+//rest. When only one succeeds its forecast is used on its own, so we only fail when both inners do.
+pub struct EnsemblePredictor<A, B>{
+    pub first: A,
+    pub second: B,
+    pub first_weight: f64,
+}
+
+impl<A: BrainPredictor, B: BrainPredictor> EnsemblePredictor<A, B>{
+    pub fn new(first: A, second: B) -> EnsemblePredictor<A, B>{
+        Self::with_weight(first, second, 0.5)
+    }
+
+    pub fn with_weight(first: A, second: B, first_weight: f64) -> EnsemblePredictor<A, B>{
+        assert!((0.0..=1.0).contains(&first_weight), "first_weight must be in [0, 1]");
+        EnsemblePredictor{ first, second, first_weight }
+    }
+}
+
+impl<A: BrainPredictor, B: BrainPredictor> BrainPredictor for EnsemblePredictor<A, B> {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let first = self.first.predict(window, coef_sink);
+        let second = self.second.predict(window, coef_sink);
+        //A missing forecast gets no weight, so the other is used as is
+        let first_weight = match (&first, &second) {
+            (None, None) => return None,
+            (Some(_), None) => 1.0,
+            (None, Some(_)) => 0.0,
+            (Some(_), Some(_)) => self.first_weight,
+        };
+        let first_confidence = first.as_ref().map_or(0.0, |(_, confidence)| *confidence);
+        let second_confidence = second.as_ref().map_or(0.0, |(_, confidence)| *confidence);
+        let confidence = first_weight * first_confidence + (1.0 - first_weight) * second_confidence;
+        //Return the function of relative brain position wrt time
+        Some(( move |x: f64|{
+            let first_forecast = first.as_ref().map_or(0.0, |(f, _)| f(x));
+            let second_forecast = second.as_ref().map_or(0.0, |(f, _)| f(x));
+            first_weight * first_forecast + (1.0 - first_weight) * second_forecast
+        }, confidence))
+    }
+
+    fn train(&self) -> bool{
+        self.first.train() && self.second.train()
+    }
+
+    //We only fail when both inners do, so we only wait out a dropout when both are
+    fn awaiting_clean_samples(&self, window: &DistanceWindow) -> bool{
+        self.first.awaiting_clean_samples(window) && self.second.awaiting_clean_samples(window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::predictor::quadratic_regression::{QuadraticRegression, LR_SIZE};
+    use crate::predictor::taylor_approx::TaylorQuadraticApproximator;
+    use crate::interface::OCTError;
+    use tokio::time::{Duration, Instant};
+
+    //A brain 1mm away moving at 200nm/ms, sampled at the given offsets (in ms) before now
+    fn ramp(offsets: &[u64]) -> (Vec<Result<u64, OCTError>>, Vec<Instant>){
+        let now = Instant::now();
+        let distances = offsets.iter().map(|ms| Ok(1_000_000 - 200 * ms)).collect();
+        let times = offsets.iter().map(|ms| now - Duration::from_millis(*ms)).collect();
+        (distances, times)
+    }
+
+    //Too few samples for the regression, but evenly spaced enough for Taylor
+    #[test]
+    fn test_falls_back_to_taylor() {
+        let (distances, times) = ramp(&[15, 10, 5, 0]);
+        assert!(distances.len() < LR_SIZE);
+        assert!(QuadraticRegression{}.predict(&DistanceWindow::new(&distances, &times), None).is_none());
+        let ensemble = EnsemblePredictor::new(TaylorQuadraticApproximator{}, QuadraticRegression{});
+        let window = DistanceWindow::new(&distances, &times);
+        let (forecast, _) = ensemble.predict(&window, None).unwrap();
+        assert!((forecast(10.0) - 1_002_000.0).abs() < 1.0, "Expected 1002000 but forecast {}", forecast(10.0));
+    }
+
+    //Samples too irregularly spaced for Taylor, but enough of them for the regression
+    #[test]
+    fn test_falls_back_to_regression() {
+        let (distances, times) = ramp(&[28, 26, 14, 12, 0]);
+        assert!(TaylorQuadraticApproximator{}.predict(&DistanceWindow::new(&distances, &times), None).is_none());
+        let ensemble = EnsemblePredictor::with_weight(TaylorQuadraticApproximator{}, QuadraticRegression{}, 0.9);
+        let window = DistanceWindow::new(&distances, &times);
+        let (forecast, confidence) = ensemble.predict(&window, None).unwrap();
+        assert!((forecast(10.0) - 1_002_000.0).abs() < 1.0, "Expected 1002000 but forecast {}", forecast(10.0));
+        assert!(confidence > 0.99);
+    }
+
+    #[test]
+    fn test_both_fail() {
+        let (distances, times) = ramp(&[10, 0]);
+        let ensemble = EnsemblePredictor::new(TaylorQuadraticApproximator{}, QuadraticRegression{});
+        assert!(ensemble.predict(&DistanceWindow::new(&distances, &times), None).is_none());
+    }
+}
@@ -0,0 +1,92 @@
+use tokio::time::Instant;
+use crate::predictor::{skip_coincident, BrainPredictor, CoefSink, DistanceWindow};
+use crate::predictor::quadratic_regression::{QuadraticRegression, LR_SIZE};
+
+//Fewest valid samples we need before the smoothed trend means anything
+const MIN_SAMPLES: usize = 2;
+
+//Double exponential smoothing (Holt's method) over the valid distance samples. It keeps a level and
+//a trend (in nm/ms) and forecasts in a straight line, so there is no matrix to invert and nothing to
+//fail on degenerate data. alpha smooths the level and beta smooths the trend, both in [0, 1].
+//predict only gets &self, so the smoothed state is rebuilt from the whole history on every call.
+pub struct ExponentialSmoothingPredictor{
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+impl ExponentialSmoothingPredictor{
+    pub fn new(alpha: f64, beta: f64) -> ExponentialSmoothingPredictor{
+        assert!((0.0..=1.0).contains(&alpha) && (0.0..=1.0).contains(&beta), "alpha and beta must be in [0, 1]");
+        ExponentialSmoothingPredictor{ alpha, beta }
+    }
+
+    //Returns the (level, trend) after smoothing over every sample. Samples may be unevenly spaced,
+    //so the trend is carried forward by the actual time between samples
+    fn smooth(&self, distance_queue: &[u64], time_queue: &[Instant]) -> (f64, f64){
+        let mut level = distance_queue[0] as f64;
+        let mut trend = 0.0;
+        for i in 1..distance_queue.len() {
+            let dt = time_queue[i].duration_since(time_queue[i-1]).as_millis() as f64;
+            if dt == 0.0 {
+                continue;
+            }
+            let previous_level = level;
+            level = self.alpha * distance_queue[i] as f64 + (1.0 - self.alpha) * (level + trend * dt);
+            trend = self.beta * (level - previous_level) / dt + (1.0 - self.beta) * trend;
+        }
+        (level, trend)
+    }
+}
+
+impl BrainPredictor for ExponentialSmoothingPredictor {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (distances, times) = (window.distances(), window.times());
+        let (mut distance_queue, mut time_queue): (Vec<u64>, Vec<Instant>) = skip_coincident(distances.iter().zip(times.iter()).rev()
+            .filter_map(|(distance, time)| distance.as_ref().ok().map(|d| (*d, *time))))
+            .unzip();
+        distance_queue.reverse();
+        time_queue.reverse();
+        if distance_queue.len() < MIN_SAMPLES {
+            println!("Failing because distance queue is too small");
+            return None;
+        }
+        let newest = distance_queue.len().min(LR_SIZE);
+        QuadraticRegression::passes_latency_assumptions(&time_queue[time_queue.len() - newest..]).ok()?;
+        let (level, trend) = self.smooth(&distance_queue, &time_queue);
+        if let Some(sink) = coef_sink {
+            sink(&[level, trend], None);
+        }
+        //Return the function of relative brain position wrt time
+        Some(( move |dt: f64|{
+            level + trend*dt
+        }, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::OCTError;
+    use tokio::time::Duration;
+
+    // A ramp of 200nm/ms sampled every 5ms, starting from a trend of 0
+    #[test]
+    fn test_trend_converges_on_linear_ramp() {
+        let predictor = ExponentialSmoothingPredictor::new(0.5, 0.3);
+        let now = Instant::now();
+        let ramp = |ms: u64| 1_000_000 + 200 * ms;
+        let times = (0..100u64).rev().map(|i| now - Duration::from_millis(5 * i)).collect::<Vec<Instant>>();
+        let distances = (0..100u64).map(|i| Ok(ramp(5 * i))).collect::<Vec<Result<u64, OCTError>>>();
+
+        let (_, trend) = predictor.smooth(&[ramp(0), ramp(5), ramp(10)], &times[..3]);
+        assert!((trend - 200.0).abs() > 50.0, "Trend should not have converged after 3 samples: {}", trend);
+        let (level, trend) = predictor.smooth(&distances.iter().map(|d| *d.as_ref().unwrap()).collect::<Vec<u64>>(), &times);
+        assert!((trend - 200.0).abs() < 1.0, "Expected a trend of 200 but got {}", trend);
+        assert!((level - ramp(495) as f64).abs() < 100.0, "Expected a level of {} but got {}", ramp(495), level);
+
+        let window = DistanceWindow::new(&distances, &times);
+
+        let (forecast, _) = predictor.predict(&window, None).unwrap();
+        assert!((forecast(5.0) - ramp(500) as f64).abs() < 100.0, "Expected {} but forecast {}", ramp(500), forecast(5.0));
+    }
+}
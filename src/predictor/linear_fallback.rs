@@ -0,0 +1,109 @@
+use crate::predictor::{skip_coincident, BrainPredictor, CoefSink, DistanceWindow};
+use crate::predictor::quadratic_regression::QuadraticRegression;
+
+//Confidence of a straight line through two samples, which says nothing about how well it fits
+pub const FALLBACK_CONFIDENCE: f64 = 0.5;
+
+//Fewest valid samples a line can be drawn through
+const MIN_SAMPLES: usize = 2;
+
+//Extrapolates a straight line through the newest two valid samples while the inner predictor is still
+//warming up, i.e. has fewer than full_window valid samples and no forecast. This lets us move on the first
+//samples in the brain instead of idling until the inner window fills. The line is returned with
+//FALLBACK_CONFIDENCE, so it can be ruled out with min_prediction_confidence. Once full_window samples are
+//in, the inner predictor's None is passed on, as it is then rejecting the samples rather than waiting for them.
+pub struct LinearFallbackPredictor<P>{
+    pub inner: P,
+    pub full_window: usize,
+}
+
+impl<P: BrainPredictor> LinearFallbackPredictor<P>{
+    pub fn new(inner: P, full_window: usize) -> LinearFallbackPredictor<P>{
+        LinearFallbackPredictor{ inner, full_window }
+    }
+
+    //Returns the (position, velocity in nm/ms) of the line through the newest two valid samples, at the newest.
+    //None if they are stale or too far apart, as for the regression
+    fn fit_line(&self, window: &DistanceWindow) -> Option<(f64, f64)>{
+        let num_valid = window.valid_only().count();
+        if num_valid < MIN_SAMPLES || num_valid >= self.full_window {
+            return None;
+        }
+        //Walk back from the newest sample, so the line is drawn without copying the window
+        let mut newest = skip_coincident(window.valid_only().rev());
+        let ((distance, time), (previous_distance, previous_time)) = (newest.next()?, newest.next()?);
+        //The line is held to the same staleness and latency as the regression it stands in for
+        QuadraticRegression::passes_latency_assumptions(&[previous_time, time]).ok()?;
+        let dt = time.duration_since(previous_time).as_millis() as f64;
+        Some((distance as f64, (distance as f64 - previous_distance as f64) / dt))
+    }
+}
+
+impl<P: BrainPredictor> BrainPredictor for LinearFallbackPredictor<P> {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (forecast, confidence, line) = match self.inner.predict(window, coef_sink) {
+            Some((forecast, confidence)) => (Some(forecast), confidence, (0.0, 0.0)),
+            None => {
+                let line = self.fit_line(window)?;
+                if let Some(sink) = coef_sink {
+                    sink(&[line.0, line.1], None);
+                }
+                (None, FALLBACK_CONFIDENCE, line)
+            }
+        };
+        //Return the function of relative brain position wrt time
+        Some(( move |x: f64|{
+            forecast.as_ref().map_or(line.0 + line.1 * x, |forecast| forecast(x))
+        }, confidence))
+    }
+
+    fn train(&self) -> bool{
+        self.inner.train()
+    }
+
+    fn awaiting_clean_samples(&self, window: &DistanceWindow) -> bool{
+        self.inner.awaiting_clean_samples(window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::OCTError;
+    use crate::predictor::quadratic_regression::{QuadraticRegression, LR_SIZE};
+    use tokio::time::{Duration, Instant};
+
+    //Two valid samples 10ms apart, around an error, are too few for the regression but enough for a line
+    #[test]
+    fn test_two_samples_give_a_linear_forecast() {
+        let now = Instant::now();
+        let times = vec![now - Duration::from_millis(10), now - Duration::from_millis(5), now];
+        let distances = vec![Ok(1_000_000), Err(OCTError::AcquisitionError { msg: "test".to_string(), at_ms: None }), Ok(1_010_000)];
+        let window = DistanceWindow::new(&distances, &times);
+        assert!(QuadraticRegression{}.predict(&window, None).is_none());
+
+        let predictor = LinearFallbackPredictor::new(QuadraticRegression{}, LR_SIZE);
+        let (forecast, confidence) = predictor.predict(&window, None).unwrap();
+        assert!(confidence == FALLBACK_CONFIDENCE);
+        //1.01mm at the newest sample, receding by 1um every ms
+        assert!((forecast(0.0) - 1_010_000.0).abs() < 1e-6);
+        assert!((forecast(20.0) - 1_030_000.0).abs() < 1e-6, "Forecast {} 20ms ahead", forecast(20.0));
+
+        //A single valid sample has no line through it
+        let window = DistanceWindow::new(&distances[1..], &times[1..]);
+        assert!(predictor.predict(&window, None).is_none());
+    }
+
+    //A line through samples the regression would reject as stale is no better, so there is none
+    #[test]
+    fn test_stale_samples_give_no_line() {
+        let now = Instant::now();
+        let predictor = LinearFallbackPredictor::new(QuadraticRegression{}, LR_SIZE);
+        let times = vec![now - Duration::from_millis(210), now - Duration::from_millis(200)];
+        let distances = vec![Ok(1_000_000), Ok(1_010_000)];
+        assert!(predictor.predict(&DistanceWindow::new(&distances, &times), None).is_none());
+        //Fresh samples too far apart to extrapolate from are rejected as well
+        let times = vec![now - Duration::from_millis(100), now];
+        assert!(predictor.predict(&DistanceWindow::new(&distances, &times), None).is_none());
+    }
+}
@@ -0,0 +1,64 @@
+use crate::predictor::{BrainPredictor, CoefSink, DistanceWindow};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+//Returns scripted predictions so the controller's state machine can be tested apart from the numerics.
+//Each call to predict takes the next scripted output, a polynomial's coefficients (constant term
+//first) or None for a failed prediction. Once the script runs out every call returns the fallback.
+//The controller predicts for every distance sample as well as for moves, so scripts are only
+//predictable when nothing else is predicting.
+pub struct MockPredictor{
+    script: Mutex<VecDeque<Option<Vec<f64>>>>,
+    fallback: Option<Vec<f64>>,
+}
+
+impl MockPredictor{
+    pub fn scripted(script: Vec<Option<Vec<f64>>>, fallback: Option<Vec<f64>>) -> MockPredictor{
+        MockPredictor{ script: Mutex::new(script.into()), fallback }
+    }
+
+    pub fn always(coefs: Vec<f64>) -> MockPredictor{
+        Self::scripted(Vec::new(), Some(coefs))
+    }
+
+    pub fn always_none() -> MockPredictor{
+        Self::scripted(Vec::new(), None)
+    }
+}
+
+impl BrainPredictor for MockPredictor {
+    fn predict(&self, _: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let coefs = self.script.lock().unwrap().pop_front().unwrap_or_else(|| self.fallback.clone())?;
+        Some(( move |x: f64|{
+            coefs.iter().rev().fold(0.0, |acc, coef| acc * x + coef)
+        }, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_then_fallback() {
+        let predictor = MockPredictor::scripted(vec![Some(vec![1.0, 2.0, 3.0]), None], Some(vec![5.0]));
+        let window = DistanceWindow::new(&[], &[]);
+        let (first, _) = predictor.predict(&window, None).unwrap();
+        assert!(first(2.0) == 1.0 + 2.0 * 2.0 + 3.0 * 4.0);
+        assert!(predictor.predict(&DistanceWindow::new(&[], &[]), None).is_none());
+        let window = DistanceWindow::new(&[], &[]);
+        let (fallback, _) = predictor.predict(&window, None).unwrap();
+        assert!(fallback(2.0) == 5.0);
+        assert!(MockPredictor::always_none().predict(&DistanceWindow::new(&[], &[]), None).is_none());
+    }
+
+    //The default kinematics finite difference the position function
+    #[test]
+    fn test_default_kinematics() {
+        let kinematics = MockPredictor::always(vec![1_000_000.0, -200.0, 3.0]).predict_kinematics(&DistanceWindow::new(&[], &[])).unwrap();
+        assert!((kinematics.position - 1_000_000.0).abs() < 1e-6, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.velocity + 200.0).abs() < 1e-6, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.acceleration - 2.0 * 3.0).abs() < 1e-6, "Unexpected kinematics: {:?}", kinematics);
+        assert!(MockPredictor::always_none().predict_kinematics(&DistanceWindow::new(&[], &[])).is_none());
+    }
+}
@@ -1,13 +1,148 @@
 use crate::interface::OCTError;
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 
+pub mod any;
+#[cfg(any(test, feature = "virtual-clock"))]
+pub mod counting;
+pub mod cubic_spline;
+pub mod ensemble;
+pub mod exp_smoothing;
+pub mod linear_fallback;
+#[cfg(test)]
+pub mod mock;
 pub mod oracle_approx;
+pub mod parabolic;
 pub mod quadratic_regression;
+pub mod robust_quadratic_regression;
 pub mod taylor_approx;
 
+//Step (in ms) the default predict_kinematics finite differences the position function over
+const KINEMATICS_STEP_MS: f64 = 1.0;
+
+/// Kinematics is the brain's estimated motion relative to the inserter at the newest sample.
+///  - position: distance from the inserter, in nm
+///  - velocity: rate the distance grows at, in nm/ms
+///  - acceleration: in nm/ms^2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Kinematics {
+    pub position: f64,
+    pub velocity: f64,
+    pub acceleration: f64,
+}
+
+/// DistanceWindow is a view over distance samples, oldest first, and the times they were taken at, which
+/// predictors fit the brain's motion to. It borrows the samples, so narrowing it down copies nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct DistanceWindow<'a> {
+    distances: &'a [Result<u64, OCTError>],
+    times: &'a [Instant],
+}
+
+impl<'a> DistanceWindow<'a> {
+    pub fn new(distances: &'a [Result<u64, OCTError>], times: &'a [Instant]) -> DistanceWindow<'a> {
+        assert!(distances.len() == times.len(), "{} distances but {} times", distances.len(), times.len());
+        DistanceWindow { distances, times }
+    }
+
+    /// The samples, errors included.
+    pub fn distances(&self) -> &'a [Result<u64, OCTError>] {
+        self.distances
+    }
+
+    /// The time each sample was taken at.
+    pub fn times(&self) -> &'a [Instant] {
+        self.times
+    }
+
+    pub fn len(&self) -> usize {
+        self.distances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.distances.is_empty()
+    }
+
+    /// Each sample along with the time it was taken at, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&'a Result<u64, OCTError>, Instant)> + 'a {
+        self.distances.iter().zip(self.times.iter().copied())
+    }
+
+    /// The samples that are distances, skipping the errors.
+    pub fn valid_only(&self) -> impl DoubleEndedIterator<Item = (u64, Instant)> + 'a {
+        self.iter().filter_map(|(distance, time)| distance.as_ref().ok().map(|distance| (*distance, time)))
+    }
+
+    /// The newest n samples, or all of them if there are fewer.
+    pub fn tail(&self, n: usize) -> DistanceWindow<'a> {
+        let start = self.len().saturating_sub(n);
+        DistanceWindow { distances: &self.distances[start..], times: &self.times[start..] }
+    }
+}
+
+/// Receives the coefficients a predictor fitted, lowest order first, along with the fit's R^2 for
+/// predictors that measure one, e.g. to log them.
+pub type CoefSink<'a> = Option<&'a dyn Fn(&[f64], Option<f64>)>;
+
+//Predictors return the brain position function along with a confidence in [0, 1] of how well
+//the function fits the data it was built from. Predictors that can't measure this return 1.0
+//Predictors that fit coefficients hand them to coef_sink, if given
+//The function owns everything it needs, so the controller can hold on to it and reuse it for a later call
 pub trait BrainPredictor {
-    fn predict(&self, distances: &Vec<Result<u64, OCTError>>, times: &Vec<Instant>, print_coefs: bool) -> Option<impl Fn(f64) -> f64>;
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>;
+    //By default the derivatives are central differences of the position function around the newest sample.
+    //Polynomial predictors override this to read them straight off their coefficients
+    fn predict_kinematics(&self, window: &DistanceWindow) -> Option<Kinematics>{
+        let (position_fn, _) = self.predict(window, None)?;
+        let (before, position, after) = (position_fn(-KINEMATICS_STEP_MS), position_fn(0.0), position_fn(KINEMATICS_STEP_MS));
+        Some(Kinematics{
+            position,
+            velocity: (after - before) / (2.0 * KINEMATICS_STEP_MS),
+            acceleration: (after - 2.0 * position + before) / (KINEMATICS_STEP_MS * KINEMATICS_STEP_MS),
+        })
+    }
     fn train(&self) -> bool{
         return true;
     }
-}
\ No newline at end of file
+    //Whether predict has no forecast only because OCT errors are among the newest samples it needs, i.e. the
+    //predictor is waiting out a dropout rather than rejecting what it sees. The controller doesn't count the
+    //samples it can't predict then as abnormal. By default a predictor never says so
+    fn awaiting_clean_samples(&self, _window: &DistanceWindow) -> bool{
+        false
+    }
+}
+
+//Drops every sample less than a millisecond older than the newer one kept before it, from samples given
+//newest first. Latencies are measured in whole milliseconds, so such a gap counts as 0 and would divide the
+//predictions by zero. Only the coincident sample is dropped, the rest of the window is still used
+pub(crate) fn skip_coincident<T>(newest_first: impl Iterator<Item = (T, Instant)>) -> impl Iterator<Item = (T, Instant)> {
+    let mut newer: Option<Instant> = None;
+    newest_first.filter(move |(_, time)| {
+        let keep = newer.is_none_or(|newer| newer.saturating_duration_since(*time) >= Duration::from_millis(1));
+        if keep {
+            newer = Some(*time);
+        }
+        keep
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance_window_views() {
+        let start = Instant::now();
+        let distances = vec![Ok(100), Err(OCTError::AcquisitionError { msg: "test".to_string(), at_ms: None }), Ok(300), Ok(400)];
+        let times: Vec<Instant> = (0..4u64).map(|i| start + Duration::from_millis(i * 10)).collect();
+        let window = DistanceWindow::new(&distances, &times);
+
+        let valid: Vec<(u64, Instant)> = window.valid_only().collect();
+        assert_eq!(valid, vec![(100, times[0]), (300, times[2]), (400, times[3])]);
+        assert_eq!(window.iter().count(), 4);
+
+        let tail = window.tail(2);
+        assert_eq!(tail.len(), 2);
+        assert_eq!(tail.times(), &times[2..]);
+        assert_eq!(window.tail(10).len(), 4);
+    }
+}
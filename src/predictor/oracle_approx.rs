@@ -1,57 +1,101 @@
-//THE FOLLOWING CODE IS BUGGY, DO NOT USE
 use tokio::time::Instant;
 use crate::interface::OCTError;
-use crate::predictor::BrainPredictor;
+use crate::predictor::{BrainPredictor, CoefSink, DistanceWindow};
 const MIN_SIZE: usize =3;
 const MAX_LATENCY_MS: u64 = 18;
 
+//The oracle knows the brain's true path, so it is an upper bound on how well any predictor can do.
+//It is given the same brain function as the robot simulation along with the time that function is
+//measured from (the robot's init time). The only thing it learns from the data is where the inserter
+//is, which it infers from the most recent distance reading.
 pub struct OraclePredictor{
-    init_time: Instant,
+    brain_location_fn: fn(u64) -> u64,
+    reference_time: Instant,
 }
 
 impl OraclePredictor{
-    pub fn new() -> OraclePredictor{
+    pub fn new(brain_location_fn: fn(u64) -> u64, reference_time: Instant) -> OraclePredictor{
         OraclePredictor{
-            init_time: Instant::now(),
+            brain_location_fn,
+            reference_time,
         }
     }
 
-    fn passes_predict_assumptions(distance_queue: &Vec<Result<u64, OCTError>>, time_queue: &Vec<Instant>) -> Result<(Vec<u64>, Vec<Instant>), ()> {
+    fn brain_location_at(&self, time: Instant) -> u64{
+        (self.brain_location_fn)(time.saturating_duration_since(self.reference_time).as_millis() as u64)
+    }
+
+    fn passes_predict_assumptions(distance_queue: &[Result<u64, OCTError>], time_queue: &[Instant]) -> Result<(), ()> {
         const data_len: usize = MIN_SIZE+1;
         //We must have enough data to do a Taylor approximation
         if distance_queue.len() < data_len{
             println!("Failing because distance queue is too small");
             return Err(());
         }
-        let mut distance_queue = Vec::from(distance_queue.clone());
-        let Some(distance_queue) = distance_queue.last_chunk_mut::<data_len>() else {return Err(()); };
-        let mut time_queue = Vec::from(time_queue.clone());
-        let Some(time_queue) = time_queue.last_chunk_mut::<data_len>() else{ return Err(()); };
+        let Some(distance_queue) = distance_queue.last_chunk::<data_len>() else {return Err(()); };
+        let Some(time_queue) = time_queue.last_chunk::<data_len>() else{ return Err(()); };
         //Our data must be relatively new (cannot be stale)
         if Instant::now().duration_since(time_queue[time_queue.len()-1]).as_millis() as u64 > MAX_LATENCY_MS{
             println!("Failing because latency is too big: {}", Instant::now().duration_since(time_queue[time_queue.len()-1]).as_millis());
             return Err(());
         }
         //We must have enough non error data to do a Taylor approximation
-        let distance_queue = distance_queue.iter().filter(|x| x.is_ok()).map(|x| *x.as_ref().unwrap()).collect::<Vec<u64>>();
-        if distance_queue.len() < data_len{
+        if distance_queue.iter().any(|x| x.is_err()){
             println!("Failing because distance queue has too many errors");
             return Err(());
         }
-        return Ok((distance_queue, Vec::from(time_queue)));
+        Ok(())
     }
 }
 
 impl BrainPredictor for OraclePredictor{
-    fn predict(&self, distances: &Vec<Result<u64, OCTError>>, times: &Vec<Instant>, _: bool) -> Option<impl Fn(f64) -> f64>{
-        if !Self::passes_predict_assumptions(distances, times).is_ok(){
+    fn predict(&self, window: &DistanceWindow, _: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (distances, times) = (window.distances(), window.times());
+        if Self::passes_predict_assumptions(distances, times).is_err(){
             return None
         };
-        return Some(  |x: f64| {
-            let x = x + self.init_time.elapsed().as_millis() as f64;
-            7_000_000.0 - 5332309.0 
-                + 500_000.0 * (6.0 * x as f64/1000.0).sin()
-                + 1_000_000.0 * (x as f64/1000.0).sin()
-        });
+        //The inserter's offset is wherever the brain was when the latest reading was taken, minus that reading
+        let (distance, time) = distances.iter().zip(times.iter()).rev().find_map(|(distance, time)| distance.as_ref().ok().map(|d| (*d, *time)))?;
+        let inserter_offset = self.brain_location_at(time) as f64 - distance as f64;
+        let now_ms = Instant::now().saturating_duration_since(self.reference_time).as_millis() as f64;
+        let brain_location_fn = self.brain_location_fn;
+        //Return the function of relative brain position wrt time from now
+        Some((move |x: f64| {
+            brain_location_fn((now_ms + x) as u64) as f64 - inserter_offset
+        }, 1.0))
     }
-}
\ No newline at end of file
+
+    fn awaiting_clean_samples(&self, window: &DistanceWindow) -> bool{
+        window.tail(MIN_SIZE + 1).distances().iter().any(|distance| distance.is_err())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::RobotArmBuilder;
+    use tokio::time::Duration;
+
+    const INSERTER_Z: u64 = 1_000_000;
+
+    #[test]
+    fn test_oracle_matches_brain_function() {
+        let arm = RobotArmBuilder::new().build();
+        let oracle = OraclePredictor::new(arm.brain_location_fn, arm.get_init_time());
+        std::thread::sleep(Duration::from_millis(20));
+        let now = Instant::now();
+        let times = (0..4u64).rev().map(|i| now - Duration::from_millis(5 * i)).collect::<Vec<Instant>>();
+        let distances = times.iter().map(|t| Ok(oracle.brain_location_at(*t) - INSERTER_Z)).collect::<Vec<Result<u64, OCTError>>>();
+
+        //The oracle measures from the time predict is called, which we can only pin down to a millisecond range
+        let before = arm.get_init_time().elapsed().as_millis() as u64;
+        let window = DistanceWindow::new(&distances, &times);
+        let (forecast, confidence) = oracle.predict(&window, None).unwrap();
+        let after = arm.get_init_time().elapsed().as_millis() as u64;
+        assert!(confidence == 1.0);
+        for x in 0..=500u64 {
+            let expected = (before..=after).map(|ms| (arm.brain_location_fn)(ms + x) as f64 - INSERTER_Z as f64).collect::<Vec<f64>>();
+            assert!(expected.iter().any(|e| (forecast(x as f64) - e).abs() < 1.0), "At {}ms forecast {} but expected one of {:?}", x, forecast(x as f64), expected);
+        }
+    }
+}
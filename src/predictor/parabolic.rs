@@ -0,0 +1,111 @@
+use crate::interface::OCTError;
+use tokio::time::Instant;
+use crate::predictor::{skip_coincident, BrainPredictor, CoefSink, DistanceWindow};
+use crate::predictor::quadratic_regression::{QuadraticRegression, LR_SIZE};
+
+//Fewest samples a quadratic can be fit through
+const MIN_WINDOW: usize = 3;
+
+//Same quadratic model as QuadraticRegression, but fit over the last `window` valid samples instead of
+//exactly LR_SIZE. A longer window averages out more OCT noise at the cost of reacting more slowly
+//to changes in the brain's motion. The staleness and latency checks only look at the newest samples,
+//so a long window doesn't fail them just because its oldest samples are old.
+pub struct ParabolicPredictor{
+    pub window: usize,
+}
+
+impl ParabolicPredictor{
+    pub fn new(window: usize) -> ParabolicPredictor{
+        ParabolicPredictor{ window }
+    }
+
+    //Returns the last `window` valid samples, or None if there are too few or the newest are stale
+    fn select_samples(&self, distances: &[Result<u64, OCTError>], times: &[Instant]) -> Option<(Vec<u64>, Vec<Instant>)>{
+        let window = self.window.max(MIN_WINDOW);
+        let (mut distance_queue, mut time_queue): (Vec<u64>, Vec<Instant>) = skip_coincident(distances.iter().zip(times.iter()).rev()
+            .filter_map(|(distance, time)| distance.as_ref().ok().map(|d| (*d, *time))))
+            .unzip();
+        distance_queue.reverse();
+        time_queue.reverse();
+        if distance_queue.len() < window {
+            println!("Failing because distance queue is too small");
+            return None;
+        }
+        let distance_queue = distance_queue[distance_queue.len() - window..].to_vec();
+        let time_queue = time_queue[time_queue.len() - window..].to_vec();
+        let newest = window.min(LR_SIZE);
+        QuadraticRegression::passes_latency_assumptions(&time_queue[window - newest..]).ok()?;
+        Some((distance_queue, time_queue))
+    }
+}
+
+impl BrainPredictor for ParabolicPredictor {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (distances, times) = (window.distances(), window.times());
+        let (distance_queue, time_queue) = self.select_samples(distances, times)?;
+        let weights = vec![1.0; distance_queue.len()];
+        let coefs = QuadraticRegression::weighted_regress(&distance_queue, &time_queue, &weights)?;
+        let r_squared = QuadraticRegression::weighted_r_squared(&distance_queue, &time_queue, &weights, &coefs);
+        if let Some(sink) = coef_sink {
+            sink(&coefs, Some(r_squared));
+        }
+        //Return the function of relative brain position wrt time
+        Some(( move |x: f64|{
+            coefs[0] + coefs[1]*x + coefs[2]*x*x
+        }, r_squared))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::robot::RobotArmBuilder;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use tokio::time::Duration;
+
+    const SAMPLE_MILLIS: u64 = 5;
+    const NOISE_NM: f64 = 5_000.0;
+    const HORIZON_MS: f64 = 20.0;
+
+    //Variance of the error in forecasting the simulated brain HORIZON_MS ahead from noisy OCT samples
+    fn forecast_error_variance(window: usize) -> f64 {
+        let brain_location_fn = RobotArmBuilder::new().build().brain_location_fn;
+        let predictor = ParabolicPredictor::new(window);
+        let mut rng = StdRng::seed_from_u64(7);
+        let now = Instant::now();
+        let errors = (0..50u64).map(|trial| {
+            //Start each trial at a different point of the brain's motion
+            let end_ms = 1_000 + trial * 97;
+            let times = (0..window as u64).rev().map(|i| now - Duration::from_millis(i * SAMPLE_MILLIS)).collect::<Vec<Instant>>();
+            let distances = (0..window as u64).rev().map(|i| {
+                let truth = brain_location_fn(end_ms - i * SAMPLE_MILLIS) as f64;
+                Ok((truth + rng.gen_range(-NOISE_NM..NOISE_NM)) as u64)
+            }).collect::<Vec<Result<u64, OCTError>>>();
+            let window = DistanceWindow::new(&distances, &times);
+            let (brain_position_function, _) = predictor.predict(&window, None).unwrap();
+            brain_position_function(HORIZON_MS) - brain_location_fn(end_ms + HORIZON_MS as u64) as f64
+        }).collect::<Vec<f64>>();
+        let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+        errors.iter().map(|e| (e - mean).powi(2)).sum::<f64>() / errors.len() as f64
+    }
+
+    #[test]
+    fn test_longer_window_reduces_forecast_variance() {
+        let short = forecast_error_variance(5);
+        let long = forecast_error_variance(30);
+        assert!(long < short / 4.0, "Expected window 30 to be much less noisy than window 5 but got {} vs {}", long, short);
+    }
+
+    //Errors are skipped rather than counted towards the window
+    #[test]
+    fn test_window_skips_errors() {
+        let now = Instant::now();
+        let times = (0..6u64).rev().map(|i| now - Duration::from_millis(i * SAMPLE_MILLIS)).collect::<Vec<Instant>>();
+        let mut distances = (0..6u64).map(|i| Ok(1_000_000 + i * 1_000)).collect::<Vec<Result<u64, OCTError>>>();
+        distances[1] = Err(OCTError::AcquisitionError { msg: "test".to_string(), at_ms: None });
+        distances[4] = Err(OCTError::AcquisitionError { msg: "test".to_string(), at_ms: None });
+        assert!(ParabolicPredictor::new(4).predict(&DistanceWindow::new(&distances, &times), None).is_some());
+        assert!(ParabolicPredictor::new(5).predict(&DistanceWindow::new(&distances, &times), None).is_none());
+    }
+}
@@ -3,10 +3,10 @@ use std::{char::MAX, f32::MIN};
 use crate::interface::OCTError;
 use tokio::time::Instant;
 use nalgebra::{DMatrix, DVector};
-use crate::predictor::BrainPredictor;
+use crate::predictor::{skip_coincident, BrainPredictor, CoefSink, DistanceWindow, Kinematics};
 
 const MAX_LATENCY_MS: u64 = 18;
-const LR_SIZE: usize = 5;
+pub(crate) const LR_SIZE: usize = 5;
 const MAX_LR_LATENCY_MS: u64 = LR_SIZE as u64 * 25 ;
 
 //To predict where the brain will be in the future, we use a Taylor series approximation of degree 2
@@ -20,15 +20,22 @@ pub struct QuadraticRegression;
 impl QuadraticRegression{
 
     fn regress(distance_queue: &Vec<u64>, time_queue: &Vec<Instant>) -> Option<Vec<f64>>{
+        Self::weighted_regress(distance_queue, time_queue, &vec![1.0; distance_queue.len()])
+    }
+
+    //Weighted least squares over the same design matrix as regress. Each row is scaled by the
+    //square root of its weight, so a weight of 0 removes the sample from the fit entirely
+    pub(crate) fn weighted_regress(distance_queue: &[u64], time_queue: &[Instant], weights: &[f64]) -> Option<Vec<f64>>{
         let mut x_rows = Vec::new();
         let comp_time = *time_queue.last().unwrap();
 
-        for i in 0..distance_queue.len(){
-            let time = comp_time.duration_since(time_queue[i]).as_millis() as f64;
-            x_rows.push(vec![1.0, -time, time*time]);
+        for (sample_time, weight) in time_queue.iter().zip(weights.iter()){
+            let time = comp_time.duration_since(*sample_time).as_millis() as f64;
+            let w = weight.sqrt();
+            x_rows.push(vec![w, -time*w, time*time*w]);
         }
         let x = DMatrix::from_vec(3, x_rows.len(), x_rows.concat()).transpose();
-        let y = DVector::from_vec(distance_queue.iter().map(|x| *x as f64).collect());
+        let y = DVector::from_vec(distance_queue.iter().zip(weights.iter()).map(|(x, w)| *x as f64 * w.sqrt()).collect());
         let xt_x = x.transpose() * x.clone();
         let xt_y = x.transpose() * y;
 
@@ -41,16 +48,45 @@ impl QuadraticRegression{
         }
     }
 
+    //Weighted coefficient of determination (R^2) of the fit. A flat signal that is fit exactly has no
+    //variance to explain, so we report a perfect fit for it instead of dividing by zero
+    pub(crate) fn weighted_r_squared(distance_queue: &[u64], time_queue: &[Instant], weights: &[f64], coefs: &[f64]) -> f64{
+        let comp_time = *time_queue.last().unwrap();
+        let weight_sum = weights.iter().sum::<f64>();
+        let mean = distance_queue.iter().zip(weights.iter()).map(|(y, w)| *y as f64 * w).sum::<f64>() / weight_sum;
+        let mut residual_sum_squares = 0.0;
+        let mut total_sum_squares = 0.0;
+        for ((y, sample_time), w) in distance_queue.iter().zip(time_queue.iter()).zip(weights.iter()){
+            let time = comp_time.duration_since(*sample_time).as_millis() as f64;
+            let fitted = coefs[0] - coefs[1]*time + coefs[2]*time*time;
+            residual_sum_squares += w * (*y as f64 - fitted).powi(2);
+            total_sum_squares += w * (*y as f64 - mean).powi(2);
+        }
+        if total_sum_squares == 0.0 {
+            return 1.0;
+        }
+        (1.0 - residual_sum_squares / total_sum_squares).clamp(0.0, 1.0)
+    }
+
     //Check if our assumptions for prediction hold
-    fn passes_predict_assumptions(distance_queue: &Vec<Result<u64, OCTError>>, time_queue: &Vec<Instant>) -> Result<(f64, Vec<u64>, Vec<Instant>), ()> {
-        let keep_indices = distance_queue.iter().enumerate().filter(|(_, x)| x.is_ok()).map(|(i, _)| i).collect::<Vec<usize>>();
-        let mut distance_queue = distance_queue.iter().filter(|x| x.is_ok()).map(|x| *x.as_ref().unwrap()).collect::<Vec<u64>>();
-        let mut time_queue = time_queue.iter().enumerate().filter(|(i, _)| keep_indices.contains(i)).map(|(_, x)| *x).collect::<Vec<Instant>>();
-        let Some(distance_queue) = distance_queue.last_chunk_mut::<LR_SIZE>() else {
+    pub(crate) fn passes_predict_assumptions(distance_queue: &[Result<u64, OCTError>], time_queue: &[Instant]) -> Result<(f64, Vec<u64>, Vec<Instant>), ()> {
+        //Walk back from the newest sample so we only ever look at the last LR_SIZE valid ones
+        let (mut distance_queue, mut time_queue): (Vec<u64>, Vec<Instant>) = skip_coincident(distance_queue.iter().zip(time_queue.iter()).rev()
+            .filter_map(|(distance, time)| distance.as_ref().ok().map(|d| (*d, *time))))
+            .take(LR_SIZE)
+            .unzip();
+        if distance_queue.len() < LR_SIZE {
             println!("Failing because distance queue is too small");
             return Err(());
-        };
-        let Some(time_queue) = time_queue.last_chunk_mut::<LR_SIZE>() else{ return Err(()); };
+        }
+        distance_queue.reverse();
+        time_queue.reverse();
+        let latency_mean = Self::passes_latency_assumptions(&time_queue)?;
+        Ok((latency_mean, distance_queue, time_queue))
+    }
+
+    //Check that the given (newest) sample times are fresh and close enough together, returning the mean gap between them
+    pub(crate) fn passes_latency_assumptions(time_queue: &[Instant]) -> Result<f64, ()> {
         //Our data must be relatively new (cannot be stale)
         if Instant::now().duration_since(*time_queue.first().unwrap()).as_millis() as u64 > MAX_LR_LATENCY_MS{
             println!("Failing because latency is too big: {}", Instant::now().duration_since(*time_queue.first().unwrap()).as_millis());
@@ -63,25 +99,99 @@ impl QuadraticRegression{
         if latency_mean > MAX_LATENCY_MS as f64{
             return Err(());
         }
-        return Ok((latency_mean, distance_queue.to_vec(), time_queue.to_vec()));
+        Ok(latency_mean)
     }
 }
 
 impl BrainPredictor for QuadraticRegression {
-    fn predict(&self, distances: &Vec<Result<u64, OCTError>>, times: &Vec<Instant>, print_coefs: bool) -> Option<impl Fn(f64) -> f64>{
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (distances, times) = (window.distances(), window.times());
         let Ok((__, distance_queue, time_queue)) = Self::passes_predict_assumptions(distances, times) else {
             return None
         };
         let Some(coefs) = Self::regress(&distance_queue, &time_queue) else {
             return None;
         };
-        if print_coefs{
-            println!("Coefs: {:?}", coefs);
+        let r_squared = Self::weighted_r_squared(&distance_queue, &time_queue, &vec![1.0; distance_queue.len()], &coefs);
+        if let Some(sink) = coef_sink {
+            sink(&coefs, Some(r_squared));
         }
         //Return the function of relative brain position wrt time
-        return Some( move |x: f64|{
+        return Some(( move |x: f64|{
             //x += OCT_LATENCY_MS as f64;
             coefs[0] + coefs[1]*x + coefs[2]*x*x
-        });
+        }, r_squared));
+    }
+
+    fn predict_kinematics(&self, window: &DistanceWindow) -> Option<Kinematics>{
+        let (distances, times) = (window.distances(), window.times());
+        let (_, distance_queue, time_queue) = Self::passes_predict_assumptions(distances, times).ok()?;
+        let coefs = Self::regress(&distance_queue, &time_queue)?;
+        Some(Kinematics{ position: coefs[0], velocity: coefs[1], acceleration: 2.0 * coefs[2] })
     }
-}
\ No newline at end of file
+
+    //We walk back over errors, but the gap they leave among the newest LR_SIZE samples throws off the latency check
+    fn awaiting_clean_samples(&self, window: &DistanceWindow) -> bool{
+        window.tail(LR_SIZE).distances().iter().any(|distance| distance.is_err())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    //The fitted coefficients of an exact parabola reach the sink, curvature last, along with a perfect R^2
+    #[test]
+    fn test_coef_sink_captures_curvature() {
+        let now = Instant::now();
+        let times = (0..LR_SIZE as u64).rev().map(|i| now - Duration::from_millis(i * 5)).collect::<Vec<Instant>>();
+        let distances = times.iter().map(|time| {
+            let ms = time.duration_since(times[0]).as_millis() as u64;
+            Ok(1_000_000 + 2_000 * ms + 30 * ms * ms)
+        }).collect::<Vec<Result<u64, OCTError>>>();
+        let captured = std::cell::RefCell::new(Vec::new());
+        let sink = |coefs: &[f64], r_squared: Option<f64>| captured.borrow_mut().push((coefs.to_vec(), r_squared));
+        assert!(QuadraticRegression{}.predict(&DistanceWindow::new(&distances, &times), Some(&sink)).is_some());
+        let captured = captured.into_inner();
+        assert!(captured.len() == 1);
+        let (coefs, r_squared) = &captured[0];
+        assert!((coefs[2] - 30.0).abs() < 1e-3, "Expected a curvature of 30 but got {:?}", coefs);
+        assert!(r_squared.is_some_and(|r_squared| (r_squared - 1.0).abs() < 1e-6), "Expected an R^2 of 1 but got {:?}", r_squared);
+    }
+
+    //Two samples stamped at the same instant are rejected rather than regressed over
+    #[test]
+    fn test_duplicate_timestamps_are_rejected() {
+        let now = Instant::now();
+        let distances = (0..LR_SIZE as u64).map(|i| Ok(1_000_000 + i * 1_000)).collect::<Vec<Result<u64, OCTError>>>();
+        let mut times = (0..LR_SIZE as u64).rev().map(|i| now - Duration::from_millis(i * 5)).collect::<Vec<Instant>>();
+        assert!(QuadraticRegression{}.predict(&DistanceWindow::new(&distances, &times), None).is_some());
+        times[LR_SIZE - 2] = times[LR_SIZE - 1];
+        assert!(QuadraticRegression{}.predict(&DistanceWindow::new(&distances, &times), None).is_none());
+    }
+
+    //A coincident sample is dropped on its own, and the older samples before it fill the window back up
+    #[test]
+    fn test_duplicate_timestamp_is_dropped_from_a_longer_window() {
+        let now = Instant::now();
+        let distances = (0..LR_SIZE as u64 + 1).map(|i| Ok(1_000_000 + i * 1_000)).collect::<Vec<Result<u64, OCTError>>>();
+        let mut times = (0..LR_SIZE as u64 + 1).rev().map(|i| now - Duration::from_millis(i * 5)).collect::<Vec<Instant>>();
+        times[LR_SIZE - 1] = times[LR_SIZE];
+        let (forecast, _) = QuadraticRegression{}.predict(&DistanceWindow::new(&distances, &times), None).unwrap();
+        assert!((forecast(0.0) - (1_000_000.0 + LR_SIZE as f64 * 1_000.0)).abs() < 1e-3, "Unexpected forecast: {}", forecast(0.0));
+    }
+
+    //Samples of the parabola 1mm - 200x + 3x^2, where x is ms after the newest sample
+    #[test]
+    fn test_kinematics_of_parabola() {
+        let now = Instant::now();
+        let parabola = |x: f64| 1_000_000.0 - 200.0 * x + 3.0 * x * x;
+        let times = (0..LR_SIZE as u64).rev().map(|i| now - Duration::from_millis(i * 5)).collect::<Vec<Instant>>();
+        let distances = (0..LR_SIZE as u64).rev().map(|i| Ok(parabola(-5.0 * i as f64) as u64)).collect::<Vec<Result<u64, OCTError>>>();
+        let kinematics = QuadraticRegression{}.predict_kinematics(&DistanceWindow::new(&distances, &times)).unwrap();
+        assert!((kinematics.position - 1_000_000.0).abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.velocity + 200.0).abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.acceleration - 2.0 * 3.0).abs() < 1e-3, "Unexpected kinematics: {:?}", kinematics);
+    }
+}
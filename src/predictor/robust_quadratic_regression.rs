@@ -0,0 +1,98 @@
+use tokio::time::Instant;
+use crate::predictor::{BrainPredictor, CoefSink, DistanceWindow};
+use crate::predictor::quadratic_regression::QuadraticRegression;
+
+//Residual (in nm) above which a sample starts being down-weighted
+const HUBER_THRESHOLD_NM: f64 = 2_000.0;
+//Number of reweighting passes after the initial least squares fit
+const IRLS_ITERATIONS: u64 = 10;
+
+//Same quadratic model as QuadraticRegression, but fit with iteratively reweighted least squares
+//using Huber weights. A single bad OCT reading gets a weight of roughly threshold/residual
+//instead of pulling the whole fit towards it, so it no longer trips is_abnormal_distance by itself.
+pub struct RobustQuadraticRegression{
+    pub huber_threshold_nm: f64,
+    pub iterations: u64,
+}
+
+impl Default for RobustQuadraticRegression{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl RobustQuadraticRegression{
+    pub fn new() -> RobustQuadraticRegression{
+        RobustQuadraticRegression{
+            huber_threshold_nm: HUBER_THRESHOLD_NM,
+            iterations: IRLS_ITERATIONS,
+        }
+    }
+
+    fn huber_weight(&self, residual: f64) -> f64{
+        if residual.abs() <= self.huber_threshold_nm {
+            return 1.0;
+        }
+        self.huber_threshold_nm / residual.abs()
+    }
+
+    //Returns the coefficients along with the final weight of each sample
+    fn regress(&self, distance_queue: &[u64], time_queue: &[Instant]) -> Option<(Vec<f64>, Vec<f64>)>{
+        let comp_time = *time_queue.last().unwrap();
+        let mut weights = vec![1.0; distance_queue.len()];
+        let mut coefs = QuadraticRegression::weighted_regress(distance_queue, time_queue, &weights)?;
+        for _ in 0..self.iterations {
+            //Reweight every sample by how far it sits from the current fit
+            for i in 0..distance_queue.len(){
+                let time = comp_time.duration_since(time_queue[i]).as_millis() as f64;
+                let fitted = coefs[0] - coefs[1]*time + coefs[2]*time*time;
+                weights[i] = self.huber_weight(distance_queue[i] as f64 - fitted);
+            }
+            coefs = QuadraticRegression::weighted_regress(distance_queue, time_queue, &weights)?;
+        }
+        Some((coefs, weights))
+    }
+}
+
+impl BrainPredictor for RobustQuadraticRegression {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (distances, times) = (window.distances(), window.times());
+        let Ok((_, distance_queue, time_queue)) = QuadraticRegression::passes_predict_assumptions(distances, times) else {
+            return None
+        };
+        let (coefs, weights) = self.regress(&distance_queue, &time_queue)?;
+        //Judge the fit the same way it was made, so a rejected outlier doesn't count against it
+        let r_squared = QuadraticRegression::weighted_r_squared(&distance_queue, &time_queue, &weights, &coefs);
+        if let Some(sink) = coef_sink {
+            sink(&coefs, Some(r_squared));
+        }
+        //Return the function of relative brain position wrt time
+        Some(( move |x: f64|{
+            coefs[0] + coefs[1]*x + coefs[2]*x*x
+        }, r_squared))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    // Five samples of y = 1_000_000 + 100*age + 2*age^2 taken 5ms apart, plus one gross outlier
+    #[test]
+    fn test_robust_regression_rejects_outlier() {
+        let start = Instant::now();
+        let times = (0..6).map(|i| start + Duration::from_millis(5 * i)).collect::<Vec<Instant>>();
+        let clean = |age: f64| 1_000_000.0 + 100.0 * age + 2.0 * age * age;
+        let mut distances = times.iter().map(|t| clean(times[5].duration_since(*t).as_millis() as f64) as u64).collect::<Vec<u64>>();
+        let clean_coefs = QuadraticRegression::weighted_regress(&distances, &times, &[1.0; 6]).unwrap();
+        distances[2] += 500_000;
+
+        let ols_coefs = QuadraticRegression::weighted_regress(&distances, &times, &[1.0; 6]).unwrap();
+        let (robust_coefs, _) = RobustQuadraticRegression::new().regress(&distances, &times).unwrap();
+        //Compare the fits where it matters: the predicted position 20ms into the future
+        let at = |c: &[f64], x: f64| c[0] + c[1]*x + c[2]*x*x;
+        assert!((at(&robust_coefs, 20.0) - at(&clean_coefs, 20.0)).abs() < 20_000.0, "Robust fit was off: {:?} vs {:?}", robust_coefs, clean_coefs);
+        assert!((at(&ols_coefs, 20.0) - at(&clean_coefs, 20.0)).abs() > 100_000.0, "OLS fit was not skewed: {:?} vs {:?}", ols_coefs, clean_coefs);
+    }
+}
@@ -1,6 +1,6 @@
 use tokio::time::Instant;
 use crate::interface::OCTError;
-use crate::predictor::BrainPredictor;
+use crate::predictor::{skip_coincident, BrainPredictor, CoefSink, DistanceWindow, Kinematics};
 const MAX_LATENCY_MS: u64 = 18;
 const MAX_LATENCY_STD_MS: u64 = 3;
 const TAYLOR_POLY_ORDER: u64 = 2; 
@@ -8,38 +8,57 @@ const TAYLOR_POLY_ORDER: u64 = 2;
 pub struct TaylorQuadraticApproximator;
 
 impl TaylorQuadraticApproximator{
-    fn _get_taylor_coefs(data: &Vec<u64>, n: u64, latency: f64) -> Vec<f64>{
-        assert!(n > 0 && n <= data.len() as u64);
-        let mut current = data.iter().map(|&x| x as f64).collect::<Vec<f64>>();
-        let mut coefs = Vec::new();
-        coefs.push(current[current.len() - 1] as f64);
-        let mut factorial = 1;
-
-        // Apply the backward difference n times
-        for i in 0..n {
-            // Compute the backward difference: Δf(x) = f(x) - f(x-1)
-            factorial *= i+1;
-            let next = current
-                .windows(2)
-                .map(|w| (w[1] - w[0]) as f64/ latency as f64) // (w[1] - w[0])
+    //Coefficients of the order n polynomial through the newest n+1 samples, in powers of the time (in ms) since
+    //the newest one, lowest order first. These are the Taylor coefficients around the newest sample.
+    //Newton's divided differences divide each difference by the actual gap between its samples, so they stay
+    //exact however unevenly the samples are spaced
+    fn _get_taylor_coefs(data: &[u64], times: &[Instant], n: u64) -> Vec<f64>{
+        assert!(n > 0 && n < data.len() as u64 && data.len() == times.len());
+        let newest = times[times.len() - 1];
+        //Nodes newest first, so the Newton form is anchored at the newest sample
+        let nodes = times.iter().rev().take(n as usize + 1)
+            .map(|time| -(newest.duration_since(*time).as_secs_f64() * 1000.0))
+            .collect::<Vec<f64>>();
+        let mut current = data.iter().rev().take(n as usize + 1).map(|&x| x as f64).collect::<Vec<f64>>();
+        // f[z0], f[z0, z1], ..., f[z0, ..., zn]
+        let mut newton = vec![current[0]];
+        for order in 1..=n as usize {
+            current = current.windows(2).enumerate()
+                .map(|(i, w)| (w[0] - w[1]) / (nodes[i] - nodes[i + order]))
                 .collect::<Vec<f64>>();
-            coefs.push(next[next.len() - 1] as f64 / factorial as f64);
-            current = next;
+            newton.push(current[0]);
+        }
+        //Expand the Newton form c0 + c1(x - z0) + c2(x - z0)(x - z1) + ... into powers of x
+        let mut coefs = vec![newton[n as usize]];
+        for k in (0..n as usize).rev() {
+            //coefs * (x - z_k) + c_k
+            let mut next = vec![0.0; coefs.len() + 1];
+            for (power, coef) in coefs.iter().enumerate() {
+                next[power + 1] += coef;
+                next[power] -= coef * nodes[k];
+            }
+            next[0] += newton[k];
+            coefs = next;
         }
-        return coefs;
+        coefs
     }
 
-    fn passes_predict_assumptions(distance_queue: &Vec<Result<u64, OCTError>>, time_queue: &Vec<Instant>) -> Result<(f64, f64, Vec<u64>, Vec<Instant>), ()> {
+    fn passes_predict_assumptions(distance_queue: &[Result<u64, OCTError>], time_queue: &[Instant]) -> Result<(f64, f64, Vec<u64>, Vec<Instant>), ()> {
         const data_len: usize = TAYLOR_POLY_ORDER as usize+1;
         //We must have enough data to do a Taylor approximation
         if distance_queue.len() < data_len{
             println!("Failing because distance queue is too small");
             return Err(());
         }
-        let mut distance_queue = Vec::from(distance_queue.clone());
-        let Some(distance_queue) = distance_queue.last_chunk_mut::<data_len>() else {return Err(()); };
-        let mut time_queue = Vec::from(time_queue.clone());
-        let Some(time_queue) = time_queue.last_chunk_mut::<data_len>() else{ return Err(()); };
+        //Walk back from the newest sample, dropping any that coincide with a newer one
+        let (mut distance_queue, mut time_queue): (Vec<Result<u64, OCTError>>, Vec<Instant>) = skip_coincident(distance_queue.iter().cloned().zip(time_queue.iter().cloned()).rev())
+            .take(data_len)
+            .unzip();
+        if distance_queue.len() < data_len{
+            return Err(());
+        }
+        distance_queue.reverse();
+        time_queue.reverse();
         //Our data must be relatively new (cannot be stale)
         if Instant::now().duration_since(time_queue[time_queue.len()-1]).as_millis() as u64 > MAX_LATENCY_MS{
             println!("Failing because latency is too big: {}", Instant::now().duration_since(time_queue[time_queue.len()-1]).as_millis());
@@ -60,23 +79,95 @@ impl TaylorQuadraticApproximator{
         if distance_queue.len() < data_len{
             return Err(());
         }
-        return Ok((latency_mean, latency_std, distance_queue, Vec::from(time_queue)));
+        return Ok((latency_mean, latency_std, distance_queue, time_queue));
     }
 }
 
 impl BrainPredictor for TaylorQuadraticApproximator {
-    fn predict(&self, distances: &Vec<Result<u64, OCTError>>, times: &Vec<Instant>, print_coefs: bool) -> Option<impl Fn(f64) -> f64>{
-        let Ok((latency_mean, _, distance_queue, __)) = Self::passes_predict_assumptions(distances, times) else {
+    fn predict(&self, window: &DistanceWindow, coef_sink: CoefSink) -> Option<(impl Fn(f64) -> f64 + Send + Sync + 'static, f64)>{
+        let (distances, times) = (window.distances(), window.times());
+        let Ok((_, _, distance_queue, time_queue)) = Self::passes_predict_assumptions(distances, times) else {
             return None
         };
-        let coefs = Self::_get_taylor_coefs(&distance_queue, TAYLOR_POLY_ORDER, latency_mean);
-        if print_coefs{
-            println!("Coefs: {:?}", coefs);
+        let coefs = Self::_get_taylor_coefs(&distance_queue, &time_queue, TAYLOR_POLY_ORDER);
+        if let Some(sink) = coef_sink {
+            sink(&coefs, None);
         }
         //Return the function of relative brain position wrt time
-        return Some( move |x: f64|{
+        //The Taylor coefficients interpolate the data exactly, so we have no residuals to judge the fit by
+        return Some(( move |x: f64|{
             //x += OCT_LATENCY_MS as f64;
             coefs[0] + coefs[1]*x + coefs[2]*x*x
-        });
+        }, 1.0));
+    }
+
+    fn predict_kinematics(&self, window: &DistanceWindow) -> Option<Kinematics>{
+        let (distances, times) = (window.distances(), window.times());
+        let (_, _, distance_queue, time_queue) = Self::passes_predict_assumptions(distances, times).ok()?;
+        let coefs = Self::_get_taylor_coefs(&distance_queue, &time_queue, TAYLOR_POLY_ORDER);
+        Some(Kinematics{ position: coefs[0], velocity: coefs[1], acceleration: 2.0 * coefs[2] })
+    }
+
+    //The coefficients are only taken from the newest samples, so an error among them leaves us without any
+    fn awaiting_clean_samples(&self, window: &DistanceWindow) -> bool{
+        window.tail(TAYLOR_POLY_ORDER as usize + 1).distances().iter().any(|distance| distance.is_err())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    //Identical timestamps give a mean latency of 0, which would make every coefficient NaN
+    #[test]
+    fn test_duplicate_timestamps_are_rejected() {
+        let now = Instant::now();
+        let distances = vec![Ok(1_000_000), Ok(1_001_000), Ok(1_002_000)];
+        assert!(TaylorQuadraticApproximator{}.predict(&DistanceWindow::new(&distances, &[now - Duration::from_millis(10), now - Duration::from_millis(5), now]), None).is_some());
+        assert!(TaylorQuadraticApproximator{}.predict(&DistanceWindow::new(&distances, &[now, now, now]), None).is_none());
+        assert!(TaylorQuadraticApproximator{}.predict(&DistanceWindow::new(&distances, &[now - Duration::from_millis(3), now, now]), None).is_none());
+    }
+
+    //Only the coincident sample is dropped, so an older sample can take its place
+    #[test]
+    fn test_duplicate_timestamp_is_dropped_from_a_longer_window() {
+        let now = Instant::now();
+        let distances = vec![Ok(1_000_000), Ok(1_001_000), Ok(1_002_000), Ok(1_002_000)];
+        let times = [now - Duration::from_millis(10), now - Duration::from_millis(5), now, now];
+        let (forecast, _) = TaylorQuadraticApproximator{}.predict(&DistanceWindow::new(&distances, &times), None).unwrap();
+        assert!((forecast(0.0) - 1_002_000.0).abs() < 1e-3, "Unexpected forecast: {}", forecast(0.0));
+    }
+
+    //The second backward difference of a parabola is exact, so its acceleration is too
+    #[test]
+    fn test_kinematics_of_parabola() {
+        let now = Instant::now();
+        let parabola = |x: f64| 1_000_000.0 - 200.0 * x + 3.0 * x * x;
+        let times = [now - Duration::from_millis(10), now - Duration::from_millis(5), now];
+        let distances = [Ok(parabola(-10.0) as u64), Ok(parabola(-5.0) as u64), Ok(parabola(0.0) as u64)];
+        let kinematics = TaylorQuadraticApproximator{}.predict_kinematics(&DistanceWindow::new(&distances, &times)).unwrap();
+        assert!(kinematics.position == 1_000_000.0, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.acceleration - 2.0 * 3.0).abs() < 1e-9, "Unexpected kinematics: {:?}", kinematics);
     }
-}
\ No newline at end of file
+
+    //A parabola sampled 11ms and then 6ms apart. Dividing every difference by the 8.5ms mean gap would get its
+    //velocity and acceleration wrong, the divided differences recover it exactly
+    #[test]
+    fn test_coefs_of_irregularly_sampled_parabola() {
+        let now = Instant::now();
+        let parabola = |x: f64| 1_000_000.0 - 200.0 * x + 3.0 * x * x;
+        let offsets = [-17.0, -6.0, 0.0];
+        let times = offsets.iter().map(|x: &f64| now - Duration::from_millis(-x as u64)).collect::<Vec<Instant>>();
+        let data = offsets.iter().map(|x| parabola(*x) as u64).collect::<Vec<u64>>();
+        let coefs = TaylorQuadraticApproximator::_get_taylor_coefs(&data, &times, TAYLOR_POLY_ORDER);
+        for (coef, expected) in coefs.iter().zip([1_000_000.0, -200.0, 3.0]) {
+            assert!((coef - expected).abs() < 1e-6, "Expected {} but got coefficients {:?}", expected, coefs);
+        }
+
+        let distances = data.iter().map(|distance| Ok(*distance)).collect::<Vec<Result<u64, OCTError>>>();
+        let kinematics = TaylorQuadraticApproximator{}.predict_kinematics(&DistanceWindow::new(&distances, &times)).unwrap();
+        assert!((kinematics.velocity + 200.0).abs() < 1e-6, "Unexpected kinematics: {:?}", kinematics);
+        assert!((kinematics.acceleration - 2.0 * 3.0).abs() < 1e-6, "Unexpected kinematics: {:?}", kinematics);
+    }
+}
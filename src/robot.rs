@@ -1,32 +1,216 @@
-use crate::interface::{Move, RobotError, OCTError, RobotState};
-use rand::Rng;
-use tokio::time::{sleep, Duration, Instant};
+use crate::interface::{Move, RobotError, OCTError, RobotState, Robot, OCTService, RobotLimits};
+use crate::interface::{DistanceRequest, StateRequest, MoveRequest, MovePriority};
+use std::collections::VecDeque;
+use crate::motion;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
 use tokio::sync::Mutex;
 use std::sync::Arc;
-use tokio::sync::{oneshot,mpsc};
+use tokio::sync::mpsc;
 
+//Default motion and error parameters, overridable through RobotArmBuilder
 const NEEDLE_ACCELERATION_NM_MS: i64 = 250;     // nm/ms² (for needle)
 const NEEDLE_VELOCITY_NM_MS: u64 = 250_000;     // nm/ms (for needle)
 const INSERTER_VELOCITY_NM_MS: u64 = 9_500;    // nm/ms (for inserter arm)
 const PROBABILITY_OF_ERROR: f64 = 0.1;
+//Fraction of state read errors that report a position error instead of a lost connection
+const POSITION_ERROR_FRACTION: f64 = 0.0001;
+//How often the state is sampled into the trajectory while a move is in progress
+const TRAJECTORY_SAMPLE_MILLIS: u64 = 5;
+//Most trajectory samples kept unless `RobotArmBuilder::trajectory_cap` overrides it, about 8 minutes of motion
+const TRAJECTORY_CAP: usize = 100_000;
+/// Mean time (in ms) the OCT takes to answer a distance read, unless `RobotArmBuilder::oct_latency_ms` overrides it.
+pub const OCT_LATENCY_MILLIS: u64 = 15;
+//Period of the brain's shaking during a seizure
+const SEIZURE_PERIOD_MILLIS: f64 = 40.0;
+/// Where the default brain motion is centred below the inserter's origin, in nm.
+pub const BRAIN_BASELINE_NM: u64 = 7_000_000;
+//Furthest the default brain motion swings either side of its baseline
+const BRAIN_MOTION_AMPLITUDE_NM: u64 = 1_500_000;
+
+/// Variation of the OCT read latency around its mean, sampled independently for every read.
+/// Latencies that would come out negative are clamped to 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OCTJitter {
+    None,
+    //Uniform in [mean - max_ms, mean + max_ms]
+    Uniform { max_ms: u64 },
+    //Normal around the mean
+    Gaussian { std_ms: f64 },
+}
+
+/// How OCT read errors are spread over the session when `distance_errors` is set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OCTDropouts {
+    //Every read fails with error_probability, independently of the others
+    Independent,
+    //Reads fail in bursts. After a good read the next one fails with enter_probability, after a failed read
+    //with stay_probability. Setting both to error_probability is the same as Independent
+    Markov { enter_probability: f64, stay_probability: f64 },
+}
+
+/// Slowly drifting offset added to the brain's distance from the inserter, like the zero offset of a real
+/// OCT drifting. It is applied to the brain position everywhere, so distance reads and the brain distances
+/// recorded by moves stay consistent, and calibration has to absorb it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OCTDrift {
+    None,
+    //Grows steadily from 0 at the rate given. Negative rates bring the brain closer
+    Linear { nm_per_s: f64 },
+    //Swings around 0
+    Sinusoid { amplitude_nm: f64, period_ms: f64 },
+}
+
+/// How far the needle pushes the brain surface away as it goes in, given how deep (in nm) the needle tip is
+/// past where the surface would be without it. The brain springs back once the needle is out. Dimpling moves
+/// the brain position everywhere, so it shows in the OCT reads and in the brain distances recorded by moves.
+/// The displacement has to stay below the penetration, the needle always ends up in the brain.
+#[derive(Debug, Clone, Copy)]
+pub enum Dimpling {
+    None,
+    //The surface gives way by this fraction of the penetration
+    Proportional { fraction: f64 },
+    Custom(fn(u64) -> u64),
+}
+
+impl Dimpling {
+    fn displacement_nm(&self, penetration_nm: u64) -> u64 {
+        match *self {
+            Dimpling::None => 0,
+            Dimpling::Proportional { fraction } => (fraction * penetration_nm as f64) as u64,
+            Dimpling::Custom(displacement) => displacement(penetration_nm),
+        }
+    }
+}
+
+/// A scheduled burst of abnormal brain motion. For `duration_ms` from `start_ms` after the robot started, the
+/// brain shakes by up to `amplitude_nm` on top of `brain_location_fn`, far faster than any predictor follows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BrainSeizure {
+    pub start_ms: u64,
+    pub duration_ms: u64,
+    pub amplitude_nm: f64,
+}
+
+impl BrainSeizure {
+    //Offset the seizure adds to the brain position at elapsed_ms, 0 outside its window
+    fn offset_nm(&self, elapsed_ms: u64) -> f64 {
+        if elapsed_ms < self.start_ms || elapsed_ms >= self.start_ms + self.duration_ms {
+            return 0.0;
+        }
+        let since_start_ms = (elapsed_ms - self.start_ms) as f64;
+        self.amplitude_nm * (2.0 * std::f64::consts::PI * since_start_ms / SEIZURE_PERIOD_MILLIS).sin()
+    }
+}
+
+/// Velocity and acceleration of both axes through one move, numerically differentiated from the arm's
+/// interpolated position as sampled every `RobotArmBuilder::kinematics_sample_ms`.
+///  - move_cmd: the move they were sampled during
+///  - samples: one for every sample between the move's start and where it ended, oldest first
+#[derive(Debug, Clone)]
+pub struct MoveKinematics {
+    pub move_cmd: Move,
+    pub samples: Vec<KinematicsSample>,
+}
+
+/// One sample of `MoveKinematics`, with velocities in nm/ms and accelerations in nm/ms², positive away from
+/// the origin. The velocity is a central difference over the samples either side, as is the acceleration.
+///  - elapsed_ms: time since the move started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KinematicsSample {
+    pub elapsed_ms: f64,
+    pub needle_velocity_nm_ms: f64,
+    pub needle_accel_nm_ms2: f64,
+    pub inserter_velocity_nm_ms: f64,
+    pub inserter_accel_nm_ms2: f64,
+}
+
+/// What the robot is doing at one instant, see `RobotArm::snapshot`.
+///  - elapsed_ms: time since the robot started
+///  - state: where both axes are, interpolated through the move in progress
+///  - is_moving: whether a move is in progress
+///  - progress: fraction of the move in progress that has elapsed, 0 when not moving
+///  - target: where the move in progress ends up, None when not moving. A move that is going to fail ends
+///    short of its commanded target, and this is where it stops
+///  - move_duration_ms: how long the move in progress takes as a whole, 0 when not moving
+///  - error_scheduled: whether the move in progress is going to fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RobotSnapshot {
+    pub elapsed_ms: u64,
+    pub state: RobotState,
+    pub is_moving: bool,
+    pub progress: f64,
+    pub target: Option<RobotState>,
+    pub move_duration_ms: u64,
+    pub error_scheduled: bool,
+}
+
+//One axis' part of a move in progress: where it started, where it ends up and how long it takes to get there
+#[derive(Debug, Clone, Copy)]
+struct AxisMove {
+    start_z: u64,
+    target_z: u64,
+    duration: Duration,
+    //Cap on the axis' velocity for this move, below its usual velocity
+    max_velocity: Option<u64>,
+}
 
 pub struct RobotArm {
     pub distance_errors: bool,
     pub state_errors: bool,
     pub move_errors: bool,
     pub brain_location_fn: fn(u64) -> u64,
+    //Where the brain rests, brain_location_fn is shifted from BRAIN_BASELINE_NM to it
+    brain_baseline_nm: u64,
+    needle_velocity_nm_ms: u64,
+    inserter_velocity_nm_ms: u64,
+    //None moves the inserter at constant velocity, starting and stopping instantly
+    inserter_accel_nm_ms2: Option<i64>,
+    needle_accel_nm_ms2: i64,
+    needle_retract_velocity_nm_ms: u64,
+    needle_retract_accel_nm_ms2: i64,
+    error_probability: f64,
+    max_needle_z_nm: u64,
+    max_inserter_z_nm: u64,
+    grasp_error_probability: f64,
+    oct_latency_ms: u64,
+    oct_jitter: OCTJitter,
+    oct_drift: OCTDrift,
+    oct_dropouts: OCTDropouts,
+    //Whether the last OCT read failed, which the next one depends on under Markov dropouts
+    oct_failing: bool,
+    //Resolution of the OCT's distance reads, None reads to the nm
+    quantization_nm: Option<u64>,
+    seizures: Vec<BrainSeizure>,
+    dimpling: Dimpling,
     init_time: Instant,
     state: RobotState,
     is_moving: bool,
     last_move_time: Option<Instant>,
     last_move: Option<Move>,
+    //How many moves have been started, so a move can tell that another one has taken over from it
+    moves_started: u64,
     total_move_duration: Duration,
-    start_z: u64,
-    target_z: u64,
-    is_inserter_move: bool,
-    is_needle_move: bool,
+    //The axes the move in progress moves, a Both move moves the two at once
+    inserter_move: Option<AxisMove>,
+    needle_move: Option<AxisMove>,
     error_scheduled: bool,
     pub brain_distances: Vec<u64>,
+    //Whether the last brain distance belongs to the insertion in progress
+    insertion_recorded: bool,
+    trajectory: VecDeque<(u64, RobotState)>, // (elapsed ms since init, state)
+    trajectory_cap: usize,
+    //How often the state is sampled for the move's kinematics, None doesn't sample it
+    kinematics_sample_ms: Option<u64>,
+    kinematics_positions: Vec<(f64, RobotState)>, // (elapsed ms since the move started, state) of the move in progress
+    //Held to trajectory_cap samples in all, like the trajectory
+    move_kinematics: VecDeque<MoveKinematics>,
+    /// The seed every random draw of the robot comes from, so a session can be repeated with it.
+    pub seed: u64,
+    //Source of every random error, partial move and latency, seeded for reproducible sessions
+    rng: StdRng,
 }
 
 impl RobotArm {
@@ -36,127 +220,224 @@ impl RobotArm {
     ///
     /// The `move_errors` flag indicates whether or not a move should fail to actually move the robot. If this flag is set,
     /// the robot will instead move to a position that is 20% of the way to the target position.
+    ///
+    /// All other parameters take their defaults, use `RobotArmBuilder` to change them.
     pub fn new(initial_z: u64, distance_errors: bool, move_errors: bool) -> RobotArm {
-        RobotArm {
-            distance_errors,
-            state_errors: false,
-            move_errors,
-            init_time: Instant::now(),
-            //Arbitrary function to mock brains location
-            brain_location_fn: |x: u64| {
-                (7_000_000.0
-                    + 500_000.0 * (6.0 * x as f64/1000.0).sin()
-                    + 1_000_000.0 * (x as f64/1000.0).sin()) as u64
-            },
-            state: RobotState {
-                inserter_z: initial_z,
-                needle_z: 0,
-            },
-            is_moving: false,
-            last_move_time: None,
-            last_move: None,
-            total_move_duration: Duration::from_millis(0),
-            start_z: initial_z,
-            target_z: initial_z,
-            is_inserter_move: false,
-            is_needle_move: false,
-            error_scheduled: false,
-            brain_distances: Vec::new(),
-        }
+        RobotArmBuilder::new()
+            .initial_z(initial_z)
+            .distance_errors(distance_errors)
+            .move_errors(move_errors)
+            .build()
     }
 
-    /// Calculate total move time for needle moves using a trapezoidal profile.
-    /// Inserter moves are handled separately.
-    fn calculate_needlez_move_time(distance_nm: i64) -> Duration {
-        let a = NEEDLE_ACCELERATION_NM_MS as f64;
-        let v = NEEDLE_VELOCITY_NM_MS as f64;
-        let d = distance_nm.abs() as f64;
-        let d_min = v * v / a;
-
-        let total_time_ms = if d < d_min {
-            2.0*(d / a).sqrt()
+    //Needle (velocity, acceleration) for a move from start_z to target_z. Moves towards 0 are retractions.
+    //A move's max_velocity caps the velocity, the acceleration is unchanged
+    fn needle_motion(&self, start_z: i64, target_z: i64, max_velocity: Option<u64>) -> (u64, i64) {
+        let (velocity, accel) = if target_z < start_z {
+            (self.needle_retract_velocity_nm_ms, self.needle_retract_accel_nm_ms2)
         } else {
-            let t_accel = v / a;
-            let d_accel = 0.5 * a * t_accel * t_accel;
-            let d_cruise = d - 2.0 * d_accel;
-            let t_cruise = d_cruise / v;
-            t_accel + t_cruise + t_accel
+            (self.needle_velocity_nm_ms, self.needle_accel_nm_ms2)
+        };
+        (max_velocity.map_or(velocity, |max_velocity| velocity.min(max_velocity)), accel)
+    }
+
+    fn calculate_needlez_move_time(&self, start_z: i64, target_z: i64, max_velocity: Option<u64>) -> Duration {
+        let (velocity, accel) = self.needle_motion(start_z, target_z, max_velocity);
+        motion::calculate_needlez_move_time(target_z - start_z, velocity, accel)
+    }
+
+    fn interpolate_needlez_position(&self, start_z: i64, target_z: i64, elapsed: Duration, total: Duration, max_velocity: Option<u64>) -> i64 {
+        let (velocity, accel) = self.needle_motion(start_z, target_z, max_velocity);
+        motion::interpolate_needlez_position(start_z, target_z, elapsed, total, velocity, accel)
+    }
+
+    fn calculate_inserter_move_time(&self, distance_nm: i64) -> Duration {
+        motion::calculate_inserter_move_time(distance_nm, self.inserter_velocity_nm_ms, self.inserter_accel_nm_ms2)
+    }
+
+    /// Returns the furthest each axis is allowed to travel.
+    pub fn limits(&self) -> RobotLimits {
+        RobotLimits { inserter_z_max: self.max_inserter_z_nm, needle_z_max: self.max_needle_z_nm }
+    }
+
+    /// Returns the time `brain_location_fn` is measured from.
+    pub fn get_init_time(&self) -> Instant {
+        self.init_time
+    }
+
+    /// Where `brain_location_fn` puts the brain `elapsed_ms` after the robot started, once shifted to the
+    /// robot's brain baseline. Drift, seizures and dimpling come on top of it.
+    pub fn brain_location(&self, elapsed_ms: u64) -> u64 {
+        ((self.brain_location_fn)(elapsed_ms) + self.brain_baseline_nm).saturating_sub(BRAIN_BASELINE_NM)
+    }
+
+    /// Milliseconds elapsed since the robot started, which its errors are stamped with.
+    fn elapsed_ms(&self) -> u64 {
+        self.init_time.elapsed().as_millis() as u64
+    }
+
+    /// Returns the brain's current position, including any drift, seizures and dimpling by the needle.
+    fn brain_position(&self) -> u64 {
+        let elapsed_ms = self.elapsed_ms();
+        let drift = match self.oct_drift {
+            OCTDrift::None => 0.0,
+            OCTDrift::Linear { nm_per_s } => nm_per_s * elapsed_ms as f64 / 1000.0,
+            OCTDrift::Sinusoid { amplitude_nm, period_ms } => amplitude_nm * (2.0 * std::f64::consts::PI * elapsed_ms as f64 / period_ms).sin(),
         };
+        let seizure: f64 = self.seizures.iter().map(|seizure| seizure.offset_nm(elapsed_ms)).sum();
+        let undimpled = (self.brain_location(elapsed_ms) as f64 + drift + seizure).max(0.0) as u64;
+        let state = self._get_state().unwrap();
+        let penetration = (state.inserter_z + state.needle_z).saturating_sub(undimpled);
+        undimpled + self.dimpling.displacement_nm(penetration)
+    }
 
-        Duration::from_millis(total_time_ms as u64)
-    }
-
-    /// Interpolate needle moves using trapezoidal profile.
-    fn interpolate_needlez_position(
-        start_z: i64,
-        target_z: i64,
-        elapsed: Duration,
-        total: Duration,
-    ) -> i64 {
-        let a = NEEDLE_ACCELERATION_NM_MS as f64;
-        let v = NEEDLE_VELOCITY_NM_MS as f64;
-        let d = (target_z - start_z) as f64;
-        let direction = if target_z >= start_z { 1.0 } else { -1.0 };
-
-        let t = elapsed.as_millis() as f64;
-        let total_t = total.as_millis() as f64;
-        let d_min = 2.0 * (v * v / a);
-
-        if t >= total_t {
-            return target_z;
-        }
-
-        if d.abs() < d_min {
-            let half_t = total_t / 2.0;
-            if t <= half_t {
-                let s = 0.5 * a * t * t;
-                (start_z as f64 + direction * s) as i64
-            } else {
-                let half_v = a * half_t;
-                let dt = t - half_t;
-                let s = (d / 2.0) + half_v * dt - 0.5 * a * dt * dt;
-                (start_z as f64 + direction * s) as i64
+    /// Samples how long the next OCT read takes.
+    fn sample_oct_latency(&mut self) -> Duration {
+        let mean = self.oct_latency_ms as f64;
+        let latency_ms = match self.oct_jitter {
+            OCTJitter::None => mean,
+            OCTJitter::Uniform { max_ms } => mean + self.rng.gen_range(-(max_ms as f64)..=max_ms as f64),
+            OCTJitter::Gaussian { std_ms } => {
+                //Box-Muller transform, 1 - u keeps the log away from 0
+                let u1: f64 = 1.0 - self.rng.gen::<f64>();
+                let u2: f64 = self.rng.gen();
+                mean + std_ms * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
             }
-        } else {
-            let t_accel = v / a;
-            let d_accel = 0.5 * a * t_accel * t_accel;
-            let t_cruise = total_t - 2.0 * t_accel;
-            if t <= t_accel {
-                let s = 0.5 * a * t * t;
-                (start_z as f64 + direction * s) as i64
-            } else if t <= t_accel + t_cruise {
-                let dt = t - t_accel;
-                let s = d_accel + v * dt;
-                (start_z as f64 + direction * s) as i64
-            } else {
-                let dt = t - (t_accel + t_cruise);
-                let d_cruise = v * t_cruise;
-                let s = d_accel + d_cruise + v * dt - 0.5 * a * dt * dt;
-                (start_z as f64 + direction * s) as i64
+        };
+        Duration::from_secs_f64(latency_ms.max(0.0) / 1000.0)
+    }
+
+    /// Rolls whether the next OCT read fails, if distance errors are on.
+    fn roll_oct_error(&mut self) -> bool {
+        let probability = match self.oct_dropouts {
+            OCTDropouts::Independent => self.error_probability,
+            OCTDropouts::Markov { enter_probability, stay_probability } => if self.oct_failing { stay_probability } else { enter_probability },
+        };
+        self.oct_failing = self.rng.gen_bool(probability);
+        self.oct_failing
+    }
+
+    /// Returns the recorded (elapsed ms, state) samples of the most recent moves, oldest first.
+    pub fn get_trajectory(&self) -> Vec<(u64, RobotState)> {
+        self.trajectory.iter().copied().collect()
+    }
+
+    /// Returns the kinematics of the most recent moves, oldest first. Empty unless the robot was built with a
+    /// `RobotArmBuilder::kinematics_sample_ms`.
+    pub fn get_move_kinematics(&self) -> Vec<MoveKinematics> {
+        self.move_kinematics.iter().cloned().collect()
+    }
+
+    /// Copies what the robot is doing right now, for tests to inspect mid-run through the arm's mutex.
+    pub fn snapshot(&self) -> RobotSnapshot {
+        let state = self._get_state().unwrap();
+        let (progress, target) = match self.last_move_time.filter(|_| self.is_moving) {
+            Some(move_time) => {
+                let progress = if self.total_move_duration.is_zero() { 1.0 } else { move_time.elapsed().as_secs_f64() / self.total_move_duration.as_secs_f64() };
+                let target = RobotState {
+                    inserter_z: self.inserter_move.map_or(self.state.inserter_z, |axis| axis.target_z),
+                    needle_z: self.needle_move.map_or(self.state.needle_z, |axis| axis.target_z),
+                };
+                (progress.min(1.0), Some(target))
             }
+            None => (0.0, None),
+        };
+        RobotSnapshot {
+            elapsed_ms: self.elapsed_ms(),
+            state,
+            is_moving: self.is_moving,
+            progress,
+            target,
+            move_duration_ms: if self.is_moving { self.total_move_duration.as_millis() as u64 } else { 0 },
+            error_scheduled: self.is_moving && self.error_scheduled,
+        }
+    }
+
+    //Where the axis move_cmd drives is right now: the needle for moves that drive it, the inserter otherwise
+    fn achieved_z(&self, move_cmd: &Move) -> u64 {
+        let state = self._get_state().unwrap();
+        match move_cmd {
+            Move::InserterZ(_) => state.inserter_z,
+            Move::NeedleZ(_) | Move::Both { .. } | Move::NeedleZWithVelocity { .. } => state.needle_z,
         }
     }
 
-    /// For inserter moves, we have constant velocity motion:
-    /// total_time = distance / INSERTER_VELOCITY_NM_MS
-    fn calculate_inserter_move_time(distance_nm: i64) -> Duration {
-        let distance = distance_nm.abs() as f64;
-        let time_ms = distance / INSERTER_VELOCITY_NM_MS as f64;
-        Duration::from_millis(time_ms as u64)
+    //Samples where the axes have got to in the move in progress, if its kinematics are sampled
+    fn record_kinematics(&mut self) {
+        let Some(move_start) = self.last_move_time.filter(|_| self.kinematics_sample_ms.is_some()) else {
+            return;
+        };
+        let state = self._get_state().unwrap();
+        self.kinematics_positions.push((move_start.elapsed().as_secs_f64() * 1000.0, state));
+    }
+
+    //Differentiates the positions sampled through the move that just ended into its kinematics
+    fn finish_kinematics(&mut self, move_cmd: Move) {
+        if self.kinematics_sample_ms.is_none() {
+            return;
+        }
+        let positions = std::mem::take(&mut self.kinematics_positions);
+        let samples = positions.windows(3).filter(|window| window[0].0 < window[1].0 && window[1].0 < window[2].0).map(|window| {
+            let (t0, t1, t2) = (window[0].0, window[1].0, window[2].0);
+            let differentiate = |z: fn(&RobotState) -> u64| {
+                let (z0, z1, z2) = (z(&window[0].1) as f64, z(&window[1].1) as f64, z(&window[2].1) as f64);
+                let velocity = (z2 - z0) / (t2 - t0);
+                let accel = 2.0 * ((z2 - z1) / (t2 - t1) - (z1 - z0) / (t1 - t0)) / (t2 - t0);
+                (velocity, accel)
+            };
+            let (needle_velocity_nm_ms, needle_accel_nm_ms2) = differentiate(|state| state.needle_z);
+            let (inserter_velocity_nm_ms, inserter_accel_nm_ms2) = differentiate(|state| state.inserter_z);
+            KinematicsSample { elapsed_ms: t1, needle_velocity_nm_ms, needle_accel_nm_ms2, inserter_velocity_nm_ms, inserter_accel_nm_ms2 }
+        }).collect();
+        self.move_kinematics.push_back(MoveKinematics { move_cmd, samples });
+        //Drop the oldest moves past the cap, but always keep the newest one whole
+        let mut num_samples = self.move_kinematics.iter().map(|kinematics| kinematics.samples.len()).sum::<usize>();
+        while num_samples > self.trajectory_cap && self.move_kinematics.len() > 1 {
+            num_samples -= self.move_kinematics.pop_front().unwrap().samples.len();
+        }
     }
 
-    /// Interpolate inserter moves linearly based on constant velocity.
-    fn interpolate_inserter_position(
-        start_z: i64,
-        target_z: i64,
-        elapsed: Duration,
-        total: Duration,
-    ) -> i64 {
-        let total_t = total.as_millis() as f64;
-        let t = elapsed.as_millis() as f64;
-        let d = (target_z - start_z) as f64;
-        let fraction = (t / total_t).min(1.0);
-        (start_z as f64 + d * fraction) as i64
+    /// Appends the current state to the trajectory, dropping the oldest samples past the cap.
+    fn record_trajectory(&mut self) {
+        let state = self._get_state().unwrap();
+        self.trajectory.push_back((self.init_time.elapsed().as_millis() as u64, state));
+        while self.trajectory.len() > self.trajectory_cap {
+            self.trajectory.pop_front();
+        }
+    }
+
+    //Stops the move in progress with each axis where it has got to, without recording a brain distance for it
+    fn stop_move(&mut self) {
+        if !self.is_moving {
+            return;
+        }
+        self.record_kinematics();
+        let move_cmd = self.last_move.clone();
+        self.state = self._get_state().unwrap();
+        self.is_moving = false;
+        self.last_move_time = None;
+        self.last_move = None;
+        if let Some(move_cmd) = move_cmd {
+            self.finish_kinematics(move_cmd);
+        }
+        self.inserter_move = None;
+        self.needle_move = None;
+        self.error_scheduled = false;
+        self.record_trajectory();
+    }
+
+    //Whether the move_id-th move was stopped before it finished, by an emergency move or a caller that gave up on it
+    fn interrupted(&self, move_id: u64) -> bool {
+        !self.is_moving || self.moves_started != move_id
+    }
+
+    //The error a move stopped by an emergency move fails with, reporting where it left the axis it drives
+    fn preempted_error(&self, move_cmd: &Move) -> RobotError {
+        RobotError::MoveError {
+            msg: "Preempted by an emergency move".to_string(),
+            at_ms: Some(self.elapsed_ms()),
+            achieved_z: self.achieved_z(move_cmd),
+        }
     }
 
     fn _get_state(&self) -> Result<RobotState, RobotError> {
@@ -164,26 +445,27 @@ impl RobotArm {
         if self.is_moving {
             let elapsed = self.last_move_time.unwrap().elapsed();
             let mut state = self.state.clone();
-            if self.is_inserter_move {
-                // InserterZ move: interpolate inserter_z only, needle_z unchanged
-                let pos = RobotArm::interpolate_inserter_position(
-                    self.start_z as i64,
-                    self.target_z as i64,
+            // Each moving axis is interpolated over its own duration, axes that aren't moving keep their position
+            if let Some(axis) = self.inserter_move {
+                let pos = motion::interpolate_inserter_position(
+                    axis.start_z as i64,
+                    axis.target_z as i64,
                     elapsed,
-                    self.total_move_duration,
+                    axis.duration,
+                    self.inserter_velocity_nm_ms,
+                    self.inserter_accel_nm_ms2,
                 );
-                assert!(pos >= 0);
-                state.inserter_z = pos as u64;
-            } else if self.is_needle_move {
-                // NeedleZ move: interpolate needle_z only, inserter_z unchanged
-                let pos = RobotArm::interpolate_needlez_position(
-                    self.start_z as i64,
-                    self.target_z as i64,
+                state.inserter_z = u64::try_from(pos).unwrap_or(0);
+            }
+            if let Some(axis) = self.needle_move {
+                let pos = self.interpolate_needlez_position(
+                    axis.start_z as i64,
+                    axis.target_z as i64,
                     elapsed,
-                    self.total_move_duration,
+                    axis.duration,
+                    axis.max_velocity,
                 );
-                assert!(pos >= 0);
-                state.needle_z = pos as u64;
+                state.needle_z = u64::try_from(pos).unwrap_or(0);
             }
             return Ok(state.clone());
         } else {
@@ -194,124 +476,559 @@ impl RobotArm {
 
 }
 
+/// Builder for `RobotArm` so the simulation's motion and error parameters can be changed
+/// without adding more positional arguments to `RobotArm::new`.
+/// Any parameter that isn't set keeps the default defined at the top of this file.
+pub struct RobotArmBuilder {
+    initial_z: u64,
+    distance_errors: bool,
+    move_errors: bool,
+    state_errors: bool,
+    needle_velocity_nm_ms: u64,
+    inserter_velocity_nm_ms: u64,
+    inserter_accel_nm_ms2: Option<i64>,
+    needle_accel_nm_ms2: i64,
+    needle_retract_velocity_nm_ms: Option<u64>,
+    needle_retract_accel_nm_ms2: Option<i64>,
+    error_probability: f64,
+    max_needle_z_nm: u64,
+    max_inserter_z_nm: u64,
+    grasp_error_probability: f64,
+    oct_latency_ms: u64,
+    oct_jitter: OCTJitter,
+    oct_drift: OCTDrift,
+    oct_dropouts: OCTDropouts,
+    quantization_nm: Option<u64>,
+    seizures: Vec<BrainSeizure>,
+    dimpling: Dimpling,
+    brain_baseline_nm: u64,
+    trajectory_cap: usize,
+    kinematics_sample_ms: Option<u64>,
+    seed: Option<u64>,
+}
+
+impl Default for RobotArmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RobotArmBuilder {
+    pub fn new() -> RobotArmBuilder {
+        RobotArmBuilder {
+            initial_z: 0,
+            distance_errors: false,
+            move_errors: false,
+            state_errors: false,
+            needle_velocity_nm_ms: NEEDLE_VELOCITY_NM_MS,
+            inserter_velocity_nm_ms: INSERTER_VELOCITY_NM_MS,
+            inserter_accel_nm_ms2: None,
+            needle_accel_nm_ms2: NEEDLE_ACCELERATION_NM_MS,
+            needle_retract_velocity_nm_ms: None,
+            needle_retract_accel_nm_ms2: None,
+            error_probability: PROBABILITY_OF_ERROR,
+            max_needle_z_nm: u64::MAX,
+            max_inserter_z_nm: u64::MAX,
+            grasp_error_probability: 0.0,
+            oct_latency_ms: OCT_LATENCY_MILLIS,
+            oct_jitter: OCTJitter::None,
+            oct_drift: OCTDrift::None,
+            oct_dropouts: OCTDropouts::Independent,
+            quantization_nm: None,
+            seizures: Vec::new(),
+            dimpling: Dimpling::None,
+            brain_baseline_nm: BRAIN_BASELINE_NM,
+            trajectory_cap: TRAJECTORY_CAP,
+            kinematics_sample_ms: None,
+            seed: None,
+        }
+    }
+
+    pub fn initial_z(mut self, initial_z: u64) -> Self {
+        self.initial_z = initial_z;
+        self
+    }
+
+    pub fn distance_errors(mut self, distance_errors: bool) -> Self {
+        self.distance_errors = distance_errors;
+        self
+    }
+
+    pub fn move_errors(mut self, move_errors: bool) -> Self {
+        self.move_errors = move_errors;
+        self
+    }
+
+    pub fn state_errors(mut self, state_errors: bool) -> Self {
+        self.state_errors = state_errors;
+        self
+    }
+
+    pub fn needle_velocity_nm_ms(mut self, needle_velocity_nm_ms: u64) -> Self {
+        self.needle_velocity_nm_ms = needle_velocity_nm_ms;
+        self
+    }
+
+    pub fn inserter_velocity_nm_ms(mut self, inserter_velocity_nm_ms: u64) -> Self {
+        self.inserter_velocity_nm_ms = inserter_velocity_nm_ms;
+        self
+    }
+
+    /// Inserter acceleration, ramping its moves up and down on the needle's trapezoidal profile.
+    /// Without one the inserter moves at constant velocity, starting and stopping instantly.
+    pub fn inserter_accel_nm_ms2(mut self, inserter_accel_nm_ms2: i64) -> Self {
+        self.inserter_accel_nm_ms2 = Some(inserter_accel_nm_ms2);
+        self
+    }
+
+    pub fn needle_accel_nm_ms2(mut self, needle_accel_nm_ms2: i64) -> Self {
+        self.needle_accel_nm_ms2 = needle_accel_nm_ms2;
+        self
+    }
+
+    /// Needle velocity when retracting. Defaults to the insertion velocity.
+    pub fn needle_retract_velocity_nm_ms(mut self, needle_retract_velocity_nm_ms: u64) -> Self {
+        self.needle_retract_velocity_nm_ms = Some(needle_retract_velocity_nm_ms);
+        self
+    }
+
+    /// Needle acceleration when retracting. Defaults to the insertion acceleration.
+    pub fn needle_retract_accel_nm_ms2(mut self, needle_retract_accel_nm_ms2: i64) -> Self {
+        self.needle_retract_accel_nm_ms2 = Some(needle_retract_accel_nm_ms2);
+        self
+    }
+
+    pub fn error_probability(mut self, error_probability: f64) -> Self {
+        self.error_probability = error_probability;
+        self
+    }
+
+    /// Grasps fail with `grasp_error_probability`. Grasps are only requested through `SimulatedRobot`,
+    /// the channels started by `start` have no grasp request, so over them the grasp always succeeds.
+    pub fn grasp_error_probability(mut self, grasp_error_probability: f64) -> Self {
+        self.grasp_error_probability = grasp_error_probability;
+        self
+    }
+
+    /// Mean time an OCT distance read takes to answer.
+    pub fn oct_latency_ms(mut self, oct_latency_ms: u64) -> Self {
+        self.oct_latency_ms = oct_latency_ms;
+        self
+    }
+
+    /// How much each OCT read's latency varies around `oct_latency_ms`.
+    pub fn oct_jitter(mut self, oct_jitter: OCTJitter) -> Self {
+        self.oct_jitter = oct_jitter;
+        self
+    }
+
+    /// How the brain's distance drifts over the session.
+    pub fn oct_drift(mut self, oct_drift: OCTDrift) -> Self {
+        self.oct_drift = oct_drift;
+        self
+    }
+
+    /// How OCT read errors cluster, see `OCTDropouts`.
+    pub fn oct_dropouts(mut self, oct_dropouts: OCTDropouts) -> Self {
+        self.oct_dropouts = oct_dropouts;
+        self
+    }
+
+    /// Rounds every distance the OCT reads to the nearest multiple of `quantization_nm`, like a sensor of finite
+    /// resolution. A brain closer than half a step still reads one step, as 0 would mean touching it. Brain
+    /// distances recorded by moves are still exact.
+    pub fn quantization_nm(mut self, quantization_nm: u64) -> Self {
+        assert!(quantization_nm > 0, "The OCT can't read at a resolution of 0nm");
+        self.quantization_nm = Some(quantization_nm);
+        self
+    }
+
+    /// Seizures to play over the session, overlapping seizures add up.
+    pub fn seizures(mut self, seizures: Vec<BrainSeizure>) -> Self {
+        self.seizures = seizures;
+        self
+    }
+
+    /// How the brain surface gives way to the needle.
+    pub fn dimpling(mut self, dimpling: Dimpling) -> Self {
+        self.dimpling = dimpling;
+        self
+    }
+
+    /// Where the brain rests below the inserter's origin. The brain keeps its motion around it, so the default
+    /// motion swings 1.5mm either side. The inserter is parked `margin_nm` above the closest the brain comes,
+    /// e.g. by the controller's calibration margin, so the baseline has to be past the swing and the margin.
+    pub fn brain_baseline_nm(mut self, brain_baseline_nm: u64, margin_nm: u64) -> Self {
+        assert!(brain_baseline_nm > BRAIN_MOTION_AMPLITUDE_NM + margin_nm,
+            "A brain baseline of {}nm leaves no room to park the inserter {}nm above the brain", brain_baseline_nm, margin_nm);
+        self.brain_baseline_nm = brain_baseline_nm;
+        self
+    }
+
+    /// NeedleZ moves past `max_needle_z_nm` are rejected with a `PositionError` without moving.
+    pub fn max_needle_z_nm(mut self, max_needle_z_nm: u64) -> Self {
+        self.max_needle_z_nm = max_needle_z_nm;
+        self
+    }
+
+    /// InserterZ moves past `max_inserter_z_nm` are rejected with a `PositionError` without moving.
+    pub fn max_inserter_z_nm(mut self, max_inserter_z_nm: u64) -> Self {
+        self.max_inserter_z_nm = max_inserter_z_nm;
+        self
+    }
+
+    /// Keep at most `trajectory_cap` trajectory samples, dropping the oldest first. Defaults to `TRAJECTORY_CAP`.
+    /// The move kinematics are held to as many samples, dropping the oldest moves first.
+    pub fn trajectory_cap(mut self, trajectory_cap: usize) -> Self {
+        self.trajectory_cap = trajectory_cap;
+        self
+    }
+
+    /// Samples the axes every `kinematics_sample_ms` through each move to record its velocities and
+    /// accelerations, see `RobotArm::get_move_kinematics`.
+    pub fn kinematics_sample_ms(mut self, kinematics_sample_ms: u64) -> Self {
+        assert!(kinematics_sample_ms > 0, "Kinematics have to be sampled at least every ms");
+        self.kinematics_sample_ms = Some(kinematics_sample_ms);
+        self
+    }
+
+    /// Seeds every random draw of the robot, so sessions with the same seed and timing see the same errors
+    /// and latencies. Unseeded robots draw their seed from the OS and keep it in `RobotArm::seed`, so their
+    /// session can be repeated.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> RobotArm {
+        let seed = self.seed.unwrap_or_else(|| StdRng::from_entropy().gen());
+        RobotArm {
+            distance_errors: self.distance_errors,
+            state_errors: self.state_errors,
+            move_errors: self.move_errors,
+            needle_velocity_nm_ms: self.needle_velocity_nm_ms,
+            inserter_velocity_nm_ms: self.inserter_velocity_nm_ms,
+            inserter_accel_nm_ms2: self.inserter_accel_nm_ms2,
+            needle_accel_nm_ms2: self.needle_accel_nm_ms2,
+            needle_retract_velocity_nm_ms: self.needle_retract_velocity_nm_ms.unwrap_or(self.needle_velocity_nm_ms),
+            needle_retract_accel_nm_ms2: self.needle_retract_accel_nm_ms2.unwrap_or(self.needle_accel_nm_ms2),
+            error_probability: self.error_probability,
+            max_needle_z_nm: self.max_needle_z_nm,
+            max_inserter_z_nm: self.max_inserter_z_nm,
+            grasp_error_probability: self.grasp_error_probability,
+            oct_latency_ms: self.oct_latency_ms,
+            oct_jitter: self.oct_jitter,
+            oct_drift: self.oct_drift,
+            oct_dropouts: self.oct_dropouts,
+            oct_failing: false,
+            quantization_nm: self.quantization_nm,
+            seizures: self.seizures,
+            dimpling: self.dimpling,
+            init_time: Instant::now(),
+            //Arbitrary function to mock brains location
+            brain_location_fn: |x: u64| {
+                (BRAIN_BASELINE_NM as f64
+                    + 500_000.0 * (6.0 * x as f64/1000.0).sin()
+                    + 1_000_000.0 * (x as f64/1000.0).sin()) as u64
+            },
+            brain_baseline_nm: self.brain_baseline_nm,
+            state: RobotState {
+                inserter_z: self.initial_z,
+                needle_z: 0,
+            },
+            is_moving: false,
+            last_move_time: None,
+            last_move: None,
+            moves_started: 0,
+            total_move_duration: Duration::from_millis(0),
+            inserter_move: None,
+            needle_move: None,
+            error_scheduled: false,
+            brain_distances: Vec::new(),
+            insertion_recorded: false,
+            trajectory: VecDeque::new(),
+            trajectory_cap: self.trajectory_cap,
+            kinematics_sample_ms: self.kinematics_sample_ms,
+            kinematics_positions: Vec::new(),
+            move_kinematics: VecDeque::new(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
 /// Get the current state of the robot
 /// We know this function is fast
-async fn get_state(robot: Arc<Mutex<RobotArm>>, mut state_rx: mpsc::Receiver<((), oneshot::Sender<Result<RobotState, RobotError>>)>) -> () {
+/// If `state_errors` is set, reads fail with `error_probability`, almost always as a lost connection
+/// and very rarely as a position error
+async fn get_state(robot: Arc<Mutex<RobotArm>>, mut state_rx: mpsc::Receiver<StateRequest>) -> () {
     println!("get_state");
     while let Some((_, tx)) = state_rx.recv().await {
-        tx.send({
-            robot.lock().await._get_state()}
-        ).unwrap();
+        tx.send(read_state(&robot).await).unwrap();
     }
 }
 
+async fn read_state(robot: &Mutex<RobotArm>) -> Result<RobotState, RobotError> {
+    let mut guard = robot.lock().await;
+    let error_probability = guard.error_probability;
+    if guard.state_errors && guard.rng.gen_bool(error_probability) {
+        if guard.rng.gen_bool(POSITION_ERROR_FRACTION) {
+            Err(RobotError::PositionError { msg: "Encoder reported an out of range position".to_string(), at_ms: Some(guard.elapsed_ms()) })
+        } else {
+            Err(RobotError::ConnectionError { msg: "Connection error".to_string(), at_ms: Some(guard.elapsed_ms()) })
+        }
+    } else {
+        guard._get_state()
+    }
+}
+
+/// Grasp the thread, failing with `grasp_error_probability`
+async fn execute_grasp(robot: &Mutex<RobotArm>) -> Result<(), RobotError> {
+    let mut guard = robot.lock().await;
+    let grasp_error_probability = guard.grasp_error_probability;
+    if guard.rng.gen_bool(grasp_error_probability) {
+        let needle_z = guard.state.needle_z;
+        return Err(RobotError::MoveError { msg: "Failed to grasp the thread".to_string(), at_ms: Some(guard.elapsed_ms()), achieved_z: needle_z });
+    }
+    Ok(())
+}
+
 /// Move the robot. Decide if an error will occur before starting the move. If so, pick a partial error position and move there, 
 /// then return the error. Otherwise, move to the target position, which is the commanded depth.
-async fn mv(robot: Arc<Mutex<RobotArm>>, mut move_rx: mpsc::Receiver<(Move, oneshot::Sender<Result<(), RobotError>>)>,) -> (){
+/// NeedleZ targets past the needle limit are rejected with a position error and the robot doesn't move.
+///
+/// Moves are made one at a time in the order they came in, but requests keep being taken while a move is in progress.
+/// An emergency move stops the move in progress where it is, fails it and every normal move queued behind it with a
+/// move error, and is made straight away.
+async fn mv(robot: Arc<Mutex<RobotArm>>, mut move_rx: mpsc::Receiver<MoveRequest>,) -> (){
     println!("mv");
-    while let Some((move_cmd, tx)) = move_rx.recv().await {
-        let (is_inserter_move, is_needle_move, start_z, target_z, total_move_duration, error_scheduled);
-
-        {
-            let mut guard = robot.lock().await;
-            assert!(!guard.is_moving);
-            // Decide if an error will occur now, before starting the move
-            let mut rng = rand::thread_rng();
-            let mut will_error = guard.move_errors && rng.gen_bool(PROBABILITY_OF_ERROR);
-
-            match move_cmd {
-                Move::InserterZ(z) => {
-                    guard.is_inserter_move = true;
-                    guard.is_needle_move = false;
-                    guard.start_z = guard.state.inserter_z;
-                    if will_error {
-                        // Pick a partial error position
-                        let partial_factor: f64 = rng.gen();
-                        guard.target_z = (guard.start_z as i64 + ((z as i64 - guard.start_z as i64) as f64 * partial_factor) as i64) as u64;
-                    } else {
-                        guard.target_z = z;
-                    }
-                    let distance = (guard.target_z as i64 - guard.start_z as i64).abs();
-                    guard.total_move_duration = RobotArm::calculate_inserter_move_time(distance);
-                }
-                Move::NeedleZ(z) => {
-                    guard.is_inserter_move = false;
-                    guard.is_needle_move = true;
-                    guard.start_z = guard.state.needle_z;
-                    // if(z == 0){
-                    //     will_error = false;
-                    // }
-                    if(z != 0){
-                        assert!(guard.state.needle_z == 0);
+    //Moves that came in while another was in progress
+    let mut queued = VecDeque::new();
+    loop {
+        let request = match queued.pop_front() {
+            Some(request) => Some(request),
+            None => move_rx.recv().await,
+        };
+        let Some((move_cmd, _, tx)) = request else {
+            return;
+        };
+        let emergency = {
+            let execution = execute_move(&robot, move_cmd.clone());
+            tokio::pin!(execution);
+            loop {
+                tokio::select! {
+                    biased;
+                    response = &mut execution => {
+                        let _ = tx.send(response);
+                        break None;
                     }
-                    if will_error {
-                        let partial_factor: f64 = rng.gen();
-                        guard.target_z = (guard.start_z as i64 + ((z as i64 - guard.start_z as i64) as f64 * partial_factor) as i64) as u64;
-                    } else {
-                        guard.target_z = z;
+                    Some(request) = move_rx.recv() => {
+                        if request.1 == MovePriority::Emergency {
+                            break Some((request, tx));
+                        }
+                        queued.push_back(request);
                     }
-                    let distance = (guard.target_z as i64 - guard.start_z as i64).abs();
-                    guard.total_move_duration = RobotArm::calculate_needlez_move_time(distance);
                 }
             }
+        };
+        //Dropping the execution above stopped it from finishing the move, so we stop the arm where it got to
+        if let Some((emergency, tx)) = emergency {
+            let mut guard = robot.lock().await;
+            guard.stop_move();
+            //The controller may have stopped waiting on any of these
+            let _ = tx.send(Err(guard.preempted_error(&move_cmd)));
+            for (move_cmd, _, tx) in queued.drain(..) {
+                let _ = tx.send(Err(guard.preempted_error(&move_cmd)));
+            }
+            drop(guard);
+            queued.push_back(emergency);
+        }
+    }
+}
 
-            guard.is_moving = true;
-            guard.last_move_time = Some(Instant::now());
-            guard.last_move = Some(move_cmd.clone());
-            guard.error_scheduled = will_error;
+//Stops the move it was made for where it has got to if that move is dropped before it finishes, as when the
+//controller aborts an insertion and stops waiting on it, so the arm isn't left moving with nothing to finish the move
+struct MoveInProgress<'a> {
+    robot: &'a Mutex<RobotArm>,
+    move_id: u64,
+}
 
-            // Extract fields for use outside lock (to avoid long lock time during sleep)
-            is_inserter_move = guard.is_inserter_move;
-            is_needle_move = guard.is_needle_move;
-            start_z = guard.start_z;
-            target_z = guard.target_z;
-            total_move_duration = guard.total_move_duration;
-            error_scheduled = guard.error_scheduled;
+impl Drop for MoveInProgress<'_> {
+    fn drop(&mut self) {
+        //The arm is never locked across an await, so on our single threaded runtime it is free whenever a move is dropped
+        if let Ok(mut guard) = self.robot.try_lock() {
+            if guard.moves_started == self.move_id {
+                guard.stop_move();
+            }
         }
+    }
+}
 
-        // Simulate the move duration
-        match move_cmd {
-            Move::NeedleZ(z) => {
-                println!("InserterZ: {} -> {} with duration {}", start_z, z, total_move_duration.as_millis());
+async fn execute_move(robot: &Mutex<RobotArm>, move_cmd: Move) -> Result<(), RobotError> {
+    let (inserter_target, needle_target) = match move_cmd {
+        Move::InserterZ(z) => (Some(z), None),
+        Move::NeedleZ(z) => (None, Some(z)),
+        Move::Both { inserter_z, needle_z } => (Some(inserter_z), Some(needle_z)),
+        Move::NeedleZWithVelocity { target, .. } => (None, Some(target)),
+    };
+    let max_velocity = match move_cmd {
+        Move::NeedleZWithVelocity { max_velocity, .. } => Some(max_velocity),
+        _ => None,
+    };
+    //Targets past either axis' travel are rejected before the robot starts moving
+    {
+        let guard = robot.lock().await;
+        if let Some(z) = needle_target.filter(|z| *z > guard.max_needle_z_nm) {
+            return Err(RobotError::PositionError {
+                msg: format!("NeedleZ({}) is past the needle limit of {}", z, guard.max_needle_z_nm),
+                at_ms: Some(guard.elapsed_ms()),
+            });
+        }
+        if let Some(z) = inserter_target.filter(|z| *z > guard.max_inserter_z_nm) {
+            return Err(RobotError::PositionError {
+                msg: format!("InserterZ({}) is past the inserter limit of {}", z, guard.max_inserter_z_nm),
+                at_ms: Some(guard.elapsed_ms()),
+            });
+        }
+    }
+    let (inserter_move, needle_move, total_move_duration, error_scheduled, kinematics_sample_ms, move_id);
+
+    {
+        let mut guard = robot.lock().await;
+        assert!(!guard.is_moving);
+        // Decide if an error will occur now, before starting the move
+        let error_probability = guard.error_probability;
+        let will_error = guard.move_errors && guard.rng.gen_bool(error_probability);
+        // Pick a partial error position, the same fraction of the way along every moving axis
+        let partial_factor: f64 = if will_error { guard.rng.gen() } else { 1.0 };
+        let partial_target = |start_z: u64, z: u64| (start_z as i64 + ((z as i64 - start_z as i64) as f64 * partial_factor) as i64) as u64;
+
+        guard.inserter_move = inserter_target.map(|z| {
+            let start_z = guard.state.inserter_z;
+            let target_z = if will_error { partial_target(start_z, z) } else { z };
+            let duration = guard.calculate_inserter_move_time((target_z as i64 - start_z as i64).abs());
+            AxisMove { start_z, target_z, duration, max_velocity: None }
+        });
+        guard.needle_move = needle_target.map(|z| {
+            let start_z = guard.state.needle_z;
+            //The needle only moves deeper, unless it is retracting all the way
+            if z != 0 {
+                assert!(z >= start_z, "NeedleZ({}) would back the needle out from {}", z, start_z);
             }
-            _ => {}
+            let target_z = if will_error { partial_target(start_z, z) } else { z };
+            let duration = guard.calculate_needlez_move_time(start_z as i64, target_z as i64, max_velocity);
+            AxisMove { start_z, target_z, duration, max_velocity }
+        });
+        //The move is done once the slower axis is
+        guard.total_move_duration = guard.inserter_move.iter().chain(guard.needle_move.iter())
+            .map(|axis| axis.duration)
+            .max()
+            .unwrap_or_default();
+
+        guard.is_moving = true;
+        guard.moves_started += 1;
+        guard.last_move_time = Some(Instant::now());
+        guard.last_move = Some(move_cmd.clone());
+        guard.error_scheduled = will_error;
+        guard.record_trajectory();
+        guard.record_kinematics();
+
+        // Extract fields for use outside lock (to avoid long lock time during sleep)
+        inserter_move = guard.inserter_move;
+        needle_move = guard.needle_move;
+        total_move_duration = guard.total_move_duration;
+        error_scheduled = guard.error_scheduled;
+        kinematics_sample_ms = guard.kinematics_sample_ms;
+        move_id = guard.moves_started;
+    }
+    let _in_progress = MoveInProgress { robot, move_id };
+
+    // Simulate the move duration
+    if let (Some(axis), Some(z)) = (needle_move, needle_target) {
+        println!("InserterZ: {} -> {} with duration {}", axis.start_z, z, total_move_duration.as_millis());
+    }
+    //Sample the interpolated state into the trajectory, and the kinematics if they are sampled, while the move
+    //is in progress. Samples due once the move is done are left to its end
+    let move_start = Instant::now();
+    let trajectory_every = Duration::from_millis(TRAJECTORY_SAMPLE_MILLIS);
+    let kinematics_every = kinematics_sample_ms.map(Duration::from_millis);
+    let (mut next_trajectory, mut next_kinematics) = (trajectory_every, kinematics_every);
+    loop {
+        let next = next_kinematics.map_or(next_trajectory, |next_kinematics| next_kinematics.min(next_trajectory));
+        if next >= total_move_duration {
+            break;
         }
-        sleep(total_move_duration).await;
-        {
-            let mut guard = robot.lock().await;
-            guard.is_moving = false;
-            guard.last_move_time = None;
-            guard.last_move = None;
-            // At this point, the robot physically ends at target_z.
-            if is_inserter_move {
-                guard.state.inserter_z = target_z;
-            } else if is_needle_move {
-                let brain_position = (guard.brain_location_fn)(guard.init_time.elapsed().as_millis() as u64) - guard.state.inserter_z;
-                if !error_scheduled && target_z != 0 {
-                    assert!(guard.move_errors || brain_position < target_z, "brain position: {}, target position: {}", brain_position, target_z);
-                    guard.brain_distances.push(if target_z < brain_position {0} else {target_z - brain_position});
+        sleep_until(move_start + next).await;
+        let mut guard = robot.lock().await;
+        //An emergency move stopped this one where it was
+        if guard.interrupted(move_id) {
+            return Err(guard.preempted_error(&move_cmd));
+        }
+        if next == next_trajectory {
+            guard.record_trajectory();
+            next_trajectory += trajectory_every;
+        }
+        if let (Some(every), true) = (kinematics_every, next_kinematics == Some(next)) {
+            guard.record_kinematics();
+            next_kinematics = Some(next + every);
+        }
+    }
+    sleep_until(move_start + total_move_duration).await;
+    {
+        let mut guard = robot.lock().await;
+        if guard.interrupted(move_id) {
+            return Err(guard.preempted_error(&move_cmd));
+        }
+        guard.record_kinematics();
+        guard.is_moving = false;
+        guard.last_move_time = None;
+        guard.last_move = None;
+        // At this point, the robot physically ends at its targets. The inserter lands first, so the
+        // needle's depth below the brain is measured from where the inserter ended up
+        if let Some(axis) = inserter_move {
+            guard.state.inserter_z = axis.target_z;
+        }
+        if let Some(axis) = needle_move {
+            let target_z = axis.target_z;
+            //The needle is in place before we look at the brain, which it may have dimpled
+            guard.state.needle_z = target_z;
+            //If the inserter has reached the brain there is no meaningful brain distance to record
+            let brain_position = guard.brain_position().checked_sub(guard.state.inserter_z);
+            if let Some(brain_position) = brain_position.filter(|_| !error_scheduled && target_z != 0) {
+                assert!(guard.move_errors || brain_position < target_z, "brain position: {}, target position: {}", brain_position, target_z);
+                //An insertion made in several moves reached the depth of its last one
+                if axis.start_z != 0 && guard.insertion_recorded {
+                    guard.brain_distances.pop();
                 }
-                guard.state.needle_z = target_z;
+                guard.brain_distances.push(if target_z < brain_position {0} else {target_z - brain_position});
+                guard.insertion_recorded = true;
             }
+            if target_z == 0 {
+                guard.insertion_recorded = false;
+            }
+        }
 
-            guard.is_inserter_move = false;
-            guard.is_needle_move = false;
+        guard.inserter_move = None;
+        guard.needle_move = None;
+        guard.record_trajectory();
+        guard.finish_kinematics(move_cmd.clone());
 
-            if error_scheduled {
-                guard.error_scheduled = false;
-                tx.send(Err(RobotError::MoveError {
-                    msg: "Random error occurred after move".to_string(),
-                })).unwrap();
-            } else{
-                tx.send(Ok(())).unwrap();
-            }
+        if error_scheduled {
+            guard.error_scheduled = false;
+            Err(RobotError::MoveError {
+                msg: "Random error occurred after move".to_string(),
+                at_ms: Some(guard.elapsed_ms()),
+                achieved_z: guard.achieved_z(&move_cmd),
+            })
+        } else{
+            Ok(())
         }
     }
 }
 
-pub async fn start(distance_rx: mpsc::Receiver<((), oneshot::Sender<Result<u64, OCTError>>)>,
-                    state_rx: mpsc::Receiver<((), oneshot::Sender<Result<RobotState, RobotError>>)>,
-                    move_rx: mpsc::Receiver<(Move, oneshot::Sender<Result<(), RobotError>>)>,
+pub async fn start(distance_rx: mpsc::Receiver<DistanceRequest>,
+                    state_rx: mpsc::Receiver<StateRequest>,
+                    move_rx: mpsc::Receiver<MoveRequest>,
                     mut dead_rx: mpsc::Receiver<()>,
                     robot: Arc<Mutex<RobotArm>>) {
 
@@ -326,24 +1043,572 @@ pub async fn start(distance_rx: mpsc::Receiver<((), oneshot::Sender<Result<u64,
 }
 
 //Using a function defined in the struct, at any query, calculate the brains simulate position in real time and return the value
-async fn get_distance(robot: Arc<Mutex<RobotArm>>, mut distance_rx: mpsc::Receiver<((), oneshot::Sender<Result<u64, OCTError>>)>,) -> () {
+async fn get_distance(robot: Arc<Mutex<RobotArm>>, mut distance_rx: mpsc::Receiver<DistanceRequest>,) -> () {
     println!("get_distance");
     while let Some((_, tx)) = distance_rx.recv().await {
-        let will_error = { rand::thread_rng().gen_bool(PROBABILITY_OF_ERROR)};
-        let (diff, distance_errors) = 
-        {
-            let guard = robot.lock().await;
-            let robot_position = guard._get_state().unwrap().inserter_z;
-            //Brains position in real time
-            let brain_position = (guard.brain_location_fn)(guard.init_time.elapsed().as_millis() as u64);
-            assert!(brain_position > 0 && brain_position > robot_position, "brain position: {}, robot position: {}", brain_position, robot_position);
-            (brain_position - robot_position, guard.distance_errors)
-        };
-        sleep(Duration::from_millis(15)).await;
-        if will_error && distance_errors {
-            tx.send(Err(OCTError::CommunicationError { msg: "Connection error".to_string() })).unwrap();
+        tx.send(read_distance(&robot).await).unwrap();
+    }
+}
+
+async fn read_distance(robot: &Mutex<RobotArm>) -> Result<u64, OCTError> {
+    let (diff, distance_errors, will_error, latency, init_time, quantization_nm) = 
+    {
+        let mut guard = robot.lock().await;
+        let will_error = guard.roll_oct_error();
+        let robot_position = guard._get_state().unwrap().inserter_z;
+        //Brains position in real time
+        let brain_position = guard.brain_position();
+        (brain_position.checked_sub(robot_position).filter(|diff| *diff > 0), guard.distance_errors, will_error, guard.sample_oct_latency(), guard.init_time, guard.quantization_nm)
+    };
+    sleep(latency).await;
+    //Errors are stamped with when the read is answered
+    let at_ms = Some(init_time.elapsed().as_millis() as u64);
+    //An inserter at or past the brain surface can't be measured, which the controller treats like any other bad read
+    let Some(diff) = diff else {
+        return Err(OCTError::AcquisitionError { msg: "Inserter is at or past the brain surface".to_string(), at_ms });
+    };
+    if will_error && distance_errors {
+        Err(OCTError::CommunicationError { msg: "Connection error".to_string(), at_ms })
+    } else {
+        //The OCT only resolves the distance to the nearest step, and never reads a brain it isn't touching as 0
+        Ok(quantization_nm.map_or(diff, |step| ((diff + step / 2) / step * step).max(step)))
+    }
+}
+
+/// Drives the simulation in process, without the channels or the tasks started by `start`.
+/// Requests go through the same functions as the channel tasks. Like the `get_distance` task,
+/// the OCT answers one request at a time, so concurrent reads queue up behind each other.
+/// The `SimulatedRobot` takes the arm over, reading its limits before anything else can lock it. `arm` shares it
+/// afterwards, to inspect the simulation.
+/// An emergency move stops the move in progress where it is and is made straight away, the stopped move fails
+/// with a move error.
+pub struct SimulatedRobot {
+    arm: Arc<Mutex<RobotArm>>,
+    oct: Mutex<()>,
+    limits: RobotLimits,
+}
+
+impl SimulatedRobot {
+    pub fn new(arm: RobotArm) -> SimulatedRobot {
+        let limits = arm.limits();
+        SimulatedRobot { arm: Arc::new(Mutex::new(arm)), oct: Mutex::new(()), limits }
+    }
+
+    pub fn arm(&self) -> Arc<Mutex<RobotArm>> {
+        Arc::clone(&self.arm)
+    }
+}
+
+impl Robot for SimulatedRobot {
+    async fn get_robot_state(&self) -> Result<RobotState, RobotError> {
+        read_state(&self.arm).await
+    }
+
+    async fn command_move(&self, command: &Move) -> Result<(), RobotError> {
+        execute_move(&self.arm, command.clone()).await
+    }
+
+    //The move in progress is stopped where it is, and fails with a move error once its caller looks at it again
+    async fn command_emergency_move(&self, command: &Move) -> Result<(), RobotError> {
+        self.arm.lock().await.stop_move();
+        execute_move(&self.arm, command.clone()).await
+    }
+
+    async fn command_grasp(&self) -> Result<(), RobotError> {
+        execute_grasp(&self.arm).await
+    }
+
+    fn limits(&self) -> RobotLimits {
+        self.limits
+    }
+}
+
+impl OCTService for SimulatedRobot {
+    async fn get_surface_distance(&self) -> Result<u64, OCTError> {
+        let _busy = self.oct.lock().await;
+        read_distance(&self.arm).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    //A simulated robot held behind a trait object still moves the arm
+    #[tokio::test(start_paused = true)]
+    async fn test_dyn_robot_command_move() {
+        use crate::interface::{DynOCTService, DynRobot};
+        let simulated = Arc::new(SimulatedRobot::new(RobotArmBuilder::new().error_probability(0.0).build()));
+        let robot: Arc<dyn DynRobot> = simulated.clone();
+        let oct: Arc<dyn DynOCTService> = simulated;
+        robot.command_move(&Move::InserterZ(1_000_000)).await.unwrap();
+        assert_eq!(robot.get_robot_state().await.unwrap(), RobotState { inserter_z: 1_000_000, needle_z: 0 });
+        assert!(oct.get_surface_distance().await.is_ok());
+    }
+
+    // Commands a single needle move past the brain and checks the sampled path
+    #[tokio::test]
+    async fn test_needle_trajectory_is_monotonic() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().build()));
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        let (tx, rx) = oneshot::channel();
+        move_tx.send((Move::NeedleZ(10_000_000), MovePriority::Normal, tx)).await.unwrap();
+        rx.await.unwrap().unwrap();
+
+        let trajectory = robot.lock().await.get_trajectory();
+        assert!(trajectory.len() > 2, "Expected interpolated samples but got {:?}", trajectory);
+        for window in trajectory.windows(2) {
+            assert!(window[0].0 <= window[1].0);
+            assert!(window[0].1.needle_z <= window[1].1.needle_z, "Trajectory went backwards: {:?}", window);
+            assert!(window[0].1.inserter_z == window[1].1.inserter_z);
+        }
+        assert!(trajectory.last().unwrap().1 == RobotState{inserter_z: 0, needle_z: 10_000_000});
+    }
+
+    // Both axes reach their targets at once, taking as long as the slower of the two would on its own
+    #[tokio::test(start_paused = true)]
+    async fn test_simultaneous_move() {
+        //The needle has to end up past the brain, which sits between 5.5mm and 8.5mm below the origin
+        for (inserter_z, needle_z) in [(1_000_000, 8_000_000), (5_000_000, 4_000_000)] {
+            let robot = Arc::new(Mutex::new(RobotArmBuilder::new().error_probability(0.0).build()));
+            let expected = {
+                let arm = robot.lock().await;
+                arm.calculate_inserter_move_time(inserter_z as i64).max(arm.calculate_needlez_move_time(0, needle_z as i64, None))
+            };
+            let (move_tx, move_rx) = mpsc::channel(1);
+            tokio::spawn(mv(Arc::clone(&robot), move_rx));
+            let (tx, rx) = oneshot::channel();
+            let start = Instant::now();
+            move_tx.send((Move::Both { inserter_z, needle_z }, MovePriority::Normal, tx)).await.unwrap();
+            rx.await.unwrap().unwrap();
+            let elapsed = start.elapsed();
+            assert!(elapsed.abs_diff(expected) <= Duration::from_millis(1), "Took {:?} instead of {:?}", elapsed, expected);
+
+            let trajectory = robot.lock().await.get_trajectory();
+            assert!(trajectory.last().unwrap().1 == RobotState{inserter_z, needle_z});
+            //Both axes were moving together part of the way
+            assert!(trajectory.iter().any(|(_, state)| (1..inserter_z).contains(&state.inserter_z) && (1..needle_z).contains(&state.needle_z)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trajectory_cap() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().trajectory_cap(3).build()));
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        let (tx, rx) = oneshot::channel();
+        move_tx.send((Move::InserterZ(1_000_000), MovePriority::Normal, tx)).await.unwrap();
+        rx.await.unwrap().unwrap();
+
+        let trajectory = robot.lock().await.get_trajectory();
+        assert!(trajectory.len() == 3);
+        assert!(trajectory.last().unwrap().1.inserter_z == 1_000_000);
+        //Without an explicit cap the trajectory is still bounded
+        assert!(RobotArmBuilder::new().build().trajectory_cap == TRAJECTORY_CAP);
+    }
+
+    // A long needle move ramps up at the configured acceleration and then cruises at the configured velocity,
+    // while the inserter stays put
+    #[tokio::test(start_paused = true)]
+    async fn test_needle_move_kinematics_follow_profile() {
+        const VELOCITY: u64 = 2_000;
+        const ACCEL: i64 = 250;
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().needle_velocity_nm_ms(VELOCITY).needle_accel_nm_ms2(ACCEL)
+            .error_probability(0.0).kinematics_sample_ms(1).build()));
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        let (tx, rx) = oneshot::channel();
+        move_tx.send((Move::NeedleZ(10_000_000), MovePriority::Normal, tx)).await.unwrap();
+        rx.await.unwrap().unwrap();
+
+        let kinematics = robot.lock().await.get_move_kinematics();
+        assert!(kinematics.len() == 1 && matches!(kinematics[0].move_cmd, Move::NeedleZ(10_000_000)));
+        let samples = &kinematics[0].samples;
+        //Roughly one sample a ms over a 5s move
+        assert!(samples.len() > 4_900, "Only {} samples", samples.len());
+        let peak = samples.iter().map(|sample| sample.needle_velocity_nm_ms).fold(f64::MIN, f64::max);
+        assert!((peak - VELOCITY as f64).abs() <= 0.01 * VELOCITY as f64, "Peaked at {}nm/ms", peak);
+        //The ramp up takes VELOCITY / ACCEL ms, its samples either side of it aside
+        let ramp_ms = (VELOCITY / ACCEL as u64) as f64;
+        let ramp = samples.iter().filter(|sample| sample.elapsed_ms < ramp_ms - 1.0).collect::<Vec<_>>();
+        assert!(!ramp.is_empty());
+        for sample in ramp {
+            assert!((sample.needle_accel_nm_ms2 - ACCEL as f64).abs() <= 0.01 * ACCEL as f64, "Unexpected ramp sample: {:?}", sample);
+        }
+        assert!(samples.iter().all(|sample| sample.inserter_velocity_nm_ms == 0.0 && sample.inserter_accel_nm_ms2 == 0.0));
+    }
+
+    // The kinematics are capped like the trajectory, so only the newest move's are left once two don't fit
+    #[tokio::test(start_paused = true)]
+    async fn test_move_kinematics_cap() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().error_probability(0.0).kinematics_sample_ms(1).trajectory_cap(150).build()));
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        for target in [1_000_000, 0] {
+            let (tx, rx) = oneshot::channel();
+            move_tx.send((Move::InserterZ(target), MovePriority::Normal, tx)).await.unwrap();
+            rx.await.unwrap().unwrap();
+        }
+        let kinematics = robot.lock().await.get_move_kinematics();
+        assert!(kinematics.len() == 1 && matches!(kinematics[0].move_cmd, Move::InserterZ(0)), "Unexpected kinematics: {:?}", kinematics);
+        assert!(kinematics[0].samples.len() > 75, "Only {} samples", kinematics[0].samples.len());
+    }
+
+    // Without a sample interval no kinematics are recorded
+    #[tokio::test(start_paused = true)]
+    async fn test_kinematics_are_opt_in() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().error_probability(0.0).build()));
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        let (tx, rx) = oneshot::channel();
+        move_tx.send((Move::InserterZ(1_000_000), MovePriority::Normal, tx)).await.unwrap();
+        rx.await.unwrap().unwrap();
+        assert!(robot.lock().await.get_move_kinematics().is_empty());
+    }
+
+    // The arm's interpolated state mid move should be exactly what the shared motion math gives
+    #[test]
+    fn test_arm_interpolation_matches_motion() {
+        // At 100um/ms and 500nm/ms^2 the needle takes 200ms to reach cruise velocity, covering 10mm on the way.
+        // A 50µm move never gets there and is done in 2*sqrt(50_000/500) = 20ms, a 30mm move cruises
+        // 10mm in between for a 500ms total. Retractions are given the same profile.
+        let arm = RobotArmBuilder::new().needle_velocity_nm_ms(100_000).needle_accel_nm_ms2(500)
+            .needle_retract_velocity_nm_ms(100_000).needle_retract_accel_nm_ms2(500).build();
+        let short = |t: f64| if t <= 10.0 { 250.0 * t * t } else { 50_000.0 - 250.0 * (20.0 - t) * (20.0 - t) };
+        let long = |t: f64| if t <= 200.0 {
+            250.0 * t * t
+        } else if t <= 300.0 {
+            10_000_000.0 + 100_000.0 * (t - 200.0)
         } else {
-            tx.send(Ok(diff)).unwrap();
+            30_000_000.0 - 250.0 * (500.0 - t) * (500.0 - t)
+        };
+        //A move from start_z to target_z, how many ms it takes and where the needle should be at each of them
+        type Case<'a> = (i64, i64, u64, &'a dyn Fn(f64) -> f64);
+        let cases: [Case; 3] = [
+            (0, 50_000, 20, &short),
+            (50_000, 0, 20, &|t| 50_000.0 - short(t)),
+            (0, 30_000_000, 500, &long),
+        ];
+        for (start_z, target_z, total_ms, expected) in cases {
+            let total = arm.calculate_needlez_move_time(start_z, target_z, None);
+            assert!(total == Duration::from_millis(total_ms), "{} -> {} took {:?}", start_z, target_z, total);
+            for ms in 0..=total_ms {
+                let pos = arm.interpolate_needlez_position(start_z, target_z, Duration::from_millis(ms), total, None);
+                assert!((pos as f64 - expected(ms as f64)).abs() <= 1.0,
+                    "{} -> {} at {}ms: {} instead of {}", start_z, target_z, ms, pos, expected(ms as f64));
+            }
+        }
+        // Without an acceleration the inserter covers 950um at 9.5um/ms in 100ms
+        assert!(arm.calculate_inserter_move_time(950_000) == Duration::from_millis(100));
+    }
+
+    // Halfway through a long inserter move the snapshot sees it in flight, and once it is done at rest on its target
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot_mid_move() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().error_probability(0.0).build()));
+        assert!(robot.lock().await.snapshot() == RobotSnapshot{elapsed_ms: 0, state: RobotState{inserter_z: 0, needle_z: 0}, is_moving: false, progress: 0.0, target: None, move_duration_ms: 0, error_scheduled: false});
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        let (tx, rx) = oneshot::channel();
+        move_tx.send((Move::InserterZ(5_000_000), MovePriority::Normal, tx)).await.unwrap();
+        let move_ms = motion::calculate_inserter_move_time(5_000_000, INSERTER_VELOCITY_NM_MS, None).as_millis() as u64;
+        sleep(Duration::from_millis(move_ms / 2)).await;
+
+        let snapshot = robot.lock().await.snapshot();
+        assert!(snapshot.is_moving && snapshot.progress > 0.0 && snapshot.progress < 1.0, "Unexpected snapshot: {:?}", snapshot);
+        assert!(snapshot.state.inserter_z > 0 && snapshot.state.inserter_z < 5_000_000, "Unexpected snapshot: {:?}", snapshot);
+        assert!(snapshot.target == Some(RobotState{inserter_z: 5_000_000, needle_z: 0}) && snapshot.move_duration_ms == move_ms);
+
+        rx.await.unwrap().unwrap();
+        let snapshot = robot.lock().await.snapshot();
+        assert!(!snapshot.is_moving && snapshot.target.is_none() && snapshot.state == RobotState{inserter_z: 5_000_000, needle_z: 0}, "Unexpected snapshot: {:?}", snapshot);
+    }
+
+    // With an acceleration the inserter's velocity ramps up to its maximum and back down, instead of jumping to it
+    #[tokio::test(start_paused = true)]
+    async fn test_inserter_velocity_ramps() {
+        const ACCEL: i64 = 50;
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().inserter_accel_nm_ms2(ACCEL).build()));
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        let (tx, rx) = oneshot::channel();
+        move_tx.send((Move::InserterZ(5_000_000), MovePriority::Normal, tx)).await.unwrap();
+
+        //Sample the inserter every ms until the move is done
+        let mut positions = Vec::new();
+        loop {
+            positions.push(robot.lock().await._get_state().unwrap().inserter_z as i64);
+            if !robot.lock().await.is_moving && positions.len() > 1 {
+                break;
+            }
+            sleep(Duration::from_millis(1)).await;
+        }
+        rx.await.unwrap().unwrap();
+
+        assert!(*positions.last().unwrap() == 5_000_000);
+        //Move times are rounded down to the ms, so the move snaps onto its target in its last ms, leave that one out
+        let arrived = positions.iter().position(|position| *position == 5_000_000).unwrap();
+        let velocities = positions[..arrived].windows(2).map(|w| w[1] - w[0]).collect::<Vec<i64>>();
+        let max_velocity = INSERTER_VELOCITY_NM_MS as i64;
+        //Starting from rest, the first ms covers at most a full ms of acceleration
+        assert!(velocities[0] <= ACCEL, "Inserter jumped to {}nm/ms", velocities[0]);
+        assert!(velocities.iter().all(|v| *v <= max_velocity + ACCEL), "Inserter exceeded its velocity: {:?}", velocities);
+        //No ms gains or loses more than a ms of acceleration, give or take a ms of rounding
+        assert!(velocities.windows(2).all(|w| (w[1] - w[0]).abs() <= 2 * ACCEL), "Inserter velocity jumped: {:?}", velocities);
+        //It reaches cruise, then stops the same way it started
+        assert!(velocities.iter().any(|v| *v >= max_velocity - ACCEL));
+        assert!(*velocities.last().unwrap() <= 2 * ACCEL, "Inserter stopped from {}nm/ms", velocities.last().unwrap());
+
+        //Ramping takes longer than the constant velocity move
+        let ramped = robot.lock().await.calculate_inserter_move_time(5_000_000);
+        assert!(ramped > motion::calculate_inserter_move_time(5_000_000, INSERTER_VELOCITY_NM_MS, None));
+        assert!(ramped == motion::calculate_needlez_move_time(5_000_000, INSERTER_VELOCITY_NM_MS, ACCEL));
+    }
+
+    // An emergency retract doesn't wait behind the move in progress or the moves queued after it
+    #[tokio::test(start_paused = true)]
+    async fn test_emergency_move_preempts() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().needle_velocity_nm_ms(1_000).build()));
+        let (move_tx, move_rx) = mpsc::channel(3);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        //A 10s insertion, and another one queued behind it
+        let (long_tx, long_rx) = oneshot::channel();
+        move_tx.send((Move::NeedleZ(10_000_000), MovePriority::Normal, long_tx)).await.unwrap();
+        let (queued_tx, queued_rx) = oneshot::channel();
+        move_tx.send((Move::NeedleZ(11_000_000), MovePriority::Normal, queued_tx)).await.unwrap();
+        sleep(Duration::from_millis(500)).await;
+        assert!(robot.lock().await._get_state().unwrap().needle_z > 0);
+
+        let retract_start = Instant::now();
+        let (retract_tx, retract_rx) = oneshot::channel();
+        move_tx.send((Move::NeedleZ(0), MovePriority::Emergency, retract_tx)).await.unwrap();
+        retract_rx.await.unwrap().unwrap();
+        //Retracting from where the needle got to takes as long as it took to get there
+        assert!(retract_start.elapsed() < Duration::from_secs(1), "Retract took {:?}", retract_start.elapsed());
+        assert!(matches!(long_rx.await.unwrap(), Err(RobotError::MoveError{..})));
+        assert!(matches!(queued_rx.await.unwrap(), Err(RobotError::MoveError{..})));
+
+        let guard = robot.lock().await;
+        assert!(!guard.is_moving);
+        assert!(guard._get_state().unwrap().needle_z == 0);
+        //The preempted insertion never got to its depth, so no brain distance is recorded for it
+        assert!(guard.brain_distances.is_empty());
+    }
+
+    // An emergency move made on a simulated robot stops the move it is making for another caller
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated_emergency_move_preempts() {
+        let simulated = SimulatedRobot::new(RobotArmBuilder::new().needle_velocity_nm_ms(1_000).build());
+        let retract = async {
+            sleep(Duration::from_millis(500)).await;
+            let retract_start = Instant::now();
+            simulated.command_emergency_move(&Move::NeedleZ(0)).await.unwrap();
+            retract_start.elapsed()
+        };
+        let (insertion, retract_time) = tokio::join!(simulated.command_move(&Move::NeedleZ(10_000_000)), retract);
+        assert!(retract_time < Duration::from_secs(1), "Retract took {:?}", retract_time);
+        assert!(matches!(insertion, Err(RobotError::MoveError{..})), "Unexpected insertion result: {:?}", insertion);
+
+        let arm = simulated.arm();
+        let guard = arm.lock().await;
+        assert!(!guard.is_moving);
+        assert!(guard._get_state().unwrap().needle_z == 0);
+        assert!(guard.brain_distances.is_empty());
+    }
+
+    // A shallower or deeper brain keeps its motion around its baseline, leaving room to park the inserter the
+    // calibration margin above the closest it comes
+    #[test]
+    fn test_brain_baseline_shifts_motion() {
+        let margin = 250_000;
+        for baseline in [5_000_000, BRAIN_BASELINE_NM, 9_000_000] {
+            let arm = RobotArmBuilder::new().brain_baseline_nm(baseline, margin).build();
+            //The slower of the two sines comes round every 2π seconds
+            let positions = (0..6_300).map(|ms| arm.brain_location(ms)).collect::<Vec<u64>>();
+            let (closest, furthest) = (*positions.iter().min().unwrap(), *positions.iter().max().unwrap());
+            assert!(closest >= baseline - BRAIN_MOTION_AMPLITUDE_NM && furthest <= baseline + BRAIN_MOTION_AMPLITUDE_NM,
+                "The brain swung from {} to {} around {}", closest, furthest, baseline);
+            assert!(closest > margin, "The brain came to {} with a baseline of {}", closest, baseline);
+        }
+    }
+
+    // A baseline the brain would swing to within the calibration margin of the origin is rejected
+    #[test]
+    #[should_panic(expected = "leaves no room")]
+    fn test_brain_baseline_without_room_for_the_margin() {
+        RobotArmBuilder::new().brain_baseline_nm(BRAIN_MOTION_AMPLITUDE_NM + 100_000, 250_000);
+    }
+
+    // An inserter sitting past the brain surface reads as an acquisition error instead of aborting the task
+    #[tokio::test]
+    async fn test_distance_past_brain_is_an_error() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().initial_z(9_000_000).build()));
+        let (distance_tx, distance_rx) = mpsc::channel(1);
+        let task = tokio::spawn(get_distance(Arc::clone(&robot), distance_rx));
+        for _ in 0..3 {
+            let (tx, rx) = oneshot::channel();
+            distance_tx.send(((), tx)).await.unwrap();
+            assert!(matches!(rx.await.unwrap(), Err(OCTError::AcquisitionError{..})));
+        }
+        drop(distance_tx);
+        task.await.unwrap();
+    }
+
+    // Distance errors are stamped with when they were answered, the read's latency after it was requested
+    #[tokio::test(start_paused = true)]
+    async fn test_distance_error_timestamp() {
+        let robot = Arc::new(Mutex::new(RobotArmBuilder::new().distance_errors(true).error_probability(1.0).build()));
+        let (distance_tx, distance_rx) = mpsc::channel(1);
+        tokio::spawn(get_distance(Arc::clone(&robot), distance_rx));
+        sleep(Duration::from_millis(2_000)).await;
+        let (tx, rx) = oneshot::channel();
+        distance_tx.send(((), tx)).await.unwrap();
+        let error = rx.await.unwrap().unwrap_err();
+        assert!(matches!(error, OCTError::CommunicationError{..}));
+        let at_ms = error.at_ms().unwrap();
+        assert!((2_000 + OCT_LATENCY_MILLIS..=2_000 + OCT_LATENCY_MILLIS + 1).contains(&at_ms), "Stamped at {}ms", at_ms);
+    }
+
+    // Fraction of back to back OCT reads after which the Taylor predictor accepts the samples so far
+    async fn taylor_acceptance(oct_jitter: OCTJitter) -> f64 {
+        use crate::predictor::{BrainPredictor, DistanceWindow};
+        use crate::predictor::taylor_approx::TaylorQuadraticApproximator;
+        const READS: usize = 100;
+        let robot = Mutex::new(RobotArmBuilder::new().error_probability(0.0).oct_jitter(oct_jitter).build());
+        let mut distances = Vec::new();
+        let mut times = Vec::new();
+        let predictor = TaylorQuadraticApproximator{};
+        let mut accepted = 0;
+        for _ in 0..READS {
+            distances.push(read_distance(&robot).await);
+            times.push(Instant::now());
+            if predictor.predict(&DistanceWindow::new(&distances, &times), None).is_some() {
+                accepted += 1;
+            }
+        }
+        accepted as f64 / READS as f64
+    }
+
+    // Latency jitter well past the Taylor predictor's latency std limit should make it reject most samples.
+    // The clock is paused so the steady reads aren't jittered by a busy test runner instead
+    #[tokio::test(start_paused = true)]
+    async fn test_oct_jitter_breaks_taylor_assumptions() {
+        let steady = taylor_acceptance(OCTJitter::None).await;
+        assert!(steady > 0.9, "Expected steady reads to be accepted but only {} were", steady);
+        for jitter in [OCTJitter::Uniform { max_ms: 12 }, OCTJitter::Gaussian { std_ms: 8.0 }] {
+            let jittered = taylor_acceptance(jitter).await;
+            assert!(jittered < steady / 2.0, "Expected {:?} to be rejected more often but {} were accepted vs {}", jitter, jittered, steady);
+        }
+    }
+
+    // A needle 500um past a still brain pushes it away by half of that, which the OCT sees until the needle is out
+    #[tokio::test(start_paused = true)]
+    async fn test_dimpling_displaces_brain() {
+        let mut arm = RobotArmBuilder::new().error_probability(0.0).dimpling(Dimpling::Proportional { fraction: 0.5 }).build();
+        arm.brain_location_fn = |_| 1_000_000;
+        let robot = Arc::new(Mutex::new(arm));
+        execute_move(&robot, Move::NeedleZ(1_500_000)).await.unwrap();
+        assert_eq!(read_distance(&robot).await.unwrap(), 1_250_000);
+        assert_eq!(robot.lock().await.brain_distances, vec![250_000]);
+        execute_move(&robot, Move::NeedleZ(0)).await.unwrap();
+        assert_eq!(read_distance(&robot).await.unwrap(), 1_000_000);
+    }
+
+    // Distances are rounded to the nearest step, halfway rounds up, and a brain within half a step reads one step
+    #[tokio::test(start_paused = true)]
+    async fn test_quantized_distance() {
+        let far: fn(u64) -> u64 = |_| 1_234_567;
+        let near: fn(u64) -> u64 = |_| 3_000;
+        let cases = [(far, None, 1_234_567), (far, Some(1_000), 1_235_000), (far, Some(10_000), 1_230_000),
+            (far, Some(1_234_566 * 2), 2_469_132), (near, None, 3_000), (near, Some(10_000), 10_000)];
+        for (brain_location_fn, quantization_nm, expected) in cases {
+            let mut builder = RobotArmBuilder::new().error_probability(0.0);
+            if let Some(quantization_nm) = quantization_nm {
+                builder = builder.quantization_nm(quantization_nm);
+            }
+            let mut arm = builder.build();
+            arm.brain_location_fn = brain_location_fn;
+            let robot = Arc::new(Mutex::new(arm));
+            assert_eq!(read_distance(&robot).await.unwrap(), expected, "Quantized to {:?}", quantization_nm);
         }
     }
-}
\ No newline at end of file
+
+    // Mean length of the runs of failed reads over 2000 reads
+    async fn mean_dropout_length(oct_dropouts: OCTDropouts) -> f64 {
+        let arm = RobotArmBuilder::new().distance_errors(true).error_probability(0.1).oct_dropouts(oct_dropouts).seed(1834).build();
+        let robot = Arc::new(Mutex::new(arm));
+        let mut failed = Vec::new();
+        for _ in 0..2_000 {
+            failed.push(read_distance(&robot).await.is_err());
+        }
+        let runs = failed.windows(2).filter(|w| w[1] && !w[0]).count() + failed[0] as usize;
+        failed.iter().filter(|failed| **failed).count() as f64 / runs as f64
+    }
+
+    // Sticky Markov dropouts fail in long bursts where independent errors rarely fail twice in a row
+    #[tokio::test(start_paused = true)]
+    async fn test_markov_dropouts_cluster() {
+        let independent = mean_dropout_length(OCTDropouts::Independent).await;
+        let bursty = mean_dropout_length(OCTDropouts::Markov { enter_probability: 0.02, stay_probability: 0.9 }).await;
+        assert!(independent < 1.5, "Independent errors came in runs of {}", independent);
+        assert!(bursty > 5.0, "Markov errors came in runs of {}", bursty);
+    }
+
+    // Times a needle insert past the brain to 10mm and the retraction back to 0
+    async fn insert_and_retract_times(arm: RobotArm) -> (Duration, Duration) {
+        let robot = Arc::new(Mutex::new(arm));
+        let (move_tx, move_rx) = mpsc::channel(1);
+        tokio::spawn(mv(Arc::clone(&robot), move_rx));
+        let mut times = Vec::new();
+        for target in [10_000_000, 0] {
+            let (tx, rx) = oneshot::channel();
+            let start = Instant::now();
+            move_tx.send((Move::NeedleZ(target), MovePriority::Normal, tx)).await.unwrap();
+            rx.await.unwrap().unwrap();
+            times.push(start.elapsed());
+        }
+        (times[0], times[1])
+    }
+
+    #[tokio::test]
+    async fn test_needle_retract_speed() {
+        let (insert, retract) = insert_and_retract_times(RobotArmBuilder::new().build()).await;
+        assert!(insert.abs_diff(retract) < Duration::from_millis(20), "Expected equal durations but got {:?} and {:?}", insert, retract);
+        //Four times the acceleration halves the time of a move that never reaches its top speed
+        let (insert, retract) = insert_and_retract_times(RobotArmBuilder::new().needle_retract_accel_nm_ms2(4 * NEEDLE_ACCELERATION_NM_MS).build()).await;
+        assert!(retract < insert * 2 / 3, "Expected a faster retraction but got {:?} and {:?}", insert, retract);
+    }
+
+    //Every point of a retraction, including fractional ms and just past its end, stays between its start and zero
+    #[test]
+    fn test_retraction_interpolates_to_zero() {
+        let arm = RobotArmBuilder::new().build();
+        let start_z: i64 = 3_300_000;
+        let total = arm.calculate_needlez_move_time(start_z, 0, None);
+        let mut last = start_z;
+        for quarter_ms in 0..=(total.as_micros() as u64 / 250 + 4) {
+            let elapsed = Duration::from_micros(quarter_ms * 250 + 125);
+            let pos = arm.interpolate_needlez_position(start_z, 0, elapsed, total, None);
+            assert!((0..=start_z).contains(&pos), "Position {} at {:?} left the move", pos, elapsed);
+            assert!(pos <= last, "Retraction went back up from {} to {} at {:?}", last, pos, elapsed);
+            last = pos;
+        }
+        assert!(last == 0);
+    }
+
+    // A move that errors part way reports where it stopped, somewhere between where it started and its target
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_move_reports_achieved_z() {
+        let arm = RobotArmBuilder::new().move_errors(true).error_probability(1.0).seed(1842).build();
+        let robot = Arc::new(Mutex::new(arm));
+        let start_z = robot.lock().await._get_state().unwrap().inserter_z;
+        let target_z = start_z + 2_000_000;
+        let Err(RobotError::MoveError { achieved_z, .. }) = execute_move(&robot, Move::InserterZ(target_z)).await else {
+            panic!("Expected the move to fail");
+        };
+        assert!(start_z < achieved_z && achieved_z < target_z, "Move from {} to {} stopped at {}", start_z, target_z, achieved_z);
+        assert_eq!(robot.lock().await._get_state().unwrap().inserter_z, achieved_z);
+    }
+}
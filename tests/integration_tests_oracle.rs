@@ -1,62 +1,19 @@
-use neuralink_final::robot;
 use neuralink_final::robot::RobotArm;
-use neuralink_final::controller;
-use std::{sync::Arc, thread};
-use tokio::sync::Mutex;
-use tokio::runtime::Builder;
-use tokio::task::LocalSet;
+use neuralink_final::harness::{self, SessionResult};
 use neuralink_final::predictor::oracle_approx::OraclePredictor;
 use tokio::time::Instant;
 
 const PRECISION: u64 = 200_000;
 //THIS IS BUGGY, DO NOT RUN!!
 
-//This function creates the robot and controller and runs them on their own threads
-//It then returns the controller and robot so that they can be checked in tests
+//This function runs a session of the robot and controller on their own threads
+//It then returns the session result so that it can be checked in tests
 //All tests rely on this function
-fn make_state_oracle_predictor(commands: Vec<u64>,distance_errors: bool, move_errors: bool) -> (Arc<controller::Controller<OraclePredictor>>, Arc<Mutex<RobotArm>>) {
+fn make_state_oracle_predictor(commands: Vec<u64>,distance_errors: bool, move_errors: bool) -> SessionResult {
     println!("Oracle predictor");
-    let (distance_tx, distance_rx) = tokio::sync::mpsc::channel(100);
-    let (state_tx, state_rx) = tokio::sync::mpsc::channel(100);
-    let (move_tx, move_rx) = tokio::sync::mpsc::channel(100);
-    let (dead_tx, dead_rx) = tokio::sync::mpsc::channel(100);
-
-    //Creates the robot simulation
-    let robot = Arc::new(Mutex::new(RobotArm::new(0, distance_errors, move_errors)));
-    let robot_clone = Arc::clone(&robot);
-    //Creates the controller simulation
-    let controller = Arc::new(controller::Controller::new(distance_tx, state_tx, move_tx, dead_tx, OraclePredictor::new()));
-    let controller_clone = Arc::clone(&controller);
-
-     // Create and run the controller on its own thread
-    let handle_one = thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async {
-            controller::start(controller, &commands).await
-        });
-    });
-
-    // Create and run the robot sim on its own thread
-    let handle_two = std::thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async move {
-            robot::start(distance_rx, state_rx, move_rx, dead_rx,robot).await; 
-        });
-    });
-
-    // Wait for both threads to finish
-    handle_one.join().unwrap();
-    handle_two.join().unwrap();
-
-    return (controller_clone, robot_clone);
+    let robot_arm = RobotArm::new(0, distance_errors, move_errors);
+    let oracle = OraclePredictor::new(robot_arm.brain_location_fn, robot_arm.get_init_time());
+    harness::run_session(commands, oracle, robot_arm)
 }
 
 
@@ -70,17 +27,17 @@ fn test_controller_distance_errors_oracle() {
                                 5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
                                 5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
     let time = Instant::now();
-    let (controller, robot) = make_state_oracle_predictor(distances.clone(),true, false);
+    let session = make_state_oracle_predictor(distances.clone(),true, false);
     //Assert that the surgery takes less than 10 seconds per thread
     assert!(time.elapsed().as_secs() < distances.len() as u64 * 10, "Test took longer than expected");
-    //Filter indices with true value from controller_clone.outcomes
-    let outcome_indices = controller.get_outcomes().iter().enumerate().filter(|(_, &x)| x).map(|(i, _)| i).collect::<Vec<usize>>();
-    assert!(outcome_indices.len() == robot.blocking_lock().brain_distances.len());
+    //Filter indices with true value from session.outcomes
+    let outcome_indices = session.outcomes.iter().enumerate().filter(|(_, &x)| x).map(|(i, _)| i).collect::<Vec<usize>>();
+    assert!(outcome_indices.len() == session.brain_distances.len());
 
     let mut abs_distances = Vec::new();
     //Print the commanded vs actual distance
     for (j, i) in outcome_indices.iter().enumerate() {
-        let actual_distance = robot.blocking_lock().brain_distances[j];
+        let actual_distance = session.brain_distances[j];
         let commanded_distance = distances[*i];
         abs_distances.push(actual_distance.abs_diff(commanded_distance));
         print!("{}, {}, {} ", commanded_distance, actual_distance, *i);
@@ -104,11 +61,11 @@ fn test_controller_move_errors_oracle() {
                                 5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
                                 5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
     let time = Instant::now();
-    let (controller, robot) = make_state_oracle_predictor(distances.clone(),false, true);
+    let session = make_state_oracle_predictor(distances.clone(),false, true);
     //Assert that the surgery takes less than 10 seconds per thread
     assert!(time.elapsed().as_secs() < distances.len() as u64 * 10, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    let outcomes = session.outcomes;
+    let robot_distances = session.brain_distances;
     //Find the indices of the moves that succeeded
     let outcome_indices = outcomes.iter().enumerate().filter(|(_, &x)| x).map(|(i, _)| i).collect::<Vec<usize>>();
     assert!(outcome_indices.len() == robot_distances.len());
@@ -133,11 +90,11 @@ fn test_controller_no_errors_oracle() {
                                 5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
                                 5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
     let time = Instant::now();
-    let (controller, robot) = make_state_oracle_predictor(distances.clone(),false, false);
+    let session = make_state_oracle_predictor(distances.clone(),false, false);
     //Assert that the surgery takes less than 10 seconds per thread
     assert!(time.elapsed().as_secs() < distances.len() as u64 * 10, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    let outcomes = session.outcomes;
+    let robot_distances = session.brain_distances;
     //Assert that there were no fails
     //Asser thtat the commanded distances were close enough to the actual distances
     for (i, distance) in robot_distances.iter().enumerate() {
@@ -1,7 +1,10 @@
 use neuralink_final::robot;
-use neuralink_final::robot::RobotArm;
+use neuralink_final::robot::{RobotArm, RobotArmBuilder, SimulatedRobot};
 use neuralink_final::controller;
-use std::{sync::Arc, thread};
+use neuralink_final::controller::{ControllerConfig, ControllerError, ControllerState};
+use neuralink_final::harness::{self, SessionResult};
+use neuralink_final::interface::OCTError;
+use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::runtime::Builder;
 use tokio::task::LocalSet;
@@ -10,53 +13,35 @@ use tokio::time::Instant;
 
 const PRECISION: u64 = 200_000;
 
-//This function creates the robot and controller and runs them on their own threads
-//It then returns the controller and robot so that they can be checked in tests
-//All tests rely on this function
-fn make_state_taylor_predictor(commands: Vec<u64>,distance_errors: bool, move_errors: bool) -> (Arc<controller::Controller<QuadraticRegression>>, Arc<Mutex<RobotArm>>) {
-    let (distance_tx, distance_rx) = tokio::sync::mpsc::channel(100);
-    let (state_tx, state_rx) = tokio::sync::mpsc::channel(100);
-    let (move_tx, move_rx) = tokio::sync::mpsc::channel(100);
-    let (dead_tx, dead_rx) = tokio::sync::mpsc::channel(100);
-
-    //Creates the robot simulation
-    let robot = Arc::new(Mutex::new(RobotArm::new(0, distance_errors, move_errors)));
-    let robot_clone = Arc::clone(&robot);
-    //Creates the controller simulation
-    let controller = Arc::new(controller::Controller::new(distance_tx, state_tx, move_tx, dead_tx, QuadraticRegression{}));
-    let controller_clone = Arc::clone(&controller);
-     // Create and run the controller on its own thread
-    let handle_one = thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async {
-            controller::start(controller, &commands).await
-        });
-    });
-
-    // Create and run the robot sim on its own thread
-    let handle_two = std::thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async move {
-            robot::start(distance_rx, state_rx, move_rx, dead_rx,robot).await; 
-        });
-    });
+//This function runs a session of the robot and controller on their own threads
+//It then returns the session result so that it can be checked in tests
+//Most tests rely on this function
+fn make_state_taylor_predictor(commands: Vec<u64>,distance_errors: bool, move_errors: bool) -> SessionResult {
+    return make_state_with_robot(commands, RobotArm::new(0, distance_errors, move_errors));
+}
 
-    // Wait for both threads to finish
-    handle_one.join().unwrap();
-    handle_two.join().unwrap();
+//Same as make_state_taylor_predictor, but runs against an already configured robot simulation
+fn make_state_with_robot(commands: Vec<u64>, robot_arm: RobotArm) -> SessionResult {
+    return make_state_with_config(commands, robot_arm, ControllerConfig::default());
+}
 
-    return (controller_clone, robot_clone);
+//Same as make_state_with_robot, but with a configured controller as well
+fn make_state_with_config(commands: Vec<u64>, robot_arm: RobotArm, config: ControllerConfig) -> SessionResult {
+    harness::run_session_with_config(commands, QuadraticRegression{}, robot_arm, config)
 }
 
 
+//Smoke test of the harness entry point over the default session
+#[test]
+fn test_run_session_default_commands() {
+    let commands = harness::default_commands();
+    assert!(commands.len() == 30);
+    let session = harness::run_session(commands.clone(), QuadraticRegression{}, RobotArm::new(0, false, false));
+    assert!(session.outcomes.len() == commands.len());
+    assert!(session.move_records.len() == commands.len());
+    assert!(session.brain_distances.len() == session.outcomes.iter().filter(|outcome| **outcome).count());
+}
+
 //Testing sim with no errors
 //Testing with robot state errors are ignored in this testing suite
 #[test]
@@ -68,11 +53,11 @@ fn test_controller_no_errors_quadratic() {
                                 5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
                                 5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
     let time = Instant::now();
-    let (controller, robot) = make_state_taylor_predictor(distances.clone(),false, false);
+    let session = make_state_taylor_predictor(distances.clone(),false, false);
     //Assert that the surgery takes less than 20 seconds per thread
     assert!(time.elapsed().as_secs() < distances.len() as u64 * 20, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    let outcomes = session.outcomes;
+    let robot_distances = session.brain_distances;
     //Assert that there were no fails
     //Asser thtat the commanded distances were close enough to the actual distances
     for (i, distance) in robot_distances.iter().enumerate() {
@@ -91,11 +76,11 @@ fn test_controller_distance_errors_quadratic() {
                                 5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
                                 5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
     let time = Instant::now();
-    let (controller, robot) = make_state_taylor_predictor(distances.clone(),true, false);
+    let session = make_state_taylor_predictor(distances.clone(),true, false);
     //Assert that the surgery takes less than 30 seconds per thread
     assert!(time.elapsed().as_secs() < distances.len() as u64 * 30, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    let outcomes = session.outcomes;
+    let robot_distances = session.brain_distances;
     //Assert that there were no fails
     //Asser thtat the commanded distances were close enough to the actual distances
     for (i, distance) in robot_distances.iter().enumerate() {
@@ -114,11 +99,11 @@ fn test_controller_move_errors_quadratic() {
                                 5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
                                 5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
     let time = Instant::now();
-    let (controller, robot) = make_state_taylor_predictor(distances.clone(),false, true);
+    let session = make_state_taylor_predictor(distances.clone(),false, true);
     //Assert that the surgery takes less than 20 seconds per thread
     assert!(time.elapsed().as_secs() < distances.len() as u64 * 20, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    let outcomes = session.outcomes;
+    let robot_distances = session.brain_distances;
     //Find the indices of the moves that succeeded
     let outcome_indices = outcomes.iter().enumerate().filter(|(_, &x)| x).map(|(i, _)| i).collect::<Vec<usize>>();
     assert!(outcome_indices.len() == robot_distances.len());
@@ -130,4 +115,270 @@ fn test_controller_move_errors_quadratic() {
         assert!(actual_distance.abs_diff(commanded_distance) < PRECISION, "Expected {} but got {}", commanded_distance, actual_distance);
     }
 
-}
\ No newline at end of file
+}
+//Testing that the controller keeps exactly one move record per commanded depth
+#[test]
+fn test_controller_move_records_quadratic() {
+    let distances = vec![3_100_000, 4_000_000, 5_000_000, 6_000_000];
+    let session = make_state_taylor_predictor(distances.clone(),false, true);
+    let records = session.move_records;
+    assert!(records.len() == distances.len(), "Expected {} records but got {}", distances.len(), records.len());
+    //The records should be in commanded order and agree with the robot on the number of successes
+    for (record, distance) in records.iter().zip(distances.iter()) {
+        assert!(record.commanded_depth == *distance);
+        assert!(record.attempts >= 1);
+    }
+    assert!(records.iter().filter(|record| record.success).count() == session.brain_distances.len());
+    assert!(session.outcomes == records.iter().map(|record| record.success).collect::<Vec<bool>>());
+}
+
+//Testing sim with an inserter at half its default speed, which slows calibration and panics
+//but should not affect the precision of the needle moves
+#[test]
+fn test_controller_slow_inserter_quadratic() {
+    let distances = vec![3_100_000, 4_000_000, 5_000_000, 6_000_000];
+    let robot_arm = RobotArmBuilder::new().inserter_velocity_nm_ms(4_750).build();
+    let session = make_state_with_robot(distances.clone(), robot_arm);
+    let outcomes = session.outcomes;
+    let robot_distances = session.brain_distances;
+    for (i, distance) in robot_distances.iter().enumerate() {
+        assert!(outcomes[i], "Move failed in no error environment for move {} with outcome {}", i, outcomes[i]);
+        assert!(distance.abs_diff(distances[i]) < PRECISION, "Expected {} but got {}", distances[i], distance);
+    }
+}
+
+//Testing sim with only robot state errors
+//Connection errors on state reads are retried, a rare position error stops the run early,
+//so we only check that the run completes and that the successful moves were accurate
+#[test]
+fn test_controller_state_errors() {
+    let distances = vec![3_100_000, 4_000_000, 5_000_000, 6_000_000];
+    let robot_arm = RobotArmBuilder::new().state_errors(true).build();
+    let session = make_state_with_robot(distances.clone(), robot_arm);
+    let records = session.move_records;
+    let robot_distances = session.brain_distances;
+    assert!(records.len() <= distances.len());
+    let successful_records = records.iter().filter(|record| record.success).collect::<Vec<_>>();
+    assert!(successful_records.len() == robot_distances.len());
+    for (record, actual_distance) in successful_records.iter().zip(robot_distances.iter()) {
+        assert!(actual_distance.abs_diff(record.commanded_depth) < PRECISION, "Expected {} but got {}", record.commanded_depth, actual_distance);
+    }
+}
+
+//Testing that a controller session cleans up after itself so another can run on the same runtime
+#[test]
+fn test_sequential_sessions_one_runtime() {
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+    local.block_on(&rt, async {
+        for _ in 0..2 {
+            let (distance_tx, distance_rx) = tokio::sync::mpsc::channel(100);
+            let (state_tx, state_rx) = tokio::sync::mpsc::channel(100);
+            let (move_tx, move_rx) = tokio::sync::mpsc::channel(100);
+            let (dead_tx, dead_rx) = tokio::sync::mpsc::channel(100);
+            let robot = Arc::new(Mutex::new(RobotArm::new(0, false, false)));
+            let controller = Arc::new(controller::Controller::new(distance_tx, state_tx, move_tx, dead_tx, QuadraticRegression{}));
+            let robot_task = tokio::task::spawn_local(robot::start(distance_rx, state_rx, move_rx, dead_rx, Arc::clone(&robot)));
+            controller::start(Arc::clone(&controller), &[4_000_000]).await.unwrap();
+            robot_task.await.unwrap();
+            //Every polling and processing task holds a clone of the controller, so we should hold the only one left
+            assert!(Arc::strong_count(&controller) == 1, "Controller tasks still alive: {}", Arc::strong_count(&controller));
+            assert!(controller.get_outcomes().len() == 1);
+        }
+    });
+}
+
+//Testing that a depth the needle can't reach is given up on instead of retried forever
+#[test]
+fn test_controller_unreachable_depth() {
+    let distances = vec![6_000_000, 3_100_000];
+    let robot_arm = RobotArmBuilder::new().max_needle_z_nm(4_500_000).build();
+    let config = ControllerConfig{max_attempts_per_depth: 2, ..ControllerConfig::default()};
+    let session = make_state_with_config(distances.clone(), robot_arm, config);
+    let records = session.move_records;
+    assert!(records.len() == distances.len());
+    assert!(!records[0].success, "Unreachable depth was marked a success");
+    assert!(records[0].attempts == 2, "Expected 2 attempts but got {}", records[0].attempts);
+    assert!(records.iter().filter(|record| record.success).count() == session.brain_distances.len());
+}
+
+//Testing a full insertion sequence with the controller calling the robot simulation directly,
+//with no channels and no robot tasks, on a single thread
+#[test]
+fn test_controller_direct_robot() {
+    let distances = vec![3_100_000, 4_000_000, 5_000_000, 6_000_000];
+    let simulated = Arc::new(SimulatedRobot::new(RobotArm::new(0, false, false)));
+    let robot = simulated.arm();
+    let controller = Arc::new(controller::Controller::with_robot(simulated, QuadraticRegression{}));
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+    local.block_on(&rt, controller::start(Arc::clone(&controller), &distances)).unwrap();
+    let outcomes = controller.get_outcomes();
+    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    assert!(outcomes.len() == distances.len());
+    assert!(robot_distances.len() == distances.len());
+    for (i, distance) in robot_distances.iter().enumerate() {
+        assert!(outcomes[i], "Move failed in no error environment for move {} with outcome {}", i, outcomes[i]);
+        assert!(distance.abs_diff(distances[i]) < PRECISION, "Expected {} but got {}", distances[i], distance);
+    }
+}
+
+//Testing that a brain resting at 5mm instead of the default 7mm is calibrated for where it is, parking
+//the inserter the calibration margin above the closest it came, and that insertions still meet PRECISION
+#[test]
+fn test_shallow_brain_baseline() {
+    let distances = vec![3_100_000, 4_000_000, 5_000_000];
+    let simulated = Arc::new(SimulatedRobot::new(RobotArmBuilder::new().brain_baseline_nm(5_000_000, ControllerConfig::default().calibration_margin_nm).error_probability(0.0).build()));
+    let robot = simulated.arm();
+    let controller = Arc::new(controller::Controller::with_robot(simulated, QuadraticRegression{}));
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+    local.block_on(&rt, controller::start(Arc::clone(&controller), &distances)).unwrap();
+    //The brain swings 1.5mm either side of its baseline
+    let calibration = controller.last_calibration().unwrap();
+    assert!((3_500_000..=6_500_000).contains(&calibration.min_distance), "Unexpected calibration: {:?}", calibration);
+    let margin = ControllerConfig::default().calibration_margin_nm;
+    assert!(calibration.pre_move_location == Some(calibration.min_distance - margin), "Unexpected calibration: {:?}", calibration);
+    let outcomes = controller.get_outcomes();
+    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    assert!(robot_distances.len() == distances.len());
+    for (i, distance) in robot_distances.iter().enumerate() {
+        assert!(outcomes[i], "Move failed at a 5mm baseline for move {}", i);
+        assert!(distance.abs_diff(distances[i]) < PRECISION, "Expected {} but got {}", distances[i], distance);
+    }
+}
+
+//Testing that with an in brain time budget too tight for any needle move, the controller refuses
+//to start moves instead of overshooting the budget
+#[test]
+fn test_controller_tight_ib_time() {
+    //The controller's polling interval, the most we allow an insertion to overshoot by
+    const POLL_MILLIS: u64 = 5;
+    let distances = vec![3_100_000, 4_000_000];
+    let config = ControllerConfig{max_ib_time_ms: 150, ..ControllerConfig::default()};
+    let session = make_state_with_config(distances.clone(), RobotArm::new(0, false, false), config.clone());
+    let records = session.move_records;
+    assert!(records.len() == distances.len());
+    assert!(session.brain_distances.is_empty(), "A move was started that couldn't finish within the budget");
+    for record in records {
+        assert!(!record.success);
+        assert!(record.time_in_brain_ms <= record.attempts * (config.max_ib_time_ms + POLL_MILLIS), "Spent {}ms in brain over {} attempts", record.time_in_brain_ms, record.attempts);
+    }
+}
+
+
+//Testing that replaying a recorded seizure, where the brain suddenly lunges at the inserter,
+//panics on exactly the sample the lunge fills most of the too close window in
+#[test]
+fn test_replayed_seizure_panics() {
+    const SAMPLE_MILLIS: u64 = 5;
+    const SEIZURE_SAMPLE: usize = 1_080;
+    //A still brain 1mm away for calibration, 300um away once we move to the premove location so that
+    //we never move, then a lunge to within 50um
+    let trace = (0..SEIZURE_SAMPLE + 10).map(|i| {
+        let distance = if i < 1_020 {1_000_000} else if i < SEIZURE_SAMPLE {300_000} else {50_000};
+        (i as u64 * SAMPLE_MILLIS, Ok(distance))
+    }).collect::<Vec<(u64, Result<u64, OCTError>)>>();
+    let simulated = Arc::new(SimulatedRobot::new(RobotArm::new(0, false, false)));
+    let robot = simulated.arm();
+    let controller = Arc::new(controller::Controller::with_robot(simulated, QuadraticRegression{}));
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+    local.block_on(&rt, controller::start_with_distance_source(Arc::clone(&controller), &[3_100_000], trace)).unwrap();
+    //The default too close window of 3 needs 2 samples of the lunge
+    let panic_sample = SEIZURE_SAMPLE as u64 + 1;
+    assert!(controller.get_panic_samples() == vec![panic_sample], "Expected a panic at sample {} but got {:?}", panic_sample, controller.get_panic_samples());
+    assert!(robot.blocking_lock().brain_distances.is_empty());
+    assert!(!controller.get_move_records()[0].success);
+}
+
+//Testing that status snapshots follow the state machine through calibration, using a recording
+//of a still brain so the run is short and deterministic
+#[test]
+fn test_status_tracks_calibration() {
+    const SAMPLE_MILLIS: u64 = 5;
+    let trace = (0..1_080u64).map(|i| (i * SAMPLE_MILLIS, Ok(if i < 1_020 {1_000_000} else {300_000})))
+        .collect::<Vec<(u64, Result<u64, OCTError>)>>();
+    let simulated = Arc::new(SimulatedRobot::new(RobotArm::new(0, false, false)));
+    let controller = Arc::new(controller::Controller::with_robot(simulated, QuadraticRegression{}));
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+    let states = local.block_on(&rt, async {
+        //Records every state change until the controller has started and then died
+        let watcher = tokio::task::spawn_local({let controller = Arc::clone(&controller);
+        async move {
+            let mut states = vec![controller.status().state];
+            while !(states.len() > 1 && *states.last().unwrap() == ControllerState::Dead) {
+                tokio::time::sleep(tokio::time::Duration::from_millis(SAMPLE_MILLIS)).await;
+                let state = controller.status().state;
+                if state != *states.last().unwrap() {
+                    states.push(state);
+                }
+            }
+            states
+        }});
+        controller::start_with_distance_source(Arc::clone(&controller), &[3_100_000], trace).await.unwrap();
+        watcher.await.unwrap()
+    });
+    let uncalibrated = states.iter().position(|state| *state == ControllerState::OutOfBrainUncalibrated);
+    let calibrated = states.iter().position(|state| *state == ControllerState::OutOfBrainCalibrated);
+    assert!(matches!((uncalibrated, calibrated), (Some(u), Some(c)) if u < c), "Unexpected state sequence: {:?}", states);
+    let status = controller.status();
+    assert!(status.pre_move_location == Some(1_000_000 - ControllerConfig::default().calibration_margin_nm));
+    assert!(matches!(status.last_distance, Some(Ok(300_000))));
+}
+
+//Testing that when the thread can never be grasped, every depth is recorded as a failure after
+//its attempts run out and the needle never enters the brain
+#[test]
+fn test_controller_grasp_failures() {
+    let distances = vec![3_100_000, 4_000_000];
+    let simulated = Arc::new(SimulatedRobot::new(RobotArmBuilder::new().grasp_error_probability(1.0).build()));
+    let robot = simulated.arm();
+    let controller = Arc::new(controller::Controller::with_robot(simulated, QuadraticRegression{}));
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+    local.block_on(&rt, controller::start(Arc::clone(&controller), &distances)).unwrap();
+    let records = controller.get_move_records();
+    assert!(records.len() == distances.len());
+    for record in records {
+        assert!(!record.success, "Depth {} succeeded without a grasp", record.commanded_depth);
+        assert!(record.attempts == ControllerConfig::default().max_attempts_per_depth);
+        assert!(record.predicted_target.is_none());
+    }
+    assert!(robot.blocking_lock().brain_distances.is_empty());
+}
+
+//Testing that a brain that comes closer than the safety margin during calibration makes the
+//controller give up after a few failed calibrations instead of panicking the thread
+#[test]
+fn test_calibration_without_safe_location() {
+    let distances = vec![3_100_000, 4_000_000];
+    let mut robot_arm = RobotArm::new(0, false, false);
+    robot_arm.brain_location_fn = |_| 150_000;
+    let config = ControllerConfig{max_failed_calibrations: 2, ..ControllerConfig::default()};
+    let session = make_state_with_config(distances, robot_arm, config);
+    assert!(session.move_records.len() == 1, "Expected to stop after the first depth but got {:?}", session.move_records);
+    assert!(!session.move_records[0].success);
+    assert!(session.move_records[0].attempts == 0);
+    assert!(session.brain_distances.is_empty());
+    assert!(session.error == Some(ControllerError::CalibrationFailed), "Unexpected error: {:?}", session.error);
+}
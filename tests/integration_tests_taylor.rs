@@ -1,133 +1,101 @@
-use neuralink_final::robot;
-use neuralink_final::robot::RobotArm;
-use neuralink_final::controller;
-use std::{sync::Arc, thread};
-use tokio::sync::Mutex;
+use neuralink_final::robot::{OCTJitter, RobotArmBuilder};
+use neuralink_final::controller::{ControllerConfig, COMMANDED_DEPTH_MAX_NM, COMMANDED_DEPTH_MIN_NM};
+use neuralink_final::harness::{self, SessionResult};
+use neuralink_final::predictor::taylor_approx::TaylorQuadraticApproximator;
 use tokio::runtime::Builder;
 use tokio::task::LocalSet;
-use neuralink_final::predictor::taylor_approx::TaylorQuadraticApproximator;
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 
 const PRECISION: u64 = 300_000;
 
-//This function creates the robot and controller and runs them on their own threads
-//It then returns the controller and robot so that they can be checked in tests
-//All tests rely on this function
-fn make_state_taylor_predictor(commands: Vec<u64>,distance_errors: bool, move_errors: bool) -> (Arc<controller::Controller<TaylorQuadraticApproximator>>, Arc<Mutex<RobotArm>>) {
-    let (distance_tx, distance_rx) = tokio::sync::mpsc::channel(100);
-    let (state_tx, state_rx) = tokio::sync::mpsc::channel(100);
-    let (move_tx, move_rx) = tokio::sync::mpsc::channel(100);
-    let (dead_tx, dead_rx) = tokio::sync::mpsc::channel(100);
-
-    //Creates the robot simulation
-    let robot = Arc::new(Mutex::new(RobotArm::new(0, distance_errors, move_errors)));
-    let robot_clone = Arc::clone(&robot);
-    //Creates the controller simulation
-    let controller = Arc::new(controller::Controller::new(distance_tx, state_tx, move_tx, dead_tx, TaylorQuadraticApproximator{}));
-    let controller_clone = Arc::clone(&controller);
-     // Create and run the controller on its own thread
-    let handle_one = thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async {
-            controller::start(controller, &commands).await
-        });
-    });
-
-    // Create and run the robot sim on its own thread
-    let handle_two = std::thread::spawn(move || {
-        let rt = Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let local = LocalSet::new();
-        local.block_on(&rt,async move {
-            robot::start(distance_rx, state_rx, move_rx, dead_rx,robot).await; 
-        });
-    });
-
-    // Wait for both threads to finish
-    handle_one.join().unwrap();
-    handle_two.join().unwrap();
-
-    return (controller_clone, robot_clone);
+//This function runs a session of the robot and controller on one thread with a paused clock, so the session
+//only depends on its seed and not on how busy the machine is. It returns the session result so that it can be
+//checked in tests, along with how long the session took in simulated time.
+//All tests rely on this function. The robot is seeded by harness::session_seed, so a failure can be re-run
+//with the seed its assertion reports
+fn make_state_taylor_predictor(commands: Vec<u64>,distance_errors: bool, move_errors: bool) -> (SessionResult, Duration) {
+    let robot_arm = RobotArmBuilder::new().distance_errors(distance_errors).move_errors(move_errors).seed(harness::session_seed()).build();
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    LocalSet::new().block_on(&rt, async {
+        let time = Instant::now();
+        let session = harness::run_session_local(commands, TaylorQuadraticApproximator{}, robot_arm, ControllerConfig::default()).await;
+        (session, time.elapsed())
+    })
 }
 
+//Runs a session of the given commands and asserts every successful move landed within precision of its
+//commanded depth. The robot only records a distance for successful moves, so the n-th distance belongs to
+//the n-th successful command. Returns the session so tests can check the outcomes they expect
+fn run_and_assert(commands: Vec<u64>, distance_errors: bool, move_errors: bool, precision: u64) -> SessionResult {
+    let (session, elapsed) = make_state_taylor_predictor(commands.clone(), distance_errors, move_errors);
+    //Assert that the surgery takes less than 20 seconds per thread. Dropouts stall the predictions for a few samples
+    //each, so like the quadratic suite we give distance errors 30 seconds per thread
+    let secs_per_thread = if distance_errors { 30 } else { 20 };
+    assert!(elapsed.as_secs() < commands.len() as u64 * secs_per_thread, "Test took {}s, longer than expected, seed={}", elapsed.as_secs(), session.seed);
+    assert!(session.outcomes.len() == commands.len(), "Session stopped after {:?}, seed={}", session.outcomes, session.seed);
+    //Find the indices of the moves that succeeded
+    let outcome_indices = session.outcomes.iter().enumerate().filter(|(_, &x)| x).map(|(i, _)| i).collect::<Vec<usize>>();
+    assert!(outcome_indices.len() == session.brain_distances.len(), "{} successes but {} brain distances, seed={}", outcome_indices.len(), session.brain_distances.len(), session.seed);
+    //Assert that the commanded distances were close enough to the actual distances on the successful moves
+    for (i, actual_distance) in outcome_indices.iter().zip(session.brain_distances.iter()) {
+        let commanded_distance = commands[*i];
+        assert!(actual_distance.abs_diff(commanded_distance) < precision, "Expected {} but got {} for move {}, seed={}", commanded_distance, actual_distance, i, session.seed);
+    }
+    session
+}
 
 //Testing sim with no errors
 //Testing with robot state errors are ignored in this testing suite
 #[test]
 fn test_controller_no_errors_taylor() {
-    let distances = vec![3_100_000, 3_200_000, 3_300_000, 3_400_000, 3_500_000,
-                                3_600_000, 3_700_000, 3_800_000, 3_900_000, 4_000_000,
-                                4_100_000, 4_200_000, 4_300_000, 4_400_000, 4_500_000,
-                                4_600_000, 4_700_000, 4_800_000, 4_900_000, 5_000_000,
-                                5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
-                                5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
-    let time = Instant::now();
-    let (controller, robot) = make_state_taylor_predictor(distances.clone(),false, false);
-    //Assert that the surgery takes less than 10 seconds per thread
-    assert!(time.elapsed().as_secs() < distances.len() as u64 * 20, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
+    let session = run_and_assert(harness::default_commands(), false, false, PRECISION);
     //Assert that there were no fails
-    //Asser thtat the commanded distances were close enough to the actual distances
-    for (i, distance) in robot_distances.iter().enumerate() {
-        assert!(outcomes[i], "Move failed in no error environment for move {} with outcome {}", i, outcomes[i]);
-        assert!(distance.abs_diff(distances[i]) < PRECISION, "Expected {} but got {}", distances[i], distance);
-    }
+    assert!(session.outcomes.iter().all(|outcome| *outcome), "Move failed in no error environment: {:?}, seed={}", session.outcomes, session.seed);
 }
 
 //Testing sim with only distance errors
 #[test]
 fn test_controller_distance_errors_taylor() {
-    let distances = vec![3_100_000, 3_200_000, 3_300_000, 3_400_000, 3_500_000,
-                                3_600_000, 3_700_000, 3_800_000, 3_900_000, 4_000_000,
-                                4_100_000, 4_200_000, 4_300_000, 4_400_000, 4_500_000,
-                                4_600_000, 4_700_000, 4_800_000, 4_900_000, 5_000_000,
-                                5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
-                                5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
-    let time = Instant::now();
-    let (controller, robot) = make_state_taylor_predictor(distances.clone(),true, false);
-    //Assert that the surgery takes less than 10 seconds per thread
-    assert!(time.elapsed().as_secs() < distances.len() as u64 * 20, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
-    //Assert that there were no fails
-    //Asser thtat the commanded distances were close enough to the actual distances
-    for (i, distance) in robot_distances.iter().enumerate() {
-        assert!(outcomes[i], "Move didnt succeeded in distance error environment for move {} with outcome {}", i, outcomes[i]);
-        assert!(distance.abs_diff(distances[i]) < PRECISION, "Expected {} but got {}", distances[i], distance);
-    }
+    let session = run_and_assert(harness::default_commands(), true, false, PRECISION);
+    assert!(session.outcomes.iter().all(|outcome| *outcome), "Move didnt succeed in distance error environment: {:?}, seed={}", session.outcomes, session.seed);
 }
 
-//Testing sim with only move errors
+//Testing sim with only move errors, where failed moves are expected
 #[test]
 fn test_controller_move_errors_taylor() {
-    let distances = vec![3_100_000, 3_200_000, 3_300_000, 3_400_000, 3_500_000,
-                                3_600_000, 3_700_000, 3_800_000, 3_900_000, 4_000_000,
-                                4_100_000, 4_200_000, 4_300_000, 4_400_000, 4_500_000,
-                                4_600_000, 4_700_000, 4_800_000, 4_900_000, 5_000_000,
-                                5_100_000, 5_200_000, 5_300_000, 5_400_000, 5_500_000,
-                                5_600_000, 5_700_000, 5_800_000, 5_900_000, 6_000_000];
-    let time = Instant::now();
-    let (controller, robot) = make_state_taylor_predictor(distances.clone(),false, true);
-    //Assert that the surgery takes less than 10 seconds per thread
-    assert!(time.elapsed().as_secs() < distances.len() as u64 * 20, "Test took longer than expected");
-    let outcomes = controller.get_outcomes();
-    let robot_distances = robot.blocking_lock().brain_distances.clone();
-    //Find the indices of the moves that succeeded
-    let outcome_indices = outcomes.iter().enumerate().filter(|(_, &x)| x).map(|(i, _)| i).collect::<Vec<usize>>();
-    assert!(outcome_indices.len() == robot_distances.len());
-    //Asser thtat the commanded distances were close enough to the actual distances on the successful moves
-    for (j, i) in outcome_indices.iter().enumerate() {
-        let actual_distance = robot_distances[j];
-        let commanded_distance = distances[*i];
-        print!("{}, {}, {} ", commanded_distance, actual_distance, *i);
-        assert!(actual_distance.abs_diff(commanded_distance) < PRECISION, "Expected {} but got {}", commanded_distance, actual_distance);
-    }
+    run_and_assert(harness::default_commands(), false, true, PRECISION);
+}
+
+//Testing the shallowest and deepest depths the controller accepts, which the evenly spaced list never reaches
+#[test]
+fn test_controller_boundary_depths_taylor() {
+    let session = run_and_assert(vec![COMMANDED_DEPTH_MIN_NM, 5_000_000, COMMANDED_DEPTH_MAX_NM], false, false, PRECISION);
+    assert!(session.outcomes.iter().all(|outcome| *outcome), "Move failed at a boundary depth: {:?}, seed={}", session.outcomes, session.seed);
+}
 
-}
\ No newline at end of file
+//Testing the boundary depths with move errors, where failed moves are expected
+#[test]
+fn test_controller_boundary_depths_move_errors_taylor() {
+    run_and_assert(vec![COMMANDED_DEPTH_MIN_NM, 5_000_000, COMMANDED_DEPTH_MAX_NM], false, true, PRECISION);
+}
+
+//Testing sim with OCT latency jitter well past what the Taylor predictor accepts. Moves get rarer,
+//but the run should still finish every depth and the moves it does make should stay accurate
+#[test]
+fn test_controller_oct_jitter_taylor() {
+    let distances = vec![3_100_000, 4_000_000];
+    let robot_arm = RobotArmBuilder::new().oct_jitter(OCTJitter::Uniform { max_ms: 12 }).seed(harness::session_seed()).build();
+    //Most attempts run out their budget waiting for an accepted prediction, so keep them few
+    let config = ControllerConfig{max_attempts_per_depth: 2, ..ControllerConfig::default()};
+    let session = harness::run_session_with_config(distances.clone(), TaylorQuadraticApproximator{}, robot_arm, config);
+    assert!(session.move_records.len() == distances.len(), "Session stopped after {} records, seed={}", session.move_records.len(), session.seed);
+    let successful_records = session.move_records.iter().filter(|record| record.success).collect::<Vec<_>>();
+    assert!(successful_records.len() == session.brain_distances.len(), "{} successes but {} brain distances, seed={}", successful_records.len(), session.brain_distances.len(), session.seed);
+    for (record, actual_distance) in successful_records.iter().zip(session.brain_distances.iter()) {
+        assert!(actual_distance.abs_diff(record.commanded_depth) < PRECISION, "Expected {} but got {}, seed={}", record.commanded_depth, actual_distance, session.seed);
+    }
+}
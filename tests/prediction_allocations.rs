@@ -0,0 +1,82 @@
+use neuralink_final::interface::OCTError;
+use neuralink_final::predictor::{BrainPredictor, DistanceWindow};
+use neuralink_final::predictor::oracle_approx::OraclePredictor;
+use neuralink_final::predictor::quadratic_regression::QuadraticRegression;
+use neuralink_final::predictor::taylor_approx::TaylorQuadraticApproximator;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+//Counts the bytes allocated on the current thread, so other test threads don't skew the count
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED_BYTES.with(|bytes| bytes.set(bytes.get() + layout.size()));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocated_bytes() -> usize {
+    ALLOCATED_BYTES.with(|bytes| bytes.get())
+}
+
+//Size of the calibration queues, the largest the controller ever predicts from
+const QUEUE_LEN: usize = 1_000;
+
+//Predicting from a full queue should cost a handful of small buffers, not a copy of the queue
+fn assert_prediction_does_not_copy_queues<P: BrainPredictor>(predictor: P) {
+    let now = Instant::now();
+    //Fill the queues the way the controller does, pushing to the back and trimming the front,
+    //so they wrap around and make_contiguous has to do some work
+    let mut distance_queue = VecDeque::new();
+    let mut time_queue = VecDeque::new();
+    for i in 0..QUEUE_LEN + QUEUE_LEN / 2 {
+        distance_queue.push_back(Ok(1_000_000 + i as u64));
+        time_queue.push_back(now - Duration::from_millis((QUEUE_LEN + QUEUE_LEN / 2 - i) as u64));
+        while distance_queue.len() > QUEUE_LEN {
+            distance_queue.pop_front();
+            time_queue.pop_front();
+        }
+    }
+
+    let before = allocated_bytes();
+    let predicted = predictor.predict(&DistanceWindow::new(distance_queue.make_contiguous(), time_queue.make_contiguous()), None).is_some();
+    let allocated = allocated_bytes() - before;
+    assert!(predicted);
+
+    let queue_bytes = QUEUE_LEN * (std::mem::size_of::<Result<u64, OCTError>>() + std::mem::size_of::<Instant>());
+    let before = allocated_bytes();
+    let copies = (Vec::from(distance_queue.clone()), Vec::from(time_queue.clone()));
+    let copy_allocated = allocated_bytes() - before;
+    drop(copies);
+    assert!(copy_allocated >= queue_bytes, "Copying the queues allocated {} bytes, less than their {} bytes", copy_allocated, queue_bytes);
+    assert!(allocated < queue_bytes / 10, "Prediction allocated {} bytes, more than a tenth of the {} byte queues", allocated, queue_bytes);
+}
+
+#[test]
+fn test_quadratic_prediction_does_not_copy_queues() {
+    assert_prediction_does_not_copy_queues(QuadraticRegression{});
+}
+
+#[test]
+fn test_taylor_prediction_does_not_copy_queues() {
+    assert_prediction_does_not_copy_queues(TaylorQuadraticApproximator{});
+}
+
+#[test]
+fn test_oracle_prediction_does_not_copy_queues() {
+    assert_prediction_does_not_copy_queues(OraclePredictor::new(|_| 7_000_000, Instant::now()));
+}